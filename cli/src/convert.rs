@@ -30,6 +30,10 @@ pub(crate) fn parse_proto(src: &str) -> Result<ShareProtocolNexus, &str> {
         "nbd" => Ok(ShareProtocolNexus::NexusNbd),
         "nvmf" => Ok(ShareProtocolNexus::NexusNvmf),
         "iscsi" => Ok(ShareProtocolNexus::NexusIscsi),
-        _ => Err("Protocol needs be either NVMf, iSCSI or NBD"),
+        "vhost-user-blk" => Ok(ShareProtocolNexus::NexusVhostUserBlk),
+        "ublk" => Ok(ShareProtocolNexus::NexusUblk),
+        _ => Err(
+            "Protocol needs be either NVMf, iSCSI, NBD, ublk or vhost-user-blk",
+        ),
     }
 }
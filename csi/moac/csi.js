@@ -314,20 +314,19 @@ class CsiServer {
         i++
       ) {
         const reqs = args.accessibilityRequirements.requisite[i];
-        for (const key in reqs.segments) {
-          // We are not able to evaluate any other topology requirements than
-          // the hostname req. Reject all others.
-          if (key !== 'kubernetes.io/hostname') {
-            return cb(
-              new GrpcError(
-                grpc.status.INVALID_ARGUMENT,
-                'Volume topology other than hostname not supported'
-              )
-            );
-          } else {
-            mustNodes.push(reqs.segments[key]);
-          }
+        // We are not able to evaluate any other topology requirements than
+        // the hostname req. Other segments reported by the node (zone, rack)
+        // are implied by the hostname. Reject all others.
+        const nodeName = reqs.segments['kubernetes.io/hostname'];
+        if (!nodeName) {
+          return cb(
+            new GrpcError(
+              grpc.status.INVALID_ARGUMENT,
+              'Volume topology other than hostname not supported'
+            )
+          );
         }
+        mustNodes.push(nodeName);
       }
       for (
         let i = 0;
@@ -374,6 +374,42 @@ module.exports = function () {
         });
       });
 
+      it('should create volume on node with zone and rack', async () => {
+        createVolumeStub.resolves(returnedVolume);
+        await client.createVolume().sendMessage({
+          name: 'pvc-' + UUID,
+          capacityRange: {
+            requiredBytes: 50,
+            limitBytes: 0
+          },
+          volumeCapabilities: [
+            {
+              accessMode: { mode: 'SINGLE_NODE_WRITER' },
+              filesystem: {}
+            }
+          ],
+          accessibilityRequirements: {
+            requisite: [
+              {
+                segments: {
+                  'kubernetes.io/hostname': 'node',
+                  'topology.kubernetes.io/zone': 'zone',
+                  'openebs.io/rack': 'rack'
+                }
+              }
+            ]
+          },
+          parameters: { protocol: 'nbd' }
+        });
+        sinon.assert.calledWith(createVolumeStub, UUID, {
+          replicaCount: 1,
+          preferredNodes: [],
+          requiredNodes: ['node'],
+          requiredBytes: 50,
+          limitBytes: 0
+        });
+      });
+
       it('should create volume on preferred node', async () => {
         createVolumeStub.resolves(returnedVolume);
         await client.createVolume().sendMessage({
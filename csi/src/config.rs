@@ -0,0 +1,68 @@
+//! Configuration of the CSI plugin, read from an optional YAML file. Options
+//! given on the command line take precedence over the ones from the file.
+
+use std::{collections::HashMap, fs};
+
+use serde::Deserialize;
+
+/// Topology key of the node, the only one understood by the controller
+pub const TOPOLOGY_KEY_HOSTNAME: &str = "kubernetes.io/hostname";
+/// Topology key of the zone the node is in
+pub const TOPOLOGY_KEY_ZONE: &str = "topology.kubernetes.io/zone";
+/// Topology key of the rack the node is in
+pub const TOPOLOGY_KEY_RACK: &str = "openebs.io/rack";
+
+/// Maximum number of volumes published on a node when none is configured
+pub const DEFAULT_MAX_VOLUMES: i64 = 128;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// zone the node is in
+    pub zone: Option<String>,
+    /// rack the node is in
+    pub rack: Option<String>,
+    /// maximum number of volumes that can be published on the node
+    pub max_volumes: Option<i64>,
+    /// gRPC endpoints of the mayastor instances used by the controller,
+    /// keyed by node name
+    pub mayastor_nodes: HashMap<String, String>,
+}
+
+impl Config {
+    /// Read the configuration from the YAML file.
+    pub fn read(file: &str) -> Result<Config, String> {
+        debug!("loading configuration file from {}", file);
+        let cfg = fs::read(file).map_err(|err| {
+            format!("Failed to read config file {}: {}", file, err)
+        })?;
+        if cfg.is_empty() {
+            return Ok(Config::default());
+        }
+        serde_yaml::from_slice(&cfg)
+            .map_err(|err| format!("Invalid config file {}: {}", file, err))
+    }
+
+    /// Maximum number of volumes that can be published on the node, which
+    /// must be positive whether it comes from the file or the command line.
+    pub fn max_volumes(&self) -> Result<i64, String> {
+        match self.max_volumes {
+            None => Ok(DEFAULT_MAX_VOLUMES),
+            Some(n) if n > 0 => Ok(n),
+            Some(n) => Err(format!("Invalid max volumes {}", n)),
+        }
+    }
+
+    /// Topology segments of the node, the hostname always being one of them.
+    pub fn topology(&self, node_name: &str) -> HashMap<String, String> {
+        let mut segments = HashMap::new();
+        segments.insert(TOPOLOGY_KEY_HOSTNAME.to_string(), node_name.into());
+        if let Some(zone) = &self.zone {
+            segments.insert(TOPOLOGY_KEY_ZONE.to_string(), zone.clone());
+        }
+        if let Some(rack) = &self.rack {
+            segments.insert(TOPOLOGY_KEY_RACK.to_string(), rack.clone());
+        }
+        segments
+    }
+}
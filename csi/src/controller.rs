@@ -0,0 +1,1459 @@
+//! Implementation of gRPC methods from CSI Controller gRPC service.
+//!
+//! The controller talks directly to the mayastor instances of the cluster and
+//! keeps no state of its own. A volume is made of replicas, each on a pool of
+//! a different node, and of a nexus on the node of the first replica, which
+//! is the only node the volume is accessible from. All objects of a volume
+//! carry the uuid of the volume.
+//!
+//! A snapshot of a volume is a snapshot of each healthy replica taken while
+//! the nexus is frozen, so that all of them hold the same data. The replica
+//! snapshots are named by the snapshot id, which is made of the uuid of the
+//! volume, a tag derived from the name of the snapshot and the time it was
+//! taken. A volume created from a snapshot is made of clones of the replica
+//! snapshots.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    slice,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Mutex;
+use tonic::{transport::Channel, Code, Request, Response, Status};
+
+use rpc::{
+    mayastor::{
+        ChildState,
+        CloneSnapshotRequest,
+        CreateNexusRequest,
+        CreateReplicaRequest,
+        DestroyNexusRequest,
+        DestroyReplicaRequest,
+        FreezeNexusRequest,
+        Nexus,
+        NexusLayout,
+        Null,
+        Pool,
+        PoolState,
+        PublishNexusRequest,
+        Replica,
+        ShareProtocolNexus,
+        ShareProtocolReplica,
+        ShareReplicaRequest,
+        SnapshotReplicaRequest,
+        ThawNexusRequest,
+        UnpublishNexusRequest,
+    },
+    service::mayastor_client::MayastorClient,
+};
+
+use crate::{
+    config::TOPOLOGY_KEY_HOSTNAME,
+    csi::{volume_capability::access_mode::Mode, *},
+};
+
+#[cfg(test)]
+mod test;
+
+/// Prefix of the node id reported by the node plugin
+const NODE_ID_PREFIX: &str = "mayastor://";
+
+/// Number of volumes or snapshots returned by list when the CO does not limit
+/// it
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+/// Writes to the volume are held back for at most this many seconds while its
+/// replicas are snapshotted
+const FREEZE_TIMEOUT_SECS: u32 = 10;
+
+/// Number of hex digits of the snapshot uuid in the snapshot id, the id and
+/// the creation time which follows it must fit into the 63 characters of an
+/// lvol name
+const SNAPSHOT_TAG_LEN: usize = 17;
+
+static PVC_RE: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(
+        r"pvc-([0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})",
+    )
+    .unwrap()
+});
+
+static SNAPSHOT_RE: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(
+        r"snapshot-([0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})",
+    )
+    .unwrap()
+});
+
+static SNAPSHOT_ID_RE: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(
+        r"^([0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})(@[0-9a-f]{17}-)([0-9a-f]{8})$",
+    )
+    .unwrap()
+});
+
+/// Pools, replicas and nexus reported by a mayastor instance
+#[derive(Debug)]
+struct MayastorNode {
+    name: String,
+    client: MayastorClient<Channel>,
+    pools: Vec<Pool>,
+    replicas: Vec<Replica>,
+    nexus_list: Vec<Nexus>,
+}
+
+impl MayastorNode {
+    async fn connect(name: &str, endpoint: &str) -> Result<Self, String> {
+        let mut client =
+            MayastorClient::connect(format!("http://{}", endpoint))
+                .await
+                .map_err(|err| {
+                    format!("Failed to connect to {}: {}", endpoint, err)
+                })?;
+
+        let pools = client
+            .list_pools(Null {})
+            .await
+            .map_err(|err| format!("Failed to list pools: {}", err))?
+            .into_inner()
+            .pools;
+        let replicas = client
+            .list_replicas(Null {})
+            .await
+            .map_err(|err| format!("Failed to list replicas: {}", err))?
+            .into_inner()
+            .replicas;
+        let nexus_list = client
+            .list_nexus(Null {})
+            .await
+            .map_err(|err| format!("Failed to list nexus: {}", err))?
+            .into_inner()
+            .nexus_list;
+
+        Ok(Self {
+            name: name.into(),
+            client,
+            pools,
+            replicas,
+            nexus_list,
+        })
+    }
+
+    fn replica(&self, uuid: &str) -> Option<&Replica> {
+        self.replicas.iter().find(|r| r.uuid == uuid)
+    }
+
+    fn nexus(&self, uuid: &str) -> Option<&Nexus> {
+        self.nexus_list.iter().find(|n| n.uuid == uuid)
+    }
+
+    /// Replica snapshot with the given snapshot id.
+    fn snapshot(&self, snapshot_id: &str) -> Option<&Replica> {
+        self.replicas
+            .iter()
+            .find(|r| r.snapshot && r.uuid == snapshot_id)
+    }
+
+    /// Share the replica unless it is shared with the protocol already and
+    /// return its uri.
+    async fn share_replica(
+        &mut self,
+        uuid: &str,
+        share: ShareProtocolReplica,
+    ) -> Result<String, Status> {
+        if let Some(replica) = self.replica(uuid) {
+            if replica.share == share as i32 {
+                return Ok(replica.uri.clone());
+            }
+        }
+        let reply = self
+            .client
+            .share_replica(ShareReplicaRequest {
+                uuid: uuid.into(),
+                share: share as i32,
+            })
+            .await
+            .map_err(|err| {
+                failed(format!("Failed to share replica on {}", self.name), err)
+            })?;
+        Ok(reply.into_inner().uri)
+    }
+
+    /// Online pools with at least size bytes of free space.
+    fn pools_with_space(&self, size: u64) -> impl Iterator<Item = &Pool> {
+        self.pools.iter().filter(move |p| {
+            p.state == PoolState::PoolOnline as i32 && pool_free(p) >= size
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Controller {
+    /// gRPC endpoints of the mayastor instances keyed by node name
+    pub nodes: HashMap<String, String>,
+    /// serializes requests which create, destroy or publish volumes
+    pub lock: Arc<Mutex<()>>,
+}
+
+impl Controller {
+    pub fn new(nodes: HashMap<String, String>) -> Self {
+        Self {
+            nodes,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Collect the objects of all mayastor instances, sorted by node name.
+    /// Instances which cannot be reached are left out.
+    async fn scan(&self) -> Vec<MayastorNode> {
+        let mut names: Vec<&String> = self.nodes.keys().collect();
+        names.sort();
+
+        let mut nodes = Vec::new();
+        for name in names {
+            match MayastorNode::connect(name, &self.nodes[name]).await {
+                Ok(node) => nodes.push(node),
+                Err(err) => warn!("Skipping mayastor node {}: {}", name, err),
+            }
+        }
+        nodes
+    }
+}
+
+fn pool_free(pool: &Pool) -> u64 {
+    pool.capacity.saturating_sub(pool.used)
+}
+
+/// Prefix the message of a status returned by mayastor with what failed.
+fn failed(what: String, err: Status) -> Status {
+    Status::new(err.code(), format!("{}: {}", what, err.message()))
+}
+
+/// Extract the node name from a node id in the format mayastor://<node>.
+fn parse_node_id(node_id: &str) -> Result<&str, Status> {
+    let name = if node_id.starts_with(NODE_ID_PREFIX) {
+        &node_id[NODE_ID_PREFIX.len() ..]
+    } else {
+        ""
+    };
+    if !name.is_empty() && !name.contains('/') {
+        Ok(name)
+    } else {
+        Err(Status::new(
+            Code::InvalidArgument,
+            format!("Invalid mayastor node ID: {}", node_id),
+        ))
+    }
+}
+
+/// Check that the volume capabilities do not contain an unsupported access
+/// mode.
+fn check_capabilities(caps: &[VolumeCapability]) -> Result<(), Status> {
+    if caps.is_empty() {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            "Missing volume capabilities",
+        ));
+    }
+    for cap in caps {
+        let mode = cap
+            .access_mode
+            .as_ref()
+            .and_then(|m| Mode::from_i32(m.mode));
+        if mode != Some(Mode::SingleNodeWriter) {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Access mode {:?} not supported", mode),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Return the nodes the volume must be created on and the nodes it should
+/// preferably be created on. Only the hostname segment is understood, other
+/// segments reported by the node plugin (zone, rack) are implied by it.
+fn topology_nodes(
+    requirement: &Option<TopologyRequirement>,
+) -> Result<(Vec<String>, Vec<String>), Status> {
+    let mut required = Vec::new();
+    let mut preferred = Vec::new();
+
+    if let Some(requirement) = requirement {
+        for topology in &requirement.requisite {
+            match topology.segments.get(TOPOLOGY_KEY_HOSTNAME) {
+                Some(node) => required.push(node.clone()),
+                None => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        "Volume topology other than hostname not supported",
+                    ));
+                }
+            }
+        }
+        for topology in &requirement.preferred {
+            if let Some(node) = topology.segments.get(TOPOLOGY_KEY_HOSTNAME) {
+                preferred.push(node.clone());
+            }
+        }
+    }
+    Ok((required, preferred))
+}
+
+/// Number of replicas from the "repl" parameter of the storage class.
+fn replica_count(
+    parameters: &HashMap<String, String>,
+) -> Result<usize, Status> {
+    match parameters.get("repl") {
+        None => Ok(1),
+        Some(repl) => match repl.parse::<usize>() {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err(Status::new(
+                Code::InvalidArgument,
+                format!("Invalid replica count {}", repl),
+            )),
+        },
+    }
+}
+
+fn volume_size(range: &Option<CapacityRange>) -> Result<u64, Status> {
+    match range {
+        Some(range) if range.required_bytes > 0 => {
+            Ok(range.required_bytes as u64)
+        }
+        Some(range) if range.limit_bytes > 0 => Ok(range.limit_bytes as u64),
+        _ => Err(Status::new(
+            Code::InvalidArgument,
+            "Missing volume capacity range",
+        )),
+    }
+}
+
+fn share_protocol(protocol: &str) -> Result<ShareProtocolNexus, Status> {
+    match protocol {
+        "nbd" => Ok(ShareProtocolNexus::NexusNbd),
+        "ublk" => Ok(ShareProtocolNexus::NexusUblk),
+        "nvmf" => Ok(ShareProtocolNexus::NexusNvmf),
+        "iscsi" => Ok(ShareProtocolNexus::NexusIscsi),
+        _ => Err(Status::new(
+            Code::InvalidArgument,
+            format!("Invalid share protocol {}", protocol),
+        )),
+    }
+}
+
+/// Replicas on the node of the nexus are accessed locally, the others are
+/// exported to it over nvmf.
+fn replica_share(local: bool) -> ShareProtocolReplica {
+    if local {
+        ShareProtocolReplica::ReplicaNone
+    } else {
+        ShareProtocolReplica::ReplicaNvmf
+    }
+}
+
+/// Volume object accessible only from the node of its nexus.
+fn csi_volume(
+    uuid: &str,
+    size: u64,
+    node: &str,
+    volume_context: HashMap<String, String>,
+) -> Volume {
+    let mut segments = HashMap::new();
+    segments.insert(TOPOLOGY_KEY_HOSTNAME.to_string(), node.to_string());
+
+    Volume {
+        capacity_bytes: size as i64,
+        volume_id: uuid.into(),
+        volume_context,
+        content_source: None,
+        accessible_topology: vec![Topology {
+            segments,
+        }],
+    }
+}
+
+/// Start of the id of the snapshot with the given uuid taken from the volume,
+/// which is followed by its creation time.
+fn snapshot_prefix(volume: &str, uuid: &str) -> String {
+    let tag: String = uuid
+        .chars()
+        .filter(|c| *c != '-')
+        .take(SNAPSHOT_TAG_LEN)
+        .collect();
+    format!("{}@{}-", volume, tag)
+}
+
+/// Id of the snapshot with the given uuid taken from the volume at the given
+/// time. The time is part of the id as it is not stored with the replica
+/// snapshots.
+fn snapshot_id(volume: &str, uuid: &str, created: SystemTime) -> String {
+    let secs = created
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format!("{}{:08x}", snapshot_prefix(volume, uuid), secs)
+}
+
+/// Uuid of the volume the snapshot was taken from.
+fn snapshot_volume(snapshot_id: &str) -> Option<&str> {
+    SNAPSHOT_ID_RE
+        .captures(snapshot_id)
+        .map(|caps| caps.get(1).unwrap().as_str())
+}
+
+/// Part of the snapshot id which is derived from the snapshot uuid.
+fn snapshot_tag(snapshot_id: &str) -> Option<&str> {
+    SNAPSHOT_ID_RE
+        .captures(snapshot_id)
+        .map(|caps| caps.get(2).unwrap().as_str())
+}
+
+/// Time at which the snapshot was taken.
+fn snapshot_created(snapshot_id: &str) -> Option<SystemTime> {
+    let caps = SNAPSHOT_ID_RE.captures(snapshot_id)?;
+    let secs = u64::from_str_radix(&caps[3], 16).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn csi_snapshot(snapshot_id: &str, volume: &str, size: u64) -> Snapshot {
+    Snapshot {
+        size_bytes: size as i64,
+        snapshot_id: snapshot_id.into(),
+        source_volume_id: volume.into(),
+        creation_time: snapshot_created(snapshot_id).map(|t| t.into()),
+        ready_to_use: true,
+    }
+}
+
+/// Parse the starting token of a list request, which is the index of the
+/// first entry to return, and the number of entries to return.
+fn list_range(
+    starting_token: &str,
+    max_entries: i32,
+) -> Result<(usize, usize), Status> {
+    let start = if starting_token.is_empty() {
+        0
+    } else {
+        starting_token.parse::<usize>().map_err(|_| {
+            Status::new(
+                Code::Aborted,
+                format!("Invalid starting token {}", starting_token),
+            )
+        })?
+    };
+    let max_entries = if max_entries > 0 {
+        max_entries as usize
+    } else {
+        DEFAULT_MAX_ENTRIES
+    };
+    Ok((start, max_entries))
+}
+
+/// Destroy the replica snapshots with the given id on the nodes, errors are
+/// only logged.
+async fn destroy_snapshots(
+    nodes: &mut [MayastorNode],
+    which: &[usize],
+    snapshot_id: &str,
+) {
+    for i in which {
+        let node = &mut nodes[*i];
+        if let Err(err) = node
+            .client
+            .destroy_replica(DestroyReplicaRequest {
+                uuid: snapshot_id.into(),
+            })
+            .await
+        {
+            warn!(
+                "Failed to destroy snapshot {} on {}: {}",
+                snapshot_id,
+                node.name,
+                err.message()
+            );
+        }
+    }
+}
+
+/// Create the missing replicas of a new volume and return the node of the
+/// nexus and the uris of the replicas.
+async fn create_replicas(
+    nodes: &mut [MayastorNode],
+    uuid: &str,
+    size: u64,
+    count: usize,
+    required: &[String],
+    preferred: &[String],
+) -> Result<(usize, Vec<String>), Status> {
+    // replicas left behind by a previous attempt are reused
+    let existing: Vec<(usize, Replica)> = nodes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, n)| n.replica(uuid).map(|r| (i, r.clone())))
+        .collect();
+    if let Some((i, replica)) = existing.iter().find(|(_, r)| r.size < size) {
+        return Err(Status::new(
+            Code::AlreadyExists,
+            format!(
+                "Replica of volume {} on node {} is smaller than {} bytes",
+                uuid, nodes[*i].name, size
+            ),
+        ));
+    }
+
+    // pick the pools for the missing replicas, at most one per node,
+    // preferring the preferred nodes and then the pools with the most
+    // free space
+    let mut candidates: Vec<(usize, &Pool)> = nodes
+        .iter()
+        .enumerate()
+        .filter(|(i, n)| {
+            !existing.iter().any(|(j, _)| j == i)
+                && (required.is_empty() || required.contains(&n.name))
+        })
+        .flat_map(|(i, n)| n.pools_with_space(size).map(move |p| (i, p)))
+        .collect();
+    candidates.sort_by_key(|(i, p)| {
+        (!preferred.contains(&nodes[*i].name), Reverse(pool_free(p)))
+    });
+
+    let mut placement: Vec<(usize, String)> = Vec::new();
+    for (i, pool) in candidates {
+        if existing.len() + placement.len() >= count {
+            break;
+        }
+        if !placement.iter().any(|(j, _)| *j == i) {
+            placement.push((i, pool.name.clone()));
+        }
+    }
+    if existing.len() + placement.len() < count {
+        return Err(Status::new(
+            Code::ResourceExhausted,
+            format!("Cannot find {} suitable pools for volume {}", count, uuid),
+        ));
+    }
+
+    let nexus_node = existing
+        .first()
+        .map(|(i, _)| *i)
+        .unwrap_or_else(|| placement[0].0);
+
+    let mut children = Vec::new();
+    for (i, _) in existing {
+        let uri = nodes[i]
+            .share_replica(uuid, replica_share(i == nexus_node))
+            .await?;
+        children.push(uri);
+    }
+    for (i, pool) in placement {
+        let node = &mut nodes[i];
+        info!("Creating replica {} on pool {}", uuid, pool);
+        let reply = node
+            .client
+            .create_replica(CreateReplicaRequest {
+                uuid: uuid.into(),
+                pool,
+                size,
+                thin: false,
+                share: replica_share(i == nexus_node) as i32,
+            })
+            .await
+            .map_err(|err| {
+                failed(
+                    format!("Failed to create replica on {}", node.name),
+                    err,
+                )
+            })?;
+        children.push(reply.into_inner().uri);
+    }
+
+    Ok((nexus_node, children))
+}
+
+/// Clone the replica snapshots into the replicas of a new volume and return
+/// the node of the nexus, the size of the volume and the uris of the
+/// replicas. A clone lives in the pool of its snapshot, so the volume cannot
+/// have more replicas than the snapshot.
+async fn clone_replicas(
+    nodes: &mut [MayastorNode],
+    uuid: &str,
+    snapshot_id: &str,
+    size: u64,
+    count: usize,
+    required: &[String],
+    preferred: &[String],
+) -> Result<(usize, u64, Vec<String>), Status> {
+    let mut sources: Vec<(usize, u64)> = nodes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, n)| n.snapshot(snapshot_id).map(|r| (i, r.size)))
+        .collect();
+    let snapshot_size = match sources.iter().map(|(_, size)| *size).min() {
+        Some(size) => size,
+        None => {
+            return Err(Status::new(
+                Code::NotFound,
+                format!("Snapshot {} does not exist", snapshot_id),
+            ));
+        }
+    };
+    if size > snapshot_size {
+        return Err(Status::new(
+            Code::OutOfRange,
+            format!("Snapshot {} is smaller than {} bytes", snapshot_id, size),
+        ));
+    }
+
+    // clones left behind by a previous attempt come first, then the clones
+    // on the preferred nodes
+    sources.retain(|(i, _)| {
+        required.is_empty() || required.contains(&nodes[*i].name)
+    });
+    sources.sort_by_key(|(i, _)| {
+        (
+            nodes[*i].replica(uuid).is_none(),
+            !preferred.contains(&nodes[*i].name),
+        )
+    });
+    if sources.is_empty() {
+        return Err(Status::new(
+            Code::ResourceExhausted,
+            format!(
+                "Snapshot {} has no replica on the required nodes",
+                snapshot_id
+            ),
+        ));
+    }
+    if sources.len() < count {
+        warn!(
+            "Volume {} gets {} of {} replicas as its snapshot {} has no more",
+            uuid,
+            sources.len(),
+            count,
+            snapshot_id
+        );
+    }
+    sources.truncate(count);
+
+    let nexus_node = sources[0].0;
+    let mut children = Vec::new();
+    for (i, _) in sources {
+        let share = replica_share(i == nexus_node);
+        if nodes[i].replica(uuid).is_some() {
+            children.push(nodes[i].share_replica(uuid, share).await?);
+            continue;
+        }
+        let node = &mut nodes[i];
+        info!(
+            "Cloning replica {} from snapshot {} on node {}",
+            uuid, snapshot_id, node.name
+        );
+        let reply = node
+            .client
+            .clone_snapshot(CloneSnapshotRequest {
+                snapshot: snapshot_id.into(),
+                uuid: uuid.into(),
+                share: share as i32,
+            })
+            .await
+            .map_err(|err| {
+                failed(
+                    format!("Failed to clone snapshot on {}", node.name),
+                    err,
+                )
+            })?;
+        children.push(reply.into_inner().uri);
+    }
+
+    Ok((nexus_node, snapshot_size, children))
+}
+
+#[tonic::async_trait]
+impl controller_server::Controller for Controller {
+    async fn create_volume(
+        &self,
+        request: Request<CreateVolumeRequest>,
+    ) -> Result<Response<CreateVolumeResponse>, Status> {
+        let args = request.into_inner();
+
+        debug!("Request to create volume {}", args.name);
+
+        let uuid = match PVC_RE.captures(&args.name) {
+            Some(caps) => caps[1].to_string(),
+            None => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!(
+                        "Expected the volume name {} in the format pvc-<uuid>",
+                        args.name
+                    ),
+                ));
+            }
+        };
+        let snapshot_id = match &args.volume_content_source {
+            None => None,
+            Some(VolumeContentSource {
+                r#type:
+                    Some(volume_content_source::Type::Snapshot(
+                        volume_content_source::SnapshotSource {
+                            snapshot_id,
+                        },
+                    )),
+            }) => Some(snapshot_id.clone()),
+            Some(_) => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "Creating a volume from a content source other than a snapshot is not supported",
+                ));
+            }
+        };
+        check_capabilities(&args.volume_capabilities)?;
+        let (required, preferred) =
+            topology_nodes(&args.accessibility_requirements)?;
+        let count = replica_count(&args.parameters)?;
+        // a volume from a snapshot has the size of the snapshot by default
+        let size = if snapshot_id.is_some() && args.capacity_range.is_none() {
+            0
+        } else {
+            volume_size(&args.capacity_range)?
+        };
+
+        let _guard = self.lock.lock().await;
+        let mut nodes = self.scan().await;
+
+        // the volume exists already if its nexus does
+        if let Some(node) = nodes.iter().find(|n| n.nexus(&uuid).is_some()) {
+            let nexus = node.nexus(&uuid).unwrap();
+            if nexus.size < size {
+                return Err(Status::new(
+                    Code::AlreadyExists,
+                    format!(
+                        "Volume {} exists with a smaller size of {} bytes",
+                        uuid, nexus.size
+                    ),
+                ));
+            }
+            debug!("Volume {} exists on node {}", uuid, node.name);
+            let mut volume =
+                csi_volume(&uuid, nexus.size, &node.name, args.parameters);
+            volume.content_source = args.volume_content_source;
+            return Ok(Response::new(CreateVolumeResponse {
+                volume: Some(volume),
+            }));
+        }
+
+        let (nexus_node, size, children) = match &snapshot_id {
+            Some(snapshot_id) => {
+                clone_replicas(
+                    &mut nodes,
+                    &uuid,
+                    snapshot_id,
+                    size,
+                    count,
+                    &required,
+                    &preferred,
+                )
+                .await?
+            }
+            None => {
+                let (nexus_node, children) = create_replicas(
+                    &mut nodes, &uuid, size, count, &required, &preferred,
+                )
+                .await?;
+                (nexus_node, size, children)
+            }
+        };
+
+        let node = &mut nodes[nexus_node];
+        info!("Creating nexus {} on node {}", uuid, node.name);
+        node.client
+            .create_nexus(CreateNexusRequest {
+                uuid: uuid.clone(),
+                size,
+                children,
+                layout: NexusLayout::NexusMirror as i32,
+                ..Default::default()
+            })
+            .await
+            .map_err(|err| {
+                failed(format!("Failed to create nexus on {}", node.name), err)
+            })?;
+
+        info!("Volume {} created", uuid);
+        // parameters of the storage class are only presented to create
+        // volume, propagate them to the other methods through the volume
+        // context
+        let mut volume = csi_volume(&uuid, size, &node.name, args.parameters);
+        volume.content_source = args.volume_content_source;
+        Ok(Response::new(CreateVolumeResponse {
+            volume: Some(volume),
+        }))
+    }
+
+    async fn delete_volume(
+        &self,
+        request: Request<DeleteVolumeRequest>,
+    ) -> Result<Response<DeleteVolumeResponse>, Status> {
+        let uuid = request.into_inner().volume_id;
+
+        debug!("Request to destroy volume {}", uuid);
+
+        if uuid.is_empty() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Missing volume id",
+            ));
+        }
+
+        let _guard = self.lock.lock().await;
+        let mut nodes = self.scan().await;
+
+        for node in nodes.iter_mut().filter(|n| n.nexus(&uuid).is_some()) {
+            node.client
+                .destroy_nexus(DestroyNexusRequest {
+                    uuid: uuid.clone(),
+                })
+                .await
+                .map_err(|err| {
+                    failed(
+                        format!("Failed to destroy nexus on {}", node.name),
+                        err,
+                    )
+                })?;
+        }
+        for node in nodes.iter_mut().filter(|n| n.replica(&uuid).is_some()) {
+            node.client
+                .destroy_replica(DestroyReplicaRequest {
+                    uuid: uuid.clone(),
+                })
+                .await
+                .map_err(|err| {
+                    failed(
+                        format!("Failed to destroy replica on {}", node.name),
+                        err,
+                    )
+                })?;
+        }
+
+        info!("Volume {} destroyed", uuid);
+        Ok(Response::new(DeleteVolumeResponse {}))
+    }
+
+    async fn controller_publish_volume(
+        &self,
+        request: Request<ControllerPublishVolumeRequest>,
+    ) -> Result<Response<ControllerPublishVolumeResponse>, Status> {
+        let args = request.into_inner();
+
+        debug!(
+            "Request to publish volume {} on {}",
+            args.volume_id, args.node_id
+        );
+
+        let node_name = parse_node_id(&args.node_id)?;
+        if args.readonly {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Readonly volumes are not supported",
+            ));
+        }
+        match &args.volume_capability {
+            Some(cap) => check_capabilities(slice::from_ref(cap))?,
+            None => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "Missing volume capability",
+                ));
+            }
+        }
+        let protocol = match args.volume_context.get("protocol") {
+            Some(protocol) => share_protocol(protocol)?,
+            None => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    "Missing protocol",
+                ));
+            }
+        };
+        // volumes of a storage class with a key are encrypted with it, the
+        // key itself stays with the key provider of mayastor
+        let key_id = args
+            .volume_context
+            .get("encryptionKeyId")
+            .cloned()
+            .unwrap_or_default();
+
+        let _guard = self.lock.lock().await;
+        let mut nodes = self.scan().await;
+
+        let node = match nodes
+            .iter_mut()
+            .find(|n| n.nexus(&args.volume_id).is_some())
+        {
+            Some(node) => node,
+            None => {
+                return Err(Status::new(
+                    Code::NotFound,
+                    format!("Volume {} does not exist", args.volume_id),
+                ));
+            }
+        };
+        if node.name != node_name {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "Cannot publish volume {} on node {} while its nexus is on node {}",
+                    args.volume_id, node_name, node.name
+                ),
+            ));
+        }
+
+        let mut device_path =
+            node.nexus(&args.volume_id).unwrap().device_path.clone();
+        if device_path.is_empty() {
+            device_path = node
+                .client
+                .publish_nexus(PublishNexusRequest {
+                    uuid: args.volume_id.clone(),
+                    key_id,
+                    share: protocol as i32,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|err| {
+                    failed(
+                        format!("Failed to publish nexus on {}", node.name),
+                        err,
+                    )
+                })?
+                .into_inner()
+                .device_path;
+            info!("Volume {} published as {}", args.volume_id, device_path);
+        } else {
+            debug!("Volume {} already published", args.volume_id);
+        }
+
+        let mut publish_context = HashMap::new();
+        publish_context.insert("uri".to_string(), device_path);
+
+        Ok(Response::new(ControllerPublishVolumeResponse {
+            publish_context,
+        }))
+    }
+
+    async fn controller_unpublish_volume(
+        &self,
+        request: Request<ControllerUnpublishVolumeRequest>,
+    ) -> Result<Response<ControllerUnpublishVolumeResponse>, Status> {
+        let args = request.into_inner();
+
+        debug!("Request to unpublish volume {}", args.volume_id);
+
+        let node_name = parse_node_id(&args.node_id)?;
+
+        let _guard = self.lock.lock().await;
+        let mut nodes = self.scan().await;
+
+        let node = match nodes
+            .iter_mut()
+            .find(|n| n.nexus(&args.volume_id).is_some())
+        {
+            Some(node) => node,
+            None => {
+                warn!(
+                    "Request to unpublish volume {} which does not exist",
+                    args.volume_id
+                );
+                return Ok(Response::new(ControllerUnpublishVolumeResponse {}));
+            }
+        };
+        if node.name != node_name {
+            // we unpublish the volume anyway but at least we log a message
+            warn!(
+                "Request to unpublish volume {} from node {} while it was published on node {}",
+                args.volume_id, node_name, node.name
+            );
+        }
+
+        if !node.nexus(&args.volume_id).unwrap().device_path.is_empty() {
+            node.client
+                .unpublish_nexus(UnpublishNexusRequest {
+                    uuid: args.volume_id.clone(),
+                    protocols: Vec::new(),
+                })
+                .await
+                .map_err(|err| {
+                    failed(
+                        format!("Failed to unpublish nexus on {}", node.name),
+                        err,
+                    )
+                })?;
+            info!("Volume {} unpublished", args.volume_id);
+        }
+
+        Ok(Response::new(ControllerUnpublishVolumeResponse {}))
+    }
+
+    async fn validate_volume_capabilities(
+        &self,
+        request: Request<ValidateVolumeCapabilitiesRequest>,
+    ) -> Result<Response<ValidateVolumeCapabilitiesResponse>, Status> {
+        let args = request.into_inner();
+
+        debug!(
+            "Request to validate volume capabilities for {}",
+            args.volume_id
+        );
+
+        let nodes = self.scan().await;
+        if !nodes.iter().any(|n| n.nexus(&args.volume_id).is_some()) {
+            return Err(Status::new(
+                Code::NotFound,
+                format!("Volume {} does not exist", args.volume_id),
+            ));
+        }
+
+        let caps: Vec<VolumeCapability> = args
+            .volume_capabilities
+            .into_iter()
+            .filter(|cap| check_capabilities(slice::from_ref(cap)).is_ok())
+            .collect();
+
+        if caps.is_empty() {
+            Ok(Response::new(ValidateVolumeCapabilitiesResponse {
+                confirmed: None,
+                message: "The only supported capability is SINGLE_NODE_WRITER"
+                    .into(),
+            }))
+        } else {
+            Ok(Response::new(ValidateVolumeCapabilitiesResponse {
+                confirmed: Some(
+                    validate_volume_capabilities_response::Confirmed {
+                        volume_context: HashMap::new(),
+                        volume_capabilities: caps,
+                        parameters: HashMap::new(),
+                    },
+                ),
+                message: String::new(),
+            }))
+        }
+    }
+
+    async fn list_volumes(
+        &self,
+        request: Request<ListVolumesRequest>,
+    ) -> Result<Response<ListVolumesResponse>, Status> {
+        let args = request.into_inner();
+
+        debug!("Request to list volumes");
+
+        let (start, max_entries) =
+            list_range(&args.starting_token, args.max_entries)?;
+
+        let nodes = self.scan().await;
+        let mut volumes: Vec<Volume> = nodes
+            .iter()
+            .flat_map(|node| {
+                node.nexus_list.iter().map(move |nexus| {
+                    csi_volume(
+                        &nexus.uuid,
+                        nexus.size,
+                        &node.name,
+                        HashMap::new(),
+                    )
+                })
+            })
+            .collect();
+        volumes.sort_by(|a, b| a.volume_id.cmp(&b.volume_id));
+
+        if start > volumes.len() {
+            return Err(Status::new(
+                Code::Aborted,
+                format!("Invalid starting token {}", args.starting_token),
+            ));
+        }
+
+        let end = volumes.len().min(start + max_entries);
+        let next_token = if end < volumes.len() {
+            end.to_string()
+        } else {
+            String::new()
+        };
+
+        Ok(Response::new(ListVolumesResponse {
+            entries: volumes
+                .drain(start .. end)
+                .map(|volume| list_volumes_response::Entry {
+                    volume: Some(volume),
+                })
+                .collect(),
+            next_token,
+        }))
+    }
+
+    /// Free space of the pools on the node given by the hostname segment of
+    /// the topology or of all pools in the cluster.
+    async fn get_capacity(
+        &self,
+        request: Request<GetCapacityRequest>,
+    ) -> Result<Response<GetCapacityResponse>, Status> {
+        let args = request.into_inner();
+
+        if !args.volume_capabilities.is_empty() {
+            check_capabilities(&args.volume_capabilities)?;
+        }
+
+        let node_name = args
+            .accessible_topology
+            .as_ref()
+            .and_then(|t| t.segments.get(TOPOLOGY_KEY_HOSTNAME));
+
+        let nodes = self.scan().await;
+        let capacity: u64 = nodes
+            .iter()
+            .filter(|n| node_name.map_or(true, |name| *name == n.name))
+            .flat_map(|n| n.pools_with_space(0))
+            .map(pool_free)
+            .sum();
+
+        debug!("Capacity of {:?}: {} bytes", node_name, capacity);
+
+        Ok(Response::new(GetCapacityResponse {
+            available_capacity: capacity as i64,
+        }))
+    }
+
+    async fn controller_get_capabilities(
+        &self,
+        _request: Request<ControllerGetCapabilitiesRequest>,
+    ) -> Result<Response<ControllerGetCapabilitiesResponse>, Status> {
+        let caps = vec![
+            controller_service_capability::rpc::Type::CreateDeleteVolume,
+            controller_service_capability::rpc::Type::PublishUnpublishVolume,
+            controller_service_capability::rpc::Type::ListVolumes,
+            controller_service_capability::rpc::Type::GetCapacity,
+            controller_service_capability::rpc::Type::CreateDeleteSnapshot,
+            controller_service_capability::rpc::Type::ListSnapshots,
+        ];
+
+        debug!("ControllerGetCapabilities request: {:?}", caps);
+
+        Ok(Response::new(ControllerGetCapabilitiesResponse {
+            capabilities: caps
+                .into_iter()
+                .map(|c| ControllerServiceCapability {
+                    r#type: Some(controller_service_capability::Type::Rpc(
+                        controller_service_capability::Rpc {
+                            r#type: c as i32,
+                        },
+                    )),
+                })
+                .collect(),
+        }))
+    }
+
+    async fn create_snapshot(
+        &self,
+        request: Request<CreateSnapshotRequest>,
+    ) -> Result<Response<CreateSnapshotResponse>, Status> {
+        let args = request.into_inner();
+        let volume = args.source_volume_id;
+
+        debug!(
+            "Request to create snapshot {} of volume {}",
+            args.name, volume
+        );
+
+        let uuid = match SNAPSHOT_RE.captures(&args.name) {
+            Some(caps) => caps[1].to_string(),
+            None => {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!(
+                        "Expected the snapshot name {} in the format snapshot-<uuid>",
+                        args.name
+                    ),
+                ));
+            }
+        };
+        if volume.is_empty() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Missing source volume id",
+            ));
+        }
+        let prefix = snapshot_prefix(&volume, &uuid);
+        let tag = &prefix[volume.len() ..];
+
+        let _guard = self.lock.lock().await;
+        let mut nodes = self.scan().await;
+
+        // the same name must not be used for a snapshot of another volume
+        if let Some(other) =
+            nodes.iter().flat_map(|n| n.replicas.iter()).find(|r| {
+                r.snapshot
+                    && snapshot_tag(&r.uuid) == Some(tag)
+                    && snapshot_volume(&r.uuid) != Some(volume.as_str())
+            })
+        {
+            return Err(Status::new(
+                Code::AlreadyExists,
+                format!(
+                    "Snapshot {} exists for volume {}",
+                    args.name,
+                    snapshot_volume(&other.uuid).unwrap_or_default()
+                ),
+            ));
+        }
+
+        let nexus_node =
+            match nodes.iter().position(|n| n.nexus(&volume).is_some()) {
+                Some(i) => i,
+                None => {
+                    return Err(Status::new(
+                        Code::NotFound,
+                        format!("Volume {} does not exist", volume),
+                    ));
+                }
+            };
+        let nexus = nodes[nexus_node].nexus(&volume).unwrap().clone();
+
+        // only replicas holding the latest data are snapshotted
+        let sources: Vec<usize> = nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| match n.replica(&volume) {
+                Some(replica) => nexus.children.iter().any(|c| {
+                    c.uri == replica.uri
+                        && c.state == ChildState::ChildOnline as i32
+                }),
+                None => false,
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if sources.is_empty() {
+            return Err(Status::new(
+                Code::FailedPrecondition,
+                format!("Volume {} has no healthy replica", volume),
+            ));
+        }
+        let size = sources
+            .iter()
+            .map(|i| nodes[*i].replica(&volume).unwrap().size)
+            .min()
+            .unwrap();
+
+        // a snapshot taken by a previous attempt is complete only if it
+        // exists on all replicas, otherwise it is taken again
+        let mut previous: Vec<String> = nodes
+            .iter()
+            .flat_map(|n| n.replicas.iter())
+            .filter(|r| r.snapshot && r.uuid.starts_with(&prefix))
+            .map(|r| r.uuid.clone())
+            .collect();
+        previous.sort();
+        previous.dedup();
+        for id in previous {
+            let taken: Vec<usize> = nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, n)| n.snapshot(&id).is_some())
+                .map(|(i, _)| i)
+                .collect();
+            if sources.iter().all(|i| taken.contains(i)) {
+                debug!("Snapshot {} exists", id);
+                return Ok(Response::new(CreateSnapshotResponse {
+                    snapshot: Some(csi_snapshot(&id, &volume, size)),
+                }));
+            }
+            warn!("Destroying incomplete snapshot {}", id);
+            destroy_snapshots(&mut nodes, &taken, &id).await;
+        }
+        let id = snapshot_id(&volume, &uuid, SystemTime::now());
+
+        let node = &mut nodes[nexus_node];
+        node.client
+            .freeze_nexus(FreezeNexusRequest {
+                uuid: volume.clone(),
+                timeout: FREEZE_TIMEOUT_SECS,
+            })
+            .await
+            .map_err(|err| {
+                failed(format!("Failed to freeze nexus on {}", node.name), err)
+            })?;
+        let frozen = Instant::now();
+
+        let mut result = Ok(());
+        for i in &sources {
+            let node = &mut nodes[*i];
+            info!("Creating snapshot {} on node {}", id, node.name);
+            if let Err(err) = node
+                .client
+                .snapshot_replica(SnapshotReplicaRequest {
+                    uuid: volume.clone(),
+                    name: id.clone(),
+                })
+                .await
+            {
+                result = Err(failed(
+                    format!("Failed to snapshot replica on {}", node.name),
+                    err,
+                ));
+                break;
+            }
+        }
+
+        let node = &mut nodes[nexus_node];
+        if let Err(err) = node
+            .client
+            .thaw_nexus(ThawNexusRequest {
+                uuid: volume.clone(),
+            })
+            .await
+        {
+            // the nexus thaws by itself once the timeout expires
+            warn!(
+                "Failed to thaw nexus {} on {}: {}",
+                volume,
+                node.name,
+                err.message()
+            );
+        }
+        // writes may have gone through between the replica snapshots if the
+        // freeze timed out
+        if result.is_ok()
+            && frozen.elapsed()
+                >= Duration::from_secs(FREEZE_TIMEOUT_SECS.into())
+        {
+            result = Err(Status::new(
+                Code::DeadlineExceeded,
+                format!("Snapshot {} took too long to be consistent", id),
+            ));
+        }
+        if let Err(err) = result {
+            destroy_snapshots(&mut nodes, &sources, &id).await;
+            return Err(err);
+        }
+
+        info!("Snapshot {} of volume {} created", id, volume);
+        Ok(Response::new(CreateSnapshotResponse {
+            snapshot: Some(csi_snapshot(&id, &volume, size)),
+        }))
+    }
+
+    async fn delete_snapshot(
+        &self,
+        request: Request<DeleteSnapshotRequest>,
+    ) -> Result<Response<DeleteSnapshotResponse>, Status> {
+        let id = request.into_inner().snapshot_id;
+
+        debug!("Request to destroy snapshot {}", id);
+
+        if id.is_empty() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Missing snapshot id",
+            ));
+        }
+
+        let _guard = self.lock.lock().await;
+        let mut nodes = self.scan().await;
+
+        for node in nodes.iter_mut().filter(|n| n.snapshot(&id).is_some()) {
+            node.client
+                .destroy_replica(DestroyReplicaRequest {
+                    uuid: id.clone(),
+                })
+                .await
+                .map_err(|err| {
+                    failed(
+                        format!("Failed to destroy snapshot on {}", node.name),
+                        err,
+                    )
+                })?;
+        }
+
+        info!("Snapshot {} destroyed", id);
+        Ok(Response::new(DeleteSnapshotResponse {}))
+    }
+
+    async fn list_snapshots(
+        &self,
+        request: Request<ListSnapshotsRequest>,
+    ) -> Result<Response<ListSnapshotsResponse>, Status> {
+        let args = request.into_inner();
+
+        debug!("Request to list snapshots");
+
+        let (start, max_entries) =
+            list_range(&args.starting_token, args.max_entries)?;
+
+        let nodes = self.scan().await;
+        let mut snapshots: Vec<Snapshot> = Vec::new();
+        for replica in nodes
+            .iter()
+            .flat_map(|n| n.replicas.iter())
+            .filter(|r| r.snapshot)
+        {
+            let volume = match snapshot_volume(&replica.uuid) {
+                Some(volume) => volume,
+                // not taken by the controller
+                None => continue,
+            };
+            if (!args.snapshot_id.is_empty()
+                && args.snapshot_id != replica.uuid)
+                || (!args.source_volume_id.is_empty()
+                    && args.source_volume_id != volume)
+            {
+                continue;
+            }
+            match snapshots.iter_mut().find(|s| s.snapshot_id == replica.uuid) {
+                Some(snapshot) => {
+                    snapshot.size_bytes =
+                        snapshot.size_bytes.min(replica.size as i64)
+                }
+                None => snapshots.push(csi_snapshot(
+                    &replica.uuid,
+                    volume,
+                    replica.size,
+                )),
+            }
+        }
+        snapshots.sort_by(|a, b| a.snapshot_id.cmp(&b.snapshot_id));
+
+        if start > snapshots.len() {
+            return Err(Status::new(
+                Code::Aborted,
+                format!("Invalid starting token {}", args.starting_token),
+            ));
+        }
+
+        let end = snapshots.len().min(start + max_entries);
+        let next_token = if end < snapshots.len() {
+            end.to_string()
+        } else {
+            String::new()
+        };
+
+        Ok(Response::new(ListSnapshotsResponse {
+            entries: snapshots
+                .drain(start .. end)
+                .map(|snapshot| list_snapshots_response::Entry {
+                    snapshot: Some(snapshot),
+                })
+                .collect(),
+            next_token,
+        }))
+    }
+
+    async fn controller_expand_volume(
+        &self,
+        _request: Request<ControllerExpandVolumeRequest>,
+    ) -> Result<Response<ControllerExpandVolumeResponse>, Status> {
+        Err(Status::new(Code::Unimplemented, "Method not implemented"))
+    }
+}
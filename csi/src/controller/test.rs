@@ -0,0 +1,149 @@
+//! Unit tests of the helpers of the controller, which do not need a mayastor
+//! instance
+
+use super::*;
+
+const VOLUME: &str = "0f4a2f4c-3d1e-4b8a-9c6e-1a2b3c4d5e6f";
+const SNAPSHOT: &str = "7c9e6679-7425-40de-944b-e07fc1f90ae7";
+
+fn capability(mode: Mode) -> VolumeCapability {
+    VolumeCapability {
+        access_mode: Some(volume_capability::AccessMode {
+            mode: mode as i32,
+        }),
+        ..Default::default()
+    }
+}
+
+fn hostname(node: &str) -> Topology {
+    let mut segments = HashMap::new();
+    segments.insert(TOPOLOGY_KEY_HOSTNAME.to_string(), node.to_string());
+    Topology {
+        segments,
+    }
+}
+
+#[test]
+fn node_id() {
+    assert_eq!(parse_node_id("mayastor://node-1").unwrap(), "node-1");
+    for id in &["node-1", "mayastor://", "mayastor://node/1", "nvmf://node"] {
+        assert_eq!(
+            parse_node_id(id).unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+}
+
+#[test]
+fn capabilities() {
+    assert!(check_capabilities(&[capability(Mode::SingleNodeWriter)]).is_ok());
+    assert!(check_capabilities(&[]).is_err());
+    assert!(check_capabilities(&[
+        capability(Mode::SingleNodeWriter),
+        capability(Mode::MultiNodeMultiWriter),
+    ])
+    .is_err());
+    assert!(check_capabilities(&[VolumeCapability::default()]).is_err());
+}
+
+#[test]
+fn topology() {
+    let (required, preferred) = topology_nodes(&None).unwrap();
+    assert!(required.is_empty());
+    assert!(preferred.is_empty());
+
+    let requirement = TopologyRequirement {
+        requisite: vec![hostname("node-1"), hostname("node-2")],
+        preferred: vec![hostname("node-2")],
+    };
+    let (required, preferred) = topology_nodes(&Some(requirement)).unwrap();
+    assert_eq!(required, vec!["node-1", "node-2"]);
+    assert_eq!(preferred, vec!["node-2"]);
+
+    // only the hostname is understood
+    let mut segments = HashMap::new();
+    segments.insert("topology.kubernetes.io/zone".into(), "zone-a".into());
+    let requirement = TopologyRequirement {
+        requisite: vec![Topology {
+            segments,
+        }],
+        preferred: Vec::new(),
+    };
+    assert!(topology_nodes(&Some(requirement)).is_err());
+}
+
+#[test]
+fn parameters() {
+    let mut parameters = HashMap::new();
+    assert_eq!(replica_count(&parameters).unwrap(), 1);
+    parameters.insert("repl".to_string(), "3".to_string());
+    assert_eq!(replica_count(&parameters).unwrap(), 3);
+    for repl in &["0", "-1", "three"] {
+        parameters.insert("repl".to_string(), repl.to_string());
+        assert!(replica_count(&parameters).is_err());
+    }
+
+    assert_eq!(
+        share_protocol("nvmf").unwrap(),
+        ShareProtocolNexus::NexusNvmf
+    );
+    assert_eq!(
+        share_protocol("iscsi").unwrap(),
+        ShareProtocolNexus::NexusIscsi
+    );
+    assert!(share_protocol("nfs").is_err());
+}
+
+#[test]
+fn capacity() {
+    let range = |required_bytes, limit_bytes| {
+        Some(CapacityRange {
+            required_bytes,
+            limit_bytes,
+        })
+    };
+    assert_eq!(volume_size(&range(1024, 4096)).unwrap(), 1024);
+    assert_eq!(volume_size(&range(0, 4096)).unwrap(), 4096);
+    assert!(volume_size(&range(0, 0)).is_err());
+    assert!(volume_size(&None).is_err());
+}
+
+#[test]
+fn snapshot_ids() {
+    let created = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let id = snapshot_id(VOLUME, SNAPSHOT, created);
+
+    // the id is the name of the replica snapshots
+    assert!(id.len() <= 63);
+    assert!(id.starts_with(&snapshot_prefix(VOLUME, SNAPSHOT)));
+    assert_eq!(snapshot_volume(&id), Some(VOLUME));
+    assert_eq!(snapshot_created(&id), Some(created));
+    assert_eq!(
+        snapshot_tag(&id),
+        Some(&snapshot_prefix(VOLUME, SNAPSHOT)[VOLUME.len() ..])
+    );
+
+    // the same snapshot of another volume has the same tag
+    let other = "d3b07384-d9a0-4c9b-8f3e-2b1c0a9e8f7d";
+    assert_eq!(
+        snapshot_tag(&snapshot_id(other, SNAPSHOT, created)),
+        snapshot_tag(&id)
+    );
+
+    let snapshot = csi_snapshot(&id, VOLUME, 4096);
+    assert_eq!(snapshot.source_volume_id, VOLUME);
+    assert_eq!(snapshot.size_bytes, 4096);
+    assert_eq!(snapshot.creation_time.unwrap().seconds, 1_600_000_000);
+
+    // replicas which were not snapshotted by the controller
+    assert_eq!(snapshot_volume(VOLUME), None);
+    assert_eq!(snapshot_volume(&format!("{}@snap", VOLUME)), None);
+    assert_eq!(snapshot_created(&format!("{}@snap", VOLUME)), None);
+}
+
+#[test]
+fn list_tokens() {
+    assert_eq!(list_range("", 0).unwrap(), (0, DEFAULT_MAX_ENTRIES));
+    assert_eq!(list_range("10", 5).unwrap(), (10, 5));
+    assert_eq!(list_range("next", 5).unwrap_err().code(), Code::Aborted);
+}
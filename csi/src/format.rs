@@ -1,29 +1,54 @@
 //! Utility function for formatting a device with filesystem
 
-use std::process::Command;
+use std::{fmt, process::Command};
 
 // Move these to csi_common.rs in the future
 use blkid::probe::Probe;
 
-/// We probe the device for a filesystem, if there we leave it as is. We do
-/// not check at current -- if the FS is the desired FS. This is done with the
-/// mindset of, never over write/delete data.
+/// Reasons for not being able to prepare a device for staging
+#[derive(Debug)]
+pub(crate) enum FormatError {
+    /// the device could not be probed for a filesystem
+    Probe(String),
+    /// the device holds a different filesystem than the requested one
+    Mismatch(String),
+    /// the filesystem on the device failed the check
+    Check(String),
+    /// the device could not be formatted
+    Format(String),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::Probe(s)
+            | FormatError::Mismatch(s)
+            | FormatError::Check(s)
+            | FormatError::Format(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// We probe the device for a filesystem, if there we leave it as is after
+/// checking it is the desired FS and that it is consistent. This is done
+/// with the mindset of, never over write/delete data.
 
 // TODO implicit probed_format_and_mount()
 pub(crate) async fn probed_format(
     device: &str,
     fstype: &str,
-) -> Result<(), String> {
+    mkfs_options: &[String],
+) -> Result<(), FormatError> {
     let probe = Probe::new_from_filename(device);
 
     if probe.is_err() {
-        return Err("Failed to init device probing".into());
+        return Err(FormatError::Probe("Failed to init device probing".into()));
     }
 
     let probe = probe.unwrap();
 
     if probe.do_probe().is_err() {
-        return Err("Failed to probe device".into());
+        return Err(FormatError::Probe("Failed to probe device".into()));
     }
 
     // blkid used char **data as a buffer to fill in the value of the
@@ -35,6 +60,7 @@ pub(crate) async fn probed_format(
         Err(_) => {
             debug!("Formatting device {} with a {} filesystem", device, fstype);
             let output = Command::new(format!("mkfs.{}", fstype))
+                .args(mkfs_options)
                 .arg(device)
                 .output()
                 .expect("Failed to execute mkfs command");
@@ -44,22 +70,81 @@ pub(crate) async fn probed_format(
                 String::from_utf8(output.stdout).unwrap()
             );
             if !output.status.success() {
-                return Err(format!(
+                return Err(FormatError::Format(format!(
                     "Failed to format {} with {} fs: {}",
                     device,
                     fstype,
                     String::from_utf8(output.stderr).unwrap()
-                ));
+                )));
             }
             info!("Device {} formatted with {} filesystem", device, fstype);
         }
         Ok(fs) => {
+            if fs != fstype {
+                return Err(FormatError::Mismatch(format!(
+                    "Device {} contains a {} filesystem, not the requested {}",
+                    device, fs, fstype
+                )));
+            }
             info!(
                 "Skipping format: device {} contains a preexisting {} filesystem",
                 device, fs
             );
+            check_filesystem(device, &fs)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the existing filesystem on the device before it is mounted. An
+/// ext4 filesystem is repaired if that can be done safely, that is without
+/// asking questions, while xfs is checked without any modifications.
+fn check_filesystem(device: &str, fstype: &str) -> Result<(), FormatError> {
+    let (cmd, args) = match fstype {
+        "ext4" => ("e2fsck", ["-p", device]),
+        "xfs" => ("xfs_repair", ["-n", device]),
+        _ => {
+            debug!("Skipping check of {} filesystem on {}", fstype, device);
+            return Ok(());
         }
+    };
+
+    debug!("Checking {} filesystem on {}", fstype, device);
+    let output = match Command::new(cmd).args(&args).output() {
+        Ok(output) => output,
+        Err(err) => {
+            return Err(FormatError::Check(format!(
+                "Failed to execute {}: {}",
+                cmd, err
+            )))
+        }
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    trace!(
+        "Output of {} command: {}",
+        cmd,
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let code = output.status.code().unwrap_or(-1);
+    let clean = match fstype {
+        // 1 means that errors were corrected
+        "ext4" => code == 0 || code == 1,
+        // a dirty log is replayed when the filesystem is mounted
+        _ => code == 0 || stderr.contains("metadata changes in a log"),
+    };
+
+    if !clean {
+        return Err(FormatError::Check(format!(
+            "Check of {} fs on {} failed with {}: {}",
+            fstype, device, code, stderr
+        )));
+    }
+    if code != 0 {
+        warn!("Check of {} fs on {}: {}", fstype, device, stderr);
     }
+    info!("Device {} passed the check of its {} fs", device, fstype);
 
     Ok(())
 }
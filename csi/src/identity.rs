@@ -37,17 +37,29 @@ impl identity_server::Identity for Identity {
         ];
         debug!("GetPluginCapabilities request: {:?}", caps);
 
+        let mut capabilities: Vec<PluginCapability> = caps
+            .into_iter()
+            .map(|c| PluginCapability {
+                r#type: Some(plugin_capability::Type::Service(
+                    plugin_capability::Service {
+                        r#type: c as i32,
+                    },
+                )),
+            })
+            .collect();
+
+        // volumes are expanded while published
+        capabilities.push(PluginCapability {
+            r#type: Some(plugin_capability::Type::VolumeExpansion(
+                plugin_capability::VolumeExpansion {
+                    r#type: plugin_capability::volume_expansion::Type::Online
+                        as i32,
+                },
+            )),
+        });
+
         Ok(Response::new(GetPluginCapabilitiesResponse {
-            capabilities: caps
-                .into_iter()
-                .map(|c| PluginCapability {
-                    r#type: Some(plugin_capability::Type::Service(
-                        plugin_capability::Service {
-                            r#type: c as i32,
-                        },
-                    )),
-                })
-                .collect(),
+            capabilities,
         }))
     }
 
@@ -7,6 +7,7 @@ use sys_mount::{unmount, FilesystemType, Mount, MountFlags, UnmountFlags};
 pub struct MountInfo {
     pub source: String,
     pub dest: String,
+    pub fstype: String,
     pub opts: Vec<String>,
 }
 
@@ -73,6 +74,7 @@ pub fn match_mount(
                 return Some(MountInfo {
                     source: mount.source.to_string_lossy().to_string(),
                     dest: mount.dest.to_string_lossy().to_string(),
+                    fstype: mount.fstype,
                     opts: mount.options,
                 });
             }
@@ -178,3 +180,67 @@ pub fn unmount_fs(from: &str, bound: bool) -> Result<(), String> {
         Err(err) => Err(format!("Failed to unmount fs at {}: {}", from, err)),
     }
 }
+
+/// Bind mount a block device node onto a file. The read-only flag is not
+/// honoured by the initial bind mount, so it takes a remount to apply it.
+pub fn blockdevice_mount(
+    from: &str,
+    to: &str,
+    readonly: bool,
+) -> Result<(), String> {
+    debug!("Mounting block device {} ...", from);
+
+    let flags = MountFlags::BIND;
+
+    if let Err(err) =
+        Mount::new(from, to, FilesystemType::Manual("none"), flags, None)
+    {
+        return Err(format!(
+            "Failed to mount block device {} to {}: {}",
+            from, to, err,
+        ));
+    }
+
+    if readonly {
+        let flags = flags | MountFlags::REMOUNT | MountFlags::RDONLY;
+        if let Err(err) =
+            Mount::new("", to, FilesystemType::Manual("none"), flags, None)
+        {
+            let _ = unmount(to, UnmountFlags::empty());
+            return Err(format!(
+                "Failed to remount block device {} at {} read-only: {}",
+                from, to, err,
+            ));
+        }
+    }
+
+    info!(
+        "Mounted block device {} to {} (readonly={})",
+        from, to, readonly,
+    );
+    Ok(())
+}
+
+/// Unmount a block device bind mounted onto a file and remove the file.
+pub fn blockdevice_unmount(target: &str) -> Result<(), String> {
+    debug!("Unmounting block device at {} ...", target);
+
+    if let Err(err) = unmount(target, UnmountFlags::FORCE) {
+        return Err(format!(
+            "Failed to unmount block device at {}: {}",
+            target, err
+        ));
+    }
+
+    if let Err(err) = std::fs::remove_file(target) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            return Err(format!(
+                "Failed to remove block device file {}: {}",
+                target, err
+            ));
+        }
+    }
+
+    info!("Block device at {} has been unmounted", target);
+    Ok(())
+}
@@ -1,24 +1,65 @@
-use std::{boxed::Box, fs, io::ErrorKind, path::PathBuf, vec::Vec};
+use std::{
+    boxed::Box,
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time,
+    vec::Vec,
+};
 
 use tonic::{Code, Request, Response, Status};
 
-use glob::glob;
+use rpc::{
+    mayastor::{NexusState, Null},
+    service::mayastor_client::MayastorClient,
+};
 
 use crate::{
     csi::{volume_capability::access_mode::Mode, *},
-    format::probed_format,
-    mount::{match_mount, mount_fs, mount_opts_compare, unmount_fs},
+    format::{probed_format, FormatError},
+    mount::{
+        blockdevice_mount,
+        blockdevice_unmount,
+        match_mount,
+        mount_fs,
+        mount_opts_compare,
+        unmount_fs,
+    },
+    resize::{grow_filesystem, FS_METADATA_PERCENT},
+    state::{NodeState, VolumeState},
+    stats::{blockdevice_size, blockdevice_usage, filesystem_usage},
 };
 
 mod iscsiutil;
-use iscsiutil::{iscsi_attach_disk, iscsi_detach_disk, iscsi_find};
+use iscsiutil::{
+    iscsi_attach_disk,
+    iscsi_detach_disk,
+    iscsi_find,
+    iscsi_rescan,
+};
 mod nvmfutil;
-use nvmfutil::{nvmf_attach_disk, nvmf_detach_disk, nvmf_find};
+use nvmfutil::{
+    nvmf_attach_disk,
+    nvmf_detach_disk,
+    nvmf_find,
+    nvmf_path_states,
+    nvmf_rescan,
+};
+mod reconcile;
 
 #[derive(Clone, Debug)]
 pub struct Node {
     pub node_name: String,
     pub filesystems: Vec<String>,
+    /// gRPC endpoint of the mayastor instance on this node
+    pub grpc_endpoint: String,
+    /// topology segments of the node
+    pub topology: HashMap<String, String>,
+    /// maximum number of volumes that can be published on the node
+    pub max_volumes: i64,
+    /// record of the volumes staged on the node
+    pub state: NodeState,
 }
 
 // Determine if given access mode in conjunction with ro mount flag makes
@@ -62,7 +103,251 @@ fn check_access_mode(
     }
 }
 
-impl Node {}
+// Raw block volumes are staged by bind mounting the device onto a file
+// named after the volume within the staging directory, from where the
+// device is bind mounted again onto the publish target.
+fn block_staging_path(staging_path: &str, volume_id: &str) -> String {
+    Path::new(staging_path)
+        .join(volume_id)
+        .to_string_lossy()
+        .to_string()
+}
+
+// Create an empty file for a block device to be bind mounted onto,
+// including any missing parent directories.
+fn create_block_target(path: &str) -> Result<(), String> {
+    if let Some(parent) = Path::new(path).parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            return Err(format!(
+                "Failed to create directory {}: {}",
+                parent.display(),
+                err
+            ));
+        }
+    }
+    match fs::OpenOptions::new().write(true).create(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Failed to create file {}: {}", path, err)),
+    }
+}
+
+// Search for the device the volume has been attached to.
+fn find_device(volume_id: &str) -> Option<String> {
+    iscsi_find(volume_id).or_else(|| nvmf_find(volume_id))
+}
+
+// Rescan the device the volume has been attached to, so that a new size of
+// the volume is picked up.
+fn rescan_device(volume_id: &str) -> Result<(), String> {
+    let res = if iscsi_find(volume_id).is_some() {
+        iscsi_rescan(volume_id)
+    } else if nvmf_find(volume_id).is_some() {
+        nvmf_rescan(volume_id)
+    } else {
+        return Err(format!("Device of volume {} not found", volume_id));
+    };
+    res.map_err(|err| format!("Failed to rescan volume {}: {}", volume_id, err))
+}
+
+// Wait for the size of the device to reach the required size, as the new
+// size is picked up asynchronously after a rescan. Returns the last size
+// seen.
+async fn wait_for_device_size(
+    device: &str,
+    required: u64,
+) -> Result<u64, String> {
+    let second = time::Duration::from_secs(1);
+    let mut retries = 0;
+    loop {
+        let size = blockdevice_size(device).map_err(|err| {
+            format!("Failed to get size of device {}: {}", device, err)
+        })?;
+        if size >= required || retries >= 10 {
+            return Ok(size);
+        }
+        tokio::time::delay_for(second).await;
+        retries += 1;
+    }
+}
+
+// Attach the device of the volume through the target at the uri and return
+// the path to the device.
+fn attach_device(uri: &str) -> Result<String, Status> {
+    match url::Url::parse(uri) {
+        Ok(url) => match url.scheme() {
+            "iscsi" => {
+                match iscsi_attach_disk(uri) {
+                    Ok(devpath) => Ok(devpath),
+                    Err(e) => {
+                        Err(Status::new(Code::NotFound, format!("{}", e)))
+                    }
+                }
+                // The nexus may reside on another node,
+                // currently there is no way to retrieve the nexus details
+                // from a remote node.
+            }
+            "nvmf" => match nvmf_attach_disk(uri) {
+                Ok(devpath) => Ok(devpath),
+                Err(e) => Err(Status::new(Code::NotFound, format!("{}", e))),
+            },
+            "file" => Ok(String::from(url.path())),
+            _ => Err(Status::new(
+                Code::InvalidArgument,
+                format!("Unsupported {}", uri),
+            )),
+        },
+        Err(e) => Err(Status::new(
+            Code::InvalidArgument,
+            format!("Invalid uri {}", e),
+        )),
+    }
+}
+
+// Detach the device of the volume, if it is attached.
+fn detach_device(volume_id: &str) -> Result<(), String> {
+    if let Some(devpath) = iscsi_find(volume_id) {
+        debug!("iSCSI detach {}", devpath);
+        iscsi_detach_disk(volume_id).map_err(|err| format!("{}", err))
+    } else if let Some(devpath) = nvmf_find(volume_id) {
+        debug!("nvmf detach {}", devpath);
+        nvmf_detach_disk(volume_id).map_err(|err| format!("{}", err))
+    } else {
+        Ok(())
+    }
+}
+
+impl Node {
+    fn save_state(&self, volume: &VolumeState) -> Result<(), Status> {
+        self.state.save(volume).map_err(|err| {
+            Status::new(
+                Code::Internal,
+                format!(
+                    "Failed to save state of volume {}: {}",
+                    volume.volume_id, err
+                ),
+            )
+        })
+    }
+
+    /// Ask the mayastor instance on this node for the state of the nexus of
+    /// the volume and return a description of the problem if it is not
+    /// online. Nothing is known about a nexus that resides on another node.
+    async fn nexus_condition(&self, volume_id: &str) -> Option<String> {
+        let uri = format!("http://{}", self.grpc_endpoint);
+        let mut client = match MayastorClient::connect(uri).await {
+            Ok(client) => client,
+            Err(err) => {
+                debug!("Failed to connect to {}: {}", self.grpc_endpoint, err);
+                return None;
+            }
+        };
+
+        let nexus_list = match client.list_nexus(Null {}).await {
+            Ok(reply) => reply.into_inner().nexus_list,
+            Err(err) => {
+                debug!("Failed to list nexus: {}", err);
+                return None;
+            }
+        };
+
+        let nexus = nexus_list.into_iter().find(|n| n.uuid == volume_id)?;
+        match NexusState::from_i32(nexus.state) {
+            Some(NexusState::NexusOnline) => None,
+            Some(NexusState::NexusDegraded) => {
+                Some(format!("Nexus of volume {} is degraded", volume_id))
+            }
+            Some(NexusState::NexusFaulted) => {
+                Some(format!("Nexus of volume {} is faulted", volume_id))
+            }
+            _ => Some(format!(
+                "Nexus of volume {} is in an unknown state",
+                volume_id
+            )),
+        }
+    }
+
+    /// Publish a staged raw block volume by bind mounting the staged device
+    /// onto the target path.
+    fn publish_block_volume(
+        &self,
+        msg: &NodePublishVolumeRequest,
+    ) -> Result<Response<NodePublishVolumeResponse>, Status> {
+        let target_path = &msg.target_path;
+        let volume_id = &msg.volume_id;
+        let staged = block_staging_path(&msg.staging_target_path, volume_id);
+
+        if match_mount(None, Some(&staged), true).is_none() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!(
+                    "No block device {} for volume {} (hint: volume unstaged?)",
+                    staged, volume_id
+                ),
+            ));
+        }
+
+        if match_mount(None, Some(target_path), true).is_some() {
+            info!("Block volume {} already published", volume_id);
+            return Ok(Response::new(NodePublishVolumeResponse {}));
+        }
+
+        if let Err(err) = create_block_target(target_path) {
+            return Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Failed to create target for volume {}: {}",
+                    volume_id, err
+                ),
+            ));
+        }
+
+        if let Err(err) = blockdevice_mount(&staged, target_path, msg.readonly)
+        {
+            let _ = fs::remove_file(target_path);
+            Err(Status::new(
+                Code::Internal,
+                format!("Failed to publish volume {}: {}", volume_id, err),
+            ))
+        } else {
+            info!("Published block volume {}", volume_id);
+            Ok(Response::new(NodePublishVolumeResponse {}))
+        }
+    }
+
+    /// Stage a raw block volume by bind mounting the attached device onto a
+    /// file within the staging directory. The device is never formatted.
+    fn stage_block_volume(
+        &self,
+        volume_id: &str,
+        staging_path: &str,
+        device_path: &str,
+    ) -> Result<Response<NodeStageVolumeResponse>, Status> {
+        let staged = block_staging_path(staging_path, volume_id);
+
+        if match_mount(None, Some(&staged), true).is_some() {
+            // the device is already staged we should return OK
+            return Ok(Response::new(NodeStageVolumeResponse {}));
+        }
+
+        if let Err(err) = create_block_target(&staged) {
+            return Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Failed to create staging target for volume {}: {}",
+                    volume_id, err
+                ),
+            ));
+        }
+
+        match blockdevice_mount(device_path, &staged, false) {
+            Err(r) => {
+                let _ = fs::remove_file(&staged);
+                Err(Status::new(Code::Internal, r))
+            }
+            Ok(_) => Ok(Response::new(NodeStageVolumeResponse {})),
+        }
+    }
+}
 #[tonic::async_trait]
 impl node_server::Node for Node {
     async fn node_get_info(
@@ -70,18 +355,19 @@ impl node_server::Node for Node {
         _request: Request<NodeGetInfoRequest>,
     ) -> Result<Response<NodeGetInfoResponse>, Status> {
         let node_id = format!("mayastor://{}", &self.node_name);
-        let max_volumes_per_node =
-            glob("/dev/nbd*").expect("Invalid glob pattern").count() as i64;
+        let max_volumes_per_node = self.max_volumes;
 
         debug!(
-            "NodeGetInfo request: ID={}, max volumes={}",
-            node_id, max_volumes_per_node,
+            "NodeGetInfo request: ID={}, max volumes={}, topology={:?}",
+            node_id, max_volumes_per_node, self.topology,
         );
 
         Ok(Response::new(NodeGetInfoResponse {
             node_id,
             max_volumes_per_node,
-            accessible_topology: None,
+            accessible_topology: Some(Topology {
+                segments: self.topology.clone(),
+            }),
         }))
     }
 
@@ -89,11 +375,15 @@ impl node_server::Node for Node {
         &self,
         _request: Request<NodeGetCapabilitiesRequest>,
     ) -> Result<Response<NodeGetCapabilitiesResponse>, Status> {
-        let caps = vec![node_service_capability::rpc::Type::StageUnstageVolume];
+        let caps = vec![
+            node_service_capability::rpc::Type::StageUnstageVolume,
+            node_service_capability::rpc::Type::GetVolumeStats,
+            node_service_capability::rpc::Type::ExpandVolume,
+            node_service_capability::rpc::Type::VolumeCondition,
+        ];
 
         debug!("NodeGetCapabilities request: {:?}", caps);
 
-        // We don't support stage/unstage and expand volume rpcs
         Ok(Response::new(NodeGetCapabilitiesResponse {
             capabilities: caps
                 .into_iter()
@@ -146,14 +436,25 @@ impl node_server::Node for Node {
             ));
         }
 
-        // TODO: Support raw volumes
+        if msg.volume_capability.is_none() {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("No volume capabilities provided for {}", volume_id),
+            ));
+        }
+
+        if let Err(reason) = check_access_mode(
+            volume_id,
+            &msg.volume_capability.as_ref().unwrap().access_mode,
+            msg.readonly,
+        ) {
+            return Err(Status::new(Code::InvalidArgument, reason));
+        }
+
         let mnt = match msg.volume_capability.as_ref().unwrap().access_type {
             Some(volume_capability::AccessType::Mount(ref m)) => m,
             Some(volume_capability::AccessType::Block(_)) => {
-                return Err(Status::new(
-                    Code::InvalidArgument,
-                    "Raw block not ratified yet",
-                ));
+                return self.publish_block_volume(&msg);
             }
             None => {
                 return Err(Status::new(
@@ -175,14 +476,6 @@ impl node_server::Node for Node {
             ));
         }
 
-        if let Err(reason) = check_access_mode(
-            volume_id,
-            &msg.volume_capability.as_ref().unwrap().access_mode,
-            msg.readonly,
-        ) {
-            return Err(Status::new(Code::InvalidArgument, reason));
-        }
-
         let filesystem = if mnt.fs_type.is_empty() {
             &self.filesystems[0]
         } else {
@@ -266,12 +559,21 @@ impl node_server::Node for Node {
         let target_path = &msg.target_path;
         let volume_id = &msg.volume_id;
 
-        // TODO: Support raw volumes
+        // raw block volumes are published on a file rather than a directory
+        let block = fs::metadata(target_path)
+            .map(|meta| !meta.is_dir())
+            .unwrap_or(false);
+
         match match_mount(None, Some(target_path), true) {
             Some(_) => {
                 debug!("Unmount volume {} at {}...", volume_id, target_path);
 
-                if let Err(err) = unmount_fs(target_path, true) {
+                let res = if block {
+                    blockdevice_unmount(target_path)
+                } else {
+                    unmount_fs(target_path, true)
+                };
+                if let Err(err) = res {
                     return Err(Status::new(
                         Code::Internal,
                         format!(
@@ -282,45 +584,232 @@ impl node_server::Node for Node {
                 }
                 info!("Unpublished volume {} at {}", volume_id, target_path);
             }
-            None => error!("Volume {} is not published", volume_id),
+            None => {
+                error!("Volume {} is not published", volume_id);
+                if block {
+                    let _ = fs::remove_file(target_path);
+                }
+            }
         }
 
         Ok(Response::new(NodeUnpublishVolumeResponse {}))
     }
 
-    /// Get volume stats method is currently not implemented. But it's simple
-    /// to do.
-    ///
-    /// TODO: Just read the data about capacity/used space inodes/bytes from
-    /// the system using the mountpoint.
+    /// Report the usage of bytes and inodes of the filesystem of a volume
+    /// or the size of a raw block volume, along with the condition of the
+    /// volume. The volume is abnormal if its device has gone missing or its
+    /// nexus is not online.
     async fn node_get_volume_stats(
         &self,
         request: Request<NodeGetVolumeStatsRequest>,
     ) -> Result<Response<NodeGetVolumeStatsResponse>, Status> {
         let msg = request.into_inner();
+        let volume_id = &msg.volume_id;
+        let volume_path = &msg.volume_path;
+
         trace!("node_get_volume_stats {:?}", msg);
 
-        /*
+        if volume_id == "" || volume_path == "" {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Invalid volume path or volume id",
+            ));
+        }
+
+        let meta = match fs::metadata(volume_path) {
+            Ok(meta) => meta,
+            Err(err) => {
+                return Err(Status::new(
+                    Code::NotFound,
+                    format!(
+                        "Volume path {} of volume {}: {}",
+                        volume_path, volume_id, err
+                    ),
+                ))
+            }
+        };
+
+        let mount = match match_mount(None, Some(volume_path), true) {
+            Some(mount) => mount,
+            None => {
+                return Err(Status::new(
+                    Code::NotFound,
+                    format!(
+                        "Volume {} is not mounted at {}",
+                        volume_id, volume_path
+                    ),
+                ))
+            }
+        };
+
+        // raw block volumes are mounted on a file rather than a directory
+        let block = !meta.is_dir();
+        let device = if block {
+            find_device(volume_id)
+        } else {
+            Some(mount.source).filter(|dev| Path::new(dev).exists())
+        };
+
+        let usage = if block {
+            blockdevice_usage(volume_path)
+        } else {
+            filesystem_usage(volume_path)
+        };
+
+        let mut problems = Vec::new();
+        let usage = match usage {
+            Ok(usage) => usage,
+            Err(err) => {
+                problems.push(format!(
+                    "Failed to get usage of volume {}: {}",
+                    volume_id, err
+                ));
+                Vec::new()
+            }
+        };
+        if device.is_none() {
+            problems.push(format!("Device of volume {} is missing", volume_id));
+        }
+        for (address, state) in nvmf_path_states(volume_id) {
+            if state != "live" {
+                problems.push(format!(
+                    "Path {} of volume {} is {}",
+                    address, volume_id, state
+                ));
+            }
+        }
+        if let Some(problem) = self.nexus_condition(volume_id).await {
+            problems.push(problem);
+        }
+
+        let volume_condition = if problems.is_empty() {
+            VolumeCondition {
+                abnormal: false,
+                message: "Volume is healthy".to_string(),
+            }
+        } else {
+            warn!("{}", problems.join(", "));
+            VolumeCondition {
+                abnormal: true,
+                message: problems.join(", "),
+            }
+        };
+
         Ok(Response::new(NodeGetVolumeStatsResponse {
-            usage: vec![VolumeUsage {
-                total: 0 as i64,
-                unit: volume_usage::Unit::Bytes as i32,
-                available: 0,
-                used: 0,
-            }],
+            usage,
+            volume_condition: Some(volume_condition),
         }))
-        */
-        error!("Unimplemented {:?}", msg);
-        Err(Status::new(Code::Unimplemented, "Method not implemented"))
     }
 
+    /// Grow the filesystem of a volume online once the volume has been
+    /// expanded by the controller. The device is rescanned to pick up the
+    /// new size first, which is all that is needed for raw block volumes.
     async fn node_expand_volume(
         &self,
         request: Request<NodeExpandVolumeRequest>,
     ) -> Result<Response<NodeExpandVolumeResponse>, Status> {
         let msg = request.into_inner();
-        error!("Unimplemented {:?}", msg);
-        Err(Status::new(Code::Unimplemented, "Method not implemented"))
+        let volume_id = &msg.volume_id;
+        let volume_path = &msg.volume_path;
+
+        trace!("node_expand_volume {:?}", msg);
+
+        if volume_id == "" || volume_path == "" {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                "Invalid volume path or volume id",
+            ));
+        }
+
+        let mount = match match_mount(None, Some(volume_path), true) {
+            Some(mount) => mount,
+            None => {
+                return Err(Status::new(
+                    Code::NotFound,
+                    format!(
+                        "Volume {} is not mounted at {}",
+                        volume_id, volume_path
+                    ),
+                ))
+            }
+        };
+
+        let required = msg
+            .capacity_range
+            .as_ref()
+            .map(|r| r.required_bytes as u64)
+            .unwrap_or(0);
+
+        if let Err(reason) = rescan_device(volume_id) {
+            return Err(Status::new(Code::Internal, reason));
+        }
+
+        // raw block volumes are mounted on a file rather than a directory
+        let block = fs::metadata(volume_path)
+            .map(|meta| !meta.is_dir())
+            .unwrap_or(false);
+        let device = if block {
+            volume_path.clone()
+        } else {
+            mount.source.clone()
+        };
+
+        let size = match wait_for_device_size(&device, required).await {
+            Ok(size) => size,
+            Err(reason) => return Err(Status::new(Code::Internal, reason)),
+        };
+        if size < required {
+            return Err(Status::new(
+                Code::FailedPrecondition,
+                format!(
+                    "Size {} of volume {} is less than the required size {}",
+                    size, volume_id, required
+                ),
+            ));
+        }
+
+        if !block {
+            if let Err(reason) =
+                grow_filesystem(&device, volume_path, &mount.fstype).await
+            {
+                return Err(Status::new(Code::Internal, reason));
+            }
+
+            // verify that the filesystem covers the device, apart from the
+            // space taken by its metadata
+            let expected = size - size * FS_METADATA_PERCENT / 100;
+            match filesystem_usage(volume_path) {
+                Ok(after) if after[0].total as u64 >= expected => {
+                    info!(
+                        "Filesystem of volume {} grown to {} bytes",
+                        volume_id, after[0].total
+                    );
+                }
+                Ok(after) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!(
+                            "Filesystem of volume {} has been grown to {} bytes only, the volume is {} bytes",
+                            volume_id, after[0].total, size
+                        ),
+                    ))
+                }
+                Err(err) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!(
+                            "Failed to get size of filesystem of volume {}: {}",
+                            volume_id, err
+                        ),
+                    ))
+                }
+            }
+        }
+
+        info!("Expanded volume {} to {} bytes", volume_id, size);
+        Ok(Response::new(NodeExpandVolumeResponse {
+            capacity_bytes: size as i64,
+        }))
     }
 
     async fn node_stage_volume(
@@ -348,15 +837,12 @@ impl node_server::Node for Node {
             ));
         }
 
-        // TODO: support raw block volumes
+        // a raw block volume has no mount capability
         let mnt = match msg.volume_capability.as_ref().unwrap().access_type {
-            Some(volume_capability::AccessType::Mount(ref m)) => m.clone(),
-            Some(volume_capability::AccessType::Block(_)) => {
-                return Err(Status::new(
-                    Code::InvalidArgument,
-                    "Raw block support is not supported",
-                ))
+            Some(volume_capability::AccessType::Mount(ref m)) => {
+                Some(m.clone())
             }
+            Some(volume_capability::AccessType::Block(_)) => None,
             None => {
                 return Err(Status::new(
                     Code::InvalidArgument,
@@ -374,20 +860,44 @@ impl node_server::Node for Node {
             return Err(Status::new(Code::InvalidArgument, reason));
         };
 
-        let filesystem = if mnt.fs_type.is_empty() {
-            self.filesystems[0].clone()
-        } else {
-            match self.filesystems.iter().find(|ent| **ent == mnt.fs_type) {
-                Some(fs) => fs.clone(),
-                None => {
-                    return Err(Status::new(
-                        Code::InvalidArgument,
-                        format!("Filesystem {} is not supported", mnt.fs_type),
-                    ));
+        let filesystem = match mnt {
+            Some(ref mnt) if !mnt.fs_type.is_empty() => {
+                match self.filesystems.iter().find(|ent| **ent == mnt.fs_type) {
+                    Some(fs) => fs.clone(),
+                    None => {
+                        return Err(Status::new(
+                            Code::InvalidArgument,
+                            format!(
+                                "Filesystem {} is not supported",
+                                mnt.fs_type
+                            ),
+                        ));
+                    }
                 }
             }
+            _ => self.filesystems[0].clone(),
         };
 
+        // options from the storage class, mkfs options are separated by
+        // white space and mount options by commas
+        let mkfs_options: Vec<String> = msg
+            .volume_context
+            .get("mkfsOptions")
+            .map(|o| o.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        let mut mount_flags: Vec<String> = mnt
+            .as_ref()
+            .map(|m| m.mount_flags.clone())
+            .unwrap_or_default();
+        if let Some(opts) = msg.volume_context.get("mountOptions") {
+            mount_flags.extend(
+                opts.split(',')
+                    .map(str::trim)
+                    .filter(|o| !o.is_empty())
+                    .map(String::from),
+            );
+        }
+
         debug!("Staging volume {} to {}", volume_id, staging_path);
 
         if let Err(err) = fs::create_dir_all(PathBuf::from(&staging_path)) {
@@ -414,49 +924,42 @@ impl node_server::Node for Node {
 
         debug!("URI is {}", uri);
 
-        let device_path = match url::Url::parse(uri) {
-            Ok(url) => match url.scheme() {
-                "iscsi" => {
-                    match iscsi_attach_disk(uri) {
-                        Ok(devpath) => devpath,
-                        Err(e) => {
-                            return Err(Status::new(
-                                Code::NotFound,
-                                format!("{}", e),
-                            ))
-                        }
-                    }
-                    // The nexus may reside on another node,
-                    // currently there is no way to retrieve the nexus details
-                    // from a remote node.
-                }
-                "nvmf" => match nvmf_attach_disk(uri) {
-                    Ok(devpath) => devpath,
-                    Err(e) => {
-                        return Err(Status::new(
-                            Code::NotFound,
-                            format!("{}", e),
-                        ))
-                    }
-                },
-                "file" => String::from(url.path()),
-                _ => {
-                    return Err(Status::new(
-                        Code::InvalidArgument,
-                        format!("Unsupported {}", uri),
-                    ))
-                }
-            },
-            Err(e) => {
-                return Err(Status::new(
-                    Code::InvalidArgument,
-                    format!("Invalid uri {}", e),
-                ))
-            }
+        // only nvmf volumes can be attached through more than one path
+        if uri.contains(',') && !uri.starts_with("nvmf://") {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Multiple paths are only supported for nvmf {}", uri),
+            ));
+        }
+
+        // record the volume before attaching it, so that the device is
+        // cleaned up should the plugin crash before the volume is staged
+        let mut volume = VolumeState {
+            volume_id: volume_id.clone(),
+            uri: uri.clone(),
+            device_path: String::new(),
+            staging_path: staging_path.clone(),
+            fs_type: mnt.as_ref().map(|_| filesystem.clone()),
+            mount_flags: mount_flags.clone(),
         };
+        if self.state.get(volume_id).is_none() {
+            self.save_state(&volume)?;
+        }
+
+        let device_path = attach_device(uri)?;
+        volume.device_path = device_path.clone();
+        self.save_state(&volume)?;
 
         debug!("device_path is {} for uri {}", device_path, uri);
 
+        if mnt.is_none() {
+            return self.stage_block_volume(
+                volume_id,
+                staging_path,
+                &device_path,
+            );
+        }
+
         if let Some(mount) =
             match_mount(Some(&device_path), Some(&staging_path), false)
         {
@@ -473,20 +976,48 @@ impl node_server::Node for Node {
             }
         }
 
-        if let Err(e) = probed_format(&device_path, &filesystem).await {
-            return Err(Status::new(Code::Internal, e));
+        if let Err(e) =
+            probed_format(&device_path, &filesystem, &mkfs_options).await
+        {
+            let code = match e {
+                FormatError::Mismatch(_) | FormatError::Check(_) => {
+                    Code::FailedPrecondition
+                }
+                _ => Code::Internal,
+            };
+            return Err(Status::new(
+                code,
+                format!("Failed to stage volume {}: {}", volume_id, e),
+            ));
         }
 
-        match mount_fs(
+        if let Err(r) = mount_fs(
             &device_path,
             &staging_path,
             false,
             &filesystem,
-            &mnt.mount_flags,
+            &mount_flags,
         ) {
-            Err(r) => Err(Status::new(Code::Internal, r)),
-            Ok(_) => Ok(Response::new(NodeStageVolumeResponse {})),
+            return Err(Status::new(Code::Internal, r));
         }
+
+        // a filesystem with errors may be mounted read-only by the kernel
+        // rather than failing the mount
+        let readonly = match_mount(None, Some(&staging_path), false)
+            .map(|m| m.opts.iter().any(|o| o == "ro"))
+            .unwrap_or(false);
+        if readonly && !mount_flags.iter().any(|o| o == "ro") {
+            let _ = unmount_fs(&staging_path, false);
+            return Err(Status::new(
+                Code::FailedPrecondition,
+                format!(
+                    "Filesystem of volume {} on {} was mounted read-only",
+                    volume_id, device_path
+                ),
+            ));
+        }
+
+        Ok(Response::new(NodeStageVolumeResponse {}))
     }
 
     async fn node_unstage_volume(
@@ -499,26 +1030,37 @@ impl node_server::Node for Node {
 
         debug!("Unstaging volume {} at {}", volume_id, stage_path);
 
-        if match_mount(None, Some(&stage_path), false).is_none() {
+        let staged = block_staging_path(&stage_path, &volume_id);
+
+        let res = if match_mount(None, Some(&staged), false).is_some() {
+            blockdevice_unmount(&staged)
+        } else if match_mount(None, Some(&stage_path), false).is_some() {
+            unmount_fs(&stage_path, false)
+        } else if self.state.get(&volume_id).is_some() {
+            // staging did not complete, but the device may be attached
+            Ok(())
+        } else {
             // if already unstaged or staging does not match target path -
             // must reply OK
             return Ok(Response::new(NodeUnstageVolumeResponse {}));
+        };
+        if let Err(reason) = res {
+            return Err(Status::new(Code::Internal, reason));
         }
-        if let Err(reason) = unmount_fs(&stage_path, false) {
+
+        // Clean up after successful unmount, if required.
+        if let Err(reason) = detach_device(&volume_id) {
             return Err(Status::new(Code::Internal, reason));
         }
 
-        if let Some(devpath) = iscsi_find(volume_id.as_str()) {
-            debug!("unstage: iSCSI device path is {}", devpath);
-            // Clean up after successful unmount, if required.
-            if let Err(err) = iscsi_detach_disk(volume_id.as_str()) {
-                return Err(Status::new(Code::Internal, format!("{}", err)));
-            }
-        } else if let Some(devpath) = nvmf_find(volume_id.as_str()) {
-            debug!("unstage: nvmf detach {}", devpath);
-            if let Err(err) = nvmf_detach_disk(volume_id.as_str()) {
-                return Err(Status::new(Code::Internal, format!("{}", err)));
-            }
+        if let Err(err) = self.state.remove(&volume_id) {
+            return Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Failed to remove state of volume {}: {}",
+                    volume_id, err
+                ),
+            ));
         }
         Ok(Response::new(NodeUnstageVolumeResponse {}))
     }
@@ -201,6 +201,15 @@ fn detach_disk(ip_addr: &str, port: &str, iqn: &str) -> Result<(), Error> {
     Ok(())
 }
 
+static RE_DEVICE_PATH: Lazy<regex::Regex> = Lazy::new(|| {
+    regex::Regex::new(
+        r"(?x)
+        ip-(?P<ip>\d+.\d+.\d+.\d+):(?P<port>\d+)-iscsi-(?P<iqn>.*)-lun-(?P<lun>\d+)
+        ",
+    )
+    .unwrap()
+});
+
 /// Detaches nexus iscsi target matching the volume id if has
 /// been mounted.
 /// Returns error is the nexus iscsi target was not mounted.
@@ -208,15 +217,6 @@ pub fn iscsi_detach_disk(uuid: &str) -> Result<(), Error> {
     trace!("iscsi_detach_disk {}", uuid);
     let device_path = get_iscsi_device_path(uuid)?;
 
-    static RE_DEVICE_PATH: Lazy<regex::Regex> = Lazy::new(|| {
-        regex::Regex::new(
-            r"(?x)
-            ip-(?P<ip>\d+.\d+.\d+.\d+):(?P<port>\d+)-iscsi-(?P<iqn>.*)-lun-(?P<lun>\d+)
-            ",
-        )
-        .unwrap()
-    });
-
     let caps = RE_DEVICE_PATH.captures(device_path.as_str());
     match caps {
         Some(details) => {
@@ -229,6 +229,42 @@ pub fn iscsi_detach_disk(uuid: &str) -> Result<(), Error> {
     }
 }
 
+fn rescan_session(ip_addr: &str, port: &str, iqn: &str) -> Result<(), Error> {
+    let iscsiadm = get_iscsiadm()?;
+
+    let tp = format!("{}:{}", ip_addr, port);
+
+    let args_rescan = ["-m", "node", "-T", &iqn, "-p", &tp, "-R"];
+    trace!("iscsiadm {:?}", args_rescan);
+    let output = Command::new(&iscsiadm)
+        .args(&args_rescan)
+        .output()
+        .expect("Failed iscsiadm rescan");
+    if !output.status.success() {
+        return Err(Error::from(CSIError::Iscsiadm {
+            error: String::from_utf8(output.stderr).unwrap(),
+        }));
+    }
+
+    Ok(())
+}
+
+/// Rescans the session of the nexus iscsi target matching the volume id
+/// so that a change of the size of the target is picked up.
+pub fn iscsi_rescan(uuid: &str) -> Result<(), Error> {
+    trace!("iscsi_rescan {}", uuid);
+    let device_path = get_iscsi_device_path(uuid)?;
+
+    match RE_DEVICE_PATH.captures(device_path.as_str()) {
+        Some(details) => {
+            rescan_session(&details["ip"], &details["port"], &details["iqn"])
+        }
+        None => Err(Error::from(CSIError::InvalidDevicePath {
+            devpath: device_path.to_string(),
+        })),
+    }
+}
+
 fn get_iscsi_device_path(uuid: &str) -> Result<String, Error> {
     let iscsiadm = get_iscsiadm()?;
 
@@ -280,3 +316,34 @@ pub fn iscsi_find(uuid: &str) -> Option<String> {
     }
     None
 }
+
+/// Return the volume ids of all the nexus iscsi targets that have a session.
+pub fn iscsi_nexus_uuids() -> Vec<String> {
+    let iscsiadm = match get_iscsiadm() {
+        Ok(iscsiadm) => iscsiadm,
+        Err(_) => return Vec::new(),
+    };
+
+    // iscsiadm fails when there are no sessions
+    let output = match Command::new(&iscsiadm).args(&["-m", "session"]).output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => return Vec::new(),
+    };
+    let op = String::from_utf8_lossy(&output.stdout);
+
+    static RE_SESSION: Lazy<regex::Regex> = Lazy::new(|| {
+        regex::Regex::new(
+            r"iqn\.\d+-\d+\.io\.openebs:nexus-(?P<uuid>[0-9a-f-]+)",
+        )
+        .unwrap()
+    });
+
+    let mut uuids: Vec<String> = RE_SESSION
+        .captures_iter(&op)
+        .map(|cap| cap["uuid"].to_string())
+        .collect();
+    uuids.sort();
+    uuids.dedup();
+    uuids
+}
@@ -1,40 +1,56 @@
-use std::{io, thread, time};
-use udev::Enumerator;
+use std::{fs, io, thread, time};
+
+use nvmeadm::{
+    nvme_namespaces,
+    nvmf_subsystem::{NvmeSubsystems, Subsystem},
+};
 
 use crate::CSIError;
 use failure::Error;
 
-fn find_nvmf_device_by_uuid(str_uuid: &str) -> Result<String, String> {
-    let prop = "ID_WWN";
-    let value = format!("uuid.{}", str_uuid);
-
-    trace!("find_nvmf_device_by_uuid uuid={}", str_uuid);
-    let mut enumerator = Enumerator::new().unwrap();
-    enumerator.match_subsystem("block").unwrap();
-    enumerator
-        .match_property("ID_MODEL", "Mayastor NVMe controller")
-        .unwrap();
-    for dev in enumerator.scan_devices().unwrap() {
-        if let Some(udev_value) = dev.property_value(prop) {
-            if udev_value.to_str().unwrap().contains(&value) {
-                trace!(
-                    "find_nvmf_device_by_uuid {} got {:?}",
-                    str_uuid,
-                    dev.property_value("DEVNAME")
-                );
-
-                return Ok(dev
-                    .property_value("DEVNAME")
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string());
-            }
+/// kernel parameter which tells if native NVMe multipath is enabled
+const NVME_MULTIPATH_PARAM: &str = "/sys/module/nvme_core/parameters/multipath";
+
+/// The block devices of the namespaces whose NGUID matches the uuid of the
+/// nexus. The NGUID is read from the namespaces with identify so that only
+/// the namespace exported by the nexus can match.
+fn find_nvmf_devices_by_uuid(str_uuid: &str) -> Vec<String> {
+    trace!("find_nvmf_devices_by_uuid uuid={}", str_uuid);
+    match nvme_namespaces::find_by_nguid(str_uuid) {
+        Ok(devices) => {
+            trace!("find_nvmf_devices_by_uuid {} got {:?}", str_uuid, devices);
+            devices
         }
+        Err(e) => {
+            debug!("find_nvmf_devices_by_uuid {} FAILED: {}", str_uuid, e);
+            Vec::new()
+        }
+    }
+}
+
+fn find_nvmf_device_by_uuid(str_uuid: &str) -> Result<String, String> {
+    match find_nvmf_devices_by_uuid(str_uuid).into_iter().next() {
+        Some(devname) => Ok(devname),
+        None => Err(format!("device not found for {}", str_uuid)),
+    }
+}
+
+/// The controllers connected to the subsystem with the nqn, one per path.
+fn nvmf_controllers(nqn: &str) -> Vec<Subsystem> {
+    match NvmeSubsystems::new() {
+        Ok(subsystems) => subsystems
+            .filter_map(|s| s.ok())
+            .filter(|s| s.nqn == nqn)
+            .collect(),
+        Err(_) => Vec::new(),
     }
+}
 
-    // fall through
-    Err(format!("device not found for {}", str_uuid))
+fn nvme_multipath_enabled() -> bool {
+    match fs::read_to_string(NVME_MULTIPATH_PARAM) {
+        Ok(value) => value.trim() == "Y",
+        Err(_) => false,
+    }
 }
 
 fn uuid_from_str(s: &str) -> String {
@@ -154,28 +170,150 @@ pub fn nvmeadm_detach_disk(nqn: &str) -> Result<(), Error> {
     }
 }
 
+/// A path to a nexus nvmf target
+struct NvmfPath<'a> {
+    host: String,
+    port: u16,
+    nqn: String,
+    uri: &'a str,
+}
+
+impl<'a> NvmfPath<'a> {
+    fn parse(uri: &'a str) -> Result<Self, Error> {
+        if let Ok(url) = url::Url::parse(uri) {
+            if url.scheme() == "nvmf" {
+                let nqn = url.path_segments().and_then(|mut s| s.next());
+                if let (Some(host), Some(port), Some(nqn)) =
+                    (url.host_str(), url.port(), nqn)
+                {
+                    return Ok(Self {
+                        host: host.to_string(),
+                        port,
+                        nqn: nqn.to_string(),
+                        uri,
+                    });
+                }
+            }
+        }
+
+        Err(Error::from(CSIError::InvalidURI {
+            uristr: uri.to_string(),
+        }))
+    }
+
+    /// tells if the controller is connected through this path
+    fn is_controller(&self, ctrl: &Subsystem) -> bool {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        ctrl.address
+            .contains(&format!("traddr={},trsvcid={}", host, self.port))
+    }
+}
+
+/// Attach the nexus nvmf target through all of the comma separated URIs,
+/// which must be paths to the same target. With more than one path the
+/// kernel must merge them into a single multipath namespace, which is
+/// verified before returning the device of the namespace.
 pub fn nvmf_attach_disk(nvmf_uri: &str) -> Result<String, Error> {
     trace!("nvmf_attach_disk {}", nvmf_uri);
 
-    if let Some(path) = wait_for_path_to_exist(uuid_from_str(nvmf_uri), 1) {
-        return Ok(path);
+    let paths = nvmf_uri
+        .split(',')
+        .map(NvmfPath::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let nqn = &paths[0].nqn;
+    if paths.iter().any(|p| &p.nqn != nqn) {
+        return Err(Error::from(CSIError::InvalidURI {
+            uristr: nvmf_uri.to_string(),
+        }));
     }
 
-    if let Ok(url) = url::Url::parse(nvmf_uri) {
-        if url.scheme() == "nvmf" {
-            let tokens: Vec<&str> = url.path_segments().unwrap().collect();
-            return nvmeadm_attach_disk(
-                url.host_str().unwrap(),
-                u32::from(url.port().unwrap()),
-                tokens[0],
-                nvmf_uri,
-            );
+    if paths.len() > 1 && !nvme_multipath_enabled() {
+        return Err(Error::from(CSIError::Nvmf {
+            error: format!(
+                "{} paths to {} but NVMe multipath is not enabled",
+                paths.len(),
+                nqn
+            ),
+        }));
+    }
+
+    let uuid = uuid_from_str(nvmf_uri);
+    let controllers = nvmf_controllers(nqn);
+    let mut device = wait_for_path_to_exist(uuid.clone(), 1);
+
+    for path in &paths {
+        if controllers.iter().any(|c| path.is_controller(c)) {
+            trace!("nvmf path {} already connected", path.uri);
+            continue;
         }
+        device = Some(nvmeadm_attach_disk(
+            &path.host,
+            u32::from(path.port),
+            &path.nqn,
+            path.uri,
+        )?);
     }
 
-    Err(Error::from(CSIError::InvalidURI {
-        uristr: nvmf_uri.to_string(),
-    }))
+    let device = match device {
+        Some(device) => device,
+        None => {
+            return Err(Error::from(CSIError::NotFound {
+                value: "nvmf device path not found".to_string(),
+            }))
+        }
+    };
+
+    if paths.len() > 1 {
+        verify_multipath(&uuid, nqn, &paths)?;
+    }
+
+    Ok(device)
+}
+
+/// Verify that all paths are connected and that the kernel has merged the
+/// namespaces behind them into a single device.
+fn verify_multipath(
+    uuid: &str,
+    nqn: &str,
+    paths: &[NvmfPath],
+) -> Result<(), Error> {
+    let devices = find_nvmf_devices_by_uuid(uuid);
+    if devices.len() != 1 {
+        return Err(Error::from(CSIError::Nvmf {
+            error: format!(
+                "Namespaces of {} have not been merged: {:?}",
+                nqn, devices
+            ),
+        }));
+    }
+
+    let controllers = nvmf_controllers(nqn);
+    for path in paths {
+        if !controllers.iter().any(|c| path.is_controller(c)) {
+            return Err(Error::from(CSIError::Nvmf {
+                error: format!("Path {} is not connected", path.uri),
+            }));
+        }
+    }
+
+    info!(
+        "{} paths to {} merged into {}",
+        paths.len(),
+        nqn,
+        devices[0]
+    );
+    Ok(())
+}
+
+/// Return the address and state of each path to the nexus nvmf target
+/// matching the volume id. A path is usable when its state is "live".
+pub fn nvmf_path_states(uuid: &str) -> Vec<(String, String)> {
+    let nqn = format!("nqn.2019-05.io.openebs:nexus-{}", uuid);
+    nvmf_controllers(&nqn)
+        .into_iter()
+        .map(|c| (c.address, c.state))
+        .collect()
 }
 
 /// Search for and return path to the device on which a nexus nvmf
@@ -200,3 +338,39 @@ pub fn nvmf_detach_disk(uuid: &str) -> Result<(), Error> {
     trace!("nvmf_detach_disk for {} nqn is {}", uuid, nqn);
     nvmeadm_detach_disk(&nqn)
 }
+
+/// Return the volume ids of all the nexus nvmf targets that are connected.
+pub fn nvmf_nexus_uuids() -> Vec<String> {
+    let prefix = "nqn.2019-05.io.openebs:nexus-";
+    let mut uuids: Vec<String> = match NvmeSubsystems::new() {
+        Ok(subsystems) => subsystems
+            .filter_map(|s| s.ok())
+            .filter(|s| s.nqn.starts_with(prefix))
+            .map(|s| s.nqn.trim_start_matches(prefix).to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    uuids.sort();
+    uuids.dedup();
+    uuids
+}
+
+/// Rescan the controllers connected to the nexus nvmf target matching the
+/// volume id so that a change of the size of the namespace is picked up.
+pub fn nvmf_rescan(uuid: &str) -> Result<(), Error> {
+    let nqn = format!("nqn.2019-05.io.openebs:nexus-{}", uuid);
+    trace!("nvmf_rescan for {} nqn is {}", uuid, nqn);
+
+    let controllers = nvmf_controllers(&nqn);
+    for ctrl in &controllers {
+        ctrl.rescan()?;
+    }
+
+    if !controllers.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::from(CSIError::NotFound {
+            value: format!("nvmf controller for {}", nqn),
+        }))
+    }
+}
@@ -0,0 +1,152 @@
+//! Reconciliation of the volumes recorded as staged on the node with the
+//! devices and mounts actually present, run when the plugin starts.
+
+use std::fs;
+
+use super::{
+    attach_device,
+    block_staging_path,
+    create_block_target,
+    detach_device,
+    find_device,
+    iscsiutil::{iscsi_detach_disk, iscsi_nexus_uuids},
+    nvmfutil::{nvmf_detach_disk, nvmf_nexus_uuids},
+    Node,
+};
+use crate::{
+    mount::{
+        blockdevice_mount,
+        blockdevice_unmount,
+        match_mount,
+        mount_fs,
+        unmount_fs,
+    },
+    state::VolumeState,
+};
+
+impl Node {
+    /// Bring the node in line with the recorded state of the volumes: volumes
+    /// whose staging never completed are detached, missing devices of staged
+    /// volumes are attached again and connections to targets of volumes that
+    /// are not recorded are dropped.
+    pub fn reconcile(&self) {
+        let volumes = self.state.list();
+        info!("Reconciling {} staged volumes", volumes.len());
+
+        for volume in &volumes {
+            if let Err(err) = self.reconcile_volume(volume) {
+                error!(
+                    "Failed to reconcile volume {}: {}",
+                    volume.volume_id, err
+                );
+            }
+        }
+
+        let connected = nvmf_nexus_uuids()
+            .into_iter()
+            .map(|uuid| (uuid, "nvmf"))
+            .chain(iscsi_nexus_uuids().into_iter().map(|uuid| (uuid, "iscsi")));
+
+        for (uuid, protocol) in connected {
+            if volumes.iter().any(|v| v.volume_id == uuid) {
+                continue;
+            }
+            // volumes staged before their state was recorded are left alone
+            if let Some(device) = find_device(&uuid) {
+                if match_mount(Some(&device), None, false).is_some() {
+                    warn!(
+                        "Volume {} on {} is mounted but not recorded",
+                        uuid, device
+                    );
+                    continue;
+                }
+            }
+            let result = match protocol {
+                "nvmf" => nvmf_detach_disk(&uuid),
+                _ => iscsi_detach_disk(&uuid),
+            };
+            match result {
+                Ok(_) => {
+                    info!("Disconnected orphaned {} volume {}", protocol, uuid)
+                }
+                Err(err) => {
+                    error!("Failed to disconnect volume {}: {}", uuid, err)
+                }
+            }
+        }
+    }
+
+    fn reconcile_volume(&self, volume: &VolumeState) -> Result<(), String> {
+        let volume_id = &volume.volume_id;
+        let target = match volume.fs_type {
+            Some(_) => volume.staging_path.clone(),
+            None => block_staging_path(&volume.staging_path, volume_id),
+        };
+
+        let mount = match match_mount(None, Some(&target), false) {
+            Some(mount) => mount,
+            None => {
+                // staging did not complete or unstaging did not finish
+                detach_device(volume_id)?;
+                if volume.fs_type.is_none() {
+                    let _ = fs::remove_file(&target);
+                }
+                self.state.remove(volume_id).map_err(|e| e.to_string())?;
+                info!("Cleaned up volume {} that was not staged", volume_id);
+                return Ok(());
+            }
+        };
+
+        // there is nothing to attach for a local file
+        if volume.uri.starts_with("file://") {
+            return Ok(());
+        }
+
+        let (device, attached) = match find_device(volume_id) {
+            Some(device) => (device, false),
+            None => {
+                let device =
+                    attach_device(&volume.uri).map_err(|s| s.to_string())?;
+                info!("Attached missing device {} of {}", device, volume_id);
+                (device, true)
+            }
+        };
+
+        // a mount on a device that has gone away is of no use
+        let stale =
+            attached || (volume.fs_type.is_some() && mount.source != device);
+        if stale {
+            self.remount(volume, &target, &device)?;
+            warn!(
+                "Volume {} staged again on {}, it must be published again",
+                volume_id, device
+            );
+        }
+
+        if volume.device_path != device {
+            let mut volume = volume.clone();
+            volume.device_path = device;
+            self.state.save(&volume).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn remount(
+        &self,
+        volume: &VolumeState,
+        target: &str,
+        device: &str,
+    ) -> Result<(), String> {
+        match volume.fs_type {
+            Some(ref fstype) => {
+                unmount_fs(target, false)?;
+                mount_fs(device, target, false, fstype, &volume.mount_flags)
+            }
+            None => {
+                blockdevice_unmount(target)?;
+                create_block_target(target)?;
+                blockdevice_mount(device, target, false)
+            }
+        }
+    }
+}
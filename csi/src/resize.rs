@@ -0,0 +1,48 @@
+//! Utility function for growing a filesystem to the size of its device
+
+use tokio::process::Command;
+
+/// Part of the device in percent which can be taken by the metadata of a
+/// filesystem, rather than counted in its size
+pub(crate) const FS_METADATA_PERCENT: u64 = 10;
+
+/// Grow the filesystem mounted at mountpoint to fill the whole device. Both
+/// ext4 and xfs are grown online, that is while mounted.
+pub(crate) async fn grow_filesystem(
+    device: &str,
+    mountpoint: &str,
+    fstype: &str,
+) -> Result<(), String> {
+    let (cmd, arg) = match fstype {
+        "ext4" => ("resize2fs", device),
+        "xfs" => ("xfs_growfs", mountpoint),
+        _ => {
+            return Err(format!(
+                "Growing a {} filesystem is not supported",
+                fstype
+            ))
+        }
+    };
+
+    debug!("Growing {} filesystem on {} with {}", fstype, device, cmd);
+    let output = match Command::new(cmd).arg(arg).output().await {
+        Ok(output) => output,
+        Err(err) => return Err(format!("Failed to execute {}: {}", cmd, err)),
+    };
+    trace!(
+        "Output of {} command: {}",
+        cmd,
+        String::from_utf8_lossy(&output.stdout)
+    );
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to grow {} fs on {}: {}",
+            fstype,
+            device,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    info!("Grown {} filesystem on {}", fstype, device);
+
+    Ok(())
+}
@@ -14,7 +14,11 @@ use std::{
 
 use chrono::Local;
 use clap::{App, Arg};
-use csi::{identity_server::IdentityServer, node_server::NodeServer};
+use csi::{
+    controller_server::ControllerServer,
+    identity_server::IdentityServer,
+    node_server::NodeServer,
+};
 use env_logger::{Builder, Env};
 use futures::stream::TryStreamExt;
 use std::{
@@ -25,7 +29,14 @@ use std::{
 use tokio::{net::UnixListener, prelude::*};
 use tonic::transport::{server::Connected, Server};
 
-use crate::{identity::Identity, mount::probe_filesystems, node::Node};
+use crate::{
+    config::Config,
+    controller::Controller,
+    identity::Identity,
+    mount::probe_filesystems,
+    node::Node,
+    state::NodeState,
+};
 
 #[allow(dead_code)]
 #[allow(clippy::type_complexity)]
@@ -36,10 +47,15 @@ pub mod csi {
     tonic::include_proto!("csi.v1");
 }
 
+mod config;
+mod controller;
 mod format;
 mod identity;
 mod mount;
 mod node;
+mod resize;
+mod state;
+mod stats;
 
 #[macro_use]
 extern crate failure;
@@ -112,6 +128,35 @@ async fn main() -> Result<(), String> {
                 .help("CSI gRPC listen socket (default /var/tmp/csi.sock)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("YAML config file with the topology of the node")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("controller")
+                .long("controller")
+                .help("Serve the CSI controller service"),
+        )
+        .arg(
+            Arg::with_name("mayastor")
+                .long("mayastor")
+                .value_name("NODE=ADDR:PORT")
+                .help("gRPC endpoint of a mayastor used by the controller")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("grpc-endpoint")
+                .short("g")
+                .long("grpc-endpoint")
+                .value_name("ADDR:PORT")
+                .help("Local mayastor gRPC endpoint (default 127.0.0.1:10124)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("log-debug")
                 .short("l")
@@ -123,7 +168,35 @@ async fn main() -> Result<(), String> {
                 .long("node-name")
                 .value_name("NAME")
                 .help("Unique node name where this instance runs")
-                .required(true)
+                .required_unless("controller")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("zone")
+                .long("zone")
+                .value_name("ZONE")
+                .help("Zone the node is in, reported as its topology")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rack")
+                .long("rack")
+                .value_name("RACK")
+                .help("Rack the node is in, reported as its topology")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-volumes")
+                .long("max-volumes")
+                .value_name("NUMBER")
+                .help("Maximum number of volumes published on the node")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("state-dir")
+                .long("state-dir")
+                .value_name("PATH")
+                .help("State directory (default /var/tmp/mayastor-csi)")
                 .takes_value(true),
         )
         .arg(
@@ -134,10 +207,13 @@ async fn main() -> Result<(), String> {
         )
         .get_matches();
 
-    let node_name = matches.value_of("node-name").unwrap();
+    let node_name = matches.value_of("node-name");
     let csi_socket = matches
         .value_of("csi-socket")
         .unwrap_or("/var/tmp/csi.sock");
+    let grpc_endpoint = matches
+        .value_of("grpc-endpoint")
+        .unwrap_or("127.0.0.1:10124");
     let level = match matches.occurrences_of("v") as usize {
         0 => "info",
         1 => "debug",
@@ -169,6 +245,86 @@ async fn main() -> Result<(), String> {
     }
     builder.init();
 
+    let mut config = match matches.value_of("config") {
+        Some(file) => Config::read(file)?,
+        None => Config::default(),
+    };
+    if let Some(zone) = matches.value_of("zone") {
+        config.zone = Some(zone.into());
+    }
+    if let Some(rack) = matches.value_of("rack") {
+        config.rack = Some(rack.into());
+    }
+    if let Some(max_volumes) = matches.value_of("max-volumes") {
+        match max_volumes.parse::<i64>() {
+            Ok(n) => config.max_volumes = Some(n),
+            Err(_) => {
+                return Err(format!("Invalid max volumes {}", max_volumes));
+            }
+        }
+    }
+    let max_volumes = config.max_volumes()?;
+
+    if let Some(endpoints) = matches.values_of("mayastor") {
+        for endpoint in endpoints {
+            let mut parts = endpoint.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(node), Some(addr))
+                    if !node.is_empty() && !addr.is_empty() =>
+                {
+                    config.mayastor_nodes.insert(node.into(), addr.into());
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid mayastor endpoint {}",
+                        endpoint
+                    ));
+                }
+            }
+        }
+    }
+
+    let controller = if matches.is_present("controller") {
+        if config.mayastor_nodes.is_empty() {
+            return Err("No mayastor endpoints for the controller".into());
+        }
+        Some(Controller::new(config.mayastor_nodes.clone()))
+    } else {
+        None
+    };
+
+    let node = match node_name {
+        Some(node_name) => {
+            let state_dir = matches
+                .value_of("state-dir")
+                .unwrap_or("/var/tmp/mayastor-csi");
+            let state = match NodeState::open(state_dir) {
+                Ok(state) => state,
+                Err(err) => {
+                    return Err(format!(
+                        "Error opening state directory {}: {}",
+                        state_dir, err
+                    ));
+                }
+            };
+
+            let node = Node {
+                node_name: node_name.into(),
+                filesystems: probe_filesystems(),
+                grpc_endpoint: grpc_endpoint.into(),
+                topology: config.topology(node_name),
+                max_volumes,
+                state,
+            };
+
+            // fix up whatever was left behind by a previous instance before
+            // serving
+            node.reconcile();
+            Some(node)
+        }
+        None => None,
+    };
+
     // Remove stale CSI socket from previous instance if there is any
     match fs::remove_file(csi_socket) {
         Ok(_) => info!("Removed stale CSI socket {}", csi_socket),
@@ -185,13 +341,33 @@ async fn main() -> Result<(), String> {
     let mut uds_sock = UnixListener::bind(csi_socket).unwrap();
     info!("CSI plugin bound to {}", csi_socket);
 
-    let uds = Server::builder()
-        .add_service(NodeServer::new(Node {
-            node_name: node_name.into(),
-            filesystems: probe_filesystems(),
-        }))
-        .add_service(IdentityServer::new(Identity {}))
-        .serve_with_incoming(uds_sock.incoming().map_ok(UnixStream));
-    let _ = uds.await;
+    let incoming = uds_sock.incoming().map_ok(UnixStream);
+    let identity = IdentityServer::new(Identity {});
+    let _ = match (node, controller) {
+        (Some(node), Some(controller)) => {
+            Server::builder()
+                .add_service(NodeServer::new(node))
+                .add_service(ControllerServer::new(controller))
+                .add_service(identity)
+                .serve_with_incoming(incoming)
+                .await
+        }
+        (Some(node), None) => {
+            Server::builder()
+                .add_service(NodeServer::new(node))
+                .add_service(identity)
+                .serve_with_incoming(incoming)
+                .await
+        }
+        (None, Some(controller)) => {
+            Server::builder()
+                .add_service(ControllerServer::new(controller))
+                .add_service(identity)
+                .serve_with_incoming(incoming)
+                .await
+        }
+        // the node name is required unless running the controller
+        (None, None) => unreachable!(),
+    };
     Ok(())
 }
@@ -0,0 +1,120 @@
+//! Persistent record of the volumes staged on the node. There is one file
+//! per staged volume, which is replaced atomically so that the record is
+//! never left half written should the plugin crash while updating it.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The state of a volume staged on the node
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct VolumeState {
+    /// id of the volume
+    pub volume_id: String,
+    /// URI(s) of the target the volume is attached through
+    pub uri: String,
+    /// device the volume has been attached to, empty until it is attached
+    pub device_path: String,
+    /// path the volume is staged at
+    pub staging_path: String,
+    /// filesystem of the volume, none for a raw block volume
+    pub fs_type: Option<String>,
+    /// options the filesystem is mounted with
+    pub mount_flags: Vec<String>,
+}
+
+/// Directory holding the state files of the staged volumes
+#[derive(Clone, Debug)]
+pub struct NodeState {
+    dir: PathBuf,
+}
+
+impl NodeState {
+    /// Open the state directory, creating it if it does not exist yet.
+    pub fn open(dir: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    fn path(&self, volume_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", volume_id))
+    }
+
+    /// Write the state of the volume to a temporary file first and rename it
+    /// over the state file once it has been synced to disk.
+    pub fn save(&self, volume: &VolumeState) -> io::Result<()> {
+        let path = self.path(&volume.volume_id);
+        let tmp = path.with_extension("tmp");
+        let data = serde_json::to_vec_pretty(volume)?;
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+
+        // make the rename itself durable
+        File::open(&self.dir)?.sync_all()
+    }
+
+    /// Return the state of the volume, if it has been staged.
+    pub fn get(&self, volume_id: &str) -> Option<VolumeState> {
+        Self::read(&self.path(volume_id))
+    }
+
+    /// Remove the state of the volume once it has been unstaged.
+    pub fn remove(&self, volume_id: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(volume_id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the state of all the volumes staged on the node. Temporary
+    /// files left behind by a crash are removed.
+    pub fn list(&self) -> Vec<VolumeState> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                error!(
+                    "Failed to read state directory {:?}: {}",
+                    self.dir, err
+                );
+                return Vec::new();
+            }
+        };
+
+        let mut volumes = Vec::new();
+        for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => {
+                    if let Some(volume) = Self::read(&path) {
+                        volumes.push(volume);
+                    }
+                }
+                Some("tmp") => {
+                    warn!("Removing incomplete state file {:?}", path);
+                    let _ = fs::remove_file(&path);
+                }
+                _ => {}
+            }
+        }
+        volumes
+    }
+
+    fn read(path: &Path) -> Option<VolumeState> {
+        let data = fs::read(path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(volume) => Some(volume),
+            Err(err) => {
+                error!("Invalid state file {:?}: {}", path, err);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! Utility functions for gathering the usage statistics of volumes
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Seek, SeekFrom},
+    mem,
+};
+
+use crate::csi::{volume_usage, VolumeUsage};
+
+/// Return the usage of bytes and inodes of the filesystem mounted at path.
+pub fn filesystem_usage(path: &str) -> io::Result<Vec<VolumeUsage>> {
+    let cpath = CString::new(path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut st: libc::statvfs = unsafe { mem::zeroed() };
+
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let frsize = st.f_frsize as u64;
+
+    Ok(vec![
+        VolumeUsage {
+            total: (st.f_blocks as u64 * frsize) as i64,
+            available: (st.f_bavail as u64 * frsize) as i64,
+            used: ((st.f_blocks - st.f_bfree) as u64 * frsize) as i64,
+            unit: volume_usage::Unit::Bytes as i32,
+        },
+        VolumeUsage {
+            total: st.f_files as i64,
+            available: st.f_favail as i64,
+            used: (st.f_files - st.f_ffree) as i64,
+            unit: volume_usage::Unit::Inodes as i32,
+        },
+    ])
+}
+
+/// Return the size in bytes of the block device at path.
+pub fn blockdevice_size(path: &str) -> io::Result<u64> {
+    File::open(path)?.seek(SeekFrom::End(0))
+}
+
+/// Return the usage of the block device at path, which is just its size.
+pub fn blockdevice_usage(path: &str) -> io::Result<Vec<VolumeUsage>> {
+    let size = blockdevice_size(path)?;
+
+    Ok(vec![VolumeUsage {
+        total: size as i64,
+        available: 0,
+        used: 0,
+        unit: volume_usage::Unit::Bytes as i32,
+    }])
+}
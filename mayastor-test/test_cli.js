@@ -108,7 +108,7 @@ describe('cli', function () {
           method: 'PublishNexus',
           input: {
             uuid: UUID,
-            key: 'CRYPTO'
+            keyId: 'CRYPTO'
           },
           output: {
             devicePath: '/dev/blah'
@@ -171,21 +171,53 @@ function controlPlaneTest (thisProtocol) {
     );
   });
 
-  it('should fail another publish request using a different protocol', (done) => {
-    const differentProtocol = (thisProtocol === enums.NEXUS_NBD ? enums.NEXUS_ISCSI : enums.NEXUS_NBD);
+  const differentProtocol = (thisProtocol === enums.NEXUS_NBD ? enums.NEXUS_ISCSI : enums.NEXUS_NBD);
+  let differentUri;
+
+  it('should publish the nexus over a different protocol at the same time', (done) => {
     client.PublishNexus(
       {
         uuid: UUID,
         share: differentProtocol
       },
       (err, res) => {
-        if (!err) return done(new Error('Expected error'));
-        assert.equal(err.code, grpc.status.INVALID_ARGUMENT);
+        if (err) return done(err);
+        assert(res.device_path);
+        differentUri = res.device_path;
         done();
       }
     );
   });
 
+  it('should list both shares of the nexus', (done) => {
+    client.ListNexus({}, (err, res) => {
+      if (err) return done(err);
+      const nexus = res.nexus_list.find((n) => n.uuid === UUID);
+      assert.lengthOf(nexus.shares, 2);
+      assert.include(nexus.shares.map((s) => s.uri), differentUri);
+      done();
+    });
+  });
+
+  it('should un-publish the nexus over the different protocol only', (done) => {
+    client.unpublishNexus(
+      {
+        uuid: UUID,
+        protocols: [differentProtocol]
+      },
+      (err) => {
+        if (err) return done(err);
+        client.ListNexus({}, (err, res) => {
+          if (err) return done(err);
+          const nexus = res.nexus_list.find((n) => n.uuid === UUID);
+          assert.lengthOf(nexus.shares, 1);
+          assert.notEqual(nexus.shares[0].uri, differentUri);
+          done();
+        });
+      }
+    );
+  });
+
   it('should succeed another publish request using the existing protocol', (done) => {
     client.PublishNexus(
       {
@@ -214,7 +246,7 @@ function controlPlaneTest (thisProtocol) {
     });
   });
 
-  it('should re-publish the nexus using a crypto-key', (done) => {
+  it('should fail to publish the nexus with a cleartext key', (done) => {
     client.PublishNexus(
       {
         uuid: UUID,
@@ -222,11 +254,22 @@ function controlPlaneTest (thisProtocol) {
         key: '0123456789123456'
       },
       (err, res) => {
-        if (err) done(err);
-        assert(res.device_path);
-        if (thisProtocol === enums.NEXUS_NVMF) {
-          assert.equal(res.device_path, `nvmf://${externIp}:8420/nqn.2019-05.io.openebs:crypto-nexus-${UUID}`);
-        }
+        if (!err) return done(new Error('Expected error'));
+        assert.equal(err.code, grpc.status.INVALID_ARGUMENT);
+        done();
+      }
+    );
+  });
+
+  it('should fail to publish the nexus with an unknown key ID', (done) => {
+    client.PublishNexus(
+      {
+        uuid: UUID,
+        share: thisProtocol,
+        key_id: 'no-such-key'
+      },
+      (err, res) => {
+        if (!err) return done(new Error('Expected error'));
         done();
       }
     );
@@ -9,7 +9,7 @@ use std::{
 use async_trait::async_trait;
 use futures::channel::oneshot;
 use snafu::ResultExt;
-use url::Url;
+use url::{Host, Url};
 
 use spdk_sys::{
     self,
@@ -32,8 +32,10 @@ const DEFAULT_NVMF_PORT: u16 = 4420;
 pub(super) struct Nvmf {
     /// name of the bdev that should be created
     name: String,
-    /// the remote target host (address)
+    /// the remote target host (address), IPv6 addresses without brackets
     host: String,
+    /// the address family of the host
+    adrfam: spdk_sys::spdk_nvmf_adrfam,
     /// the transport service id (ie. port)
     port: u16,
     /// the nqn of the subsystem we want to connect to
@@ -47,11 +49,18 @@ impl TryFrom<&Url> for Nvmf {
     type Error = NexusBdevError;
 
     fn try_from(url: &Url) -> Result<Self, Self::Error> {
-        let host =
-            url.host_str().ok_or_else(|| NexusBdevError::UriInvalid {
-                uri: url.to_string(),
-                message: String::from("missing host"),
-            })?;
+        let (host, adrfam) = match url.host() {
+            Some(Host::Ipv6(address)) => {
+                (address.to_string(), spdk_sys::SPDK_NVMF_ADRFAM_IPV6)
+            }
+            Some(host) => (host.to_string(), spdk_sys::SPDK_NVMF_ADRFAM_IPV4),
+            None => {
+                return Err(NexusBdevError::UriInvalid {
+                    uri: url.to_string(),
+                    message: String::from("missing host"),
+                })
+            }
+        };
 
         let segments = uri::segments(url);
 
@@ -102,7 +111,8 @@ impl TryFrom<&Url> for Nvmf {
 
         Ok(Nvmf {
             name: url.to_string(),
-            host: host.to_string(),
+            host,
+            adrfam,
             port: url.port().unwrap_or(DEFAULT_NVMF_PORT),
             subnqn: segments[0].to_string(),
             prchk_flags,
@@ -250,7 +260,7 @@ impl NvmeCreateContext {
         }
 
         trid.trtype = spdk_sys::SPDK_NVME_TRANSPORT_TCP;
-        trid.adrfam = spdk_sys::SPDK_NVMF_ADRFAM_IPV4;
+        trid.adrfam = nvmf.adrfam;
 
         let hostid = spdk_nvme_host_id::default();
 
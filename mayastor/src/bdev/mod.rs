@@ -3,13 +3,23 @@ use async_trait::async_trait;
 pub use nexus::{
     nexus_bdev::{
         nexus_create,
+        nexus_create_with_opts,
         nexus_lookup,
+        Error as NexusError,
         Nexus,
+        NexusCreateOpts,
         NexusStatus,
         VerboseError,
     },
     nexus_child::ChildStatus,
     nexus_child_error_store::NexusErrStore,
+    nexus_key_provider::{
+        key_provider_from_uri,
+        Cipher,
+        EncryptionKey,
+        KeyProvider,
+        KeyProviderError,
+    },
     nexus_label::{GPTHeader, GptEntry},
     nexus_metadata_content::{
         NexusConfig,
@@ -19,21 +19,34 @@ macro_rules! c_str {
 pub mod nexus_bdev;
 pub mod nexus_bdev_children;
 pub mod nexus_bdev_rebuild;
+pub mod nexus_cache;
+pub mod nexus_cbt;
 mod nexus_channel;
 pub(crate) mod nexus_child;
 pub(crate) mod nexus_child_error_store;
 mod nexus_config;
+pub mod nexus_crypto;
+pub mod nexus_fence;
 pub mod nexus_fn_table;
+pub mod nexus_freeze;
 pub mod nexus_io;
 pub mod nexus_iscsi;
+pub mod nexus_key_provider;
+pub mod nexus_key_router;
 pub mod nexus_label;
 pub mod nexus_metadata;
 pub mod nexus_metadata_content;
 pub mod nexus_module;
 pub mod nexus_nbd;
 pub mod nexus_nvmf;
+pub mod nexus_parity;
+pub mod nexus_replication;
+pub mod nexus_reservation;
+pub mod nexus_rekey;
 pub mod nexus_rpc;
 pub mod nexus_share;
+pub mod nexus_ublk;
+pub mod nexus_vhost;
 
 /// public function which simply calls register module
 pub fn register_module() {
@@ -17,6 +17,13 @@ use snafu::{ResultExt, Snafu};
 use tonic::{Code as GrpcCode, Status};
 use uuid::Uuid;
 
+use rpc::mayastor::{
+    CreateNexusRequest,
+    NexusCacheMode,
+    NexusLayout,
+    ShareProtocolNexus,
+};
+
 use spdk_sys::{
     spdk_bdev,
     spdk_bdev_desc,
@@ -40,20 +47,35 @@ use crate::{
         nexus,
         nexus::{
             instances,
+            nexus_cache::{CacheError, NexusCache},
+            nexus_cbt::ChangeTracking,
             nexus_channel::{DREvent, NexusChannel, NexusChannelInner},
             nexus_child::{ChildError, ChildState, ChildStatus, NexusChild},
+            nexus_crypto::CryptoStack,
+            nexus_freeze::Freeze,
             nexus_io::{io_status, Bio},
             nexus_iscsi::{NexusIscsiError, NexusIscsiTarget},
+            nexus_key_provider::KeyProviderError,
             nexus_label::LabelError,
+            nexus_metadata::MetaDataError,
+            nexus_metadata_content::EncryptionState,
             nexus_nbd::{NbdDisk, NbdError},
             nexus_nvmf::{NexusNvmfError, NexusNvmfTarget},
+            nexus_parity::{ParityLayout, PARITY_MIN_WIDTH},
+            nexus_rekey::KeyRotationError,
+            nexus_replication::{Replication, ReplicationError},
+            nexus_reservation::Reservation,
+            nexus_ublk::{UblkDisk, UblkError},
+            nexus_vhost::{NexusVhostError, NexusVhostTarget},
         },
     },
-    core::{Bdev, DmaError},
+    core::{Bdev, CoreError, DmaError},
     ffihelper::errno_result_from_i32,
     jsonrpc::{Code, RpcErrorCode},
     nexus_uri::{bdev_destroy, NexusBdevError},
     rebuild::RebuildError,
+    replica,
+    subsys::{AnaState, NvmfError},
 };
 
 /// Obtain the full error chain
@@ -87,14 +109,77 @@ pub enum Error {
     NexusNotFound { name: String },
     #[snafu(display("Invalid nexus uuid \"{}\"", uuid))]
     InvalidUuid { uuid: String },
-    #[snafu(display("Invalid encryption key"))]
+    #[snafu(display(
+        "Encryption keys are referenced by their ID rather than passed in \
+         cleartext"
+    ))]
     InvalidKey {},
+    #[snafu(display("Failed to get encryption key {}", key_id))]
+    GetEncryptionKey {
+        source: KeyProviderError,
+        key_id: String,
+    },
+    #[snafu(display(
+        "Nexus {} is encrypted with key {} and cannot be published without it",
+        name,
+        key_id
+    ))]
+    EncryptionRequired { key_id: String, name: String },
+    #[snafu(display(
+        "Key {} is not the key nexus {} is encrypted with",
+        key_id,
+        name
+    ))]
+    EncryptionKeyMismatch { key_id: String, name: String },
+    #[snafu(display(
+        "Failed to load the encryption marker of nexus {} from child {}",
+        name,
+        child
+    ))]
+    LoadEncryption {
+        source: MetaDataError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display(
+        "Failed to save the encryption marker of nexus {} on child {}",
+        name,
+        child
+    ))]
+    SaveEncryption {
+        source: MetaDataError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display(
+        "Crypto driver {} of nexus {} does not support AES-XTS",
+        pmd,
+        name
+    ))]
+    UnsupportedCryptoPmd { pmd: String, name: String },
     #[snafu(display("Failed to create crypto bdev for nexus {}", name))]
     CreateCryptoBdev { source: Errno, name: String },
     #[snafu(display("Failed to destroy crypto bdev for nexus {}", name))]
     DestroyCryptoBdev { source: Errno, name: String },
+    #[snafu(display("Failed to create key router for nexus {}", name))]
+    CreateKeyRouter { source: Errno, name: String },
+    #[snafu(display("Failed to destroy key router for nexus {}", name))]
+    DestroyKeyRouter { source: Errno, name: String },
+    #[snafu(display("Nexus {} is not published with a key", name))]
+    NotEncrypted { name: String },
+    #[snafu(display(
+        "The key of nexus {} is being rotated to key {} already",
+        name,
+        key_id
+    ))]
+    KeyRotationInProgress { key_id: String, name: String },
+    #[snafu(display("Failed to rotate the key of nexus {}", name))]
+    RotateKey {
+        source: KeyRotationError,
+        name: String,
+    },
     #[snafu(display(
-        "The nexus {} has been already shared with a different protocol",
+        "The nexus {} has been already shared with a different key",
         name
     ))]
     AlreadyShared { name: String },
@@ -112,6 +197,13 @@ pub enum Error {
         source: NexusNvmfError,
         name: String,
     },
+    #[snafu(display("Failed to share nexus over ublk {}", name))]
+    ShareUblkNexus { source: UblkError, name: String },
+    #[snafu(display("Failed to share nexus over vhost-user-blk {}", name))]
+    ShareVhostNexus {
+        source: NexusVhostError,
+        name: String,
+    },
     #[snafu(display("Failed to allocate label of nexus {}", name))]
     AllocLabel { source: DmaError, name: String },
     #[snafu(display("Failed to write label of nexus {}", name))]
@@ -207,6 +299,8 @@ pub enum Error {
     },
     #[snafu(display("Invalid ShareProtocol value {}", sp_value))]
     InvalidShareProtocol { sp_value: i32 },
+    #[snafu(display("Invalid NexusLayout value {}", layout))]
+    InvalidNexusLayout { layout: i32 },
     #[snafu(display("Failed to create nexus {}", name))]
     NexusCreate { name: String },
     #[snafu(display("Failed to destroy nexus {}", name))]
@@ -222,6 +316,171 @@ pub enum Error {
         name: String,
         state: String,
     },
+    #[snafu(display(
+        "Parity nexus {} requires at least {} children, got {}",
+        name,
+        PARITY_MIN_WIDTH,
+        children
+    ))]
+    InvalidLayout { name: String, children: usize },
+    #[snafu(display(
+        "No vacant column for child {} of parity nexus {}",
+        child,
+        name
+    ))]
+    NoParityColumn { child: String, name: String },
+    #[snafu(display(
+        "Failed to load the layout of nexus {} from child {}",
+        name,
+        child
+    ))]
+    LoadLayout {
+        source: MetaDataError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display(
+        "Failed to save the layout of nexus {} on child {}",
+        name,
+        child
+    ))]
+    SaveLayout {
+        source: MetaDataError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display(
+        "Child {} does not match the layout of nexus {}: {}",
+        child,
+        name,
+        reason
+    ))]
+    LayoutMismatch {
+        child: String,
+        reason: String,
+        name: String,
+    },
+    #[snafu(display("Failed to save the dirty stripes of nexus {}", name))]
+    SaveDirtyStripes { source: MetaDataError, name: String },
+    #[snafu(display("Invalid NexusCacheMode value {}", mode))]
+    InvalidCacheMode { mode: i32 },
+    #[snafu(display("Nexus {} cannot be cached with a parity layout", name))]
+    CacheNotSupported { name: String },
+    #[snafu(display("Failed to open the cache of nexus {}", name))]
+    OpenCache { source: CacheError, name: String },
+    #[snafu(display("Failed to write back the cache of nexus {}", name))]
+    FlushCache { source: CacheError, name: String },
+    #[snafu(display(
+        "Nexus {} with a parity layout cannot have async children",
+        name
+    ))]
+    AsyncChildNotSupported { name: String },
+    #[snafu(display(
+        "Async child {} of nexus {} is {} blocks behind",
+        child,
+        name,
+        blocks
+    ))]
+    AsyncChildLagging {
+        child: String,
+        name: String,
+        blocks: u64,
+    },
+    #[snafu(display(
+        "Failed to replicate to async child {} of nexus {}",
+        child,
+        name
+    ))]
+    ReplicateChild {
+        source: ReplicationError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display("Nexus {} is not open", name))]
+    NexusNotOpen { name: String },
+    #[snafu(display(
+        "Invalid checkpoint name {} for nexus {}",
+        checkpoint,
+        name
+    ))]
+    InvalidCheckpointName { checkpoint: String, name: String },
+    #[snafu(display(
+        "Checkpoint {} of nexus {} already exists",
+        checkpoint,
+        name
+    ))]
+    CheckpointExists { checkpoint: String, name: String },
+    #[snafu(display("Checkpoint {} of nexus {} not found", checkpoint, name))]
+    CheckpointNotFound { checkpoint: String, name: String },
+    #[snafu(display(
+        "Checkpoint {} of nexus {} is older than checkpoint {}",
+        to,
+        name,
+        from
+    ))]
+    CheckpointOrder {
+        from: String,
+        to: String,
+        name: String,
+    },
+    #[snafu(display("Nexus {} has too many checkpoints", name))]
+    TooManyCheckpoints { name: String },
+    #[snafu(display(
+        "Replicas of parity nexus {} cannot be snapshotted",
+        name
+    ))]
+    CheckpointSnapshotNotSupported { name: String },
+    #[snafu(display(
+        "Failed to snapshot replica {} of nexus {}",
+        child,
+        name
+    ))]
+    SnapshotReplica {
+        source: replica::Error,
+        child: String,
+        name: String,
+    },
+    #[snafu(display("Failed to save the checkpoints of nexus {}", name))]
+    SaveCheckpoints { source: MetaDataError, name: String },
+    #[snafu(display("Nexus {} is frozen already", name))]
+    NexusFrozen { name: String },
+    #[snafu(display("Failed to freeze nexus {}", name))]
+    FreezeNexus { source: Errno, name: String },
+    #[snafu(display("Failed to thaw nexus {}", name))]
+    ThawNexus { source: Errno, name: String },
+    #[snafu(display("Failed to set the ANA state of nexus {}", name))]
+    SetAnaState { source: NvmfError, name: String },
+    #[snafu(display(
+        "Failed to write the reservation file of nexus {}",
+        name
+    ))]
+    WriteReservation {
+        source: std::io::Error,
+        name: String,
+    },
+    #[snafu(display("Failed to save the reservation of nexus {}", name))]
+    SaveReservation { source: MetaDataError, name: String },
+    #[snafu(display(
+        "Failed to take ownership of child {} of nexus {}",
+        child,
+        name
+    ))]
+    FenceChild {
+        source: CoreError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display(
+        "Child {} of nexus {} is owned by the newer generation {}",
+        child,
+        name,
+        generation
+    ))]
+    ChildFenced {
+        child: String,
+        generation: u64,
+        name: String,
+    },
 }
 
 impl RpcErrorCode for Error {
@@ -236,6 +495,18 @@ impl RpcErrorCode for Error {
             Error::InvalidKey {
                 ..
             } => Code::InvalidParams,
+            Error::EncryptionRequired {
+                ..
+            } => Code::InvalidParams,
+            Error::EncryptionKeyMismatch {
+                ..
+            } => Code::InvalidParams,
+            Error::NotEncrypted {
+                ..
+            } => Code::InvalidParams,
+            Error::KeyRotationInProgress {
+                ..
+            } => Code::InvalidParams,
             Error::AlreadyShared {
                 ..
             } => Code::InvalidParams,
@@ -263,6 +534,57 @@ impl RpcErrorCode for Error {
             Error::InvalidShareProtocol {
                 ..
             } => Code::InvalidParams,
+            Error::InvalidNexusLayout {
+                ..
+            } => Code::InvalidParams,
+            Error::InvalidLayout {
+                ..
+            } => Code::InvalidParams,
+            Error::NoParityColumn {
+                ..
+            } => Code::InvalidParams,
+            Error::LayoutMismatch {
+                ..
+            } => Code::InvalidParams,
+            Error::UnsupportedCryptoPmd {
+                ..
+            } => Code::InvalidParams,
+            Error::InvalidCacheMode {
+                ..
+            } => Code::InvalidParams,
+            Error::CacheNotSupported {
+                ..
+            } => Code::InvalidParams,
+            Error::AsyncChildNotSupported {
+                ..
+            } => Code::InvalidParams,
+            Error::AsyncChildLagging {
+                ..
+            } => Code::InvalidParams,
+            Error::NexusNotOpen {
+                ..
+            } => Code::InvalidParams,
+            Error::InvalidCheckpointName {
+                ..
+            } => Code::InvalidParams,
+            Error::CheckpointExists {
+                ..
+            } => Code::InvalidParams,
+            Error::CheckpointNotFound {
+                ..
+            } => Code::NotFound,
+            Error::CheckpointOrder {
+                ..
+            } => Code::InvalidParams,
+            Error::TooManyCheckpoints {
+                ..
+            } => Code::InvalidParams,
+            Error::CheckpointSnapshotNotSupported {
+                ..
+            } => Code::InvalidParams,
+            Error::NexusFrozen {
+                ..
+            } => Code::InvalidParams,
             _ => Code::InternalError,
         }
     }
@@ -280,6 +602,18 @@ impl From<Error> for tonic::Status {
             Error::InvalidKey {
                 ..
             } => Status::invalid_argument(e.to_string()),
+            Error::EncryptionRequired {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::EncryptionKeyMismatch {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::NotEncrypted {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::KeyRotationInProgress {
+                ..
+            } => Status::failed_precondition(e.to_string()),
             Error::AlreadyShared {
                 ..
             } => Status::invalid_argument(e.to_string()),
@@ -304,6 +638,54 @@ impl From<Error> for tonic::Status {
             Error::ChildNotFound {
                 ..
             } => Status::not_found(e.to_string()),
+            Error::InvalidNexusLayout {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::InvalidLayout {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::NoParityColumn {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::LayoutMismatch {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::InvalidCacheMode {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::CacheNotSupported {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::AsyncChildNotSupported {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::AsyncChildLagging {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::NexusNotOpen {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::InvalidCheckpointName {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::CheckpointExists {
+                ..
+            } => Status::already_exists(e.to_string()),
+            Error::CheckpointNotFound {
+                ..
+            } => Status::not_found(e.to_string()),
+            Error::CheckpointOrder {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::TooManyCheckpoints {
+                ..
+            } => Status::failed_precondition(e.to_string()),
+            Error::CheckpointSnapshotNotSupported {
+                ..
+            } => Status::invalid_argument(e.to_string()),
+            Error::NexusFrozen {
+                ..
+            } => Status::failed_precondition(e.to_string()),
             e => Status::new(GrpcCode::Internal, e.to_string()),
         }
     }
@@ -315,6 +697,8 @@ pub enum NexusTarget {
     NbdDisk(NbdDisk),
     NexusIscsiTarget(NexusIscsiTarget),
     NexusNvmfTarget(NexusNvmfTarget),
+    NexusVhostTarget(NexusVhostTarget),
+    UblkDisk(UblkDisk),
 }
 
 impl fmt::Debug for NexusTarget {
@@ -323,6 +707,33 @@ impl fmt::Debug for NexusTarget {
             NexusTarget::NbdDisk(disk) => fmt::Debug::fmt(&disk, f),
             NexusTarget::NexusIscsiTarget(tgt) => fmt::Debug::fmt(&tgt, f),
             NexusTarget::NexusNvmfTarget(tgt) => fmt::Debug::fmt(&tgt, f),
+            NexusTarget::NexusVhostTarget(tgt) => fmt::Debug::fmt(&tgt, f),
+            NexusTarget::UblkDisk(disk) => fmt::Debug::fmt(&disk, f),
+        }
+    }
+}
+
+impl NexusTarget {
+    /// the protocol the target publishes the nexus over
+    pub fn protocol(&self) -> ShareProtocolNexus {
+        match self {
+            NexusTarget::NbdDisk(_) => ShareProtocolNexus::NexusNbd,
+            NexusTarget::NexusIscsiTarget(_) => ShareProtocolNexus::NexusIscsi,
+            NexusTarget::NexusNvmfTarget(_) => ShareProtocolNexus::NexusNvmf,
+            NexusTarget::NexusVhostTarget(_) => {
+                ShareProtocolNexus::NexusVhostUserBlk
+            }
+            NexusTarget::UblkDisk(_) => ShareProtocolNexus::NexusUblk,
+        }
+    }
+
+    pub fn as_uri(&self) -> String {
+        match self {
+            NexusTarget::NbdDisk(disk) => disk.as_uri(),
+            NexusTarget::NexusIscsiTarget(tgt) => tgt.as_uri(),
+            NexusTarget::NexusNvmfTarget(tgt) => tgt.as_uri(),
+            NexusTarget::NexusVhostTarget(tgt) => tgt.as_uri(),
+            NexusTarget::UblkDisk(disk) => disk.as_uri(),
         }
     }
 }
@@ -351,8 +762,34 @@ pub struct Nexus {
     /// the handle to be used when sharing the nexus, this allows for the bdev
     /// to be shared with vbdevs on top
     pub(crate) share_handle: Option<String>,
-    /// enum containing the protocol-specific target used to publish the nexus
-    pub nexus_target: Option<NexusTarget>,
+    /// marker of the key the share handle encrypts the nexus with, if any
+    pub(crate) encryption: Option<EncryptionState>,
+    /// the bdevs encrypting the nexus beneath the share handle, if any
+    pub(crate) crypto: Option<CryptoStack>,
+    /// protocol-specific targets the nexus is published with, at most one
+    /// per protocol
+    pub nexus_targets: Vec<NexusTarget>,
+    /// geometry of the nexus when it stripes data with parity across its
+    /// children rather than mirroring it
+    pub(crate) parity: Option<ParityLayout>,
+    /// uri and mode of the local cache in front of the children
+    pub(crate) cache_spec: Option<(String, NexusCacheMode)>,
+    /// the cache, once the nexus has been opened
+    pub(crate) cache: Option<NexusCache>,
+    /// children that are written to in the background
+    pub(crate) replication: Replication,
+    /// checkpoints and the regions written since each of them
+    pub(crate) change_tracking: ChangeTracking,
+    /// lock holding back writes while the children are snapshotted
+    pub(crate) freeze: Option<Freeze>,
+    /// ANA state of the nvmf listeners when the nexus is exported from
+    /// several nodes
+    pub(crate) ana_state: AnaState,
+    /// file holding the persistent reservation while shared over nvmf
+    pub(crate) reservation: Option<Reservation>,
+    /// generation with which the nexus owns its children, zero until it has
+    /// been opened
+    pub(crate) owner_generation: u64,
 }
 
 unsafe impl core::marker::Sync for Nexus {}
@@ -440,8 +877,19 @@ impl Nexus {
             dr_complete_notify: None,
             data_ent_offset: 0,
             share_handle: None,
+            encryption: None,
+            crypto: None,
             size,
-            nexus_target: None,
+            nexus_targets: Vec::new(),
+            parity: None,
+            cache_spec: None,
+            cache: None,
+            replication: Replication::default(),
+            change_tracking: ChangeTracking::default(),
+            freeze: None,
+            ana_state: AnaState::default(),
+            reservation: None,
+            owner_generation: 0,
         });
 
         n.bdev.set_uuid(match uuid {
@@ -474,6 +922,27 @@ impl Nexus {
         u64::from(self.bdev.block_len()) * self.bdev.num_blocks()
     }
 
+    /// the layout of the data on the children
+    pub fn layout(&self) -> NexusLayout {
+        if self.parity.is_some() {
+            NexusLayout::NexusParity
+        } else {
+            NexusLayout::NexusMirror
+        }
+    }
+
+    /// the minimal size in bytes each child must provide, with a parity
+    /// layout every child only stores part of the data
+    pub(crate) fn child_size(&self) -> u64 {
+        match self.parity.as_ref() {
+            Some(layout) => {
+                let columns = layout.data_columns();
+                (self.size + columns - 1) / columns
+            }
+            None => self.size,
+        }
+    }
+
     /// reconfigure the child event handler
     pub(crate) async fn reconfigure(&mut self, event: DREvent) {
         let (s, r) = oneshot::channel::<i32>();
@@ -500,8 +969,19 @@ impl Nexus {
         debug!("Opening nexus {}", self.name);
 
         self.try_open_children()?;
+        self.fence_children().await;
         self.sync_labels().await?;
-        self.register()
+        self.open_layout().await?;
+        self.open_dirty_stripes().await;
+        self.open_change_tracking().await;
+        self.open_cache().await?;
+
+        if let Err(e) = self.register() {
+            self.close_cache().await;
+            return Err(e);
+        }
+
+        Ok(())
     }
 
     pub async fn sync_labels(&mut self) -> Result<(), Error> {
@@ -514,11 +994,18 @@ impl Nexus {
         self.data_ent_offset = label.offset();
         let size_blocks = self.size / self.bdev.block_len() as u64;
 
+        // with a parity layout, the data partition of each child only holds
+        // part of every stripe
+        let data_blocks = match self.parity.as_ref() {
+            Some(layout) => layout.nexus_blocks(label.get_block_count()),
+            None => label.get_block_count(),
+        };
+
         self.bdev.set_block_count(std::cmp::min(
             // nexus is allowed to be smaller than the children
             size_blocks,
             // label might be smaller than expected due to the on disk metadata
-            label.get_block_count(),
+            data_blocks,
         ));
 
         Ok(())
@@ -565,6 +1052,10 @@ impl Nexus {
             }
         }
 
+        if let Err(e) = self.thaw().await {
+            error!("{}: failed to thaw: {}", self.name, e);
+        }
+
         let _ = self.unshare().await;
         assert_eq!(self.share_handle, None);
 
@@ -572,6 +1063,12 @@ impl Nexus {
             self.stop_rebuild(&child.name).await.ok();
         }
 
+        // dirty lines of the cache must reach the children first
+        self.close_cache().await;
+        self.close_change_tracking().await;
+        self.close_dirty_stripes().await;
+        self.destroy_async_children().await;
+
         for child in self.children.iter_mut() {
             let _ = child.close();
             info!("Destroying child bdev {}", child.name);
@@ -949,10 +1446,29 @@ impl Nexus {
     /// Faulted
     /// No child is online so the nexus is faulted
     /// This may be made more configurable in the future
+    ///
+    /// A parity nexus is degraded when a single column is missing and
+    /// faulted when more than one column is missing
     pub fn status(&self) -> NexusStatus {
         match self.state {
             NexusState::Init => NexusStatus::Degraded,
             NexusState::Closed => NexusStatus::Faulted,
+            NexusState::Open if self.parity.is_some() => {
+                let width = self.parity.as_ref().unwrap().width as usize;
+                let online = self
+                    .children
+                    .iter()
+                    .filter(|c| c.status() == ChildStatus::Online)
+                    .count();
+
+                if online == width {
+                    NexusStatus::Online
+                } else if online + 1 == width {
+                    NexusStatus::Degraded
+                } else {
+                    NexusStatus::Faulted
+                }
+            }
             NexusState::Open => {
                 if self
                     .children
@@ -1012,6 +1528,74 @@ pub async fn nexus_create(
     size: u64,
     uuid: Option<&str>,
     children: &[String],
+) -> Result<(), Error> {
+    nexus_create_with_opts(
+        name,
+        size,
+        uuid,
+        children,
+        NexusCreateOpts::default(),
+    )
+    .await
+}
+
+/// Optional properties of a nexus that is created
+#[derive(Debug, Clone)]
+pub struct NexusCreateOpts {
+    /// how the data is laid out on the children. A parity nexus stripes the
+    /// data across its children and has the capacity of all but one of them.
+    pub layout: NexusLayout,
+    /// uri of a local bdev used to cache the data of the children
+    pub cache: Option<String>,
+    /// how writes are handled by the cache
+    pub cache_mode: NexusCacheMode,
+}
+
+impl Default for NexusCreateOpts {
+    fn default() -> Self {
+        Self {
+            layout: NexusLayout::NexusMirror,
+            cache: None,
+            cache_mode: NexusCacheMode::CacheWriteThrough,
+        }
+    }
+}
+
+impl NexusCreateOpts {
+    /// obtain the options from a gRPC or JSON-RPC create request
+    pub fn from_request(args: &CreateNexusRequest) -> Result<Self, Error> {
+        let layout = NexusLayout::from_i32(args.layout).ok_or(
+            Error::InvalidNexusLayout {
+                layout: args.layout,
+            },
+        )?;
+
+        let cache_mode = NexusCacheMode::from_i32(args.cache_mode).ok_or(
+            Error::InvalidCacheMode {
+                mode: args.cache_mode,
+            },
+        )?;
+
+        Ok(Self {
+            layout,
+            cache: if args.cache.is_empty() {
+                None
+            } else {
+                Some(args.cache.clone())
+            },
+            cache_mode,
+        })
+    }
+}
+
+/// Create a nexus with the given options.
+#[tracing::instrument(level = "debug")]
+pub async fn nexus_create_with_opts(
+    name: &str,
+    size: u64,
+    uuid: Option<&str>,
+    children: &[String],
+    opts: NexusCreateOpts,
 ) -> Result<(), Error> {
     // global variable defined in the nexus module
     let nexus_list = instances();
@@ -1023,6 +1607,25 @@ pub async fn nexus_create(
 
     let mut ni = Nexus::new(name, size, uuid, None);
 
+    if opts.layout == NexusLayout::NexusParity {
+        if opts.cache.is_some() {
+            return Err(Error::CacheNotSupported {
+                name: String::from(name),
+            });
+        }
+        if children.len() < PARITY_MIN_WIDTH as usize {
+            return Err(Error::InvalidLayout {
+                name: String::from(name),
+                children: children.len(),
+            });
+        }
+        ni.parity = Some(ParityLayout::new(children.len() as u32));
+    }
+
+    if let Some(cache) = opts.cache {
+        ni.cache_spec = Some((cache, opts.cache_mode));
+    }
+
     for child in children {
         if let Err(err) = ni.create_and_register(child).await {
             ni.destroy_children().await;
@@ -80,12 +80,18 @@ impl Nexus {
     ) -> Result<(), NexusBdevError> {
         assert_eq!(self.state, NexusState::Init);
         let name = bdev_create(&uri).await?;
-        self.children.push(NexusChild::new(
+        let mut child = NexusChild::new(
             uri.to_string(),
             self.name.clone(),
             Bdev::lookup_by_name(&name),
-        ));
+        );
+
+        // children of a parity nexus store the columns in the order given
+        if self.parity.is_some() {
+            child.column = Some(self.children.len() as u32);
+        }
 
+        self.children.push(child);
         self.child_count += 1;
         Ok(())
     }
@@ -132,6 +138,18 @@ impl Nexus {
         &mut self,
         uri: &str,
     ) -> Result<NexusStatus, Error> {
+        // a parity nexus has a fixed width, a child can only be added to take
+        // the place of one that was removed
+        let column = match self.parity.as_ref() {
+            Some(_) => Some(self.vacant_column().ok_or_else(|| {
+                Error::NoParityColumn {
+                    child: uri.to_owned(),
+                    name: self.name.clone(),
+                }
+            })?),
+            None => None,
+        };
+
         let name = bdev_create(&uri).await.context(CreateChild {
             name: self.name.clone(),
         })?;
@@ -169,7 +187,9 @@ impl Nexus {
             self.name.clone(),
             Some(child_bdev),
         );
-        match child.open(self.size) {
+        child.column = column;
+
+        match child.open(self.child_size()) {
             Ok(name) => {
                 // we have created the bdev, and created a nexusChild struct. To
                 // make use of the device itself the
@@ -185,6 +205,14 @@ impl Nexus {
                 self.children.push(child);
                 self.child_count += 1;
 
+                // a child owned by a newer nexus is kept as faulted
+                if let Err(e) = self.fence_child(self.children.len() - 1).await
+                {
+                    error!("{}", e);
+                    self.children.last_mut().unwrap().fence();
+                    return Err(e);
+                }
+
                 if let Err(e) = self.sync_labels().await {
                     error!("Failed to sync labels {:?}", e);
                     // todo: how to signal this?
@@ -210,6 +238,10 @@ impl Nexus {
     /// Destroy child with given uri.
     /// If the child does not exist the method returns success.
     pub async fn remove_child(&mut self, uri: &str) -> Result<(), Error> {
+        if self.remove_async_child(uri).await? {
+            return Ok(());
+        }
+
         if self.child_count == 1 {
             return Err(Error::DestroyLastChild {
                 name: self.name.clone(),
@@ -268,12 +300,19 @@ impl Nexus {
     ) -> Result<NexusStatus, Error> {
         trace!("{} Online child request", self.name);
 
-        if let Some(child) = self.children.iter_mut().find(|c| c.name == name) {
-            child.online(self.size).context(OpenChild {
+        let child_size = self.child_size();
+
+        if let Some(idx) = self.children.iter().position(|c| c.name == name) {
+            let child = &mut self.children[idx];
+            child.online(child_size).context(OpenChild {
                 child: name.to_owned(),
                 name: self.name.clone(),
             })?;
             child.out_of_sync(true);
+            if let Err(e) = self.fence_child(idx).await {
+                self.children[idx].fence();
+                return Err(e);
+            }
             self.start_rebuild(name).await.map(|_| {})?;
             Ok(self.status())
         } else {
@@ -335,7 +374,11 @@ impl Nexus {
 
         self.bdev.set_block_len(blk_size);
 
-        let size = self.size;
+        if let Some(layout) = self.parity.as_mut() {
+            layout.configure(blk_size);
+        }
+
+        let size = self.child_size();
 
         let (open, error): (Vec<_>, Vec<_>) = self
             .children
@@ -481,6 +524,13 @@ impl Nexus {
         blockcnt
     }
 
+    /// the lowest column of a parity nexus that has no child assigned to it
+    pub(crate) fn vacant_column(&self) -> Option<u32> {
+        let width = self.parity.as_ref()?.width;
+        (0 .. width)
+            .find(|col| !self.children.iter().any(|c| c.column == Some(*col)))
+    }
+
     pub fn get_child_by_name(
         &mut self,
         name: &str,
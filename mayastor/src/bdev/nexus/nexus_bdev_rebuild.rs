@@ -10,6 +10,7 @@ use crate::{
                 nexus_lookup,
                 CreateRebuildError,
                 Error,
+                FlushCache,
                 Nexus,
                 RebuildJobNotFound,
                 RebuildOperationError,
@@ -21,7 +22,7 @@ use crate::{
         VerboseError,
     },
     core::Reactors,
-    rebuild::{ClientOperations, RebuildJob, RebuildState},
+    rebuild::{ClientOperations, ParitySources, RebuildJob, RebuildState},
     replicas::rebuild::RebuildError,
 };
 
@@ -61,24 +62,84 @@ impl Nexus {
                 }),
             }?;
 
-        let job = RebuildJob::create(
-            &self.name,
-            &src_child_name,
-            &dst_child_name,
-            std::ops::Range::<u64> {
-                start: self.data_ent_offset,
-                end: self.bdev.num_blocks() + self.data_ent_offset,
-            },
-            |nexus, job| {
-                Reactors::current().send_future(async move {
-                    Nexus::notify_rebuild(nexus, job).await;
-                });
-            },
-        )
+        let notify_fn: fn(String, String) = |nexus, job| {
+            Reactors::current().send_future(async move {
+                Nexus::notify_rebuild(nexus, job).await;
+            });
+        };
+
+        // the rebuild copies from the children so they must hold all data
+        // that is still in the cache
+        if let Err(e) = self.cache_suspend().await {
+            self.cache_resume();
+            return Err(e).context(FlushCache {
+                name: self.name.clone(),
+            });
+        }
+
+        let job = match self.parity.as_ref() {
+            // a column of a parity nexus can only be reconstructed when all
+            // other columns are available
+            Some(layout) => {
+                let survivors = self
+                    .children
+                    .iter()
+                    .filter(|c| {
+                        c.name != name && c.status() == ChildStatus::Online
+                    })
+                    .map(|c| c.name.clone())
+                    .collect::<Vec<_>>();
+
+                if survivors.len() + 1 != layout.width as usize {
+                    return Err(Error::NoRebuildSource {
+                        name: self.name.clone(),
+                    });
+                }
+
+                // the child blocks of all stripes that hold nexus data
+                let nexus_blks = self.bdev.num_blocks();
+                let stripes = (nexus_blks + layout.stripe_blks() - 1)
+                    / layout.stripe_blks();
+
+                RebuildJob::create_parity(
+                    &self.name,
+                    ParitySources {
+                        survivors,
+                        strip_blks: layout.strip_blks,
+                        stripe_blks: layout.stripe_blks(),
+                        nexus_blks,
+                    },
+                    &dst_child_name,
+                    std::ops::Range::<u64> {
+                        start: self.data_ent_offset,
+                        end: stripes * layout.strip_blks + self.data_ent_offset,
+                    },
+                    notify_fn,
+                )
+            }
+            None => RebuildJob::create(
+                &self.name,
+                &src_child_name,
+                &dst_child_name,
+                std::ops::Range::<u64> {
+                    start: self.data_ent_offset,
+                    end: self.bdev.num_blocks() + self.data_ent_offset,
+                },
+                notify_fn,
+            ),
+        }
         .context(CreateRebuildError {
             child: name.to_owned(),
             name: self.name.clone(),
-        })?;
+        });
+
+        let job = match job {
+            Ok(job) => job,
+            Err(e) => {
+                self.cache_resume();
+                return Err(e);
+            }
+        };
 
         // We're now rebuilding the `dst_child` which means it HAS to become an
         // active participant in the frontend nexus bdev for Writes.
@@ -239,6 +300,12 @@ impl Nexus {
                 );
 
                 assert_eq!(recovering_child.status(), ChildStatus::Online);
+
+                // without the layout saved on it, the child would have to
+                // be rebuilt again when the nexus is created again
+                if let Err(e) = self.save_child_layout(&job.destination).await {
+                    error!("{}", e);
+                }
             }
             RebuildState::Stopped => {
                 info!(
@@ -274,6 +341,7 @@ impl Nexus {
             }
         }
 
+        self.cache_resume();
         self.reconfigure(DREvent::ChildRebuild).await;
         Ok(())
     }
@@ -0,0 +1,1142 @@
+//!
+//! Local cache tier of the nexus. A fast local bdev, typically a `loopback://`
+//! lvol backed by local NVMe, is placed in front of the children of the nexus
+//! which are usually remote replicas. Reads are served from the cache when the
+//! data is present. In write-back mode, writes are acknowledged as soon as
+//! they are stored in the cache and written to the children (destaged) in the
+//! background; in write-through mode the children are always written first.
+//!
+//! The cache is direct mapped, nexus line `n` can only be stored in slot
+//! `n % lines`. The cache bdev is laid out as follows:
+//!
+//! ```text
+//!   block 0                  header
+//!   block 1 .. data_offset   16 byte entry (tag, flags) for each slot
+//!   block data_offset ..     the slots, line_blks each
+//! ```
+//!
+//! The entry of a dirty slot is written only after its data, so lines that
+//! have been acknowledged survive a restart and are destaged once the nexus
+//! is created again with the same cache. Clean lines are dropped when the
+//! cache is opened as they may be stale.
+//!
+//! Writes are not absorbed by the cache while a child is being rebuilt, as
+//! the rebuild copies from the children which therefore must be current.
+
+use std::{
+    convert::{TryFrom, TryInto},
+    os::raw::c_void,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+};
+
+use futures::future::join_all;
+use snafu::{ResultExt, Snafu};
+
+use rpc::mayastor::{NexusCacheInfo, NexusCacheMode};
+use spdk_sys::{
+    spdk_bdev_io,
+    spdk_bdev_io_get_buf,
+    spdk_io_channel,
+    spdk_poller,
+    spdk_poller_register,
+    spdk_poller_unregister,
+};
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{Error, Nexus, OpenCache},
+        nexus_channel::NexusChannel,
+        nexus_child::{ChildStatus, NexusChild},
+        nexus_io::{io_type, Bio},
+        nexus_parity::{StripeLocks, Yield},
+    },
+    core::{
+        Bdev,
+        BdevHandle,
+        CoreError,
+        Descriptor,
+        DmaBuf,
+        DmaError,
+        Reactors,
+    },
+    nexus_uri::{bdev_create, bdev_destroy, NexusBdevError},
+};
+
+/// size of a cache line
+pub const CACHE_LINE_SIZE: u64 = 64 * 1024;
+
+/// identifies a cache bdev formatted by us ("MAYACACH")
+const CACHE_MAGIC: u64 = 0x4843_4143_4159_414d;
+/// replaces the header of a cache that was closed without dirty lines, which
+/// may be formatted again ("MAYADISC")
+const CACHE_DISCARD: u64 = 0x4353_4944_4159_414d;
+const CACHE_VERSION: u64 = 1;
+
+/// size of the on disk entry of a slot
+const ENTRY_SIZE: u64 = 16;
+const LINE_VALID: u64 = 1;
+const LINE_DIRTY: u64 = 1 << 1;
+
+/// interval in microseconds at which dirty lines are destaged
+const DESTAGE_INTERVAL_US: u64 = 100_000;
+
+#[derive(Debug, Snafu)]
+pub enum CacheError {
+    #[snafu(display("Failed to create cache bdev {}", uri))]
+    CreateCacheBdev { source: NexusBdevError, uri: String },
+    #[snafu(display("Cache bdev {} not found", name))]
+    CacheBdevMissing { name: String },
+    #[snafu(display("Failed to open cache bdev {}", name))]
+    OpenCacheBdev { source: CoreError, name: String },
+    #[snafu(display(
+        "Block size {} of cache bdev {} does not match the nexus ({})",
+        block_len,
+        name,
+        nexus_block_len
+    ))]
+    CacheBlockSize {
+        name: String,
+        block_len: u64,
+        nexus_block_len: u64,
+    },
+    #[snafu(display("Cache bdev {} is too small", name))]
+    CacheTooSmall { name: String },
+    #[snafu(display(
+        "Cache bdev {} holds data of another cache or device and is not marked for discard",
+        name
+    ))]
+    CacheNotDiscarded { name: String },
+    #[snafu(display("Failed to allocate buffer for cache IO"))]
+    CacheAlloc { source: DmaError },
+    #[snafu(display("IO to cache bdev {} failed", name))]
+    CacheIo { source: CoreError, name: String },
+    #[snafu(display("IO to the children failed"))]
+    BackingIo { source: CoreError },
+    #[snafu(display("No child available to read from"))]
+    NoBackingChild {},
+}
+
+/// in memory copy of the on disk entry of a slot
+#[derive(Debug, Default, Clone, Copy)]
+struct CacheEntry {
+    /// nexus line stored in the slot
+    tag: u64,
+    flags: u64,
+}
+
+impl CacheEntry {
+    fn holds(&self, line: u64) -> bool {
+        self.flags & LINE_VALID != 0 && self.tag == line
+    }
+
+    fn dirty(&self) -> bool {
+        self.flags & LINE_DIRTY != 0
+    }
+}
+
+/// contiguous part of a nexus IO that resides within a single line
+#[derive(Debug, Clone, Copy)]
+struct LineSegment {
+    line: u64,
+    /// offset in blocks within the line
+    offset: u64,
+    num_blocks: u64,
+    /// offset in blocks relative to the start of the nexus IO
+    io_offset: u64,
+}
+
+/// per core handles used to perform IO on behalf of the cache
+#[derive(Debug)]
+pub(crate) struct CacheHandles {
+    cache: BdevHandle,
+    /// handles of the children, of which the last `write_only` are being
+    /// rebuilt and cannot be read from
+    backing: Vec<BdevHandle>,
+    write_only: usize,
+}
+
+/// Cache tier of a nexus
+#[derive(Debug)]
+pub struct NexusCache {
+    /// uri of the cache bdev
+    pub(crate) uri: String,
+    pub(crate) mode: NexusCacheMode,
+    desc: Arc<Descriptor>,
+    block_len: u64,
+    /// size of a line in blocks
+    line_blks: u64,
+    /// number of slots
+    lines: u64,
+    /// first block of the first slot
+    data_offset: u64,
+    entries: Mutex<Vec<CacheEntry>>,
+    /// number of dirty slots
+    dirty: AtomicU64,
+    /// slots with IO in progress
+    locks: StripeLocks,
+    /// blocks of entries that are being written
+    meta_locks: StripeLocks,
+    /// number of rebuilds in progress
+    rebuilds: AtomicUsize,
+    /// number of writes being absorbed by the cache
+    absorbing: AtomicUsize,
+    destaging: AtomicBool,
+    poller: *mut spdk_poller,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl NexusCache {
+    /// create the cache bdev and load its state, the bdev is formatted when
+    /// it is blank or holds a cache marked for discard
+    pub(crate) async fn open(
+        uri: &str,
+        mode: NexusCacheMode,
+        nexus: &Nexus,
+    ) -> Result<Self, CacheError> {
+        let name = bdev_create(uri).await.context(CreateCacheBdev {
+            uri,
+        })?;
+
+        match Self::load(uri, &name, mode, nexus).await {
+            Ok(cache) => Ok(cache),
+            Err(e) => {
+                if let Err(err) = bdev_destroy(uri).await {
+                    error!("Failed to destroy cache bdev {}: {}", uri, err);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn load(
+        uri: &str,
+        name: &str,
+        mode: NexusCacheMode,
+        nexus: &Nexus,
+    ) -> Result<Self, CacheError> {
+        let bdev = Bdev::lookup_by_name(name).ok_or_else(|| {
+            CacheError::CacheBdevMissing {
+                name: name.to_string(),
+            }
+        })?;
+
+        let block_len = u64::from(bdev.block_len());
+        let nexus_block_len = u64::from(nexus.bdev.block_len());
+        if block_len != nexus_block_len {
+            return Err(CacheError::CacheBlockSize {
+                name: name.to_string(),
+                block_len,
+                nexus_block_len,
+            });
+        }
+
+        let line_blks = CACHE_LINE_SIZE / block_len;
+        let per_blk = block_len / ENTRY_SIZE;
+        let meta_blks = |lines: u64| (lines + per_blk - 1) / per_blk;
+        let total = bdev.num_blocks();
+
+        // each slot needs line_blks blocks plus its share of an entry block
+        let mut lines =
+            (total.saturating_sub(1) * per_blk) / (line_blks * per_blk + 1);
+        while lines > 0 && 1 + meta_blks(lines) + lines * line_blks > total {
+            lines -= 1;
+        }
+
+        if lines == 0 {
+            return Err(CacheError::CacheTooSmall {
+                name: name.to_string(),
+            });
+        }
+
+        let desc = bdev.open(true).context(OpenCacheBdev {
+            name,
+        })?;
+
+        let cache = Self {
+            uri: uri.to_string(),
+            mode,
+            desc: Arc::new(desc),
+            block_len,
+            line_blks,
+            lines,
+            data_offset: 1 + meta_blks(lines),
+            entries: Mutex::new(vec![CacheEntry::default(); lines as usize]),
+            dirty: AtomicU64::new(0),
+            locks: StripeLocks::default(),
+            meta_locks: StripeLocks::default(),
+            rebuilds: AtomicUsize::new(0),
+            absorbing: AtomicUsize::new(0),
+            destaging: AtomicBool::new(false),
+            poller: ptr::null_mut(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+
+        cache.load_entries(nexus.bdev.uuid().as_bytes()).await?;
+        Ok(cache)
+    }
+
+    /// the header identifying the cache and its geometry
+    fn header(&self, guid: [u8; 16]) -> Vec<u8> {
+        let mut header = Vec::new();
+        for v in &[
+            CACHE_MAGIC,
+            CACHE_VERSION,
+            self.block_len,
+            self.line_blks,
+            self.lines,
+        ] {
+            header.extend_from_slice(&v.to_le_bytes());
+        }
+        header.extend_from_slice(&guid);
+        header
+    }
+
+    fn handle(&self) -> Result<BdevHandle, CacheError> {
+        BdevHandle::try_from(Arc::clone(&self.desc)).context(CacheIo {
+            name: self.uri.clone(),
+        })
+    }
+
+    /// read the entries of the dirty slots, or format the cache if it is
+    /// blank or marked for discard. Anything else may hold dirty lines of
+    /// another nexus, or data of a device mistaken for the cache.
+    async fn load_entries(&self, guid: [u8; 16]) -> Result<(), CacheError> {
+        let hdl = self.handle()?;
+        let header = self.header(guid);
+
+        let mut buf = hdl
+            .dma_malloc(self.block_len as usize)
+            .context(CacheAlloc {})?;
+        hdl.read_at(0, &mut buf).await.context(CacheIo {
+            name: self.uri.clone(),
+        })?;
+
+        if buf.as_slice()[.. header.len()] != header[..] {
+            let magic =
+                u64::from_le_bytes(buf.as_slice()[.. 8].try_into().unwrap());
+            if magic != CACHE_DISCARD && buf.as_slice().iter().any(|b| *b != 0)
+            {
+                return Err(CacheError::CacheNotDiscarded {
+                    name: self.uri.clone(),
+                });
+            }
+            info!("{}: formatting cache", self.uri);
+            return self.format(&hdl, &header).await;
+        }
+
+        let mut meta = hdl
+            .dma_malloc(((self.data_offset - 1) * self.block_len) as usize)
+            .context(CacheAlloc {})?;
+        hdl.read_at(self.block_len, &mut meta)
+            .await
+            .context(CacheIo {
+                name: self.uri.clone(),
+            })?;
+
+        let mut entries = self.entries.lock().unwrap();
+        let mut dirty = 0;
+        for (slot, entry) in entries.iter_mut().enumerate() {
+            let raw = &meta.as_slice()[slot * ENTRY_SIZE as usize ..];
+            let tag = u64::from_le_bytes(raw[0 .. 8].try_into().unwrap());
+            let flags = u64::from_le_bytes(raw[8 .. 16].try_into().unwrap());
+
+            if flags & LINE_DIRTY != 0 {
+                *entry = CacheEntry {
+                    tag,
+                    flags: LINE_VALID | LINE_DIRTY,
+                };
+                dirty += 1;
+            }
+        }
+
+        self.dirty.store(dirty, Ordering::SeqCst);
+        info!("{}: loaded cache with {} dirty lines", self.uri, dirty);
+        Ok(())
+    }
+
+    /// invalidate all slots and write the header
+    async fn format(
+        &self,
+        hdl: &BdevHandle,
+        header: &[u8],
+    ) -> Result<(), CacheError> {
+        let chunk = std::cmp::min(self.data_offset - 1, 256);
+        let zeroes = hdl
+            .dma_malloc((chunk * self.block_len) as usize)
+            .context(CacheAlloc {})?;
+
+        let mut blk = 1;
+        while blk < self.data_offset {
+            let num_blocks = std::cmp::min(chunk, self.data_offset - blk);
+            let result = if num_blocks == chunk {
+                hdl.write_at(blk * self.block_len, &zeroes).await
+            } else {
+                let tail = hdl
+                    .dma_malloc((num_blocks * self.block_len) as usize)
+                    .context(CacheAlloc {})?;
+                hdl.write_at(blk * self.block_len, &tail).await
+            };
+            result.context(CacheIo {
+                name: self.uri.clone(),
+            })?;
+            blk += num_blocks;
+        }
+
+        let mut buf = hdl
+            .dma_malloc(self.block_len as usize)
+            .context(CacheAlloc {})?;
+        buf.as_mut_slice()[.. header.len()].copy_from_slice(header);
+        hdl.write_at(0, &buf).await.context(CacheIo {
+            name: self.uri.clone(),
+        })?;
+
+        Ok(())
+    }
+
+    /// replace the header, so that the cache can be formatted when it is
+    /// opened again
+    async fn discard(&self) -> Result<(), CacheError> {
+        let hdl = self.handle()?;
+        let mut buf = hdl
+            .dma_malloc(self.block_len as usize)
+            .context(CacheAlloc {})?;
+        buf.as_mut_slice()[.. 8].copy_from_slice(&CACHE_DISCARD.to_le_bytes());
+        hdl.write_at(0, &buf).await.context(CacheIo {
+            name: self.uri.clone(),
+        })?;
+        Ok(())
+    }
+
+    fn entry(&self, slot: u64) -> CacheEntry {
+        self.entries.lock().unwrap()[slot as usize]
+    }
+
+    fn set_entry(&self, slot: u64, entry: CacheEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        match (entries[slot as usize].dirty(), entry.dirty()) {
+            (false, true) => self.dirty.fetch_add(1, Ordering::SeqCst),
+            (true, false) => self.dirty.fetch_sub(1, Ordering::SeqCst),
+            _ => 0,
+        };
+        entries[slot as usize] = entry;
+    }
+
+    fn dirty_slots(&self) -> Vec<u64> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.dirty())
+            .map(|(slot, _)| slot as u64)
+            .collect()
+    }
+
+    /// write the block holding the entry of the given slot
+    async fn persist(
+        &self,
+        hdl: &BdevHandle,
+        slot: u64,
+    ) -> Result<(), CacheError> {
+        let per_blk = self.block_len / ENTRY_SIZE;
+        let blk = slot / per_blk;
+        let _guard = self.meta_locks.lock(vec![blk]).await;
+
+        let mut buf = hdl
+            .dma_malloc(self.block_len as usize)
+            .context(CacheAlloc {})?;
+        {
+            let entries = self.entries.lock().unwrap();
+            let first = (blk * per_blk) as usize;
+            let last = std::cmp::min(first + per_blk as usize, entries.len());
+            for (i, e) in entries[first .. last].iter().enumerate() {
+                let raw = &mut buf.as_mut_slice()[i * ENTRY_SIZE as usize ..];
+                raw[0 .. 8].copy_from_slice(&e.tag.to_le_bytes());
+                raw[8 .. 16].copy_from_slice(&e.flags.to_le_bytes());
+            }
+        }
+
+        hdl.write_at((1 + blk) * self.block_len, &buf)
+            .await
+            .context(CacheIo {
+                name: self.uri.clone(),
+            })?;
+
+        Ok(())
+    }
+
+    fn slot(&self, line: u64) -> u64 {
+        line % self.lines
+    }
+
+    /// byte offset of a block within a slot on the cache bdev
+    fn slot_offset(&self, slot: u64, offset: u64) -> u64 {
+        (self.data_offset + slot * self.line_blks + offset) * self.block_len
+    }
+
+    /// split a nexus IO into segments that each reside within a single line
+    fn segments(&self, offset: u64, num_blocks: u64) -> Vec<LineSegment> {
+        let mut segments = Vec::new();
+        let mut done = 0;
+
+        while done < num_blocks {
+            let lba = offset + done;
+            let offset = lba % self.line_blks;
+            let len = std::cmp::min(self.line_blks - offset, num_blocks - done);
+
+            segments.push(LineSegment {
+                line: lba / self.line_blks,
+                offset,
+                num_blocks: len,
+                io_offset: done,
+            });
+
+            done += len;
+        }
+
+        segments
+    }
+
+    /// writes are absorbed by the cache only in write-back mode and while
+    /// no child is being rebuilt
+    fn write_back(&self) -> bool {
+        self.mode == NexusCacheMode::CacheWriteBack
+            && self.rebuilds.load(Ordering::SeqCst) == 0
+    }
+
+    /// statistics of the cache as reported over gRPC
+    pub(crate) fn info(&self) -> NexusCacheInfo {
+        NexusCacheInfo {
+            uri: self.uri.clone(),
+            mode: self.mode as i32,
+            lines: self.lines,
+            dirty: self.dirty.load(Ordering::SeqCst),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Nexus {
+    /// open the cache of the nexus, if it has been configured with one
+    pub(crate) async fn open_cache(&mut self) -> Result<(), Error> {
+        let (uri, mode) = match self.cache_spec.clone() {
+            Some(spec) => spec,
+            None => return Ok(()),
+        };
+
+        let mut cache =
+            NexusCache::open(&uri, mode, self)
+                .await
+                .context(OpenCache {
+                    name: self.name.clone(),
+                })?;
+
+        cache.poller = unsafe {
+            spdk_poller_register(
+                Some(Self::cache_destage_poll),
+                self.as_ptr(),
+                DESTAGE_INTERVAL_US,
+            )
+        };
+
+        info!("{}: opened cache {} ({:?})", self.name, uri, mode);
+        self.cache = Some(cache);
+        Ok(())
+    }
+
+    /// write back all dirty lines and close the cache
+    pub(crate) async fn close_cache(&mut self) {
+        match self.cache.as_mut() {
+            Some(cache) => {
+                if !cache.poller.is_null() {
+                    unsafe { spdk_poller_unregister(&mut cache.poller) };
+                }
+            }
+            None => return,
+        }
+
+        let cache = self.cache.as_ref().unwrap();
+        // wait for a background destage to finish
+        while cache.destaging.swap(true, Ordering::SeqCst) {
+            Yield::default().await;
+        }
+
+        // the cache is only marked for discard once it holds no dirty
+        // lines, otherwise they are written back when it is opened again
+        match self.cache_flush().await {
+            Ok(()) if cache.dirty.load(Ordering::SeqCst) == 0 => {
+                if let Err(e) = cache.discard().await {
+                    error!(
+                        "{}: failed to mark the cache for discard: {}",
+                        self.name, e
+                    );
+                }
+            }
+            Ok(()) => {}
+            Err(e) => {
+                error!("{}: failed to write back the cache: {}", self.name, e)
+            }
+        }
+
+        let uri = cache.uri.clone();
+        self.cache = None;
+
+        if let Err(e) = bdev_destroy(&uri).await {
+            error!("{}: failed to destroy cache bdev: {}", self.name, e);
+        }
+    }
+
+    /// statistics of the cache, if any
+    pub fn cache_info(&self) -> Option<NexusCacheInfo> {
+        self.cache.as_ref().map(NexusCache::info)
+    }
+
+    /// construct the per core handles used by the cache. Children that are
+    /// being rebuilt are placed after those that can be read from.
+    pub(crate) fn cache_handles(&self) -> Option<CacheHandles> {
+        let cache = self.cache.as_ref()?;
+        let handle = |c: &NexusChild| {
+            c.get_descriptor().and_then(BdevHandle::try_from).ok()
+        };
+
+        let mut backing = self
+            .children
+            .iter()
+            .filter(|c| c.status() == ChildStatus::Online)
+            .filter_map(handle)
+            .collect::<Vec<_>>();
+
+        let rebuilding = self
+            .children
+            .iter()
+            .filter(|c| c.rebuilding())
+            .filter_map(handle)
+            .collect::<Vec<_>>();
+
+        let promoting = self.promoting_handles();
+
+        let write_only = rebuilding.len() + promoting.len();
+        backing.extend(rebuilding);
+        backing.extend(promoting);
+
+        Some(CacheHandles {
+            cache: cache.handle().ok()?,
+            backing,
+            write_only,
+        })
+    }
+
+    /// stop absorbing writes and write back all dirty lines, so that the
+    /// children are current before a rebuild copies from them
+    pub(crate) async fn cache_suspend(&self) -> Result<(), CacheError> {
+        if let Some(cache) = self.cache.as_ref() {
+            cache.rebuilds.fetch_add(1, Ordering::SeqCst);
+            while cache.absorbing.load(Ordering::SeqCst) != 0 {
+                Yield::default().await;
+            }
+            self.cache_flush().await?;
+        }
+        Ok(())
+    }
+
+    /// absorb writes again once the rebuild has finished
+    pub(crate) fn cache_resume(&self) {
+        if let Some(cache) = self.cache.as_ref() {
+            cache.rebuilds.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// called periodically to destage dirty lines in the background
+    extern "C" fn cache_destage_poll(ctx: *mut c_void) -> i32 {
+        let nexus = unsafe { Nexus::from_raw(ctx) };
+        let cache = match nexus.cache.as_ref() {
+            Some(cache) => cache,
+            None => return 0,
+        };
+
+        if cache.dirty.load(Ordering::SeqCst) == 0
+            || cache.destaging.swap(true, Ordering::SeqCst)
+        {
+            return 0;
+        }
+
+        // the cache, and with it the nexus, outlives the destage as closing
+        // the cache waits for it to finish
+        Reactors::current().spawn_local(async move {
+            let nexus = unsafe { Nexus::from_raw(ctx) };
+            if let Err(e) = nexus.cache_destage().await {
+                warn!("{}: failed to destage: {}", nexus.name, e);
+            }
+            if let Some(cache) = nexus.cache.as_ref() {
+                cache.destaging.store(false, Ordering::SeqCst);
+            }
+        });
+
+        1
+    }
+
+    /// destage all lines that are dirty at this point in time
+    async fn cache_destage(&self) -> Result<(), CacheError> {
+        let cache = match self.cache.as_ref() {
+            Some(cache) => cache,
+            None => return Ok(()),
+        };
+        let h = self.cache_handles().ok_or(CacheError::NoBackingChild {})?;
+
+        for slot in cache.dirty_slots() {
+            let _guard = cache.locks.lock(vec![slot]).await;
+            self.cache_destage_line(&h, slot).await?;
+        }
+
+        Ok(())
+    }
+
+    /// destage until no dirty lines remain
+    async fn cache_flush(&self) -> Result<(), CacheError> {
+        while self
+            .cache
+            .as_ref()
+            .map_or(false, |c| c.dirty.load(Ordering::SeqCst) != 0)
+        {
+            self.cache_destage().await?;
+        }
+        Ok(())
+    }
+
+    /// submit a read or write IO to a nexus with a cache
+    pub(crate) fn cache_submit(
+        &self,
+        pio: *mut spdk_bdev_io,
+        channel: *mut spdk_io_channel,
+    ) {
+        let io = Bio(pio);
+
+        if Bio::io_type(pio) == Some(io_type::READ) && io.need_buf() {
+            unsafe {
+                spdk_bdev_io_get_buf(
+                    pio,
+                    Some(Self::cache_get_buf_cb),
+                    io.num_blocks() * io.block_len(),
+                )
+            }
+            return;
+        }
+
+        Self::cache_dispatch(pio, channel);
+    }
+
+    /// callback when the buffer for a cached read has been allocated
+    extern "C" fn cache_get_buf_cb(
+        ch: *mut spdk_io_channel,
+        io: *mut spdk_bdev_io,
+        success: bool,
+    ) {
+        if !success {
+            let mut bio = Bio(io);
+            warn!(
+                "{}: Failed to get io buffer for io {:?}",
+                bio.nexus_as_ref().name,
+                bio
+            );
+            bio.fail();
+            return;
+        }
+
+        Self::cache_dispatch(io, ch);
+    }
+
+    /// drive the IO to completion on the current core
+    fn cache_dispatch(pio: *mut spdk_bdev_io, channel: *mut spdk_io_channel) {
+        Reactors::current().spawn_local(async move {
+            let mut io = Bio(pio);
+            let nexus = io.nexus_as_ref();
+            let handles =
+                NexusChannel::inner_from_channel(channel).cache.clone();
+
+            let result = match handles {
+                None => Err(CacheError::NoBackingChild {}),
+                Some(h) => match Bio::io_type(pio) {
+                    Some(io_type::READ) => nexus.cache_read(&io, &h).await,
+                    _ => nexus.cache_write(&io, &h).await,
+                },
+            };
+
+            match result {
+                Ok(_) => {
+                    if Bio::io_type(pio) == Some(io_type::WRITE) {
+                        nexus.replication_record(io.offset(), io.num_blocks());
+                        nexus.change_tracking_record(
+                            io.offset(),
+                            io.num_blocks(),
+                        );
+                    }
+                    io.ok()
+                }
+                Err(e) => {
+                    error!("{}: cached IO {:?} failed: {}", nexus.name, io, e);
+                    io.fail();
+                }
+            }
+        });
+    }
+
+    /// number of blocks of the given line, the last line of the nexus may be
+    /// partial
+    fn cache_line_len(&self, line: u64) -> u64 {
+        let line_blks = self.cache.as_ref().unwrap().line_blks;
+        std::cmp::min(line_blks, self.bdev.num_blocks() - line * line_blks)
+    }
+
+    /// read blocks of the nexus from the first child that can be read from,
+    /// a child the read fails on is faulted and the read retried on the next
+    async fn cache_read_backing(
+        &self,
+        h: &CacheHandles,
+        lba: u64,
+        num_blocks: u64,
+    ) -> Result<DmaBuf, CacheError> {
+        let block_len = u64::from(self.bdev.block_len());
+        let readable = h.backing.len() - h.write_only;
+        let mut error = None;
+
+        for handle in h.backing[.. readable].iter() {
+            let mut buf = handle
+                .dma_malloc((num_blocks * block_len) as usize)
+                .context(CacheAlloc {})?;
+            let result = handle
+                .read_at((lba + self.data_ent_offset) * block_len, &mut buf)
+                .await;
+            match result {
+                Ok(_) => return Ok(buf),
+                Err(e) => {
+                    self.child_io_failed(handle, &e);
+                    error = Some(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) => Err(e).context(BackingIo {}),
+            None => Err(CacheError::NoBackingChild {}),
+        }
+    }
+
+    /// write blocks of the nexus to all children, the children the write fails
+    /// on are faulted and it is an error only when it failed on all children
+    /// that can be read from
+    async fn cache_write_backing(
+        &self,
+        h: &CacheHandles,
+        lba: u64,
+        buf: &DmaBuf,
+    ) -> Result<(), CacheError> {
+        let offset =
+            (lba + self.data_ent_offset) * u64::from(self.bdev.block_len());
+        let readable = h.backing.len() - h.write_only;
+
+        if readable == 0 {
+            return Err(CacheError::NoBackingChild {});
+        }
+
+        let results =
+            join_all(h.backing.iter().map(|b| b.write_at(offset, buf))).await;
+        let mut written = false;
+        let mut error = None;
+        for (i, (result, handle)) in
+            results.into_iter().zip(h.backing.iter()).enumerate()
+        {
+            match result {
+                Ok(_) => written |= i < readable,
+                Err(e) => {
+                    self.child_io_failed(handle, &e);
+                    error = Some(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) if !written => Err(e).context(BackingIo {}),
+            _ => Ok(()),
+        }
+    }
+
+    /// store a full line in a slot that is not dirty, the slot must be locked
+    async fn cache_install(
+        &self,
+        h: &CacheHandles,
+        slot: u64,
+        line: u64,
+        buf: &DmaBuf,
+        flags: u64,
+    ) -> Result<(), CacheError> {
+        let cache = self.cache.as_ref().unwrap();
+
+        // the slot is invalid while its contents are replaced
+        cache.set_entry(slot, CacheEntry::default());
+        h.cache
+            .write_at(cache.slot_offset(slot, 0), buf)
+            .await
+            .context(CacheIo {
+                name: cache.uri.clone(),
+            })?;
+        cache.set_entry(
+            slot,
+            CacheEntry {
+                tag: line,
+                flags: LINE_VALID | flags,
+            },
+        );
+
+        // clean lines are never loaded, so only dirty entries are persisted
+        if flags & LINE_DIRTY != 0 {
+            cache.persist(&h.cache, slot).await?;
+        }
+
+        Ok(())
+    }
+
+    /// write a dirty line back to the children, the slot must be locked
+    async fn cache_destage_line(
+        &self,
+        h: &CacheHandles,
+        slot: u64,
+    ) -> Result<(), CacheError> {
+        let cache = self.cache.as_ref().unwrap();
+        let entry = cache.entry(slot);
+        if !entry.dirty() {
+            return Ok(());
+        }
+
+        let len = self.cache_line_len(entry.tag) * cache.block_len;
+        let mut buf =
+            h.cache.dma_malloc(len as usize).context(CacheAlloc {})?;
+        h.cache
+            .read_at(cache.slot_offset(slot, 0), &mut buf)
+            .await
+            .context(CacheIo {
+                name: cache.uri.clone(),
+            })?;
+
+        self.cache_write_backing(h, entry.tag * cache.line_blks, &buf)
+            .await?;
+
+        cache.set_entry(
+            slot,
+            CacheEntry {
+                flags: LINE_VALID,
+                ..entry
+            },
+        );
+        cache.persist(&h.cache, slot).await
+    }
+
+    /// serve a read from the cache, lines that miss are read from the
+    /// children in full and installed in the cache
+    async fn cache_read(
+        &self,
+        io: &Bio,
+        h: &CacheHandles,
+    ) -> Result<(), CacheError> {
+        let cache = self.cache.as_ref().unwrap();
+        let block_len = io.block_len();
+
+        for seg in cache.segments(io.offset(), io.num_blocks()) {
+            let slot = cache.slot(seg.line);
+            let _guard = cache.locks.lock(vec![slot]).await;
+            let entry = cache.entry(slot);
+            let dst = (seg.io_offset * block_len) as usize;
+            let len = (seg.num_blocks * block_len) as usize;
+
+            if entry.holds(seg.line) {
+                cache.hits.fetch_add(1, Ordering::Relaxed);
+                let mut buf = h.cache.dma_malloc(len).context(CacheAlloc {})?;
+                h.cache
+                    .read_at(cache.slot_offset(slot, seg.offset), &mut buf)
+                    .await
+                    .context(CacheIo {
+                        name: cache.uri.clone(),
+                    })?;
+                io.copy_to_iovs(dst, buf.as_slice());
+                continue;
+            }
+
+            cache.misses.fetch_add(1, Ordering::Relaxed);
+            let line = self
+                .cache_read_backing(
+                    h,
+                    seg.line * cache.line_blks,
+                    self.cache_line_len(seg.line),
+                )
+                .await?;
+
+            let start = (seg.offset * block_len) as usize;
+            io.copy_to_iovs(dst, &line.as_slice()[start .. start + len]);
+
+            // a dirty slot is only evicted by a write
+            if !entry.dirty() {
+                if let Err(e) =
+                    self.cache_install(h, slot, seg.line, &line, 0).await
+                {
+                    warn!("{}: failed to fill cache: {}", self.name, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// write the IO to the children and update the lines present in the
+    /// cache, or store it in the cache only when in write-back mode
+    async fn cache_write(
+        &self,
+        io: &Bio,
+        h: &CacheHandles,
+    ) -> Result<(), CacheError> {
+        let cache = self.cache.as_ref().unwrap();
+        let segments = cache.segments(io.offset(), io.num_blocks());
+        let _guard = cache
+            .locks
+            .lock(segments.iter().map(|s| cache.slot(s.line)).collect())
+            .await;
+
+        // announce ourselves before checking the mode, so that either a
+        // suspend waits for us or we observe the suspend
+        cache.absorbing.fetch_add(1, Ordering::SeqCst);
+        if !cache.write_back() {
+            cache.absorbing.fetch_sub(1, Ordering::SeqCst);
+            return self.cache_write_through(io, h, &segments).await;
+        }
+
+        let result = self.cache_write_back(io, h, &segments).await;
+        cache.absorbing.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    async fn cache_write_through(
+        &self,
+        io: &Bio,
+        h: &CacheHandles,
+        segments: &[LineSegment],
+    ) -> Result<(), CacheError> {
+        let cache = self.cache.as_ref().unwrap();
+        let block_len = io.block_len();
+
+        let mut data = h
+            .cache
+            .dma_malloc((io.num_blocks() * block_len) as usize)
+            .context(CacheAlloc {})?;
+        io.copy_from_iovs(0, data.as_mut_slice());
+        self.cache_write_backing(h, io.offset(), &data).await?;
+
+        for seg in segments {
+            let slot = cache.slot(seg.line);
+            let entry = cache.entry(slot);
+            if !entry.holds(seg.line) {
+                continue;
+            }
+
+            let src = (seg.io_offset * block_len) as usize;
+            let len = (seg.num_blocks * block_len) as usize;
+            let mut buf = h.cache.dma_malloc(len).context(CacheAlloc {})?;
+            buf.as_mut_slice()
+                .copy_from_slice(&data.as_slice()[src .. src + len]);
+
+            let result = h
+                .cache
+                .write_at(cache.slot_offset(slot, seg.offset), &buf)
+                .await
+                .context(CacheIo {
+                    name: cache.uri.clone(),
+                });
+
+            if let Err(e) = result {
+                // the remainder of a dirty line only exists in the cache
+                if entry.dirty() {
+                    return Err(e);
+                }
+                warn!("{}: invalidating cache line: {}", self.name, e);
+                cache.set_entry(slot, CacheEntry::default());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn cache_write_back(
+        &self,
+        io: &Bio,
+        h: &CacheHandles,
+        segments: &[LineSegment],
+    ) -> Result<(), CacheError> {
+        let cache = self.cache.as_ref().unwrap();
+        let block_len = io.block_len();
+
+        for seg in segments {
+            let slot = cache.slot(seg.line);
+            let entry = cache.entry(slot);
+            let src = (seg.io_offset * block_len) as usize;
+            let len = (seg.num_blocks * block_len) as usize;
+
+            if entry.holds(seg.line) {
+                let mut buf = h.cache.dma_malloc(len).context(CacheAlloc {})?;
+                io.copy_from_iovs(src, buf.as_mut_slice());
+                h.cache
+                    .write_at(cache.slot_offset(slot, seg.offset), &buf)
+                    .await
+                    .context(CacheIo {
+                        name: cache.uri.clone(),
+                    })?;
+
+                if !entry.dirty() {
+                    cache.set_entry(
+                        slot,
+                        CacheEntry {
+                            tag: seg.line,
+                            flags: LINE_VALID | LINE_DIRTY,
+                        },
+                    );
+                    cache.persist(&h.cache, slot).await?;
+                }
+                continue;
+            }
+
+            // the slot holds another line which must reach the children
+            // before the slot can be reused
+            if entry.dirty() {
+                self.cache_destage_line(h, slot).await?;
+            }
+
+            let line_blks = self.cache_line_len(seg.line);
+            let mut line = if seg.num_blocks == line_blks {
+                h.cache
+                    .dma_malloc((line_blks * block_len) as usize)
+                    .context(CacheAlloc {})?
+            } else {
+                self.cache_read_backing(
+                    h,
+                    seg.line * cache.line_blks,
+                    line_blks,
+                )
+                .await?
+            };
+
+            let start = (seg.offset * block_len) as usize;
+            io.copy_from_iovs(
+                src,
+                &mut line.as_mut_slice()[start .. start + len],
+            );
+            self.cache_install(h, slot, seg.line, &line, LINE_DIRTY)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,577 @@
+//!
+//! Changed block tracking of the nexus. A checkpoint marks a point in time of
+//! the nexus. Every write that completes on the nexus is recorded in the
+//! region map of the latest checkpoint, and creating the next checkpoint
+//! starts a new, empty map. The regions written since a checkpoint are thus
+//! the union of its map and those of all later checkpoints, which lets a
+//! backup tool copy just the extents that changed since its previous backup
+//! rather than all of the nexus.
+//!
+//! The checkpoints are saved in the MayaMeta partition of the children when
+//! they are created or destroyed and when the nexus is destroyed. Writes
+//! recorded after the last save are lost when mayastor does not shut down
+//! cleanly, so after such a restart every region is considered changed since
+//! every checkpoint, which turns the next incremental backup into a full one.
+//!
+//! With a snapshot, the local replicas of the nexus are snapshotted right
+//! after the checkpoint has been created. The snapshots are replicas as well
+//! and can be shared to read the changed extents from, at the offset of the
+//! nexus data on its replicas. Writes that race with the creation of the
+//! checkpoint may be in the snapshot but are only reported as changed since
+//! the checkpoint, so they are picked up by the next incremental backup.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use snafu::ResultExt;
+
+use rpc::mayastor::{
+    Checkpoint as RpcCheckpoint,
+    Extent as RpcExtent,
+    GetChangedExtentsReply,
+};
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{Error, Nexus, SaveCheckpoints, SnapshotReplica},
+        nexus_child::{ChildStatus, NexusChild},
+        nexus_metadata::MetaDataError,
+        nexus_metadata_content::{
+            ChangeTrackingState,
+            CheckpointState,
+            NexusConfig,
+        },
+    },
+    replica::Replica,
+};
+
+/// granularity at which changed blocks are tracked
+pub const CBT_REGION_SIZE: u64 = 1024 * 1024;
+
+/// maximum number of checkpoints of a nexus
+pub const MAX_CHECKPOINTS: usize = 16;
+
+/// set of regions of the nexus
+#[derive(Debug, Clone, Default)]
+struct RegionMap(Vec<u64>);
+
+impl RegionMap {
+    fn new(regions: u64) -> Self {
+        Self(vec![0; ((regions + 63) / 64) as usize])
+    }
+
+    /// a map in which all of the regions are set
+    fn full(regions: u64) -> Self {
+        let mut map = Self::new(regions);
+        (0 .. regions).for_each(|r| map.set(r));
+        map
+    }
+
+    fn set(&mut self, region: u64) {
+        self.0[(region / 64) as usize] |= 1 << (region % 64);
+    }
+
+    fn get(&self, region: u64) -> bool {
+        self.0[(region / 64) as usize] & (1 << (region % 64)) != 0
+    }
+
+    fn union(&mut self, other: &Self) {
+        self.0
+            .iter_mut()
+            .zip(other.0.iter())
+            .for_each(|(a, b)| *a |= *b);
+    }
+}
+
+#[derive(Debug)]
+struct Checkpoint {
+    name: String,
+    /// creation time in seconds since the epoch
+    created: u64,
+    /// regions written after this checkpoint and before the next one
+    changes: RegionMap,
+}
+
+#[derive(Debug, Default)]
+struct Tracker {
+    region_blks: u64,
+    num_blocks: u64,
+    generation: u64,
+    checkpoints: Vec<Checkpoint>,
+}
+
+impl Tracker {
+    fn regions(&self) -> u64 {
+        (self.num_blocks + self.region_blks - 1) / self.region_blks
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.checkpoints.iter().position(|c| c.name == name)
+    }
+
+    fn record(&mut self, offset: u64, num_blocks: u64) {
+        let region_blks = self.region_blks;
+        if let Some(last) = self.checkpoints.last_mut() {
+            for region in
+                offset / region_blks ..= (offset + num_blocks - 1) / region_blks
+            {
+                last.changes.set(region);
+            }
+        }
+    }
+
+    /// regions written between the checkpoints at the given positions
+    fn changes(&self, from: usize, to: usize) -> RegionMap {
+        let mut map = RegionMap::new(self.regions());
+        self.checkpoints[from .. to]
+            .iter()
+            .for_each(|c| map.union(&c.changes));
+        map
+    }
+
+    /// ranges of nexus blocks covered by the regions of the map, adjacent
+    /// regions are merged into a single range
+    fn extents(&self, map: &RegionMap) -> Vec<(u64, u64)> {
+        let mut extents: Vec<(u64, u64)> = Vec::new();
+        for region in (0 .. self.regions()).filter(|r| map.get(*r)) {
+            let offset = region * self.region_blks;
+            let len = std::cmp::min(self.region_blks, self.num_blocks - offset);
+            match extents.last_mut() {
+                Some(last) if last.0 + last.1 == offset => last.1 += len,
+                _ => extents.push((offset, len)),
+            }
+        }
+        extents
+    }
+
+    /// remove a checkpoint, its changes are still changes since the
+    /// checkpoint before it
+    fn remove(&mut self, idx: usize) {
+        let checkpoint = self.checkpoints.remove(idx);
+        if idx > 0 {
+            self.checkpoints[idx - 1].changes.union(&checkpoint.changes);
+        }
+    }
+
+    fn to_state(&mut self, clean: bool, block_len: u32) -> ChangeTrackingState {
+        self.generation += 1;
+        ChangeTrackingState {
+            generation: self.generation,
+            clean,
+            region_size: CBT_REGION_SIZE,
+            block_len,
+            checkpoints: self
+                .checkpoints
+                .iter()
+                .map(|c| CheckpointState {
+                    name: c.name.clone(),
+                    created: c.created,
+                    changes: c.changes.0.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// checkpoints of a nexus and the changes since each of them
+#[derive(Debug, Default)]
+pub struct ChangeTracking {
+    /// written on IO completion on any of the cores
+    tracker: Mutex<Tracker>,
+    /// there is at least one checkpoint, so writes have to be recorded
+    active: AtomicBool,
+}
+
+impl ChangeTracking {
+    fn update_active(&self, tracker: &Tracker) {
+        self.active
+            .store(!tracker.checkpoints.is_empty(), Ordering::SeqCst);
+    }
+}
+
+impl NexusChild {
+    /// the latest change tracking state saved on the child, if any
+    async fn load_change_tracking(
+        &self,
+    ) -> Result<Option<ChangeTrackingState>, MetaDataError> {
+        match self
+            .get_latest_selected_config_object(|c| {
+                matches!(c, NexusConfig::ChangeTracking(_))
+            })
+            .await?
+        {
+            Some(NexusConfig::ChangeTracking(state)) => Ok(Some(state)),
+            _ => Ok(None),
+        }
+    }
+
+    /// replace the change tracking state saved on the child
+    async fn save_change_tracking(
+        &mut self,
+        config: &NexusConfig,
+        now: &SystemTime,
+    ) -> Result<(), MetaDataError> {
+        self.replace_selected_config_objects(config, now, |c| {
+            matches!(c, NexusConfig::ChangeTracking(_))
+        })
+        .await
+    }
+}
+
+impl Nexus {
+    /// Load the checkpoints saved on the children, called when the nexus is
+    /// opened. Errors are not fatal, the nexus is opened without checkpoints.
+    pub(crate) async fn open_change_tracking(&mut self) {
+        let block_len = self.bdev.block_len();
+        let mut latest: Option<ChangeTrackingState> = None;
+
+        for child in self
+            .children
+            .iter()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            match child.load_change_tracking().await {
+                Ok(Some(state)) => {
+                    if latest
+                        .as_ref()
+                        .map_or(true, |l| l.generation < state.generation)
+                    {
+                        latest = Some(state);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "{}: failed to load checkpoints from child {}: {}",
+                    self.name, child.name, e
+                ),
+            }
+        }
+
+        let mut tracker = self.change_tracking.tracker.lock().unwrap();
+        *tracker = Tracker {
+            region_blks: CBT_REGION_SIZE / u64::from(block_len),
+            num_blocks: self.bdev.num_blocks(),
+            generation: 0,
+            checkpoints: Vec::new(),
+        };
+
+        if let Some(state) = latest {
+            let regions = tracker.regions();
+            let words = RegionMap::new(regions).0.len();
+            if state.region_size != CBT_REGION_SIZE
+                || state.block_len != block_len
+            {
+                warn!(
+                    "{}: discarding checkpoints with a different geometry",
+                    self.name
+                );
+            } else {
+                if !state.clean {
+                    warn!(
+                        "{}: nexus was not closed cleanly, all regions are considered changed",
+                        self.name
+                    );
+                }
+                tracker.generation = state.generation;
+                tracker.checkpoints = state
+                    .checkpoints
+                    .into_iter()
+                    .map(|c| Checkpoint {
+                        changes: if state.clean && c.changes.len() == words {
+                            RegionMap(c.changes)
+                        } else {
+                            RegionMap::full(regions)
+                        },
+                        name: c.name,
+                        created: c.created,
+                    })
+                    .collect();
+            }
+        }
+
+        self.change_tracking.update_active(&tracker);
+        let active = !tracker.checkpoints.is_empty();
+        drop(tracker);
+
+        // until the nexus is closed again, the saved state is not up to date
+        if active {
+            if let Err(e) = self.save_checkpoints(false).await {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// Save the checkpoints as the nexus is closed, called when the nexus is
+    /// destroyed
+    pub(crate) async fn close_change_tracking(&mut self) {
+        if self.change_tracking.active.load(Ordering::SeqCst) {
+            if let Err(e) = self.save_checkpoints(true).await {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// save the checkpoints on all children that are in sync, it is an error
+    /// only when none of them could be written to
+    async fn save_checkpoints(&mut self, clean: bool) -> Result<(), Error> {
+        let config = NexusConfig::ChangeTracking(
+            self.change_tracking
+                .tracker
+                .lock()
+                .unwrap()
+                .to_state(clean, self.bdev.block_len()),
+        );
+        let now = SystemTime::now();
+        let mut saved = false;
+        let mut error = None;
+
+        for child in self
+            .children
+            .iter_mut()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            match child.save_change_tracking(&config, &now).await {
+                Ok(_) => saved = true,
+                Err(e) => {
+                    error!(
+                        "{}: failed to save checkpoints on child {}: {}",
+                        self.name, child.name, e
+                    );
+                    error = Some(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) if !saved => Err(e).context(SaveCheckpoints {
+                name: self.name.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// the local replicas that are children of the nexus
+    fn local_replicas(&self) -> Vec<Replica> {
+        self.children
+            .iter()
+            .filter(|c| c.status() == ChildStatus::Online)
+            .filter_map(|c| c.bdev.as_ref())
+            .filter_map(|bdev| Replica::lookup(&bdev.name()))
+            .collect()
+    }
+
+    /// Create a checkpoint after which all writes to the nexus are tracked.
+    /// With snapshot set the local replicas are snapshotted as well, and the
+    /// uuids of the snapshots are returned.
+    pub async fn create_checkpoint(
+        &mut self,
+        name: &str,
+        snapshot: bool,
+    ) -> Result<Vec<String>, Error> {
+        if name.is_empty() || name.contains('/') {
+            return Err(Error::InvalidCheckpointName {
+                checkpoint: name.to_owned(),
+                name: self.name.clone(),
+            });
+        }
+
+        // the replicas only hold part of the data of a parity nexus
+        if snapshot && self.parity.is_some() {
+            return Err(Error::CheckpointSnapshotNotSupported {
+                name: self.name.clone(),
+            });
+        }
+
+        {
+            let mut tracker = self.change_tracking.tracker.lock().unwrap();
+            // the geometry is only known once the nexus has been opened
+            if tracker.region_blks == 0 {
+                return Err(Error::NexusNotOpen {
+                    name: self.name.clone(),
+                });
+            }
+            if tracker.position(name).is_some() {
+                return Err(Error::CheckpointExists {
+                    checkpoint: name.to_owned(),
+                    name: self.name.clone(),
+                });
+            }
+            if tracker.checkpoints.len() >= MAX_CHECKPOINTS {
+                return Err(Error::TooManyCheckpoints {
+                    name: self.name.clone(),
+                });
+            }
+
+            let regions = tracker.regions();
+            tracker.checkpoints.push(Checkpoint {
+                name: name.to_owned(),
+                created: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                changes: RegionMap::new(regions),
+            });
+            self.change_tracking.update_active(&tracker);
+        }
+
+        let mut snapshots = Vec::new();
+        let mut result = Ok(());
+        if snapshot {
+            for replica in self.local_replicas() {
+                let snapshot_name = format!("{}-{}", replica.get_uuid(), name);
+                match replica.snapshot(&snapshot_name).await {
+                    Ok(s) => snapshots.push(s),
+                    Err(source) => {
+                        result = Err(Error::SnapshotReplica {
+                            source,
+                            child: replica.get_uuid().to_owned(),
+                            name: self.name.clone(),
+                        });
+                        break;
+                    }
+                }
+            }
+        }
+
+        if result.is_ok() {
+            result = self.save_checkpoints(false).await;
+        }
+
+        if let Err(e) = result {
+            for s in snapshots {
+                if let Err(err) = s.destroy().await {
+                    error!(
+                        "{}: failed to destroy snapshot: {}",
+                        self.name, err
+                    );
+                }
+            }
+            let mut tracker = self.change_tracking.tracker.lock().unwrap();
+            if let Some(idx) = tracker.position(name) {
+                tracker.remove(idx);
+            }
+            self.change_tracking.update_active(&tracker);
+            return Err(e);
+        }
+
+        info!("{}: created checkpoint {}", self.name, name);
+        Ok(snapshots.iter().map(|s| s.get_uuid().to_owned()).collect())
+    }
+
+    /// Destroy a checkpoint and the snapshots taken with it
+    pub async fn destroy_checkpoint(
+        &mut self,
+        name: &str,
+    ) -> Result<(), Error> {
+        {
+            let mut tracker = self.change_tracking.tracker.lock().unwrap();
+            match tracker.position(name) {
+                Some(idx) => tracker.remove(idx),
+                None => {
+                    return Err(Error::CheckpointNotFound {
+                        checkpoint: name.to_owned(),
+                        name: self.name.clone(),
+                    })
+                }
+            }
+            self.change_tracking.update_active(&tracker);
+        }
+
+        for replica in self.local_replicas() {
+            let snapshot_name = format!("{}-{}", replica.get_uuid(), name);
+            if let Some(snapshot) = Replica::lookup(&snapshot_name) {
+                if let Err(e) = snapshot.destroy().await {
+                    error!(
+                        "{}: failed to destroy snapshot {}: {}",
+                        self.name, snapshot_name, e
+                    );
+                }
+            }
+        }
+
+        self.save_checkpoints(false).await?;
+        info!("{}: destroyed checkpoint {}", self.name, name);
+        Ok(())
+    }
+
+    /// record a write that completed on the nexus, this is called on the
+    /// core the IO was submitted on
+    pub(crate) fn change_tracking_record(&self, offset: u64, num_blocks: u64) {
+        if num_blocks > 0 && self.change_tracking.active.load(Ordering::Relaxed)
+        {
+            self.change_tracking
+                .tracker
+                .lock()
+                .unwrap()
+                .record(offset, num_blocks);
+        }
+    }
+
+    /// checkpoints of the nexus as reported over gRPC, oldest first
+    pub fn checkpoints(&self) -> Vec<RpcCheckpoint> {
+        let block_len = u64::from(self.bdev.block_len());
+        let tracker = self.change_tracking.tracker.lock().unwrap();
+        (0 .. tracker.checkpoints.len())
+            .map(|idx| {
+                let checkpoint = &tracker.checkpoints[idx];
+                let changes = tracker.changes(idx, tracker.checkpoints.len());
+                RpcCheckpoint {
+                    name: checkpoint.name.clone(),
+                    created: checkpoint.created,
+                    changed_bytes: tracker
+                        .extents(&changes)
+                        .iter()
+                        .map(|(_, len)| len * block_len)
+                        .sum(),
+                }
+            })
+            .collect()
+    }
+
+    /// Extents of the nexus written after the checkpoint `from` and before
+    /// the checkpoint `to`, or up to now when `to` is empty
+    pub fn changed_extents(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<GetChangedExtentsReply, Error> {
+        let block_len = u64::from(self.bdev.block_len());
+        let tracker = self.change_tracking.tracker.lock().unwrap();
+        let not_found = |checkpoint: &str| Error::CheckpointNotFound {
+            checkpoint: checkpoint.to_owned(),
+            name: self.name.clone(),
+        };
+
+        let start = tracker.position(from).ok_or_else(|| not_found(from))?;
+        let end = if to.is_empty() {
+            tracker.checkpoints.len()
+        } else {
+            tracker.position(to).ok_or_else(|| not_found(to))?
+        };
+
+        if end < start {
+            return Err(Error::CheckpointOrder {
+                from: from.to_owned(),
+                to: to.to_owned(),
+                name: self.name.clone(),
+            });
+        }
+
+        let changes = tracker.changes(start, end);
+        Ok(GetChangedExtentsReply {
+            extents: tracker
+                .extents(&changes)
+                .into_iter()
+                .map(|(offset, len)| RpcExtent {
+                    offset: offset * block_len,
+                    length: len * block_len,
+                })
+                .collect(),
+            data_offset: self.data_ent_offset * block_len,
+        })
+    }
+}
@@ -1,6 +1,6 @@
 //!
 //! IO is driven by means of so called channels.
-use std::{convert::TryFrom, ffi::c_void};
+use std::{convert::TryFrom, ffi::c_void, rc::Rc};
 
 use spdk_sys::{
     spdk_for_each_channel,
@@ -12,7 +12,14 @@ use spdk_sys::{
 };
 
 use crate::{
-    bdev::{nexus::nexus_child::ChildStatus, Nexus},
+    bdev::{
+        nexus::{
+            nexus_cache::CacheHandles,
+            nexus_child::ChildStatus,
+            nexus_parity::ParityColumn,
+        },
+        Nexus,
+    },
     core::BdevHandle,
 };
 
@@ -29,6 +36,11 @@ pub(crate) struct NexusChannelInner {
     pub(crate) ch: Vec<BdevHandle>,
     pub(crate) write_only: usize,
     pub(crate) previous: usize,
+    /// handles indexed by column when the nexus has a parity layout, IOs in
+    /// flight hold on to the set they were started with
+    pub(crate) columns: Rc<Vec<Option<ParityColumn>>>,
+    /// handles used by the cache of the nexus, if it has one
+    pub(crate) cache: Option<Rc<CacheHandles>>,
     device: *mut c_void,
 }
 
@@ -83,6 +95,8 @@ impl NexusChannelInner {
         self.ch.clear();
         self.previous = 0;
         self.write_only = 0;
+        self.columns = Rc::new(nexus.parity_columns());
+        self.cache = nexus.cache_handles().map(Rc::new);
 
         // iterate to over all our children which are in the open state
         nexus
@@ -108,6 +122,12 @@ impl NexusChannelInner {
                     )
                 })
                 .for_each(drop);
+
+            // async children being promoted receive all writes as well
+            nexus.promoting_handles().into_iter().for_each(|h| {
+                self.write_only += 1;
+                self.ch.push(h);
+            });
         }
 
         trace!(
@@ -135,6 +155,8 @@ impl NexusChannel {
             ch: Vec::new(),
             previous: 0,
             write_only: 0,
+            columns: Rc::new(nexus.parity_columns()),
+            cache: nexus.cache_handles().map(Rc::new),
             device,
         });
 
@@ -158,6 +180,8 @@ impl NexusChannel {
         debug!("{} Destroying IO channels", nexus.bdev.name());
         let inner = NexusChannel::from_raw(ctx).inner_mut();
         inner.ch.clear();
+        inner.columns = Rc::new(Vec::new());
+        inner.cache = None;
     }
 
     /// function called when we receive a Dynamic Reconfigure event (DR)
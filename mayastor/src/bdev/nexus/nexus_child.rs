@@ -74,6 +74,8 @@ struct StatusReasons {
     /// Faulted
     /// fatal error, cannot be recovered
     fatal_error: bool,
+    /// the replica is owned by a newer nexus which fenced us off
+    fenced: bool,
 }
 
 impl StatusReasons {
@@ -82,6 +84,12 @@ impl StatusReasons {
         self.fatal_error = true;
     }
 
+    /// fenced off by a newer owner, it is not recoverable either
+    fn fenced(&mut self) {
+        self.fatal_error = true;
+        self.fenced = true;
+    }
+
     /// set offline
     fn offline(&mut self, offline: bool) {
         self.offline = offline;
@@ -154,6 +162,8 @@ pub struct NexusChild {
     /// record of most-recent IO errors
     #[serde(skip_serializing)]
     pub(crate) err_store: Option<NexusErrStore>,
+    /// column of the parity layout this child stores, if any
+    pub(crate) column: Option<u32>,
 }
 
 impl Display for NexusChild {
@@ -245,6 +255,18 @@ impl NexusChild {
         self.close();
         self.status_reasons.fatal_error();
     }
+    /// Fault the child as its writes are rejected in favour of a newer owner,
+    /// or as it cannot be fenced off from other owners
+    pub(crate) fn fence(&mut self) {
+        self.close();
+        self.status_reasons.fenced();
+    }
+
+    /// whether the child was faulted by fencing
+    pub fn is_fenced(&self) -> bool {
+        self.status_reasons.fenced
+    }
+
     /// Set the child as out of sync with the nexus
     /// It requires a full rebuild before it can service IO
     /// and remains degraded until such time
@@ -363,6 +385,7 @@ impl NexusChild {
             status_reasons: Default::default(),
             bdev_handle: None,
             err_store: None,
+            column: None,
         }
     }
 
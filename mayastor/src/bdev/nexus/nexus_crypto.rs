@@ -0,0 +1,473 @@
+//!
+//! Encryption of a nexus by a crypto vbdev on top of it, which is shared in
+//! place of the nexus. The key is fetched by its ID from the key provider in
+//! the nexus options.
+//!
+//! The first time a nexus is published with a key, a marker with the ID of
+//! the key is saved in the MayaMeta partition of its children. From then on
+//! the nexus can only be published with that same key, as publishing it
+//! without a key would expose the ciphertext and publishing it with another
+//! key would return garbage and scramble whatever is written, until the key
+//! is rotated.
+//!
+//! The crypto vbdev sits on a view of the nexus rather than on the nexus
+//! itself and the share handle is a router on top of it, so that a second
+//! crypto vbdev can be added while the key is rotated, see nexus_key_router.
+
+use std::{ffi::CString, time::SystemTime};
+
+use futures::channel::oneshot;
+use snafu::ResultExt;
+
+use spdk_sys::{create_crypto_disk, delete_crypto_disk};
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{
+            CreateKeyRouter,
+            DestroyCryptoBdev,
+            DestroyKeyRouter,
+            Error,
+            GetEncryptionKey,
+            LoadEncryption,
+            Nexus,
+            SaveEncryption,
+        },
+        nexus_child::{ChildStatus, NexusChild},
+        nexus_key_provider::{key_provider_from_uri, EncryptionKey},
+        nexus_key_router::KeyRouter,
+        nexus_metadata::MetaDataError,
+        nexus_metadata_content::{EncryptionState, NexusConfig},
+        nexus_rekey::{KeyRotation, KEY_ROTATION_SEGMENT_SIZE},
+    },
+    core::Bdev,
+    ffihelper::{cb_arg, done_errno_cb, errno_result_from_i32, ErrnoResult},
+    subsys::Config,
+};
+
+/// DPDK crypto drivers of the crypto vbdev that support AES-XTS
+const XTS_CRYPTO_PMDS: &[&str] = &["crypto_qat"];
+
+/// A crypto vbdev with one key on top of a view of the nexus
+#[derive(Debug)]
+pub(crate) struct CryptoLayer {
+    pub(crate) crypto: String,
+    view: Box<KeyRouter>,
+}
+
+/// The bdevs encrypting a shared nexus
+#[derive(Debug)]
+pub(crate) struct CryptoStack {
+    /// the share handle
+    pub(crate) router: Box<KeyRouter>,
+    /// the layer with the current key, followed by the layer with the new
+    /// key while the key is rotated
+    pub(crate) layers: Vec<CryptoLayer>,
+    /// number of the next layer, which makes the names of its bdevs unique
+    next_layer: u32,
+    /// the rotation to the key of the second layer, when started
+    pub(crate) rotation: Option<KeyRotation>,
+}
+
+/// Get the key with the ID from the key provider of the nexus. This must be
+/// called from the master reactor.
+pub async fn get_encryption_key(key_id: &str) -> Result<EncryptionKey, Error> {
+    let uri = Config::get().nexus_opts.key_provider.clone();
+    let provider = key_provider_from_uri(&uri).context(GetEncryptionKey {
+        key_id,
+    })?;
+    provider.get_key(key_id).await.context(GetEncryptionKey {
+        key_id,
+    })
+}
+
+impl NexusChild {
+    /// the latest encryption marker saved on the child, if any
+    async fn load_encryption(
+        &self,
+    ) -> Result<Option<EncryptionState>, MetaDataError> {
+        match self
+            .get_latest_selected_config_object(|c| {
+                matches!(c, NexusConfig::Encryption(_))
+            })
+            .await?
+        {
+            Some(NexusConfig::Encryption(state)) => Ok(Some(state)),
+            _ => Ok(None),
+        }
+    }
+
+    /// replace the encryption marker saved on the child
+    async fn save_encryption(
+        &mut self,
+        config: &NexusConfig,
+        now: &SystemTime,
+    ) -> Result<(), MetaDataError> {
+        self.replace_selected_config_objects(config, now, |c| {
+            matches!(c, NexusConfig::Encryption(_))
+        })
+        .await
+    }
+}
+
+impl Nexus {
+    /// The latest encryption marker saved on the children. Unlike the other
+    /// metadata, a child it cannot be loaded from is an error, as the nexus
+    /// could otherwise be published unencrypted by mistake.
+    pub(crate) async fn load_encryption(
+        &self,
+    ) -> Result<Option<EncryptionState>, Error> {
+        let mut latest: Option<EncryptionState> = None;
+
+        for child in self
+            .children
+            .iter()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            let state =
+                child.load_encryption().await.context(LoadEncryption {
+                    child: child.name.clone(),
+                    name: self.name.clone(),
+                })?;
+            if let Some(state) = state {
+                if latest
+                    .as_ref()
+                    .map_or(true, |l| l.generation < state.generation)
+                {
+                    latest = Some(state);
+                }
+            }
+        }
+
+        Ok(latest)
+    }
+
+    /// Save the encryption marker on all children that are in sync, which
+    /// also brings children that have been added since up to date.
+    pub(crate) async fn save_encryption(
+        &mut self,
+        state: EncryptionState,
+    ) -> Result<(), Error> {
+        let config = NexusConfig::Encryption(state);
+        let now = SystemTime::now();
+
+        for child in self
+            .children
+            .iter_mut()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            child.save_encryption(&config, &now).await.context(
+                SaveEncryption {
+                    child: child.name.clone(),
+                    name: self.name.clone(),
+                },
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// whether the share handle encrypts the nexus with the key, or does not
+    /// encrypt it if there is none. While the key is rotated, the new key is
+    /// accepted as well.
+    pub(crate) fn is_encrypted_with(
+        &self,
+        key: Option<&EncryptionKey>,
+    ) -> bool {
+        let uuid = self.bdev.uuid_as_string();
+        match (key, self.encryption.as_ref()) {
+            (None, None) => true,
+            (Some(key), Some(state)) => {
+                key_matches(key, &uuid, &state.key_id, &state.key_check)
+                    || state.rotation.as_ref().map_or(false, |r| {
+                        key_matches(key, &uuid, &r.key_id, &r.key_check)
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// Fail if the nexus has ever been published encrypted, as it must not be
+    /// shared without a key then.
+    pub(crate) async fn check_unencrypted(&self) -> Result<(), Error> {
+        match self.load_encryption().await? {
+            Some(state) => Err(Error::EncryptionRequired {
+                key_id: state.key_id,
+                name: self.name.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Create the bdevs encrypting the nexus with the key and return the
+    /// name of the one to share. The key must be the one the nexus has been
+    /// encrypted with before, if any, or the one it is being rotated to. The
+    /// marker is saved before the share handle is shared, so nothing is
+    /// written encrypted without it. An interrupted rotation of the key is
+    /// resumed, for which the other key is fetched from the key provider.
+    pub(crate) async fn create_crypto_bdev(
+        &mut self,
+        key: EncryptionKey,
+    ) -> Result<String, Error> {
+        let uuid = self.bdev.uuid_as_string();
+        let state = match self.load_encryption().await? {
+            Some(state) => {
+                if !key_matches(&key, &uuid, &state.key_id, &state.key_check)
+                    && !state.rotation.as_ref().map_or(false, |r| {
+                        key_matches(&key, &uuid, &r.key_id, &r.key_check)
+                    })
+                {
+                    return Err(Error::EncryptionKeyMismatch {
+                        key_id: key.id().to_string(),
+                        name: self.name.clone(),
+                    });
+                }
+                state
+            }
+            None => {
+                info!(
+                    "{}: encrypting with key {} using {}",
+                    self.name,
+                    key.id(),
+                    key.cipher()
+                );
+                EncryptionState {
+                    generation: 1,
+                    key_id: key.id().to_string(),
+                    cipher: key.cipher().to_string(),
+                    key_check: key.check_value(&uuid),
+                    rotation: None,
+                }
+            }
+        };
+
+        // the current key comes first, followed by the new key if the key
+        // is being rotated
+        let (current, new) = match state.rotation.as_ref() {
+            None => (key, None),
+            Some(r) => {
+                let to_key = key_matches(&key, &uuid, &r.key_id, &r.key_check);
+                let (id, check) = if to_key {
+                    (&state.key_id, &state.key_check)
+                } else {
+                    (&r.key_id, &r.key_check)
+                };
+                let other = get_encryption_key(id).await?;
+                if !key_matches(&other, &uuid, id, check) {
+                    return Err(Error::EncryptionKeyMismatch {
+                        key_id: id.clone(),
+                        name: self.name.clone(),
+                    });
+                }
+                if to_key {
+                    (other, Some(key))
+                } else {
+                    (key, Some(other))
+                }
+            }
+        };
+
+        let stack = self.create_crypto_stack(&current).await?;
+        let name = stack.router.name().to_string();
+        self.crypto = Some(stack);
+
+        if let Err(e) = self.save_encryption(state.clone()).await {
+            if let Err(e) = self.destroy_crypto_bdev().await {
+                error!("{}", e);
+            }
+            return Err(e);
+        }
+        self.encryption = Some(state.clone());
+
+        if let (Some(rotation), Some(new)) = (state.rotation, new) {
+            info!(
+                "{}: resuming rotation to key {} at block {}",
+                self.name, rotation.key_id, rotation.watermark
+            );
+            let result = self.add_crypto_layer(&new, rotation.watermark).await;
+            if let Err(e) = result {
+                // the blocks below the watermark only decrypt with the new
+                // key
+                if let Err(e) = self.destroy_crypto_bdev().await {
+                    error!("{}", e);
+                }
+                return Err(e);
+            }
+            self.start_key_rotation(new);
+        }
+
+        Ok(name)
+    }
+
+    /// Create the router shared in place of the nexus, over a crypto vbdev
+    /// with the key.
+    async fn create_crypto_stack(
+        &self,
+        key: &EncryptionKey,
+    ) -> Result<CryptoStack, Error> {
+        let layer = self.create_crypto_layer(key, 0).await?;
+        let name = format!("crypto-{}", self.name);
+        let segment_blks =
+            KEY_ROTATION_SEGMENT_SIZE / u64::from(self.bdev.block_len());
+
+        match KeyRouter::create(&name, &layer.crypto, segment_blks) {
+            Ok(router) => Ok(CryptoStack {
+                router,
+                layers: vec![layer],
+                next_layer: 1,
+                rotation: None,
+            }),
+            Err(source) => {
+                if let Err(e) = self.destroy_crypto_layer(layer).await {
+                    error!("{}", e);
+                }
+                Err(Error::CreateKeyRouter {
+                    source,
+                    name: self.name.clone(),
+                })
+            }
+        }
+    }
+
+    /// Add a crypto vbdev with the key to the share handle, which receives
+    /// the IO to the blocks below the watermark.
+    pub(crate) async fn add_crypto_layer(
+        &mut self,
+        key: &EncryptionKey,
+        watermark: u64,
+    ) -> Result<(), Error> {
+        let id = {
+            let stack = self.crypto.as_mut().expect("nexus is not encrypted");
+            stack.next_layer += 1;
+            stack.next_layer - 1
+        };
+        let layer = self.create_crypto_layer(key, id).await?;
+
+        let router = &self.crypto.as_ref().unwrap().router;
+        if let Err(source) = router.add_base(&layer.crypto, watermark).await {
+            if let Err(e) = self.destroy_crypto_layer(layer).await {
+                error!("{}", e);
+            }
+            return Err(Error::CreateKeyRouter {
+                source,
+                name: self.name.clone(),
+            });
+        }
+        self.crypto.as_mut().unwrap().layers.push(layer);
+        Ok(())
+    }
+
+    /// Create a crypto vbdev with the key on top of a view of the nexus.
+    async fn create_crypto_layer(
+        &self,
+        key: &EncryptionKey,
+        id: u32,
+    ) -> Result<CryptoLayer, Error> {
+        let pmd = Config::get().nexus_opts.crypto_pmd.clone();
+        if !XTS_CRYPTO_PMDS.contains(&pmd.as_str()) {
+            return Err(Error::UnsupportedCryptoPmd {
+                pmd,
+                name: self.name.clone(),
+            });
+        }
+
+        let view_name = format!("{}-view-{}", self.name, id);
+        let view = KeyRouter::create(&view_name, &self.name, 0).context(
+            CreateKeyRouter {
+                name: self.name.clone(),
+            },
+        )?;
+
+        let name = format!("{}-crypto-{}", self.name, id);
+        let cname = CString::new(name.clone()).unwrap();
+        let base = CString::new(view_name).unwrap();
+        let pmd = CString::new(pmd).unwrap();
+        let cipher = CString::new(key.cipher().spdk_name()).unwrap();
+        // the keys to the castle, passed with their length as they can
+        // contain any byte
+        let (data_key, tweak_key) = key.xts_keys();
+
+        let errno = unsafe {
+            create_crypto_disk(
+                base.as_ptr(),
+                cname.as_ptr(),
+                pmd.as_ptr(),
+                data_key.as_ptr(),
+                data_key.len() as u64,
+                cipher.as_ptr(),
+                tweak_key.as_ptr(),
+                tweak_key.len() as u64,
+            )
+        };
+        if let Err(source) = errno_result_from_i32((), errno) {
+            if let Err(e) = view.destroy().await {
+                error!("{}: failed to destroy view: {}", self.name, e);
+            }
+            return Err(Error::CreateCryptoBdev {
+                source,
+                name: self.name.clone(),
+            });
+        }
+
+        Ok(CryptoLayer {
+            crypto: name,
+            view,
+        })
+    }
+
+    /// destroy a crypto vbdev and the view of the nexus beneath it
+    pub(crate) async fn destroy_crypto_layer(
+        &self,
+        layer: CryptoLayer,
+    ) -> Result<(), Error> {
+        if let Some(bdev) = Bdev::lookup_by_name(&layer.crypto) {
+            let (s, r) = oneshot::channel::<ErrnoResult<()>>();
+            unsafe {
+                delete_crypto_disk(
+                    bdev.as_ptr(),
+                    Some(done_errno_cb),
+                    cb_arg(s),
+                );
+            }
+            r.await.expect("crypto delete sender is gone").context(
+                DestroyCryptoBdev {
+                    name: self.name.clone(),
+                },
+            )?;
+        }
+
+        layer.view.destroy().await.context(DestroyKeyRouter {
+            name: self.name.clone(),
+        })
+    }
+
+    /// Destroy the share handle of an encrypted nexus and all bdevs beneath
+    /// it, stopping the rotation of its key first.
+    pub(crate) async fn destroy_crypto_bdev(&mut self) -> Result<(), Error> {
+        self.stop_key_rotation().await;
+
+        let stack = match self.crypto.take() {
+            Some(stack) => stack,
+            None => return Ok(()),
+        };
+
+        stack.router.destroy().await.context(DestroyKeyRouter {
+            name: self.name.clone(),
+        })?;
+        for layer in stack.layers {
+            self.destroy_crypto_layer(layer).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// whether the key is the one with the ID and check value
+pub(crate) fn key_matches(
+    key: &EncryptionKey,
+    nexus_uuid: &str,
+    key_id: &str,
+    key_check: &str,
+) -> bool {
+    key.id() == key_id && key.check_value(nexus_uuid) == key_check
+}
@@ -0,0 +1,405 @@
+//!
+//! Fencing of the replicas of a nexus, so that only the nexus that opened
+//! them last can write to them. Without it, two nexuses on different nodes
+//! could open the same replicas and write concurrently, for example after a
+//! network partition.
+//!
+//! Ownership is taken with an NVMe reservation on the namespace of each
+//! replica. The key of the reservation is the generation of the owner, which
+//! a nexus chooses when it is opened as one more than the newest generation
+//! found on any of its children. It registers that key on every child and
+//! acquires a write exclusive reservation, preempting the previous owner.
+//! The target then rejects writes from any other host with a reservation
+//! conflict, which the previous owner turns into a faulted child rather than
+//! retrying.
+//!
+//! The replicas keep the reservation persistently, so a replica that
+//! restarts does not accept writes from a stale owner either. A child whose
+//! reservation cannot be read or taken is faulted. Children exported without
+//! support for reservations, such as over iSCSI, and local children are used
+//! without fencing, with a warning for the former and for local replicas
+//! that are exported to other nodes at the same time. A nexus and a stale
+//! owner in the same mayastor instance share the NVMe host identifier and
+//! cannot fence each other.
+
+use std::convert::TryInto;
+
+use snafu::ResultExt;
+
+use spdk_sys::{
+    spdk_bdev,
+    SPDK_BDEV_IO_TYPE_NVME_IO,
+    SPDK_NVME_RESERVE_ACQUIRE,
+    SPDK_NVME_RESERVE_PREEMPT,
+    SPDK_NVME_RESERVE_PTPL_PERSIST_POWER_LOSS,
+    SPDK_NVME_RESERVE_REGISTER_KEY,
+    SPDK_NVME_RESERVE_REPLACE_KEY,
+    SPDK_NVME_RESERVE_WRITE_EXCLUSIVE,
+    SPDK_NVME_SCT_GENERIC,
+    SPDK_NVME_SC_RESERVATION_CONFLICT,
+};
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{nexus_lookup, Error, FenceChild, Nexus},
+        nexus_channel::DREvent,
+        nexus_child::{ChildState, NexusChild},
+        nexus_io::{io_status, io_type},
+    },
+    core::{BdevHandle, CoreError, Cores, DmaAlloc, Reactors},
+    replica::Replica,
+};
+
+/// size of the reservation status read from a child, which holds the data
+/// of up to 63 registered controllers
+const RESERVATION_REPORT_SIZE: usize = 4096;
+
+/// size of the header of the extended reservation status and of the
+/// extended data of each registered controller
+const RESERVATION_STATUS_SIZE: usize = 64;
+
+/// reservation of the namespace of a child
+#[derive(Debug, Default)]
+struct ReservationReport {
+    /// key of the holder of the reservation
+    holder: Option<u64>,
+    /// keys of all registered hosts
+    keys: Vec<u64>,
+}
+
+impl ReservationReport {
+    fn parse(data: &[u8]) -> Self {
+        let registered = u16::from_le_bytes(data[5 .. 7].try_into().unwrap());
+        let mut report = Self::default();
+
+        for ctrlr in data[RESERVATION_STATUS_SIZE ..]
+            .chunks_exact(RESERVATION_STATUS_SIZE)
+            .take(registered as usize)
+        {
+            let key = u64::from_le_bytes(ctrlr[8 .. 16].try_into().unwrap());
+            if ctrlr[2] & 1 != 0 {
+                report.holder = Some(key);
+            }
+            report.keys.push(key);
+        }
+
+        report
+    }
+
+    /// the newest generation registered with the reservation
+    fn newest(&self) -> u64 {
+        self.keys.iter().copied().max().unwrap_or(0)
+    }
+}
+
+/// how a child is kept from the writes of stale owners
+#[derive(Debug, PartialEq)]
+enum Fencing {
+    /// with a reservation on the namespace exported by its target
+    Reservation,
+    /// not exported to other nodes, only this nexus writes to it
+    Local,
+    /// exported without support for reservations, used without fencing
+    Unsupported,
+}
+
+/// whether the command failed as the reservation is held by another host
+fn is_conflict(error: &CoreError) -> bool {
+    matches!(
+        error,
+        CoreError::NvmeIoPassthruFailed {
+            sct, sc, ..
+        } if *sct == SPDK_NVME_SCT_GENERIC as i32
+            && *sc == SPDK_NVME_SC_RESERVATION_CONFLICT as i32
+    )
+}
+
+impl NexusChild {
+    /// how the child can be fenced off from other nexuses
+    fn fencing(&self) -> Fencing {
+        let bdev = match self.bdev.as_ref() {
+            Some(bdev) => bdev,
+            None => return Fencing::Unsupported,
+        };
+
+        if bdev.io_type_supported(SPDK_BDEV_IO_TYPE_NVME_IO) {
+            Fencing::Reservation
+        } else if self.name.starts_with("iscsi://") {
+            Fencing::Unsupported
+        } else if Replica::lookup(&bdev.name())
+            .map_or(false, |r| r.get_share_type().is_some())
+        {
+            // a nexus on another node may write to it through the target
+            Fencing::Unsupported
+        } else {
+            Fencing::Local
+        }
+    }
+
+    /// read the reservation of the namespace of the child
+    async fn reservation_report(&self) -> Result<ReservationReport, CoreError> {
+        let hdl = self.bdev_handle.as_ref().ok_or_else(|| {
+            CoreError::InvalidDescriptor {
+                name: self.name.clone(),
+            }
+        })?;
+        let mut buffer =
+            hdl.dma_malloc(RESERVATION_REPORT_SIZE).context(DmaAlloc {
+                size: RESERVATION_REPORT_SIZE,
+            })?;
+        hdl.nvme_resv_report(&mut buffer).await?;
+        Ok(ReservationReport::parse(buffer.as_slice()))
+    }
+
+    /// register the generation as our key and take over the reservation
+    /// from its current holder
+    async fn acquire_ownership(
+        &self,
+        generation: u64,
+        holder: Option<u64>,
+    ) -> Result<(), CoreError> {
+        let hdl = self.bdev_handle.as_ref().ok_or_else(|| {
+            CoreError::InvalidDescriptor {
+                name: self.name.clone(),
+            }
+        })?;
+        let ptpl = SPDK_NVME_RESERVE_PTPL_PERSIST_POWER_LOSS as u8;
+
+        // we may still be registered with the key of an earlier open
+        if let Err(e) = hdl
+            .nvme_resv_register(
+                0,
+                generation,
+                SPDK_NVME_RESERVE_REGISTER_KEY as u8,
+                false,
+                ptpl,
+            )
+            .await
+        {
+            if !is_conflict(&e) {
+                return Err(e);
+            }
+            hdl.nvme_resv_register(
+                0,
+                generation,
+                SPDK_NVME_RESERVE_REPLACE_KEY as u8,
+                true,
+                ptpl,
+            )
+            .await?;
+        }
+
+        let resv_type = SPDK_NVME_RESERVE_WRITE_EXCLUSIVE as u8;
+        match holder {
+            // the key of the holder changed along with ours
+            Some(key) if key == generation => Ok(()),
+            Some(key) => {
+                hdl.nvme_resv_acquire(
+                    generation,
+                    key,
+                    SPDK_NVME_RESERVE_PREEMPT as u8,
+                    resv_type,
+                )
+                .await
+            }
+            None => {
+                hdl.nvme_resv_acquire(
+                    generation,
+                    0,
+                    SPDK_NVME_RESERVE_ACQUIRE as u8,
+                    resv_type,
+                )
+                .await
+            }
+        }
+    }
+}
+
+impl Nexus {
+    /// Take ownership of the open children with a generation newer than any
+    /// owner before, called when the nexus is opened. Children that cannot
+    /// be fenced are faulted.
+    pub(crate) async fn fence_children(&mut self) {
+        let mut newest = self.owner_generation;
+        let mut failed = Vec::new();
+
+        for (idx, child) in self.children.iter().enumerate().filter(|(_, c)| {
+            c.state == ChildState::Open && c.fencing() == Fencing::Reservation
+        }) {
+            match child.reservation_report().await {
+                Ok(report) => newest = std::cmp::max(newest, report.newest()),
+                Err(e) => {
+                    error!(
+                        "{}: failed to read the reservation of child {}: {}",
+                        self.name, child.name, e
+                    );
+                    failed.push(idx);
+                }
+            }
+        }
+        for idx in failed {
+            self.children[idx].fence();
+        }
+        self.owner_generation = newest + 1;
+
+        for idx in 0 .. self.children.len() {
+            if let Err(e) = self.fence_child(idx).await {
+                error!("{}", e);
+                self.children[idx].fence();
+            }
+        }
+
+        info!(
+            "{}: owning the children with generation {}",
+            self.name, self.owner_generation
+        );
+    }
+
+    /// Take ownership of the child at the given index with the generation of
+    /// the nexus. It is an error when a newer owner has taken it already, or
+    /// its reservation cannot be taken, and the caller must fault the child.
+    pub(crate) async fn fence_child(&self, idx: usize) -> Result<(), Error> {
+        let child = &self.children[idx];
+        if child.state != ChildState::Open {
+            return Ok(());
+        }
+
+        match child.fencing() {
+            Fencing::Reservation => {}
+            Fencing::Local => return Ok(()),
+            Fencing::Unsupported => {
+                warn!(
+                    "{}: child {} cannot be fenced off from other nexuses",
+                    self.name, child.name
+                );
+                return Ok(());
+            }
+        }
+
+        let generation = self.owner_generation;
+        let report = child.reservation_report().await.context(FenceChild {
+            child: child.name.clone(),
+            name: self.name.clone(),
+        })?;
+        if report.newest() > generation {
+            return Err(Error::ChildFenced {
+                child: child.name.clone(),
+                generation: report.newest(),
+                name: self.name.clone(),
+            });
+        }
+
+        child
+            .acquire_ownership(generation, report.holder)
+            .await
+            .context(FenceChild {
+                child: child.name.clone(),
+                name: self.name.clone(),
+            })
+    }
+
+    /// Called when an IO the nexus issued to a child through a handle failed,
+    /// for the layouts that drive their child IOs from a future. A child
+    /// that a newer owner has fenced off is faulted as in the mirror. Any
+    /// other failure is recorded in the error store of the child and faults
+    /// it as well, as these layouts cannot tell which of its blocks are
+    /// stale once the IO is completed without it.
+    pub(crate) fn child_io_failed(
+        &self,
+        handle: &BdevHandle,
+        error: &CoreError,
+    ) {
+        let bdev = handle.get_bdev();
+        let (op, offset, len) = match *error {
+            CoreError::ReservationConflict {
+                ..
+            } => {
+                self.child_fenced(bdev.as_ptr());
+                return;
+            }
+            CoreError::ReadDispatch {
+                offset,
+                len,
+                ..
+            }
+            | CoreError::ReadFailed {
+                offset,
+                len,
+            } => (io_type::READ, offset, len),
+            CoreError::WriteDispatch {
+                offset,
+                len,
+                ..
+            }
+            | CoreError::WriteFailed {
+                offset,
+                len,
+            } => (io_type::WRITE, offset, len),
+            _ => return,
+        };
+
+        let block_len = u64::from(bdev.block_len());
+        self.error_record_add(
+            bdev.as_ptr(),
+            op,
+            io_status::FAILED,
+            offset / block_len,
+            len as u64 / block_len,
+        );
+        self.retire_child(bdev.as_ptr(), false);
+    }
+
+    /// Called when a write to a child was rejected as a newer owner holds
+    /// its reservation, the child is faulted on the management core.
+    pub(crate) fn child_fenced(&self, bdev: *const spdk_bdev) {
+        self.retire_child(bdev, true);
+    }
+
+    /// fault the open child of the bdev on the management core, or fence it
+    /// off unless it is already
+    fn retire_child(&self, bdev: *const spdk_bdev, fenced: bool) {
+        let nexus_name = self.name.clone();
+        let mgmt_reactor = Reactors::get_by_core(Cores::first()).unwrap();
+        mgmt_reactor.send_future(async move {
+            let nexus = match nexus_lookup(&nexus_name) {
+                Some(nexus) => nexus,
+                None => return,
+            };
+
+            let name = match nexus.children.iter().find(|c| {
+                c.bdev.as_ref().map(|b| b.as_ptr() as *const _) == Some(bdev)
+            }) {
+                Some(child) if fenced && !child.is_fenced() => {
+                    child.name.clone()
+                }
+                Some(child) if !fenced && child.state == ChildState::Open => {
+                    child.name.clone()
+                }
+                _ => return,
+            };
+
+            if fenced {
+                error!(
+                    "{}: child {} is owned by a newer nexus, faulting it",
+                    nexus.name, name
+                );
+            } else {
+                error!(
+                    "{}: IO to child {} failed, faulting it",
+                    nexus.name, name
+                );
+            }
+            nexus.cancel_child_rebuild_jobs(&name).await;
+            if let Some(child) =
+                nexus.children.iter_mut().find(|c| c.name == name)
+            {
+                if fenced {
+                    child.fence();
+                } else {
+                    child.fault();
+                }
+            }
+            nexus.reconfigure(DREvent::ChildFault).await;
+        });
+    }
+}
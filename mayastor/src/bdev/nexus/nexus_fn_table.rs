@@ -62,6 +62,13 @@ impl NexusFnTable {
         match io_type {
             // we always assume the device supports read/write commands
             io_type::READ | io_type::WRITE => true,
+            // an unmap of part of a stripe would invalidate its parity, and
+            // the cache would keep serving the unmapped data
+            io_type::UNMAP | io_type::WRITE_ZEROES
+                if nexus.parity.is_some() || nexus.cache.is_some() =>
+            {
+                false
+            }
             io_type::FLUSH
             | io_type::RESET
             | io_type::UNMAP
@@ -99,6 +106,12 @@ impl NexusFnTable {
             let nexus = nio.nexus_as_ref();
 
             match io_type {
+                io_type::READ | io_type::WRITE if nexus.parity.is_some() => {
+                    nexus.parity_submit(io, channel)
+                }
+                io_type::READ | io_type::WRITE if nexus.cache.is_some() => {
+                    nexus.cache_submit(io, channel)
+                }
                 io_type::READ => {
                     //trace!("{}: Dispatching READ {:p}", nexus.name(), io);
                     nexus.readv(io, &mut ch)
@@ -0,0 +1,169 @@
+//!
+//! Freezing the nexus holds back the writes to it so that its children can be
+//! snapshotted at the same point in time. The replicas may live on different
+//! nodes, so the snapshots are taken by the control plane while the nexus is
+//! frozen.
+//!
+//! The whole LBA range of the nexus is locked, which waits for the writes in
+//! flight to complete and queues new ones until the range is unlocked again.
+//! Reads are not affected. A write is only completed once it completed on all
+//! children, so after the lock has been taken every child holds the same
+//! data. With a cache, the dirty lines are written back to the children as
+//! well and the cache stays in write-through mode until the nexus is thawed.
+//!
+//! Writes are held back for at most the timeout given when freezing, after
+//! which the nexus thaws by itself, so that a control plane that disappears
+//! does not leave the volume hanging.
+
+use std::{fmt, os::raw::c_void, time::Duration};
+
+use snafu::ResultExt;
+
+use spdk_sys::{spdk_poller, spdk_poller_register, spdk_poller_unregister};
+
+use crate::{
+    bdev::nexus::nexus_bdev::{
+        Error,
+        FlushCache,
+        FreezeNexus,
+        Nexus,
+        NexusState,
+        ThawNexus,
+    },
+    core::{Bdev, Descriptor, IoChannel, RangeContext, Reactors},
+};
+
+/// time a nexus is frozen for when the caller does not limit it
+pub const DEFAULT_FREEZE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// lock on the LBA range of a frozen nexus
+pub(crate) struct Freeze {
+    /// the context must stay at the same address until the range has been
+    /// unlocked
+    ctx: Box<RangeContext>,
+    ch: IoChannel,
+    desc: Descriptor,
+    /// thaws the nexus once the timeout expires
+    poller: *mut spdk_poller,
+}
+
+impl fmt::Debug for Freeze {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Freeze of {} blocks from {}",
+            self.ctx.len, self.ctx.offset
+        )
+    }
+}
+
+impl Nexus {
+    /// Hold back writes to the nexus until it is thawed or the timeout
+    /// expires.
+    pub async fn freeze(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.state != NexusState::Open {
+            return Err(Error::NexusNotOpen {
+                name: self.name.clone(),
+            });
+        }
+        if self.freeze.is_some() {
+            return Err(Error::NexusFrozen {
+                name: self.name.clone(),
+            });
+        }
+
+        let desc = Bdev::open_by_name(&self.name, true).map_err(|_| {
+            Error::NexusNotFound {
+                name: self.name.clone(),
+            }
+        })?;
+        let ch = desc.get_channel().ok_or_else(|| Error::NexusNotOpen {
+            name: self.name.clone(),
+        })?;
+        let mut ctx = Box::new(RangeContext::new(0, self.bdev.num_blocks()));
+
+        desc.lock_lba_range(&mut ctx, &ch)
+            .await
+            .context(FreezeNexus {
+                name: self.name.clone(),
+            })?;
+
+        // the children must hold the data that is still in the cache
+        if let Err(e) = self.cache_suspend().await {
+            self.cache_resume();
+            if let Err(err) = desc.unlock_lba_range(&mut ctx, &ch).await {
+                error!("{}: failed to unlock: {}", self.name, err);
+            }
+            return Err(e).context(FlushCache {
+                name: self.name.clone(),
+            });
+        }
+
+        let poller = unsafe {
+            spdk_poller_register(
+                Some(Self::freeze_timeout),
+                self.as_ptr(),
+                timeout.as_micros() as u64,
+            )
+        };
+
+        self.freeze = Some(Freeze {
+            ctx,
+            ch,
+            desc,
+            poller,
+        });
+
+        info!("{}: frozen for at most {:?}", self.name, timeout);
+        Ok(())
+    }
+
+    /// Let the writes held back by freeze through. It is not an error to
+    /// thaw a nexus which is not frozen.
+    pub async fn thaw(&mut self) -> Result<(), Error> {
+        let mut freeze = match self.freeze.take() {
+            Some(freeze) => freeze,
+            None => return Ok(()),
+        };
+
+        if !freeze.poller.is_null() {
+            unsafe { spdk_poller_unregister(&mut freeze.poller) };
+        }
+
+        self.cache_resume();
+
+        let result = freeze
+            .desc
+            .unlock_lba_range(&mut freeze.ctx, &freeze.ch)
+            .await
+            .context(ThawNexus {
+                name: self.name.clone(),
+            });
+
+        info!("{}: thawed", self.name);
+        result
+    }
+
+    /// whether writes to the nexus are held back
+    pub fn is_frozen(&self) -> bool {
+        self.freeze.is_some()
+    }
+
+    /// called when the timeout of a frozen nexus expires
+    extern "C" fn freeze_timeout(ctx: *mut c_void) -> i32 {
+        let nexus = unsafe { Nexus::from_raw(ctx) };
+        if let Some(freeze) = nexus.freeze.as_mut() {
+            unsafe { spdk_poller_unregister(&mut freeze.poller) };
+        }
+
+        warn!("{}: freeze timed out", nexus.name);
+        Reactors::current().spawn_local(async move {
+            let nexus = unsafe { Nexus::from_raw(ctx) };
+            if let Err(e) = nexus.thaw().await {
+                error!("{}: failed to thaw: {}", nexus.name, e);
+            }
+        });
+
+        1
+    }
+}
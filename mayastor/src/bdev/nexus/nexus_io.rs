@@ -3,7 +3,14 @@ use std::fmt::{Debug, Formatter};
 
 use libc::c_void;
 
-use spdk_sys::{spdk_bdev_free_io, spdk_bdev_io, spdk_bdev_io_complete};
+use spdk_sys::{
+    spdk_bdev_free_io,
+    spdk_bdev_io,
+    spdk_bdev_io_complete,
+    spdk_bdev_io_get_nvme_status,
+    SPDK_NVME_SCT_GENERIC,
+    SPDK_NVME_SC_RESERVATION_CONFLICT,
+};
 
 use crate::{
     bdev::nexus::nexus_bdev::{Nexus, NEXUS_PRODUCT_ID},
@@ -38,7 +45,7 @@ pub struct NioCtx {
 /// pool in effect accessing the pointers from rust is to be considered a
 /// mutable borrow.
 ///
-/// 2.  The IO pointers are never accessed from any other thread
+/// 2. The IO pointers are never accessed from any other thread
 /// and care must be taken that you never pass an IO ptr to another core
 pub(crate) struct Bio(pub *mut spdk_bdev_io);
 
@@ -132,6 +139,11 @@ impl Bio {
                     io_offset,
                     io_num_blocks,
                 );
+
+                // a newer owner of the child has fenced us off
+                if Self::reservation_conflict(child_io) {
+                    self.nexus_as_ref().child_fenced((*child_io).bdev);
+                }
             }
         }
 
@@ -139,6 +151,24 @@ impl Bio {
             if self.ctx_as_mut_ref().status == io_status::FAILED {
                 self.fail();
             } else {
+                // the async children pick up the write from the journal, they
+                // do not take part in the completion of the IO
+                match Bio::io_type(self.0) {
+                    Some(io_type::WRITE)
+                    | Some(io_type::WRITE_ZEROES)
+                    | Some(io_type::UNMAP) => {
+                        let nexus = self.nexus_as_ref();
+                        nexus.replication_record(
+                            self.offset(),
+                            self.num_blocks(),
+                        );
+                        nexus.change_tracking_record(
+                            self.offset(),
+                            self.num_blocks(),
+                        );
+                    }
+                    _ => {}
+                }
                 self.ok();
             }
         }
@@ -202,6 +232,20 @@ impl Bio {
         Some(unsafe { (*io).type_ } as u32)
     }
 
+    /// whether the IO failed as the reservation of the device is held by
+    /// another host
+    #[inline]
+    pub(crate) fn reservation_conflict(io: *const spdk_bdev_io) -> bool {
+        let mut cdw0 = 0;
+        let mut sct = 0;
+        let mut sc = 0;
+        unsafe {
+            spdk_bdev_io_get_nvme_status(io, &mut cdw0, &mut sct, &mut sc)
+        };
+        sct == SPDK_NVME_SCT_GENERIC as i32
+            && sc == SPDK_NVME_SC_RESERVATION_CONFLICT as i32
+    }
+
     /// get the block length of this IO
     #[inline]
     pub(crate) fn block_len(&self) -> u64 {
@@ -221,6 +265,75 @@ impl Bio {
             slice[0].iov_base.is_null()
         }
     }
+
+    /// get the iovs of this IO as a slice
+    fn iov_slice(&self) -> &[spdk_sys::iovec] {
+        unsafe {
+            std::slice::from_raw_parts(self.iovs(), self.iov_count() as usize)
+        }
+    }
+
+    /// copy src into the buffers of the IO, starting at the given byte offset
+    /// relative to the start of the IO
+    pub(crate) fn copy_to_iovs(&self, offset: usize, src: &[u8]) {
+        let mut skip = offset;
+        let mut done = 0;
+
+        for iov in self.iov_slice() {
+            if done == src.len() {
+                break;
+            }
+
+            let iov_len = iov.iov_len as usize;
+            if skip >= iov_len {
+                skip -= iov_len;
+                continue;
+            }
+
+            let len = std::cmp::min(iov_len - skip, src.len() - done);
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    src[done ..].as_ptr(),
+                    (iov.iov_base as *mut u8).add(skip),
+                    len,
+                );
+            }
+
+            done += len;
+            skip = 0;
+        }
+    }
+
+    /// copy the buffers of the IO, starting at the given byte offset relative
+    /// to the start of the IO, into dst
+    pub(crate) fn copy_from_iovs(&self, offset: usize, dst: &mut [u8]) {
+        let mut skip = offset;
+        let mut done = 0;
+
+        for iov in self.iov_slice() {
+            if done == dst.len() {
+                break;
+            }
+
+            let iov_len = iov.iov_len as usize;
+            if skip >= iov_len {
+                skip -= iov_len;
+                continue;
+            }
+
+            let len = std::cmp::min(iov_len - skip, dst.len() - done);
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    (iov.iov_base as *const u8).add(skip),
+                    dst[done ..].as_mut_ptr(),
+                    len,
+                );
+            }
+
+            done += len;
+            skip = 0;
+        }
+    }
 }
 
 impl Debug for Bio {
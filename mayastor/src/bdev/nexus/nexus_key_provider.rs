@@ -0,0 +1,419 @@
+//!
+//! Providers of the keys nexus are encrypted with. Keys are referenced by
+//! their ID when a nexus is published, so they never travel over gRPC. The
+//! provider is selected by the scheme of its URI:
+//!
+//! - `file:///path` for a directory holding one file per key, named after the
+//!   ID of the key, e.g. a mounted kubernetes secret
+//! - `vault://mount/prefix?endpoint=http://host:8200` for the KV version 2
+//!   secrets engine of a Vault compatible key manager, where the key is the
+//!   `key` field of the secret `prefix/<ID>`, read with the token in the
+//!   `VAULT_TOKEN` environment variable. The endpoint defaults to the
+//!   `VAULT_ADDR` environment variable.
+//!
+//! The key is stored hex or base64 encoded and is used for AES-XTS-256, the
+//! only variant of AES-XTS the crypto vbdev supports, so it must decode to
+//! 32 bytes. The first half of it is the data key and the second half the
+//! tweak key.
+//!
+//! Files are read on a thread of their own rather than on the reactor.
+//!
+//! The Vault provider uses the tokio runtime, so its requests must be made
+//! from the master reactor.
+
+use std::{
+    fmt::{self, Debug},
+    fs,
+    path::PathBuf,
+    ptr,
+    str,
+};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use hyper::{body, client::HttpConnector, Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::Value;
+use sha2::Sha256;
+use snafu::{ResultExt, Snafu};
+use url::Url;
+
+use crate::core::Mthread;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+#[allow(missing_docs)]
+/// Errors of the key providers
+pub enum KeyProviderError {
+    #[snafu(display("Invalid key provider URI {}", uri))]
+    InvalidProviderUri { uri: String },
+    #[snafu(display("Unsupported key provider scheme {}", scheme))]
+    UnsupportedScheme { scheme: String },
+    #[snafu(display("Missing credentials for key provider {}", uri))]
+    MissingCredentials { uri: String },
+    #[snafu(display("Invalid key ID \"{}\"", id))]
+    InvalidKeyId { id: String },
+    #[snafu(display(
+        "Key {} has {} bytes, AES-XTS-256 needs 32 bytes",
+        id,
+        len
+    ))]
+    InvalidKeyMaterial { id: String, len: usize },
+    #[snafu(display("Key {} is neither hex nor base64 encoded", id))]
+    InvalidKeyEncoding { id: String },
+    #[snafu(display("Failed to read key {}", id))]
+    ReadKeyFile { source: std::io::Error, id: String },
+    #[snafu(display("Failed to build request for key {}", id))]
+    InvalidRequest {
+        source: hyper::http::Error,
+        id: String,
+    },
+    #[snafu(display("Request for key {} failed", id))]
+    HttpRequest { source: hyper::Error, id: String },
+    #[snafu(display("Request for key {} failed with status {}", id, status))]
+    HttpStatus { status: u16, id: String },
+    #[snafu(display("Invalid response for key {}", id))]
+    InvalidResponse {
+        source: serde_json::Error,
+        id: String,
+    },
+    #[snafu(display("Secret of key {} has no key field", id))]
+    MissingKeyField { id: String },
+}
+
+/// Ciphers of the crypto vbdev encrypting a nexus, which follow from the
+/// length of the key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cipher {
+    AesXts256,
+}
+
+impl Cipher {
+    /// name of the cipher as understood by the crypto vbdev
+    pub fn spdk_name(self) -> &'static str {
+        "AES_XTS"
+    }
+
+    /// length of the key in bytes, the data and the tweak key together
+    pub fn key_len(self) -> usize {
+        match self {
+            Cipher::AesXts256 => 32,
+        }
+    }
+
+    fn from_key_len(len: usize) -> Option<Self> {
+        match len {
+            32 => Some(Cipher::AesXts256),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cipher::AesXts256 => write!(f, "AES-XTS-256"),
+        }
+    }
+}
+
+/// A key as handed out by a provider. The key material is wiped when the key
+/// is dropped and never printed.
+pub struct EncryptionKey {
+    id: String,
+    cipher: Cipher,
+    material: Vec<u8>,
+}
+
+impl EncryptionKey {
+    /// Create a key from its decoded material, which determines the cipher.
+    pub fn new(id: &str, material: Vec<u8>) -> Result<Self, KeyProviderError> {
+        let cipher = match Cipher::from_key_len(material.len()) {
+            Some(cipher) => cipher,
+            None => {
+                return Err(KeyProviderError::InvalidKeyMaterial {
+                    id: id.to_string(),
+                    len: material.len(),
+                })
+            }
+        };
+
+        Ok(Self {
+            id: id.to_string(),
+            cipher,
+            material,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    /// the data key and the tweak key
+    pub(crate) fn xts_keys(&self) -> (&[u8], &[u8]) {
+        self.material.split_at(self.material.len() / 2)
+    }
+
+    /// Value stored along with the ID of the key, to tell whether the
+    /// material behind the ID is still the same without storing it. It is
+    /// bound to the nexus so that it cannot be compared across nexus.
+    pub(crate) fn check_value(&self, nexus_uuid: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.material)
+            .expect("HMAC accepts keys of any size");
+        mac.input(nexus_uuid.as_bytes());
+        hex::encode(mac.result().code())
+    }
+
+    /// Check value of the plaintext of a block of the nexus, which tells
+    /// whether a block decrypted with this key is that plaintext without
+    /// storing it.
+    pub(crate) fn block_check(
+        &self,
+        nexus_uuid: &str,
+        blk: u64,
+        data: &[u8],
+    ) -> u64 {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.material)
+            .expect("HMAC accepts keys of any size");
+        mac.input(nexus_uuid.as_bytes());
+        mac.input(&blk.to_le_bytes());
+        mac.input(data);
+
+        let mut check = [0u8; 8];
+        check.copy_from_slice(&mac.result().code()[.. 8]);
+        u64::from_le_bytes(check)
+    }
+}
+
+/// Decode the material of a key as stored by a provider, hex or base64
+/// encoded. Surrounding whitespace, such as the newline editors put at the
+/// end of a file, is ignored.
+fn decode_key(
+    id: &str,
+    encoded: &[u8],
+) -> Result<EncryptionKey, KeyProviderError> {
+    let invalid = || KeyProviderError::InvalidKeyEncoding {
+        id: id.to_string(),
+    };
+    let encoded = str::from_utf8(encoded).map_err(|_| invalid())?.trim();
+
+    let material = hex::decode(encoded)
+        .or_else(|_| base64::decode(encoded))
+        .map_err(|_| invalid())?;
+    EncryptionKey::new(id, material)
+}
+
+impl Drop for EncryptionKey {
+    fn drop(&mut self) {
+        for b in self.material.iter_mut() {
+            unsafe { ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the key material
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .field("cipher", &self.cipher)
+            .finish()
+    }
+}
+
+/// A source of keys, addressed by their ID
+#[async_trait(?Send)]
+pub trait KeyProvider: Debug {
+    /// Returns the key with the ID
+    async fn get_key(
+        &self,
+        id: &str,
+    ) -> Result<EncryptionKey, KeyProviderError>;
+}
+
+/// Returns the key provider for the URI
+pub fn key_provider_from_uri(
+    uri: &str,
+) -> Result<Box<dyn KeyProvider>, KeyProviderError> {
+    let url =
+        Url::parse(uri).map_err(|_| KeyProviderError::InvalidProviderUri {
+            uri: uri.to_string(),
+        })?;
+
+    match url.scheme() {
+        "file" => Ok(Box::new(FileKeyProvider {
+            dir: PathBuf::from(url.path()),
+        })),
+        "vault" => Ok(Box::new(VaultKeyProvider::new(uri, &url)?)),
+        scheme => Err(KeyProviderError::UnsupportedScheme {
+            scheme: scheme.to_string(),
+        }),
+    }
+}
+
+/// IDs are used as file names and in URL paths, so they are restricted to
+/// characters that are safe in both
+fn validate_key_id(id: &str) -> Result<(), KeyProviderError> {
+    let valid = !id.is_empty()
+        && !id.starts_with('.')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+
+    if valid {
+        Ok(())
+    } else {
+        Err(KeyProviderError::InvalidKeyId {
+            id: id.to_string(),
+        })
+    }
+}
+
+/// Keys stored as files in a local directory
+#[derive(Debug)]
+struct FileKeyProvider {
+    dir: PathBuf,
+}
+
+#[async_trait(?Send)]
+impl KeyProvider for FileKeyProvider {
+    async fn get_key(
+        &self,
+        id: &str,
+    ) -> Result<EncryptionKey, KeyProviderError> {
+        validate_key_id(id)?;
+        let path = self.dir.join(id);
+        let encoded = Mthread::spawn_blocking(move || fs::read(path))
+            .await
+            .context(ReadKeyFile {
+                id,
+            })?;
+        decode_key(id, &encoded)
+    }
+}
+
+/// Keys stored as secrets of the KV version 2 secrets engine of a Vault
+/// compatible key manager
+struct VaultKeyProvider {
+    endpoint: Url,
+    mount: String,
+    prefix: String,
+    token: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Debug for VaultKeyProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the token
+        f.debug_struct("VaultKeyProvider")
+            .field("endpoint", &self.endpoint.as_str())
+            .field("mount", &self.mount)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl VaultKeyProvider {
+    fn new(uri: &str, url: &Url) -> Result<Self, KeyProviderError> {
+        let invalid = || KeyProviderError::InvalidProviderUri {
+            uri: uri.to_string(),
+        };
+
+        let mount = url.host_str().ok_or_else(invalid)?.to_string();
+        let prefix = url.path().trim_matches('/').to_string();
+
+        let mut endpoint = None;
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "endpoint" => {
+                    endpoint = Some(Url::parse(&v).map_err(|_| invalid())?)
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        let endpoint = endpoint
+            .or_else(|| {
+                std::env::var("VAULT_ADDR")
+                    .ok()
+                    .and_then(|addr| Url::parse(&addr).ok())
+            })
+            .ok_or_else(invalid)?;
+
+        let token = std::env::var("VAULT_TOKEN").map_err(|_| {
+            KeyProviderError::MissingCredentials {
+                uri: uri.to_string(),
+            }
+        })?;
+
+        Ok(Self {
+            endpoint,
+            mount,
+            prefix,
+            token,
+            client: Client::builder().build(HttpsConnector::new()),
+        })
+    }
+
+    /// path of the secret holding the key relative to the endpoint
+    fn secret_path(&self, id: &str) -> String {
+        if self.prefix.is_empty() {
+            format!("/v1/{}/data/{}", self.mount, id)
+        } else {
+            format!("/v1/{}/data/{}/{}", self.mount, self.prefix, id)
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl KeyProvider for VaultKeyProvider {
+    async fn get_key(
+        &self,
+        id: &str,
+    ) -> Result<EncryptionKey, KeyProviderError> {
+        validate_key_id(id)?;
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&self.secret_path(id));
+
+        let request = Request::get(url.as_str())
+            .header("x-vault-token", self.token.as_str())
+            .body(Body::empty())
+            .context(InvalidRequest {
+                id,
+            })?;
+
+        let response =
+            self.client.request(request).await.context(HttpRequest {
+                id,
+            })?;
+        let status = response.status();
+        let body = body::to_bytes(response.into_body()).await.context(
+            HttpRequest {
+                id,
+            },
+        )?;
+
+        if !status.is_success() {
+            return Err(KeyProviderError::HttpStatus {
+                status: status.as_u16(),
+                id: id.to_string(),
+            });
+        }
+
+        let secret: Value =
+            serde_json::from_slice(&body).context(InvalidResponse {
+                id,
+            })?;
+        match secret["data"]["data"]["key"].as_str() {
+            Some(key) => decode_key(id, key.as_bytes()),
+            None => Err(KeyProviderError::MissingKeyField {
+                id: id.to_string(),
+            }),
+        }
+    }
+}
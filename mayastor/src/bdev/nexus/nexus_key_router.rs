@@ -0,0 +1,733 @@
+//!
+//! Routers beneath the share handle of an encrypted nexus. A crypto vbdev
+//! claims its base, so a nexus cannot be encrypted with two keys at the same
+//! time by putting two crypto vbdevs directly on top of it, as is needed
+//! while its key is rotated. Instead, each crypto vbdev is put on top of a
+//! view of the nexus, which is a router with the nexus as its only base, and
+//! the share handle is a router over the crypto vbdevs:
+//!
+//! ```text
+//!   crypto-<nexus>          router shared over the targets
+//!     <nexus>-crypto-<n>    crypto vbdev with the current key
+//!       <nexus>-view-<n>    router over the nexus
+//!     <nexus>-crypto-<m>    crypto vbdev with the new key, while rotating
+//!       <nexus>-view-<m>    router over the nexus
+//! ```
+//!
+//! With a second base, IO below the watermark is routed to it and all other
+//! IO to the first base. IO is split at segment boundaries and the watermark
+//! only moves by whole segments, so a single IO never needs both bases.
+//!
+//! The segment at the watermark is copied from the first base to the second
+//! under the LBA range lock of the router, which holds back writes only.
+//! Reads of that segment are held back by fencing it, as they would return
+//! garbage once the copy has overwritten part of it.
+
+use std::{
+    convert::TryFrom,
+    ffi::CString,
+    os::raw::c_void,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+        RwLock,
+    },
+};
+
+use futures::channel::oneshot;
+use nix::errno::Errno;
+use once_cell::sync::Lazy;
+
+use spdk_sys::{
+    spdk_bdev,
+    spdk_bdev_flush_blocks,
+    spdk_bdev_fn_table,
+    spdk_bdev_io,
+    spdk_bdev_io_complete,
+    spdk_bdev_io_get_buf,
+    spdk_bdev_io_type,
+    spdk_bdev_readv_blocks,
+    spdk_bdev_register,
+    spdk_bdev_reset,
+    spdk_bdev_unregister,
+    spdk_bdev_writev_blocks,
+    spdk_for_each_channel,
+    spdk_for_each_channel_continue,
+    spdk_get_io_channel,
+    spdk_io_channel,
+    spdk_io_channel_iter,
+    spdk_io_channel_iter_get_channel,
+    spdk_io_channel_iter_get_io_device,
+    spdk_io_device_register,
+    spdk_io_device_unregister,
+};
+
+use crate::{
+    bdev::{
+        nexus,
+        nexus::{
+            nexus_io::{io_status, io_type, Bio, NioCtx},
+            nexus_parity::Yield,
+        },
+    },
+    core::{Bdev, BdevHandle, CoreError, Descriptor, Reactors},
+    ffihelper::{cb_arg, done_errno_cb, errno_result_from_i32, ErrnoResult},
+};
+
+pub(crate) static KEY_ROUTER_PRODUCT_ID: &str = "Nexus Key Router";
+
+/// first block of the fenced segment when no segment is fenced
+const NOT_FENCED: u64 = u64::MAX;
+
+static KEY_ROUTER_FN_TBL: Lazy<KeyRouterFnTable> =
+    Lazy::new(KeyRouterFnTable::new);
+
+struct KeyRouterFnTable {
+    f_tbl: spdk_bdev_fn_table,
+}
+
+unsafe impl Sync for KeyRouterFnTable {}
+unsafe impl Send for KeyRouterFnTable {}
+
+impl KeyRouterFnTable {
+    fn new() -> Self {
+        let mut f_tbl = spdk_bdev_fn_table::default();
+        f_tbl.io_type_supported = Some(KeyRouter::io_supported);
+        f_tbl.submit_request = Some(KeyRouter::io_submit);
+        f_tbl.get_io_channel = Some(KeyRouter::io_channel);
+        f_tbl.destruct = Some(KeyRouter::destruct);
+        KeyRouterFnTable {
+            f_tbl,
+        }
+    }
+}
+
+/// Context of an IO to the router, which lives in the driver context the
+/// nexus module reserves for every IO.
+#[repr(C)]
+struct RouterIoCtx {
+    /// IOs to the bases that have not completed yet
+    in_flight: u8,
+    /// set when an IO to a base failed
+    failed: bool,
+    /// epoch a read was submitted in
+    epoch: u8,
+}
+
+/// io channel of a router, per core
+#[repr(C)]
+struct RouterChannel {
+    /// handles of the bases in the order of the bases of the router
+    handles: *mut Vec<BdevHandle>,
+}
+
+impl RouterChannel {
+    /// get our ctx from the channel
+    fn from_channel<'a>(ch: *mut spdk_io_channel) -> &'a mut Self {
+        unsafe {
+            use std::mem::size_of;
+            &mut *((ch as *mut u8).add(size_of::<spdk_io_channel>())
+                as *mut RouterChannel)
+        }
+    }
+
+    fn handles(&self) -> &Vec<BdevHandle> {
+        unsafe { &*self.handles }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct KeyRouter {
+    name: String,
+    bdev: Bdev,
+    /// raw pointer to bdev (to destruct it later using Box::from_raw())
+    bdev_raw: *mut spdk_bdev,
+    /// blocks per segment, IO is not split when zero
+    segment_blks: u64,
+    bases: RwLock<Vec<Arc<Descriptor>>>,
+    /// blocks below are routed to the second base, if there is one
+    watermark: AtomicU64,
+    /// first block of the segment reads of which are held back
+    fenced: AtomicU64,
+    /// incremented when a segment is fenced, reads in flight are counted
+    /// per epoch so that the fence only waits for those submitted before it
+    epoch: AtomicUsize,
+    reads: [AtomicUsize; 2],
+    /// completion of a reconfiguration of the channels or of the unregister
+    /// of the io device
+    notify: Mutex<Option<oneshot::Sender<i32>>>,
+}
+
+unsafe impl Sync for KeyRouter {}
+unsafe impl Send for KeyRouter {}
+
+impl Drop for KeyRouter {
+    fn drop(&mut self) {
+        unsafe {
+            let b: Box<spdk_bdev> = Box::from_raw(self.bdev_raw);
+            let _ = CString::from_raw(b.name);
+            let _ = CString::from_raw(b.product_name);
+        }
+    }
+}
+
+impl KeyRouter {
+    /// Create and register a router with a single base, which it matches in
+    /// size. IO is split at segment boundaries unless `segment_blks` is
+    /// zero.
+    pub(crate) fn create(
+        name: &str,
+        base: &str,
+        segment_blks: u64,
+    ) -> ErrnoResult<Box<Self>> {
+        let base = Bdev::lookup_by_name(base).ok_or(Errno::ENODEV)?;
+        let desc = match base.open(true) {
+            Ok(desc) => desc,
+            Err(CoreError::OpenBdev {
+                source,
+            }) => return Err(source),
+            Err(_) => return Err(Errno::ENODEV),
+        };
+
+        let mut b = Box::new(spdk_bdev::default());
+        b.name = c_str!(name);
+        b.product_name = c_str!(KEY_ROUTER_PRODUCT_ID);
+        b.fn_table = &KEY_ROUTER_FN_TBL.f_tbl;
+        b.module = nexus::module().unwrap();
+        b.blocklen = base.block_len();
+        b.blockcnt = base.num_blocks();
+        b.required_alignment = base.alignment();
+        if segment_blks != 0 {
+            b.optimal_io_boundary = segment_blks as u32;
+            b.split_on_optimal_io_boundary = true;
+        }
+
+        let router = Box::new(KeyRouter {
+            name: name.to_string(),
+            bdev: Bdev::from(&*b as *const _ as *mut spdk_bdev),
+            bdev_raw: Box::into_raw(b),
+            segment_blks,
+            bases: RwLock::new(vec![Arc::new(desc)]),
+            watermark: AtomicU64::new(0),
+            fenced: AtomicU64::new(NOT_FENCED),
+            epoch: AtomicUsize::new(0),
+            reads: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            notify: Mutex::new(None),
+        });
+
+        unsafe {
+            (*router.bdev.as_ptr()).ctxt = router.as_ptr();
+            spdk_io_device_register(
+                router.as_ptr(),
+                Some(Self::channel_create),
+                Some(Self::channel_destroy),
+                std::mem::size_of::<RouterChannel>() as u32,
+                (*router.bdev.as_ptr()).name,
+            );
+        }
+
+        let errno = unsafe { spdk_bdev_register(router.bdev.as_ptr()) };
+        if let Err(e) = errno_result_from_i32((), errno) {
+            // no channel has been handed out yet
+            unsafe { spdk_io_device_unregister(router.as_ptr(), None) };
+            return Err(e);
+        }
+
+        debug!("{}: key router registered over {}", name, base.name());
+        Ok(router)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn num_blocks(&self) -> u64 {
+        self.bdev.num_blocks()
+    }
+
+    pub(crate) fn block_len(&self) -> u64 {
+        u64::from(self.bdev.block_len())
+    }
+
+    /// blocks below are routed to the second base
+    pub(crate) fn watermark(&self) -> u64 {
+        self.watermark.load(Ordering::SeqCst)
+    }
+
+    /// Move the watermark, this must only be done while the segments it
+    /// moves over are locked and fenced.
+    pub(crate) fn set_watermark(&self, blk: u64) {
+        self.watermark.store(blk, Ordering::SeqCst);
+    }
+
+    /// Add a second base, with the given blocks already routed to it.
+    pub(crate) async fn add_base(
+        &self,
+        base: &str,
+        watermark: u64,
+    ) -> ErrnoResult<()> {
+        let base = Bdev::lookup_by_name(base).ok_or(Errno::ENODEV)?;
+        if base.num_blocks() != self.num_blocks()
+            || base.block_len() != self.bdev.block_len()
+        {
+            return Err(Errno::EINVAL);
+        }
+        let desc = match base.open(true) {
+            Ok(desc) => desc,
+            Err(CoreError::OpenBdev {
+                source,
+            }) => return Err(source),
+            Err(_) => return Err(Errno::ENODEV),
+        };
+
+        {
+            let mut bases = self.bases.write().unwrap();
+            assert_eq!(bases.len(), 1, "router has a second base already");
+            bases.push(Arc::new(desc));
+        }
+        self.set_watermark(watermark);
+        self.reconfigure().await
+    }
+
+    /// Remove the second base, if any, which is done when the rotation is
+    /// abandoned.
+    pub(crate) async fn remove_second_base(&self) -> ErrnoResult<()> {
+        // route everything to the first base before the channels drop the
+        // handle of the second
+        self.set_watermark(0);
+        self.bases.write().unwrap().truncate(1);
+        self.reconfigure().await
+    }
+
+    /// Remove the first base once all blocks are routed to the second one,
+    /// which then becomes the only base.
+    pub(crate) async fn remove_first_base(&self) -> ErrnoResult<()> {
+        assert_eq!(self.watermark(), self.num_blocks());
+        {
+            let mut bases = self.bases.write().unwrap();
+            assert_eq!(bases.len(), 2, "router has no second base");
+            bases.remove(0);
+        }
+        self.reconfigure().await?;
+        self.set_watermark(0);
+        Ok(())
+    }
+
+    /// Hold back reads of the segment starting at the block, and wait for
+    /// the reads that were submitted before.
+    pub(crate) async fn fence(&self, blk: u64) {
+        self.fenced.store(blk, Ordering::SeqCst);
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst) & 1;
+        while self.reads[epoch].load(Ordering::SeqCst) != 0 {
+            Yield::default().await;
+        }
+    }
+
+    /// let reads of the fenced segment through again
+    pub(crate) fn unfence(&self) {
+        self.fenced.store(NOT_FENCED, Ordering::SeqCst);
+    }
+
+    /// open a descriptor to the router itself, to lock ranges with
+    pub(crate) fn open(&self) -> ErrnoResult<Descriptor> {
+        match self.bdev.open(true) {
+            Ok(desc) => Ok(desc),
+            Err(CoreError::OpenBdev {
+                source,
+            }) => Err(source),
+            Err(_) => Err(Errno::ENODEV),
+        }
+    }
+
+    /// Unregister the router and release its bases. Nothing must be on top
+    /// of it anymore.
+    pub(crate) async fn destroy(self: Box<Self>) -> ErrnoResult<()> {
+        let (s, r) = oneshot::channel::<ErrnoResult<()>>();
+        unsafe {
+            // This will trigger a callback to destruct() in the fn_table.
+            spdk_bdev_unregister(
+                self.bdev.as_ptr(),
+                Some(done_errno_cb),
+                cb_arg(s),
+            );
+        }
+        r.await.expect("router unregister sender is gone")?;
+
+        // the router must outlive its channels
+        let (s, r) = oneshot::channel::<i32>();
+        *self.notify.lock().unwrap() = Some(s);
+        unsafe {
+            spdk_io_device_unregister(
+                self.as_ptr(),
+                Some(Self::unregister_completed),
+            );
+        }
+        r.await.expect("router io device sender is gone");
+
+        debug!("{}: key router destroyed", self.name);
+        Ok(())
+    }
+
+    fn as_ptr(&self) -> *mut c_void {
+        self as *const _ as *mut _
+    }
+
+    unsafe fn from_raw<'a>(ctx: *mut c_void) -> &'a Self {
+        &*(ctx as *const KeyRouter)
+    }
+
+    fn from_io<'a>(io: *mut spdk_bdev_io) -> &'a Self {
+        unsafe { Self::from_raw((*(*io).bdev).ctxt) }
+    }
+
+    fn io_ctx<'a>(io: *mut spdk_bdev_io) -> &'a mut RouterIoCtx {
+        debug_assert!(
+            std::mem::size_of::<RouterIoCtx>() <= std::mem::size_of::<NioCtx>()
+        );
+        unsafe {
+            &mut *((*io).driver_ctx.as_mut_ptr() as *const c_void
+                as *mut RouterIoCtx)
+        }
+    }
+
+    /// index of the base the IO at the block is routed to
+    fn route(&self, bases: usize, blk: u64) -> usize {
+        if bases > 1 && blk < self.watermark() {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// let all channels pick up the current bases
+    async fn reconfigure(&self) -> ErrnoResult<()> {
+        let (s, r) = oneshot::channel::<i32>();
+        *self.notify.lock().unwrap() = Some(s);
+        unsafe {
+            spdk_for_each_channel(
+                self.as_ptr(),
+                Some(Self::refresh_channel),
+                std::ptr::null_mut(),
+                Some(Self::reconfigure_completed),
+            );
+        }
+        let errno = r.await.expect("router reconfigure sender is gone");
+        errno_result_from_i32((), errno)
+    }
+
+    fn handles(&self) -> Result<Vec<BdevHandle>, CoreError> {
+        self.bases
+            .read()
+            .unwrap()
+            .iter()
+            .map(|d| BdevHandle::try_from(Arc::clone(d)))
+            .collect()
+    }
+
+    extern "C" fn channel_create(device: *mut c_void, ctx: *mut c_void) -> i32 {
+        let router = unsafe { Self::from_raw(device) };
+        match router.handles() {
+            Ok(handles) => {
+                let ch = unsafe { &mut *(ctx as *mut RouterChannel) };
+                ch.handles = Box::into_raw(Box::new(handles));
+                0
+            }
+            Err(e) => {
+                error!("{}: failed to create IO channel: {}", router.name, e);
+                -(Errno::ENOMEM as i32)
+            }
+        }
+    }
+
+    /// the router may be gone already, so it is not looked at
+    extern "C" fn channel_destroy(_device: *mut c_void, ctx: *mut c_void) {
+        let ch = unsafe { &mut *(ctx as *mut RouterChannel) };
+        drop(unsafe { Box::from_raw(ch.handles) });
+        ch.handles = std::ptr::null_mut();
+    }
+
+    extern "C" fn refresh_channel(ch_iter: *mut spdk_io_channel_iter) {
+        let router = unsafe {
+            Self::from_raw(spdk_io_channel_iter_get_io_device(ch_iter))
+        };
+        let channel = unsafe { spdk_io_channel_iter_get_channel(ch_iter) };
+
+        let status = match router.handles() {
+            Ok(handles) => {
+                let ch = RouterChannel::from_channel(channel);
+                drop(unsafe { Box::from_raw(ch.handles) });
+                ch.handles = Box::into_raw(Box::new(handles));
+                0
+            }
+            Err(e) => {
+                error!("{}: failed to refresh IO channel: {}", router.name, e);
+                -(Errno::ENOMEM as i32)
+            }
+        };
+        unsafe { spdk_for_each_channel_continue(ch_iter, status) };
+    }
+
+    extern "C" fn reconfigure_completed(
+        ch_iter: *mut spdk_io_channel_iter,
+        status: i32,
+    ) {
+        let router = unsafe {
+            Self::from_raw(spdk_io_channel_iter_get_io_device(ch_iter))
+        };
+        if let Some(s) = router.notify.lock().unwrap().take() {
+            let _ = s.send(status);
+        }
+    }
+
+    extern "C" fn unregister_completed(device: *mut c_void) {
+        let router = unsafe { Self::from_raw(device) };
+        if let Some(s) = router.notify.lock().unwrap().take() {
+            let _ = s.send(0);
+        }
+    }
+
+    /// reads and writes are routed, flushes and resets go to all bases
+    extern "C" fn io_supported(
+        ctx: *mut c_void,
+        io_type: spdk_bdev_io_type,
+    ) -> bool {
+        let router = unsafe { Self::from_raw(ctx) };
+        match io_type {
+            io_type::READ | io_type::WRITE => true,
+            // The crypto vbdev passes unmaps on to its base, after which the
+            // blocks decrypt to garbage rather than reading back as zeroes.
+            io_type::FLUSH | io_type::RESET => router
+                .bases
+                .read()
+                .unwrap()
+                .iter()
+                .all(|d| d.get_bdev().io_type_supported(io_type)),
+            _ => false,
+        }
+    }
+
+    extern "C" fn io_channel(ctx: *mut c_void) -> *mut spdk_io_channel {
+        unsafe { spdk_get_io_channel(ctx) }
+    }
+
+    /// the router is released by destroy() once its io device is gone
+    extern "C" fn destruct(ctx: *mut c_void) -> i32 {
+        let router = unsafe { Self::from_raw(ctx) };
+        trace!("{}: destruct", router.name);
+        0
+    }
+
+    extern "C" fn io_submit(
+        channel: *mut spdk_io_channel,
+        io: *mut spdk_bdev_io,
+    ) {
+        let router = Self::from_io(io);
+        let ctx = Self::io_ctx(io);
+        ctx.in_flight = 0;
+        ctx.failed = false;
+
+        match Bio::io_type(io) {
+            Some(io_type::READ) => {
+                let bio = Bio(io);
+                if bio.need_buf() {
+                    unsafe {
+                        spdk_bdev_io_get_buf(
+                            io,
+                            Some(Self::get_buf_cb),
+                            bio.num_blocks() * bio.block_len(),
+                        )
+                    }
+                    return;
+                }
+                router.read(channel, io);
+            }
+            Some(io_type::WRITE) => router.write(channel, io),
+            Some(io_type::FLUSH) | Some(io_type::RESET) => {
+                router.submit_all(channel, io)
+            }
+            t => {
+                error!("{}: unsupported IO type {:?}", router.name, t);
+                Self::complete(io, false);
+            }
+        }
+    }
+
+    extern "C" fn get_buf_cb(
+        channel: *mut spdk_io_channel,
+        io: *mut spdk_bdev_io,
+        success: bool,
+    ) {
+        let router = Self::from_io(io);
+        if !success {
+            warn!(
+                "{}: failed to get io buffer for io {:?}",
+                router.name,
+                Bio(io)
+            );
+            Self::complete(io, false);
+            return;
+        }
+        router.read(channel, io);
+    }
+
+    /// submit the read, or retry until the segment it falls in is no
+    /// longer fenced
+    fn read(&self, channel: *mut spdk_io_channel, io: *mut spdk_bdev_io) {
+        if self.try_read(channel, io) {
+            return;
+        }
+
+        // the router outlives the IOs submitted to it
+        let router = self.as_ptr();
+        Reactors::current().spawn_local(async move {
+            let router = unsafe { Self::from_raw(router) };
+            loop {
+                Yield::default().await;
+                if router.try_read(channel, io) {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn try_read(
+        &self,
+        channel: *mut spdk_io_channel,
+        io: *mut spdk_bdev_io,
+    ) -> bool {
+        let bio = Bio(io);
+        let epoch = self.epoch.load(Ordering::SeqCst) & 1;
+
+        // count ourselves before looking at the fence, so that either the
+        // fence waits for us or we observe the fence
+        self.reads[epoch].fetch_add(1, Ordering::SeqCst);
+        let fenced = self.fenced.load(Ordering::SeqCst);
+        if fenced != NOT_FENCED
+            && bio.offset() >= fenced
+            && bio.offset() < fenced + self.segment_blks
+        {
+            self.reads[epoch].fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+
+        let ctx = Self::io_ctx(io);
+        ctx.epoch = epoch as u8;
+        ctx.in_flight = 1;
+
+        let handles = RouterChannel::from_channel(channel).handles();
+        let (desc, ch) =
+            handles[self.route(handles.len(), bio.offset())].io_tuple();
+        let rc = unsafe {
+            spdk_bdev_readv_blocks(
+                desc,
+                ch,
+                bio.iovs(),
+                bio.iov_count(),
+                bio.offset(),
+                bio.num_blocks(),
+                Some(Self::io_completion),
+                io as *mut _,
+            )
+        };
+        if rc != 0 {
+            error!("{}: failed to submit IO {:?}", self.name, bio);
+            Self::child_done(io, false);
+        }
+        true
+    }
+
+    fn write(&self, channel: *mut spdk_io_channel, io: *mut spdk_bdev_io) {
+        let bio = Bio(io);
+        Self::io_ctx(io).in_flight = 1;
+
+        let handles = RouterChannel::from_channel(channel).handles();
+        let (desc, ch) =
+            handles[self.route(handles.len(), bio.offset())].io_tuple();
+        let rc = unsafe {
+            spdk_bdev_writev_blocks(
+                desc,
+                ch,
+                bio.iovs(),
+                bio.iov_count(),
+                bio.offset(),
+                bio.num_blocks(),
+                Some(Self::io_completion),
+                io as *mut _,
+            )
+        };
+        if rc != 0 {
+            error!("{}: failed to submit IO {:?}", self.name, bio);
+            Self::child_done(io, false);
+        }
+    }
+
+    fn submit_all(&self, channel: *mut spdk_io_channel, io: *mut spdk_bdev_io) {
+        let bio = Bio(io);
+        let handles = RouterChannel::from_channel(channel).handles();
+        // hold the IO until all have been submitted
+        Self::io_ctx(io).in_flight = handles.len() as u8 + 1;
+
+        for h in handles.iter() {
+            let (desc, ch) = h.io_tuple();
+            let rc = unsafe {
+                if Bio::io_type(io) == Some(io_type::FLUSH) {
+                    spdk_bdev_flush_blocks(
+                        desc,
+                        ch,
+                        bio.offset(),
+                        bio.num_blocks(),
+                        Some(Self::io_completion),
+                        io as *mut _,
+                    )
+                } else {
+                    spdk_bdev_reset(
+                        desc,
+                        ch,
+                        Some(Self::io_completion),
+                        io as *mut _,
+                    )
+                }
+            };
+            if rc != 0 {
+                error!("{}: failed to submit IO {:?}", self.name, bio);
+                Self::child_done(io, false);
+            }
+        }
+        Self::child_done(io, true);
+    }
+
+    extern "C" fn io_completion(
+        child_io: *mut spdk_bdev_io,
+        success: bool,
+        parent_io: *mut c_void,
+    ) {
+        Bio::io_free(child_io);
+        Self::child_done(parent_io as *mut _, success);
+    }
+
+    /// complete the IO once the last IO to the bases is done
+    fn child_done(io: *mut spdk_bdev_io, success: bool) {
+        let ctx = Self::io_ctx(io);
+        ctx.failed |= !success;
+        ctx.in_flight -= 1;
+        if ctx.in_flight != 0 {
+            return;
+        }
+
+        if Bio::io_type(io) == Some(io_type::READ) {
+            Self::from_io(io).reads[ctx.epoch as usize]
+                .fetch_sub(1, Ordering::SeqCst);
+        }
+        Self::complete(io, !ctx.failed);
+    }
+
+    fn complete(io: *mut spdk_bdev_io, success: bool) {
+        let status = if success {
+            io_status::SUCCESS
+        } else {
+            io_status::FAILED
+        };
+        unsafe { spdk_bdev_io_complete(io, status) };
+    }
+}
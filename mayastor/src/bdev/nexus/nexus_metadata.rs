@@ -824,6 +824,81 @@ impl NexusChild {
         self.write_config_object(metadata, config, now).await?;
         self.sync_metadata(metadata).await
     }
+
+    /// Retrieve the latest config object accepted by "select". A partition
+    /// without a header + index holds no config objects.
+    pub async fn get_latest_selected_config_object<F>(
+        &self,
+        select: F,
+    ) -> Result<Option<NexusConfig>, MetaDataError>
+    where
+        F: Fn(&NexusConfig) -> bool,
+    {
+        let metadata = match self.get_metadata().await {
+            Ok(metadata) => metadata,
+            Err(MetaDataError::HeaderSize {
+                ..
+            })
+            | Err(MetaDataError::HeaderSignature {}) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        for selected in (0 .. metadata.header.used_entries).rev() {
+            if let Some(config) =
+                self.get_config_object(&metadata, selected).await?
+            {
+                if select(&config) {
+                    return Ok(Some(config));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Replace the config objects accepted by "select" with a new one,
+    /// creating the header + index if there is none yet.
+    pub async fn replace_selected_config_objects<F>(
+        &mut self,
+        config: &NexusConfig,
+        now: &SystemTime,
+        select: F,
+    ) -> Result<(), MetaDataError>
+    where
+        F: Fn(&NexusConfig) -> bool,
+    {
+        let mut metadata = match self.get_metadata().await {
+            Ok(metadata) => metadata,
+            Err(MetaDataError::HeaderSize {
+                ..
+            })
+            | Err(MetaDataError::HeaderSignature {}) => {
+                self.create_metadata().await?
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut previous = Vec::new();
+        for selected in 0 .. metadata.header.used_entries {
+            if let Some(config) =
+                self.get_config_object(&metadata, selected).await?
+            {
+                if select(&config) {
+                    previous.push(selected);
+                }
+            }
+        }
+
+        // the new object is appended before the old ones are removed, so
+        // there always is one on the child
+        self.append_config_object(&mut metadata, config, now)
+            .await?;
+        for selected in previous.into_iter().rev() {
+            self.delete_config_object(&mut metadata, selected).await?;
+        }
+
+        Ok(())
+    }
 }
 
 impl NexusConfig {
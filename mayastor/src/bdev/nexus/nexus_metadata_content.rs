@@ -34,10 +34,118 @@ pub struct NexusConfigVersion3 {
     pub data: Vec<String>,
 }
 
+/// A checkpoint of the nexus together with the regions written after it and
+/// before the next checkpoint was created.
+#[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone)]
+pub struct CheckpointState {
+    pub name: String,
+    /// creation time in seconds since the epoch
+    pub created: u64,
+    /// bitmap of the written regions
+    pub changes: Vec<u64>,
+}
+
+/// Changed block tracking state of the nexus.
+#[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone)]
+pub struct ChangeTrackingState {
+    /// incremented on every save, the children hold the same state unless
+    /// one of them missed a save
+    pub generation: u64,
+    /// set when the nexus was closed, otherwise writes since the last save
+    /// may not have been recorded
+    pub clean: bool,
+    pub region_size: u64,
+    pub block_len: u32,
+    pub checkpoints: Vec<CheckpointState>,
+}
+
+/// A host registered with the reservation of the nexus namespace.
+#[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone)]
+pub struct RegistrantState {
+    pub host_uuid: String,
+    pub rkey: u64,
+}
+
+/// NVMe persistent reservation of the namespace of the nexus.
+#[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone)]
+pub struct ReservationState {
+    /// incremented on every save, the children hold the same state unless
+    /// one of them missed a save
+    pub generation: u64,
+    /// persist through power loss, the registrants and the reservation are
+    /// only kept when it has been activated
+    pub ptpl_activated: bool,
+    pub rtype: u32,
+    pub crkey: u64,
+    pub holder_uuid: String,
+    pub registrants: Vec<RegistrantState>,
+}
+
+/// Progress of the rotation of the key of an encrypted nexus to a new key.
+#[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone)]
+pub struct KeyRotationState {
+    /// ID of the new key with the key provider
+    pub key_id: String,
+    pub cipher: String,
+    /// HMAC of the nexus uuid with the new key
+    pub key_check: String,
+    /// blocks below have been encrypted with the new key
+    pub watermark: u64,
+    /// check values of the blocks of the segment at the watermark, which is
+    /// being written with the new key, to tell after a restart which of its
+    /// blocks made it
+    pub pending: Vec<u64>,
+}
+
+/// Marker of a nexus that has been published encrypted, which must not be
+/// published without the same key afterwards.
+#[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone)]
+pub struct EncryptionState {
+    /// incremented on every save, the children hold the same state unless
+    /// one of them missed a save
+    pub generation: u64,
+    /// ID of the key with the key provider
+    pub key_id: String,
+    pub cipher: String,
+    /// HMAC of the nexus uuid with the key, which tells whether the key
+    /// behind the ID has changed
+    pub key_check: String,
+    /// set while the nexus is being encrypted with another key
+    pub rotation: Option<KeyRotationState>,
+}
+
+/// Layout of the data on a child, which must be the same when the nexus is
+/// created again.
+#[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone)]
+pub struct LayoutState {
+    /// layout of the nexus, as the NexusLayout of the gRPC API
+    pub layout: i32,
+    /// number of children of a parity nexus, 0 otherwise
+    pub width: u32,
+    /// column of a parity nexus the child stores
+    pub column: u32,
+    /// size of the strips of a parity nexus in bytes
+    pub strip_size: u64,
+}
+
+/// Regions of a parity nexus with writes that may not have reached all of
+/// their columns, their parity is recomputed when the nexus is opened.
+#[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone)]
+pub struct DirtyStripesState {
+    /// number of stripes of a region
+    pub region_stripes: u64,
+    pub regions: Vec<u64>,
+}
+
 #[derive(Debug, Deserialize, PartialEq, Serialize, Clone)]
 pub enum NexusConfig {
     Version1(NexusConfigVersion1),
     Version2(NexusConfigVersion2),
     Version3(NexusConfigVersion3),
     Version4(HashMap<String, String>),
+    ChangeTracking(ChangeTrackingState),
+    Reservation(ReservationState),
+    Encryption(EncryptionState),
+    Layout(LayoutState),
+    DirtyStripes(DirtyStripesState),
 }
@@ -1,13 +1,12 @@
 //! Utility functions and wrappers for working with NVMEoF devices in SPDK.
 
-use std::fmt;
+use std::{fmt, net::IpAddr};
 
 use snafu::Snafu;
 
 use crate::{
     core::Bdev,
-    subsys::NvmfSubsystem,
-    target::nvmf::{share, unshare},
+    subsys::{AnaState, NvmfError, NvmfShareOpts, NvmfSubsystem},
 };
 
 #[derive(Debug, Snafu)]
@@ -28,7 +27,10 @@ pub struct NexusNvmfTarget {
 }
 
 impl NexusNvmfTarget {
-    pub async fn create(my_uuid: &str) -> Result<Self, NexusNvmfError> {
+    pub async fn create(
+        my_uuid: &str,
+        opts: &NvmfShareOpts,
+    ) -> Result<Self, NexusNvmfError> {
         info!("Creating nvmf nexus target: {}", my_uuid);
         let bdev = match Bdev::lookup_by_name(&my_uuid) {
             None => {
@@ -39,7 +41,7 @@ impl NexusNvmfTarget {
             Some(bd) => bd,
         };
 
-        match share(&my_uuid, &bdev).await {
+        match NvmfSubsystem::share(&my_uuid, &bdev, opts).await {
             Ok(_) => Ok(Self {
                 uuid: my_uuid.to_string(),
             }),
@@ -51,7 +53,7 @@ impl NexusNvmfTarget {
     }
     pub async fn destroy(self) {
         info!("Destroying nvmf nexus target");
-        match unshare(&self.uuid).await {
+        match NvmfSubsystem::unshare(&self.uuid).await {
             Ok(()) => (),
             Err(e) => {
                 error!("Failed to destroy nvmf frontend target, error {}", e)
@@ -59,12 +61,23 @@ impl NexusNvmfTarget {
         }
     }
 
-    pub fn as_uri(&self) -> String {
+    /// change the ANA state of the listeners on the address, or of all of
+    /// them when no address is given
+    pub async fn set_ana_state(
+        &self,
+        state: AnaState,
+        address: Option<&IpAddr>,
+    ) -> Result<(), NvmfError> {
         NvmfSubsystem::nqn_lookup(&self.uuid)
             .unwrap()
-            .uri_endpoints()
+            .set_ana_state(state, address)
+            .await
+    }
+
+    pub fn as_uri(&self) -> String {
+        NvmfSubsystem::nqn_lookup(&self.uuid)
             .unwrap()
-            .pop()
+            .uri()
             .unwrap()
     }
 }
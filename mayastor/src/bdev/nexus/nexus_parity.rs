@@ -0,0 +1,1393 @@
+//!
+//! Parity (RAID-5 style) layout of the nexus. Rather than mirroring every
+//! write to all children, data is striped across `width - 1` children while
+//! the remaining child of each stripe stores the XOR parity of the stripe. The
+//! parity strip rotates between the children so that no single child becomes
+//! the bottleneck for writes. A parity nexus has the capacity of all but one of
+//! its children and survives the loss of any one of them.
+//!
+//! A nexus LBA is mapped as follows:
+//!
+//! ```text
+//!   stripe   = lba / (strip_blks * data_columns)
+//!   data_col = (lba % (strip_blks * data_columns)) / strip_blks
+//!   child    = (parity_column(stripe) + 1 + data_col) % width
+//!   child_lba = stripe * strip_blks + lba % strip_blks
+//! ```
+//!
+//! Writes that do not cover a full stripe are handled as a read-modify-write
+//! of the rows of the stripe they touch. Reads from a child that is missing,
+//! or that is still being rebuilt, are reconstructed from the survivors.
+//!
+//! A child an IO fails on is faulted, so that it is rebuilt, and the rest of
+//! the nexus IO does without it: a failed read is reconstructed from the
+//! other columns and a failed write is part of the parity. The nexus IO only
+//! fails when a stripe cannot be reconstructed any longer.
+//!
+//! A stripe whose write reached some of its columns but not all of them, as
+//! mayastor did not shut down cleanly, has a parity that does not match its
+//! data, which would go unnoticed until a column is reconstructed from it.
+//! Before the first write to a region of stripes, the region is therefore
+//! marked in a dirty map saved in the MayaMeta partition of the children.
+//! When the nexus is opened, the parity of the regions in the map is
+//! recomputed from their data. Regions are cleared from the map lazily, when
+//! it is saved for another region and grows too large, and all of them when
+//! the nexus is destroyed.
+//!
+//! Unlike the mirror, where each nexus IO translates into exactly one child IO
+//! per child, a single IO may require several dependent child IOs here. We
+//! therefore drive the IO by means of a future on the core the IO was
+//! submitted on, using the per core handles kept in the nexus channel.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    ops::Range,
+    os::raw::c_void,
+    pin::Pin,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use futures::{
+    channel::oneshot,
+    future::join_all,
+    task::{Context, Poll},
+    Future,
+};
+use snafu::{ResultExt, Snafu};
+
+use spdk_sys::{spdk_bdev_io, spdk_bdev_io_get_buf, spdk_io_channel};
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{
+            nexus_lookup,
+            Error,
+            LayoutMismatch,
+            LoadLayout,
+            Nexus,
+            SaveDirtyStripes,
+            SaveLayout,
+        },
+        nexus_channel::NexusChannel,
+        nexus_child::{ChildStatus, NexusChild},
+        nexus_io::{io_type, Bio},
+        nexus_metadata::MetaDataError,
+        nexus_metadata_content::{DirtyStripesState, LayoutState, NexusConfig},
+    },
+    core::{BdevHandle, CoreError, Cores, DmaBuf, DmaError, Mthread, Reactors},
+};
+
+/// size of the part of a stripe that is stored on a single child
+pub const PARITY_STRIP_SIZE: u64 = 64 * 1024;
+
+/// the minimal number of children of a parity nexus, two data columns and
+/// one parity column
+pub const PARITY_MIN_WIDTH: u32 = 3;
+
+/// number of stripes of a region of the dirty map
+pub const PARITY_DIRTY_REGION_STRIPES: u64 = 64;
+
+/// number of regions in the dirty map beyond which the regions without
+/// writes in progress are cleared from it
+const PARITY_DIRTY_MAX_REGIONS: usize = 64;
+
+#[derive(Debug, Snafu)]
+pub enum ParityError {
+    #[snafu(display("Failed to allocate buffer for parity IO"))]
+    ParityAlloc { source: DmaError },
+    #[snafu(display("IO to column {} failed", column))]
+    ColumnIo { source: CoreError, column: usize },
+    #[snafu(display(
+        "Stripe {} cannot be reconstructed, column {} is unavailable",
+        stripe,
+        column
+    ))]
+    Unrecoverable { stripe: u64, column: usize },
+    #[snafu(display("Failed to mark stripes {} to {} dirty", first, last))]
+    MarkDirty { first: u64, last: u64 },
+}
+
+/// per core handle to the child that makes up one column of the layout
+#[derive(Debug)]
+pub(crate) struct ParityColumn {
+    pub(crate) handle: BdevHandle,
+    /// the child is being rebuilt, it must receive all writes but its
+    /// contents cannot be used to serve reads or to compute parity
+    pub(crate) write_only: bool,
+}
+
+/// contiguous part of a nexus IO that is stored on a single strip
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Segment {
+    /// stripe the segment belongs to
+    pub(crate) stripe: u64,
+    /// data column within the stripe
+    pub(crate) data_col: u64,
+    /// offset in blocks within the strip
+    pub(crate) offset: u64,
+    /// length of the segment in blocks
+    pub(crate) num_blocks: u64,
+    /// offset in blocks relative to the start of the nexus IO
+    pub(crate) io_offset: u64,
+}
+
+/// Geometry of a parity nexus
+#[derive(Debug)]
+pub struct ParityLayout {
+    /// number of children, including the parity column
+    pub(crate) width: u32,
+    /// size of a strip in blocks, set once the block size of the children is
+    /// known
+    pub(crate) strip_blks: u64,
+    /// stripes with a write in progress
+    locks: StripeLocks,
+    /// regions of stripes with a write in progress
+    dirty: DirtyStripes,
+}
+
+impl ParityLayout {
+    /// create the layout for a nexus with the given number of children
+    pub fn new(width: u32) -> Self {
+        Self {
+            width,
+            strip_blks: 0,
+            locks: StripeLocks::default(),
+            dirty: DirtyStripes::default(),
+        }
+    }
+
+    /// set the strip size based on the block size of the children
+    pub(crate) fn configure(&mut self, block_len: u32) {
+        self.strip_blks = PARITY_STRIP_SIZE / u64::from(block_len);
+    }
+
+    /// number of columns that hold data
+    pub(crate) fn data_columns(&self) -> u64 {
+        u64::from(self.width) - 1
+    }
+
+    /// number of data blocks in a full stripe
+    pub(crate) fn stripe_blks(&self) -> u64 {
+        self.strip_blks * self.data_columns()
+    }
+
+    /// number of nexus blocks that can be stored when each child provides
+    /// `child_blks` blocks. Only full stripes are used.
+    pub(crate) fn nexus_blocks(&self, child_blks: u64) -> u64 {
+        (child_blks / self.strip_blks) * self.stripe_blks()
+    }
+
+    /// the column holding the parity of the given stripe
+    pub(crate) fn parity_column(&self, stripe: u64) -> usize {
+        let width = u64::from(self.width);
+        (width - 1 - stripe % width) as usize
+    }
+
+    /// the column holding the data column of the given stripe
+    pub(crate) fn child_column(&self, stripe: u64, data_col: u64) -> usize {
+        let width = u64::from(self.width);
+        ((self.parity_column(stripe) as u64 + 1 + data_col) % width) as usize
+    }
+
+    /// the block on a child, relative to the start of its data partition,
+    /// where the given offset within the stripe resides
+    pub(crate) fn child_lba(&self, stripe: u64, offset: u64) -> u64 {
+        stripe * self.strip_blks + offset
+    }
+
+    /// range of nexus blocks covering the stripes that hold the given range of
+    /// child blocks (relative to the start of the data partition)
+    pub(crate) fn nexus_range(&self, child_blk: u64, len: u64) -> Range<u64> {
+        let first = child_blk / self.strip_blks;
+        let last = (child_blk + len - 1) / self.strip_blks;
+        first * self.stripe_blks() .. (last + 1) * self.stripe_blks()
+    }
+
+    /// split a nexus IO into segments that each reside on a single strip
+    pub(crate) fn segments(
+        &self,
+        offset: u64,
+        num_blocks: u64,
+    ) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut done = 0;
+
+        while done < num_blocks {
+            let lba = offset + done;
+            let within = lba % self.stripe_blks();
+            let offset = within % self.strip_blks;
+            let len =
+                std::cmp::min(self.strip_blks - offset, num_blocks - done);
+
+            segments.push(Segment {
+                stripe: lba / self.stripe_blks(),
+                data_col: within / self.strip_blks,
+                offset,
+                num_blocks: len,
+                io_offset: done,
+            });
+
+            done += len;
+        }
+
+        segments
+    }
+}
+
+/// XOR the contents of src into dst
+pub fn xor_into(dst: &mut [u8], src: &[u8]) {
+    assert_eq!(dst.len(), src.len());
+    dst.iter_mut().zip(src.iter()).for_each(|(d, s)| *d ^= *s);
+}
+
+/// Set of stripes (or cache lines) that are currently being written. The
+/// lock is taken by a future that may execute on any of the cores, so the set
+/// itself is protected by a mutex which is never held across an await point.
+/// Contended locks are queued and granted in order as the stripes are
+/// released, much like the LBA range locks of SPDK.
+#[derive(Debug, Default)]
+pub(crate) struct StripeLocks {
+    state: Mutex<LockState>,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    locked: HashSet<u64>,
+    waiters: VecDeque<Waiter>,
+}
+
+/// a request for stripes that were held by others when it was made
+#[derive(Debug)]
+struct Waiter {
+    stripes: Vec<u64>,
+    /// thread of the core the request was made on, the future waiting for
+    /// the lock must be woken up on it
+    thread: Option<Mthread>,
+    sender: oneshot::Sender<()>,
+}
+
+// the thread is only used to send the grant of the lock to
+unsafe impl Send for Waiter {}
+
+/// releases the stripes when dropped
+pub(crate) struct StripeGuard<'a> {
+    locks: &'a StripeLocks,
+    stripes: Vec<u64>,
+}
+
+impl LockState {
+    /// whether none of the stripes is locked or requested by an earlier
+    /// request that is still waiting
+    fn available(&self, stripes: &[u64]) -> bool {
+        !stripes.iter().any(|s| {
+            self.locked.contains(s)
+                || self.waiters.iter().any(|w| w.stripes.contains(s))
+        })
+    }
+
+    /// take the stripes of the waiting requests that can be granted now, in
+    /// the order they were made
+    fn grant(&mut self) -> Vec<Waiter> {
+        let mut granted = Vec::new();
+        let mut blocked = HashSet::new();
+        let mut waiting = VecDeque::new();
+
+        while let Some(waiter) = self.waiters.pop_front() {
+            if waiter
+                .stripes
+                .iter()
+                .any(|s| self.locked.contains(s) || blocked.contains(s))
+            {
+                blocked.extend(waiter.stripes.iter().copied());
+                waiting.push_back(waiter);
+            } else {
+                self.locked.extend(waiter.stripes.iter().copied());
+                granted.push(waiter);
+            }
+        }
+
+        self.waiters = waiting;
+        granted
+    }
+}
+
+impl Waiter {
+    /// wake up the future waiting for the lock on its own core
+    fn wake(self) {
+        send_on(self.thread, self.sender, ());
+    }
+}
+
+/// send the value to a future that waits for it on the given thread, the
+/// future must be woken up on its own core
+fn send_on<T>(thread: Option<Mthread>, sender: oneshot::Sender<T>, value: T) {
+    extern "C" fn send_cb<T>(arg: *mut c_void) {
+        let (sender, value) =
+            *unsafe { Box::from_raw(arg as *mut (oneshot::Sender<T>, T)) };
+        let _ = sender.send(value);
+    }
+
+    match thread {
+        Some(thread)
+            if Mthread::current()
+                .map_or(true, |t| t.inner() != thread.inner()) =>
+        {
+            thread.send_msg(
+                send_cb::<T>,
+                Box::into_raw(Box::new((sender, value))) as *mut c_void,
+            );
+        }
+        _ => {
+            let _ = sender.send(value);
+        }
+    }
+}
+
+impl StripeLocks {
+    /// wait until all given stripes are locked by us, taking all of them at
+    /// once avoids lock ordering issues between concurrent IOs. The future
+    /// must not be dropped before it completes, as the stripes are handed to
+    /// it as soon as they are released.
+    pub(crate) async fn lock(&self, mut stripes: Vec<u64>) -> StripeGuard<'_> {
+        stripes.sort_unstable();
+        stripes.dedup();
+
+        let granted = {
+            let mut state = self.state.lock().unwrap();
+            if state.available(&stripes) {
+                state.locked.extend(stripes.iter().copied());
+                None
+            } else {
+                let (s, r) = oneshot::channel::<()>();
+                state.waiters.push_back(Waiter {
+                    stripes: stripes.clone(),
+                    thread: Mthread::current(),
+                    sender: s,
+                });
+                Some(r)
+            }
+        };
+
+        if let Some(r) = granted {
+            r.await.expect("stripe lock grant dropped");
+        }
+
+        StripeGuard {
+            locks: self,
+            stripes,
+        }
+    }
+
+    /// wait until all stripes of the range are locked by us
+    pub(crate) async fn lock_range(
+        &self,
+        stripes: Range<u64>,
+    ) -> StripeGuard<'_> {
+        self.lock(stripes.collect()).await
+    }
+}
+
+impl Drop for StripeGuard<'_> {
+    fn drop(&mut self) {
+        let granted = {
+            let mut state = self.locks.state.lock().unwrap();
+            self.stripes.iter().for_each(|s| {
+                state.locked.remove(s);
+            });
+            state.grant()
+        };
+
+        granted.into_iter().for_each(Waiter::wake);
+    }
+}
+
+/// Regions of stripes with writes in progress, which must be marked in the
+/// dirty map saved on the children before the writes are issued. The map is
+/// saved on the management core, for all writes waiting for it at once.
+#[derive(Debug, Default)]
+pub(crate) struct DirtyStripes {
+    state: Mutex<DirtyState>,
+}
+
+#[derive(Debug, Default)]
+struct DirtyState {
+    /// number of writes in progress per region
+    writers: HashMap<u64, u32>,
+    /// regions marked in the map saved on the children
+    saved: HashSet<u64>,
+    /// the map is being saved
+    saving: bool,
+    /// writes waiting for the map to be saved, along with the thread to wake
+    /// them up on
+    waiters: Vec<(Option<Mthread>, oneshot::Sender<bool>)>,
+}
+
+// the threads are only used to send the outcome of the save to
+unsafe impl Send for DirtyState {}
+
+/// ends the writes to the regions when dropped
+pub(crate) struct DirtyGuard<'a> {
+    dirty: &'a DirtyStripes,
+    regions: Vec<u64>,
+}
+
+impl Drop for DirtyGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.dirty.state.lock().unwrap();
+        for region in self.regions.iter() {
+            if let Some(writers) = state.writers.get_mut(region) {
+                *writers -= 1;
+                if *writers == 0 {
+                    state.writers.remove(region);
+                }
+            }
+        }
+    }
+}
+
+/// Future that returns control to the reactor once, allowing other futures
+/// on this core to make progress while we wait for a contended resource.
+#[derive(Default)]
+pub(crate) struct Yield(bool);
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// The columns as seen by a single nexus IO. A column that an IO of it
+/// failed on is no longer used for the rest of the nexus IO, while its child
+/// is faulted.
+struct IoColumns<'a> {
+    columns: &'a [Option<ParityColumn>],
+    failed: RefCell<HashSet<usize>>,
+}
+
+impl<'a> IoColumns<'a> {
+    fn new(columns: &'a [Option<ParityColumn>]) -> Self {
+        Self {
+            columns,
+            failed: RefCell::new(HashSet::new()),
+        }
+    }
+
+    /// number of columns of the layout
+    fn width(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// return the handle of the column if it can be read from
+    fn readable(&self, col: usize) -> Option<&'a BdevHandle> {
+        match self.columns.get(col) {
+            Some(Some(c)) if !c.write_only && !self.has_failed(col) => {
+                Some(&c.handle)
+            }
+            _ => None,
+        }
+    }
+
+    /// return the handle of the column if it can be written to
+    fn writable(&self, col: usize) -> Option<&'a BdevHandle> {
+        match self.columns.get(col) {
+            Some(Some(c)) if !self.has_failed(col) => Some(&c.handle),
+            _ => None,
+        }
+    }
+
+    /// number of columns that cannot be read from
+    fn unreadable(&self) -> usize {
+        (0 .. self.width())
+            .filter(|c| self.readable(*c).is_none())
+            .count()
+    }
+
+    fn has_failed(&self, col: usize) -> bool {
+        self.failed.borrow().contains(&col)
+    }
+
+    fn fail(&self, col: usize) {
+        self.failed.borrow_mut().insert(col);
+    }
+}
+
+impl Nexus {
+    /// construct the per core handles of the columns of a parity nexus. Columns
+    /// without a usable child are left empty.
+    pub(crate) fn parity_columns(&self) -> Vec<Option<ParityColumn>> {
+        let width = match self.parity.as_ref() {
+            Some(layout) => layout.width as usize,
+            None => return Vec::new(),
+        };
+
+        let mut columns = (0 .. width).map(|_| None).collect::<Vec<_>>();
+
+        self.children
+            .iter()
+            .filter(|c| c.column.is_some())
+            .for_each(|c| {
+                let write_only = if c.status() == ChildStatus::Online {
+                    false
+                } else if c.rebuilding() {
+                    true
+                } else {
+                    return;
+                };
+
+                if let Ok(handle) =
+                    c.get_descriptor().and_then(BdevHandle::try_from)
+                {
+                    columns[c.column.unwrap() as usize] = Some(ParityColumn {
+                        handle,
+                        write_only,
+                    });
+                }
+            });
+
+        columns
+    }
+
+    /// submit a read or write IO to a nexus with a parity layout
+    pub(crate) fn parity_submit(
+        &self,
+        pio: *mut spdk_bdev_io,
+        channel: *mut spdk_io_channel,
+    ) {
+        let io = Bio(pio);
+
+        if Bio::io_type(pio) == Some(io_type::READ) && io.need_buf() {
+            unsafe {
+                spdk_bdev_io_get_buf(
+                    pio,
+                    Some(Self::parity_get_buf_cb),
+                    io.num_blocks() * io.block_len(),
+                )
+            }
+            return;
+        }
+
+        Self::parity_dispatch(pio, channel);
+    }
+
+    /// callback when the buffer for a parity read has been allocated
+    extern "C" fn parity_get_buf_cb(
+        ch: *mut spdk_io_channel,
+        io: *mut spdk_bdev_io,
+        success: bool,
+    ) {
+        if !success {
+            let mut bio = Bio(io);
+            warn!(
+                "{}: Failed to get io buffer for io {:?}",
+                bio.nexus_as_ref().name,
+                bio
+            );
+            bio.fail();
+            return;
+        }
+
+        Self::parity_dispatch(io, ch);
+    }
+
+    /// drive the IO to completion on the current core
+    fn parity_dispatch(pio: *mut spdk_bdev_io, channel: *mut spdk_io_channel) {
+        Reactors::current().spawn_local(async move {
+            let mut io = Bio(pio);
+            let nexus = io.nexus_as_ref();
+            let columns =
+                NexusChannel::inner_from_channel(channel).columns.clone();
+            let columns = IoColumns::new(&columns);
+
+            let result = match Bio::io_type(pio) {
+                Some(io_type::READ) => nexus.parity_read(&io, &columns).await,
+                _ => nexus.parity_write(&io, &columns).await,
+            };
+
+            match result {
+                Ok(_) => {
+                    if Bio::io_type(pio) == Some(io_type::WRITE) {
+                        nexus.change_tracking_record(
+                            io.offset(),
+                            io.num_blocks(),
+                        );
+                    }
+                    io.ok()
+                }
+                Err(e) => {
+                    error!("{}: parity IO {:?} failed: {}", nexus.name, io, e);
+                    io.fail();
+                }
+            }
+        });
+    }
+
+    /// read the segments of the IO, reconstructing those of missing columns
+    async fn parity_read(
+        &self,
+        io: &Bio,
+        columns: &IoColumns<'_>,
+    ) -> Result<(), ParityError> {
+        let layout = self.parity.as_ref().unwrap();
+        let block_len = io.block_len();
+        let segments = layout.segments(io.offset(), io.num_blocks());
+        let last = segments.last().unwrap().stripe;
+
+        // reconstructing requires a consistent view of the stripe so we must
+        // not race with a read-modify-write of the same stripe
+        let mut guard = if columns.unreadable() > 0 {
+            let first = segments.first().unwrap().stripe;
+            Some(layout.locks.lock_range(first .. last + 1).await)
+        } else {
+            None
+        };
+
+        for seg in segments {
+            let column = layout.child_column(seg.stripe, seg.data_col);
+            let lba =
+                layout.child_lba(seg.stripe, seg.offset) + self.data_ent_offset;
+
+            let buf = loop {
+                match self
+                    .parity_read_column(
+                        columns,
+                        seg.stripe,
+                        column,
+                        lba,
+                        seg.num_blocks,
+                        block_len,
+                        guard.is_some(),
+                    )
+                    .await
+                {
+                    Ok(buf) => break buf,
+                    // the column failed, it is reconstructed from here on
+                    // while holding the locks of the stripes left
+                    Err(ParityError::ColumnIo {
+                        column: failed, ..
+                    }) if failed == column && guard.is_none() => {
+                        guard = Some(
+                            layout
+                                .locks
+                                .lock_range(seg.stripe .. last + 1)
+                                .await,
+                        );
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+
+            io.copy_to_iovs(
+                (seg.io_offset * block_len) as usize,
+                buf.as_slice(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Read a range of blocks of a column, if the column cannot be read from
+    /// the data is reconstructed from all other columns. That requires the
+    /// lock of the stripe, without it a failed read is returned as an error
+    /// rather than reconstructed.
+    #[allow(clippy::too_many_arguments)]
+    async fn parity_read_column(
+        &self,
+        columns: &IoColumns<'_>,
+        stripe: u64,
+        column: usize,
+        lba: u64,
+        num_blocks: u64,
+        block_len: u64,
+        locked: bool,
+    ) -> Result<DmaBuf, ParityError> {
+        let len = (num_blocks * block_len) as usize;
+        let mut buf =
+            DmaBuf::new(len, self.bdev.alignment()).context(ParityAlloc {})?;
+
+        if let Some(handle) = columns.readable(column) {
+            let result = handle.read_at(lba * block_len, &mut buf).await;
+            match self.column_result(columns, handle, column, result) {
+                Ok(_) => return Ok(buf),
+                Err(e) if !locked => return Err(e),
+                Err(e) => {
+                    warn!("{}: {}", self.name, e);
+                    buf.fill(0);
+                }
+            }
+        }
+
+        trace!(
+            "{}: reconstructing column {} of stripe {}",
+            self.name,
+            column,
+            stripe
+        );
+
+        let mut scratch =
+            DmaBuf::new(len, self.bdev.alignment()).context(ParityAlloc {})?;
+
+        for other in (0 .. columns.width()).filter(|c| *c != column) {
+            let handle = columns.readable(other).ok_or_else(|| {
+                ParityError::Unrecoverable {
+                    stripe,
+                    column: other,
+                }
+            })?;
+
+            let result = handle.read_at(lba * block_len, &mut scratch).await;
+            self.column_result(columns, handle, other, result)?;
+            xor_into(buf.as_mut_slice(), scratch.as_slice());
+        }
+
+        Ok(buf)
+    }
+
+    /// write the IO stripe by stripe while holding the stripe locks, once
+    /// the stripes are marked dirty
+    async fn parity_write(
+        &self,
+        io: &Bio,
+        columns: &IoColumns<'_>,
+    ) -> Result<(), ParityError> {
+        let layout = self.parity.as_ref().unwrap();
+        let block_len = io.block_len();
+        let segments = layout.segments(io.offset(), io.num_blocks());
+
+        let mut data = vec![0u8; (io.num_blocks() * block_len) as usize];
+        io.copy_from_iovs(0, &mut data);
+
+        let first = segments.first().unwrap().stripe;
+        let last = segments.last().unwrap().stripe;
+        let _dirty = self.parity_mark_dirty(first .. last + 1).await?;
+        let _guard = layout.locks.lock_range(first .. last + 1).await;
+
+        for stripe in first ..= last {
+            let segs = segments
+                .iter()
+                .filter(|s| s.stripe == stripe)
+                .collect::<Vec<_>>();
+
+            self.parity_write_stripe(columns, stripe, &segs, &data, block_len)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the segments of a single stripe and update its parity. Only the
+    /// rows of the stripe touched by the segments are read and rewritten. The
+    /// old contents of the rows are read for all data columns not fully
+    /// overwritten, after which the new parity is computed from the merged
+    /// data. Columns that a write fails on are faulted, the stripe holds the
+    /// write as long as it can be reconstructed.
+    async fn parity_write_stripe(
+        &self,
+        columns: &IoColumns<'_>,
+        stripe: u64,
+        segs: &[&Segment],
+        data: &[u8],
+        block_len: u64,
+    ) -> Result<(), ParityError> {
+        let layout = self.parity.as_ref().unwrap();
+
+        let rows_start = segs.iter().map(|s| s.offset).min().unwrap();
+        let rows_end =
+            segs.iter().map(|s| s.offset + s.num_blocks).max().unwrap();
+        let rows = rows_end - rows_start;
+        let rows_len = (rows * block_len) as usize;
+        let lba = layout.child_lba(stripe, rows_start) + self.data_ent_offset;
+
+        let mut parity = DmaBuf::new(rows_len, self.bdev.alignment())
+            .context(ParityAlloc {})?;
+
+        for data_col in 0 .. layout.data_columns() {
+            let column = layout.child_column(stripe, data_col);
+            let seg = segs.iter().find(|s| s.data_col == data_col);
+
+            let covered = match seg {
+                Some(s) => s.offset == rows_start && s.num_blocks == rows,
+                None => false,
+            };
+
+            let mut buf = if covered {
+                DmaBuf::new(rows_len, self.bdev.alignment())
+                    .context(ParityAlloc {})?
+            } else {
+                self.parity_read_column(
+                    columns, stripe, column, lba, rows, block_len, true,
+                )
+                .await?
+            };
+
+            if let Some(s) = seg {
+                let start = ((s.offset - rows_start) * block_len) as usize;
+                let len = (s.num_blocks * block_len) as usize;
+                let src = (s.io_offset * block_len) as usize;
+                buf.as_mut_slice()[start .. start + len]
+                    .copy_from_slice(&data[src .. src + len]);
+            }
+
+            xor_into(parity.as_mut_slice(), buf.as_slice());
+        }
+
+        // a write to a missing column is not lost, it is part of the parity
+        let mut writes = Vec::new();
+        for s in segs.iter() {
+            let column = layout.child_column(stripe, s.data_col);
+            if let Some(handle) = columns.writable(column) {
+                let len = (s.num_blocks * block_len) as usize;
+                let src = (s.io_offset * block_len) as usize;
+                let mut buf = DmaBuf::new(len, self.bdev.alignment())
+                    .context(ParityAlloc {})?;
+                buf.as_mut_slice().copy_from_slice(&data[src .. src + len]);
+
+                let offset = (layout.child_lba(stripe, s.offset)
+                    + self.data_ent_offset)
+                    * block_len;
+                writes.push((column, handle, offset, buf));
+            }
+        }
+
+        let parity_column = layout.parity_column(stripe);
+        if let Some(handle) = columns.writable(parity_column) {
+            writes.push((parity_column, handle, lba * block_len, parity));
+        }
+
+        let results = join_all(
+            writes
+                .iter()
+                .map(|(_, handle, offset, buf)| handle.write_at(*offset, buf)),
+        )
+        .await;
+
+        let mut error = None;
+        for (result, (column, handle, ..)) in
+            results.into_iter().zip(writes.iter())
+        {
+            if let Err(e) = self.column_result(columns, handle, *column, result)
+            {
+                error = Some(e);
+            }
+        }
+
+        match error {
+            Some(e) if columns.unreadable() > 1 => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// the result of an IO to a column, a failed IO is reported to the nexus
+    /// and the column is not used for the rest of the nexus IO
+    fn column_result<T>(
+        &self,
+        columns: &IoColumns<'_>,
+        handle: &BdevHandle,
+        column: usize,
+        result: Result<T, CoreError>,
+    ) -> Result<T, ParityError> {
+        if let Err(e) = result.as_ref() {
+            columns.fail(column);
+            self.child_io_failed(handle, e);
+        }
+        result.context(ColumnIo {
+            column,
+        })
+    }
+
+    /// Mark the regions of the stripes dirty for the duration of a write,
+    /// waiting for the map to be saved first unless they are marked already
+    async fn parity_mark_dirty(
+        &self,
+        stripes: Range<u64>,
+    ) -> Result<DirtyGuard<'_>, ParityError> {
+        let dirty = &self.parity.as_ref().unwrap().dirty;
+        let regions = (stripes.start / PARITY_DIRTY_REGION_STRIPES
+            ..= (stripes.end - 1) / PARITY_DIRTY_REGION_STRIPES)
+            .collect::<Vec<_>>();
+
+        let (saved, save) = {
+            let mut state = dirty.state.lock().unwrap();
+            regions
+                .iter()
+                .for_each(|r| *state.writers.entry(*r).or_default() += 1);
+            if regions.iter().all(|r| state.saved.contains(r)) {
+                (None, false)
+            } else {
+                let (s, r) = oneshot::channel::<bool>();
+                state.waiters.push((Mthread::current(), s));
+                let save = !state.saving;
+                state.saving = true;
+                (Some(r), save)
+            }
+        };
+        let guard = DirtyGuard {
+            dirty,
+            regions,
+        };
+
+        if save {
+            let name = self.name.clone();
+            Reactors::get_by_core(Cores::first()).unwrap().send_future(
+                async move {
+                    if let Some(nexus) = nexus_lookup(&name) {
+                        nexus.save_dirty_regions().await;
+                    }
+                },
+            );
+        }
+
+        if let Some(r) = saved {
+            if !r.await.unwrap_or(false) {
+                return MarkDirty {
+                    first: stripes.start,
+                    last: stripes.end - 1,
+                }
+                .fail();
+            }
+        }
+
+        Ok(guard)
+    }
+}
+
+impl NexusChild {
+    /// the layout of the data saved on the child, if any
+    async fn load_layout(&self) -> Result<Option<LayoutState>, MetaDataError> {
+        match self
+            .get_latest_selected_config_object(|c| {
+                matches!(c, NexusConfig::Layout(_))
+            })
+            .await?
+        {
+            Some(NexusConfig::Layout(state)) => Ok(Some(state)),
+            _ => Ok(None),
+        }
+    }
+
+    /// replace the layout of the data saved on the child
+    async fn save_layout(
+        &mut self,
+        state: LayoutState,
+    ) -> Result<(), MetaDataError> {
+        self.replace_selected_config_objects(
+            &NexusConfig::Layout(state),
+            &SystemTime::now(),
+            |c| matches!(c, NexusConfig::Layout(_)),
+        )
+        .await
+    }
+
+    /// the dirty map saved on the child, if any
+    async fn load_dirty_stripes(
+        &self,
+    ) -> Result<Option<DirtyStripesState>, MetaDataError> {
+        match self
+            .get_latest_selected_config_object(|c| {
+                matches!(c, NexusConfig::DirtyStripes(_))
+            })
+            .await?
+        {
+            Some(NexusConfig::DirtyStripes(state)) => Ok(Some(state)),
+            _ => Ok(None),
+        }
+    }
+
+    /// replace the dirty map saved on the child
+    async fn save_dirty_stripes(
+        &mut self,
+        config: &NexusConfig,
+        now: &SystemTime,
+    ) -> Result<(), MetaDataError> {
+        self.replace_selected_config_objects(config, now, |c| {
+            matches!(c, NexusConfig::DirtyStripes(_))
+        })
+        .await
+    }
+}
+
+impl Nexus {
+    /// the layout of the data a child with the column stores
+    fn layout_state(&self, column: Option<u32>) -> LayoutState {
+        match self.parity.as_ref() {
+            Some(layout) => LayoutState {
+                layout: self.layout() as i32,
+                width: layout.width,
+                column: column.unwrap_or_default(),
+                strip_size: PARITY_STRIP_SIZE,
+            },
+            None => LayoutState {
+                layout: self.layout() as i32,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Check the layout saved on the children against the layout the nexus
+    /// is created with, so that the data is never served with another
+    /// layout. The columns of a parity nexus are taken from the children, as
+    /// they may be given in another order when the nexus is created again.
+    /// A child without a layout is saved the one of the nexus, unless the
+    /// other children of a parity nexus have theirs already: the child then
+    /// takes a vacant column and must be rebuilt first. A mirror is still
+    /// opened when the layout cannot be loaded from or saved on a child.
+    pub(crate) async fn open_layout(&mut self) -> Result<(), Error> {
+        let mut saved = Vec::new();
+        for child in self
+            .children
+            .iter()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            let state = match child.load_layout().await.context(LoadLayout {
+                child: child.name.clone(),
+                name: self.name.clone(),
+            }) {
+                Ok(state) => state,
+                Err(e) if self.parity.is_some() => return Err(e),
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+            saved.push((child.name.clone(), state));
+        }
+
+        let expected = self.layout_state(None);
+        let mut columns = HashSet::new();
+        for (child, state) in saved.iter() {
+            let state = match state {
+                Some(state) => state,
+                None => continue,
+            };
+            let reason = if state.layout != expected.layout
+                || state.width != expected.width
+                || state.strip_size != expected.strip_size
+            {
+                Some(format!(
+                    "it stores a layout {} of width {} with strips of {} bytes",
+                    state.layout, state.width, state.strip_size
+                ))
+            } else if self.parity.is_some()
+                && (state.column >= state.width
+                    || !columns.insert(state.column))
+            {
+                Some(format!("column {} is not vacant", state.column))
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                return LayoutMismatch {
+                    child: child.clone(),
+                    reason,
+                    name: self.name.clone(),
+                }
+                .fail();
+            }
+        }
+
+        // the columns saved on the children take precedence over the order
+        // they are given in, which only applies to a new nexus
+        if self.parity.is_some() && !columns.is_empty() {
+            for child in self.children.iter_mut() {
+                child.column = saved
+                    .iter()
+                    .find(|(name, _)| *name == child.name)
+                    .and_then(|(_, state)| state.as_ref())
+                    .map(|state| state.column);
+            }
+            for i in 0 .. self.children.len() {
+                if self.children[i].column.is_some() {
+                    continue;
+                }
+                self.children[i].column = self.vacant_column();
+                let child = &mut self.children[i];
+                if child.status() == ChildStatus::Online {
+                    warn!(
+                        "{}: child {} has no column of the layout and must be rebuilt",
+                        self.name, child.name
+                    );
+                    child.out_of_sync(true);
+                }
+            }
+        }
+
+        for (name, state) in saved {
+            if state.is_some() {
+                continue;
+            }
+            let i = self.children.iter().position(|c| c.name == name).unwrap();
+            if self.children[i].status() != ChildStatus::Online {
+                continue;
+            }
+            let state = self.layout_state(self.children[i].column);
+            if let Err(e) =
+                self.children[i]
+                    .save_layout(state)
+                    .await
+                    .context(SaveLayout {
+                        child: name.clone(),
+                        name: self.name.clone(),
+                    })
+            {
+                if self.parity.is_some() {
+                    return Err(e);
+                }
+                warn!("{}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// save the layout on a child once it has been rebuilt
+    pub(crate) async fn save_child_layout(
+        &mut self,
+        name: &str,
+    ) -> Result<(), Error> {
+        let i = match self.children.iter().position(|c| c.name == name) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let state = self.layout_state(self.children[i].column);
+        self.children[i]
+            .save_layout(state)
+            .await
+            .context(SaveLayout {
+                child: name.to_string(),
+                name: self.name.clone(),
+            })
+    }
+
+    /// Recompute the parity of the regions in the dirty map saved on the
+    /// children, called when the nexus is opened. This needs all of the
+    /// columns, without one the parity of a stripe cannot be told from its
+    /// data. The regions then stay in the map for a later open.
+    pub(crate) async fn open_dirty_stripes(&mut self) {
+        let stripe_blks = match self.parity.as_ref() {
+            Some(layout) => layout.stripe_blks(),
+            None => return,
+        };
+
+        let mut loaded = false;
+        let mut regions = HashSet::new();
+        for child in self
+            .children
+            .iter()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            match child.load_dirty_stripes().await {
+                Ok(Some(state)) => {
+                    loaded = true;
+                    for region in state.regions {
+                        let first = region * state.region_stripes;
+                        let last = first + state.region_stripes - 1;
+                        regions.extend(
+                            first / PARITY_DIRTY_REGION_STRIPES
+                                ..= last / PARITY_DIRTY_REGION_STRIPES,
+                        );
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "{}: failed to load the dirty stripes from child {}: {}",
+                    self.name, child.name, e
+                ),
+            }
+        }
+
+        let mut dirty = regions.into_iter().collect::<Vec<_>>();
+        dirty.sort_unstable();
+
+        if !dirty.is_empty() {
+            let columns = self.parity_columns();
+            match columns.iter().position(|c| match c {
+                Some(c) => c.write_only,
+                None => true,
+            }) {
+                Some(column) => warn!(
+                    "{}: cannot resync the parity of {} regions, column {} is unavailable",
+                    self.name,
+                    dirty.len(),
+                    column
+                ),
+                None => {
+                    warn!(
+                        "{}: nexus was not closed cleanly, resyncing the parity of {} regions",
+                        self.name,
+                        dirty.len()
+                    );
+                    let stripes = self.bdev.num_blocks() / stripe_blks;
+                    let mut left = Vec::new();
+                    for region in dirty {
+                        if let Err(e) = self
+                            .parity_resync_region(&columns, region, stripes)
+                            .await
+                        {
+                            error!(
+                                "{}: failed to resync the parity of region {}: {}",
+                                self.name, region, e
+                            );
+                            left.push(region);
+                        }
+                    }
+                    dirty = left;
+                }
+            }
+        }
+
+        if loaded {
+            if let Err(e) = self.save_dirty_map(dirty.clone()).await {
+                error!("{}", e);
+            }
+        }
+        let layout = self.parity.as_ref().unwrap();
+        layout.dirty.state.lock().unwrap().saved = dirty.into_iter().collect();
+    }
+
+    /// Clear the dirty map as the nexus is closed, called when the nexus is
+    /// destroyed
+    pub(crate) async fn close_dirty_stripes(&mut self) {
+        if self.parity.is_some() {
+            if let Err(e) = self.save_dirty_map(Vec::new()).await {
+                error!("{}", e);
+            }
+        }
+    }
+
+    /// recompute the parity of the stripes of a region from their data
+    async fn parity_resync_region(
+        &self,
+        columns: &[Option<ParityColumn>],
+        region: u64,
+        stripes: u64,
+    ) -> Result<(), ParityError> {
+        let layout = self.parity.as_ref().unwrap();
+        let block_len = u64::from(self.bdev.block_len());
+        let len = (layout.strip_blks * block_len) as usize;
+        let mut parity =
+            DmaBuf::new(len, self.bdev.alignment()).context(ParityAlloc {})?;
+        let mut buf =
+            DmaBuf::new(len, self.bdev.alignment()).context(ParityAlloc {})?;
+
+        let first = region * PARITY_DIRTY_REGION_STRIPES;
+        let last = std::cmp::min(first + PARITY_DIRTY_REGION_STRIPES, stripes);
+        for stripe in first .. last {
+            let offset = (layout.child_lba(stripe, 0) + self.data_ent_offset)
+                * block_len;
+
+            parity.fill(0);
+            for data_col in 0 .. layout.data_columns() {
+                let column = layout.child_column(stripe, data_col);
+                let handle = &columns[column].as_ref().unwrap().handle;
+                handle.read_at(offset, &mut buf).await.context(ColumnIo {
+                    column,
+                })?;
+                xor_into(parity.as_mut_slice(), buf.as_slice());
+            }
+
+            let column = layout.parity_column(stripe);
+            let handle = &columns[column].as_ref().unwrap().handle;
+            handle.write_at(offset, &parity).await.context(ColumnIo {
+                column,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Save the dirty map for the writes waiting for it, on the management
+    /// core, until none are left. Regions without writes in progress are
+    /// kept in the map unless it grows too large, so that writes to them
+    /// need not wait for the map to be saved again.
+    async fn save_dirty_regions(&mut self) {
+        loop {
+            let (regions, waiters) = {
+                let layout = self.parity.as_ref().unwrap();
+                let mut state = layout.dirty.state.lock().unwrap();
+                if state.waiters.is_empty() {
+                    state.saving = false;
+                    return;
+                }
+                let waiters = std::mem::take(&mut state.waiters);
+                let mut regions =
+                    state.writers.keys().copied().collect::<HashSet<_>>();
+                if regions.len() + state.saved.len() <= PARITY_DIRTY_MAX_REGIONS
+                {
+                    regions.extend(state.saved.iter().copied());
+                }
+                (regions, waiters)
+            };
+
+            let mut map = regions.iter().copied().collect::<Vec<_>>();
+            map.sort_unstable();
+            let saved = match self.save_dirty_map(map).await {
+                Ok(_) => true,
+                Err(e) => {
+                    error!("{}", e);
+                    false
+                }
+            };
+
+            {
+                let layout = self.parity.as_ref().unwrap();
+                let mut state = layout.dirty.state.lock().unwrap();
+                if saved {
+                    state.saved = regions;
+                } else {
+                    // the children hold either map
+                    state.saved.retain(|r| regions.contains(r));
+                }
+            }
+
+            waiters
+                .into_iter()
+                .for_each(|(thread, sender)| send_on(thread, sender, saved));
+        }
+    }
+
+    /// save the dirty map on all children that are in sync, it is an error
+    /// only when none of them could be written to
+    async fn save_dirty_map(&mut self, regions: Vec<u64>) -> Result<(), Error> {
+        let config = NexusConfig::DirtyStripes(DirtyStripesState {
+            region_stripes: PARITY_DIRTY_REGION_STRIPES,
+            regions,
+        });
+        let now = SystemTime::now();
+        let mut saved = false;
+        let mut error = None;
+
+        for child in self
+            .children
+            .iter_mut()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            match child.save_dirty_stripes(&config, &now).await {
+                Ok(_) => saved = true,
+                Err(e) => {
+                    error!(
+                        "{}: failed to save the dirty stripes on child {}: {}",
+                        self.name, child.name, e
+                    );
+                    error = Some(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) if !saved => Err(e).context(SaveDirtyStripes {
+                name: self.name.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
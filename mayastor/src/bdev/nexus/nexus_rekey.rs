@@ -0,0 +1,498 @@
+//!
+//! Rotation of the key of a published nexus. The nexus is re-encrypted with
+//! the new key in the background, one segment at a time, by reading the
+//! segment through the crypto vbdev with the current key and writing it
+//! through the crypto vbdev with the new key. The router shared in place of
+//! the nexus sends the IO below the watermark to the new key, and holds back
+//! the IO to the segment being copied.
+//!
+//! Before a segment is written with the new key, the watermark and a check
+//! value of each block of the segment are saved in the encryption marker of
+//! the nexus. When the nexus is published again after a restart, the
+//! rotation resumes at the watermark, and the check values tell which blocks
+//! of the segment had been written with the new key before the restart.
+//! Once written, the watermark past the segment is saved before the IO to
+//! the segment is let through to the new key.
+//!
+//! Once all segments are copied, the marker is switched to the new key and
+//! the crypto vbdev with the current key is destroyed.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use futures::channel::oneshot;
+use nix::errno::Errno;
+use snafu::{ResultExt, Snafu};
+
+use rpc::mayastor::NexusKeyRotation;
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{
+            Error,
+            KeyRotationInProgress,
+            Nexus,
+            NotEncrypted,
+            RotateKey,
+        },
+        nexus_crypto::key_matches,
+        nexus_key_provider::EncryptionKey,
+        nexus_key_router::KeyRouter,
+        nexus_metadata_content::{EncryptionState, KeyRotationState},
+    },
+    core::{BdevHandle, CoreError, DmaBuf, DmaError, RangeContext, Reactors},
+};
+
+/// size of the segments the nexus is re-encrypted in
+pub const KEY_ROTATION_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum KeyRotationError {
+    #[snafu(display("Failed to open key router {}", name))]
+    OpenRouter { source: Errno, name: String },
+    #[snafu(display("Failed to open crypto bdev {}", name))]
+    OpenCryptoBdev { source: CoreError, name: String },
+    #[snafu(display(
+        "Failed to allocate buffer for segment at block {}",
+        blk
+    ))]
+    SegmentAlloc { source: DmaError, blk: u64 },
+    #[snafu(display(
+        "Failed to lock LBA range at block {}, len {}",
+        blk,
+        len
+    ))]
+    RangeLock { source: Errno, blk: u64, len: u64 },
+    #[snafu(display(
+        "Failed to unlock LBA range at block {}, len {}",
+        blk,
+        len
+    ))]
+    RangeUnlock { source: Errno, blk: u64, len: u64 },
+    #[snafu(display("Failed to read segment at block {}", blk))]
+    ReadSegment { source: CoreError, blk: u64 },
+    #[snafu(display("Failed to write segment at block {}", blk))]
+    WriteSegment { source: CoreError, blk: u64 },
+    #[snafu(display("Block {} decrypts with neither key", blk))]
+    CorruptBlock { blk: u64 },
+    #[snafu(display("Failed to remove the current key from the key router"))]
+    ReconfigureRouter { source: Errno },
+}
+
+/// Handle of the job rotating the key of the nexus.
+#[derive(Debug)]
+pub(crate) struct KeyRotation {
+    /// asks the job to stop after the segment it is copying
+    stop: Arc<AtomicBool>,
+    /// cleared when the job has ended
+    running: Arc<AtomicBool>,
+    /// fires when the job has ended
+    done: oneshot::Receiver<()>,
+}
+
+/// the blocks to copy and the crypto vbdevs to copy them with
+struct RotationHandles<'a> {
+    router: &'a KeyRouter,
+    from: BdevHandle,
+    to: BdevHandle,
+}
+
+impl Nexus {
+    /// Start re-encrypting the nexus with the key in the background. The
+    /// nexus must be published with a key. Rotating to the key the nexus is
+    /// encrypted with or being rotated to already is a no-op, other than
+    /// restarting a rotation that stopped on an error.
+    pub async fn rotate_key(
+        &mut self,
+        key: EncryptionKey,
+    ) -> Result<(), Error> {
+        let mut state = match (self.crypto.as_ref(), self.encryption.as_ref()) {
+            (Some(_), Some(state)) => state.clone(),
+            _ => {
+                return NotEncrypted {
+                    name: self.name.clone(),
+                }
+                .fail()
+            }
+        };
+        let uuid = self.bdev.uuid_as_string();
+
+        if let Some(rotation) = state.rotation.as_ref() {
+            if !key_matches(&key, &uuid, &rotation.key_id, &rotation.key_check)
+            {
+                return KeyRotationInProgress {
+                    key_id: rotation.key_id.clone(),
+                    name: self.name.clone(),
+                }
+                .fail();
+            }
+            if !self.key_rotation_running() {
+                self.start_key_rotation(key);
+            }
+            return Ok(());
+        }
+
+        if key_matches(&key, &uuid, &state.key_id, &state.key_check) {
+            return Ok(());
+        }
+
+        info!(
+            "{}: rotating key {} to {} using {}",
+            self.name,
+            state.key_id,
+            key.id(),
+            key.cipher()
+        );
+        self.add_crypto_layer(&key, 0).await?;
+
+        state.generation += 1;
+        state.rotation = Some(KeyRotationState {
+            key_id: key.id().to_string(),
+            cipher: key.cipher().to_string(),
+            key_check: key.check_value(&uuid),
+            watermark: 0,
+            pending: Vec::new(),
+        });
+        if let Err(e) = self.save_encryption(state.clone()).await {
+            if let Err(e) = self.remove_crypto_layer().await {
+                error!("{}", e);
+            }
+            return Err(e);
+        }
+        self.encryption = Some(state);
+
+        self.start_key_rotation(key);
+        Ok(())
+    }
+
+    /// progress of the rotation of the key, as reported over gRPC
+    pub fn key_rotation(&self) -> Option<NexusKeyRotation> {
+        let rotation = self.encryption.as_ref()?.rotation.as_ref()?;
+        let num_blocks = self.bdev.num_blocks();
+        let progress = if num_blocks == 0 {
+            0
+        } else {
+            (rotation.watermark * 100 / num_blocks) as u32
+        };
+
+        Some(NexusKeyRotation {
+            key_id: rotation.key_id.clone(),
+            progress,
+            running: self.key_rotation_running(),
+        })
+    }
+
+    fn key_rotation_running(&self) -> bool {
+        self.crypto
+            .as_ref()
+            .and_then(|c| c.rotation.as_ref())
+            .map_or(false, |r| r.running.load(Ordering::SeqCst))
+    }
+
+    /// spawn the job copying the nexus to the key of the second layer
+    pub(crate) fn start_key_rotation(&mut self, key: EncryptionKey) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(AtomicBool::new(true));
+        let (s, r) = oneshot::channel::<()>();
+        self.crypto.as_mut().unwrap().rotation = Some(KeyRotation {
+            stop: Arc::clone(&stop),
+            running: Arc::clone(&running),
+            done: r,
+        });
+
+        // the nexus and its share handle outlive the job, as they are only
+        // destroyed after it has stopped
+        let ctx = self.as_ptr();
+        Reactors::current().spawn_local(async move {
+            let nexus = unsafe { Nexus::from_raw(ctx) };
+            match nexus.rotate_segments(&key, &stop).await {
+                Ok(true) => {
+                    info!("{}: key rotated to {}", nexus.name, key.id())
+                }
+                Ok(false) => info!("{}: key rotation stopped", nexus.name),
+                Err(e) => error!("{}: key rotation failed: {}", nexus.name, e),
+            }
+            running.store(false, Ordering::SeqCst);
+            let _ = s.send(());
+        });
+    }
+
+    /// stop the job rotating the key, if any, and wait for it to end
+    pub(crate) async fn stop_key_rotation(&mut self) {
+        if let Some(rotation) =
+            self.crypto.as_mut().and_then(|c| c.rotation.take())
+        {
+            rotation.stop.store(true, Ordering::SeqCst);
+            let _ = rotation.done.await;
+        }
+    }
+
+    /// abandon the layer with the new key before anything was written to it
+    async fn remove_crypto_layer(&mut self) -> Result<(), Error> {
+        let stack = self.crypto.as_mut().unwrap();
+        let layer = stack.layers.pop().unwrap();
+        if let Err(source) = stack.router.remove_second_base().await {
+            stack.layers.push(layer);
+            return Err(Error::DestroyKeyRouter {
+                source,
+                name: self.name.clone(),
+            });
+        }
+        self.destroy_crypto_layer(layer).await
+    }
+
+    /// Copy the segments from the watermark on to the new key and switch
+    /// the nexus over to it. Returns false when asked to stop before all
+    /// segments were copied.
+    async fn rotate_segments(
+        &mut self,
+        key: &EncryptionKey,
+        stop: &AtomicBool,
+    ) -> Result<bool, Error> {
+        let stack = self.crypto.as_ref().unwrap();
+        // the router outlives the job
+        let router = unsafe { &*(&*stack.router as *const KeyRouter) };
+        let open = |name: &str, read_write: bool| {
+            BdevHandle::open(name, read_write, false)
+                .context(OpenCryptoBdev {
+                    name,
+                })
+                .context(RotateKey {
+                    name: self.name.clone(),
+                })
+        };
+        let handles = RotationHandles {
+            router,
+            from: open(&stack.layers[0].crypto, false)?,
+            to: open(&stack.layers[1].crypto, true)?,
+        };
+        let desc = router
+            .open()
+            .context(OpenRouter {
+                name: router.name(),
+            })
+            .context(RotateKey {
+                name: self.name.clone(),
+            })?;
+        let ch = desc.get_channel().expect("Failed to get router channel");
+        let segment_blks = KEY_ROTATION_SEGMENT_SIZE / router.block_len();
+
+        let mut state = self.encryption.clone().unwrap();
+        loop {
+            let blk = state.rotation.as_ref().unwrap().watermark;
+            if blk >= router.num_blocks() {
+                break;
+            }
+            if stop.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            let len = std::cmp::min(segment_blks, router.num_blocks() - blk);
+
+            // Wait for LBA range to be locked.
+            // This prevents writes being issued to this LBA range whilst it
+            // is being copied, reads are held back by the fence.
+            let mut ctx = RangeContext::new(blk, len);
+            desc.lock_lba_range(&mut ctx, &ch)
+                .await
+                .context(RangeLock {
+                    blk,
+                    len,
+                })
+                .context(RotateKey {
+                    name: self.name.clone(),
+                })?;
+            router.fence(blk).await;
+
+            let result = self.rotate_segment(&handles, key, &mut state).await;
+
+            router.unfence();
+            desc.unlock_lba_range(&mut ctx, &ch)
+                .await
+                .context(RangeUnlock {
+                    blk,
+                    len,
+                })
+                .context(RotateKey {
+                    name: self.name.clone(),
+                })?;
+
+            // the next rotation picks up where this one left off
+            self.encryption = Some(state.clone());
+            result?;
+        }
+
+        self.finish_key_rotation(handles, state).await?;
+        Ok(true)
+    }
+
+    /// Copy the segment at the watermark to the new key and move the
+    /// watermark past it, the segment must be locked and fenced.
+    async fn rotate_segment(
+        &mut self,
+        h: &RotationHandles<'_>,
+        key: &EncryptionKey,
+        state: &mut EncryptionState,
+    ) -> Result<(), Error> {
+        let uuid = self.bdev.uuid_as_string();
+        let block_len = h.router.block_len();
+        let rotation = state.rotation.as_ref().unwrap();
+        let blk = rotation.watermark;
+        let len = std::cmp::min(
+            KEY_ROTATION_SEGMENT_SIZE / block_len,
+            h.router.num_blocks() - blk,
+        );
+
+        let mut buf =
+            h.to.dma_malloc((len * block_len) as usize)
+                .context(SegmentAlloc {
+                    blk,
+                })
+                .context(RotateKey {
+                    name: self.name.clone(),
+                })?;
+
+        if rotation.pending.len() as u64 == len {
+            // a restart interrupted the write of this segment
+            self.recover_segment(h, key, rotation, &mut buf)
+                .await
+                .context(RotateKey {
+                    name: self.name.clone(),
+                })?;
+        } else {
+            h.from
+                .read_at(blk * block_len, &mut buf)
+                .await
+                .context(ReadSegment {
+                    blk,
+                })
+                .context(RotateKey {
+                    name: self.name.clone(),
+                })?;
+
+            let pending = buf
+                .as_slice()
+                .chunks(block_len as usize)
+                .enumerate()
+                .map(|(i, data)| key.block_check(&uuid, blk + i as u64, data))
+                .collect();
+            state.generation += 1;
+            state.rotation.as_mut().unwrap().pending = pending;
+            self.save_encryption(state.clone()).await?;
+        }
+
+        h.to.write_at(blk * block_len, &buf)
+            .await
+            .context(WriteSegment {
+                blk,
+            })
+            .context(RotateKey {
+                name: self.name.clone(),
+            })?;
+
+        // Writes to the segment go to the new key once it is unlocked, after
+        // which its blocks no longer match the check values, so the
+        // watermark is saved first. Should that fail, the segment is routed
+        // to the new key all the same, as that is what it is encrypted with.
+        h.router.set_watermark(blk + len);
+        let rotation = state.rotation.as_mut().unwrap();
+        rotation.watermark = blk + len;
+        rotation.pending.clear();
+        state.generation += 1;
+        self.save_encryption(state.clone()).await
+    }
+
+    /// Read the plaintext of a segment the write of which to the new key
+    /// was interrupted, taking each block from whichever key it decrypts to
+    /// the saved check value with.
+    async fn recover_segment(
+        &self,
+        h: &RotationHandles<'_>,
+        key: &EncryptionKey,
+        rotation: &KeyRotationState,
+        buf: &mut DmaBuf,
+    ) -> Result<(), KeyRotationError> {
+        let uuid = self.bdev.uuid_as_string();
+        let block_len = h.router.block_len() as usize;
+        let blk = rotation.watermark;
+
+        h.to.read_at(blk * block_len as u64, buf).await.context(
+            ReadSegment {
+                blk,
+            },
+        )?;
+
+        let mut current: Option<DmaBuf> = None;
+        for (i, check) in rotation.pending.iter().enumerate() {
+            let range = i * block_len .. (i + 1) * block_len;
+            let lba = blk + i as u64;
+            if key.block_check(&uuid, lba, &buf.as_slice()[range.clone()])
+                == *check
+            {
+                continue;
+            }
+
+            // the block has not been written with the new key
+            if current.is_none() {
+                let mut from =
+                    h.from.dma_malloc(buf.len()).context(SegmentAlloc {
+                        blk,
+                    })?;
+                h.from
+                    .read_at(blk * block_len as u64, &mut from)
+                    .await
+                    .context(ReadSegment {
+                        blk,
+                    })?;
+                current = Some(from);
+            }
+            let data = &current.as_ref().unwrap().as_slice()[range.clone()];
+            if key.block_check(&uuid, lba, data) != *check {
+                return Err(KeyRotationError::CorruptBlock {
+                    blk: lba,
+                });
+            }
+            buf.as_mut_slice()[range].copy_from_slice(data);
+        }
+
+        debug!("{}: recovered segment at block {}", self.name, blk);
+        Ok(())
+    }
+
+    /// Switch the marker over to the new key and destroy the crypto vbdev
+    /// with the current key, all blocks are routed to the new key by now.
+    async fn finish_key_rotation(
+        &mut self,
+        h: RotationHandles<'_>,
+        mut state: EncryptionState,
+    ) -> Result<(), Error> {
+        let rotation = state.rotation.take().unwrap();
+        state.generation += 1;
+        state.key_id = rotation.key_id;
+        state.cipher = rotation.cipher;
+        state.key_check = rotation.key_check;
+        self.save_encryption(state.clone()).await?;
+        self.encryption = Some(state);
+
+        // the crypto vbdev cannot be destroyed while it is open
+        let RotationHandles {
+            router,
+            from,
+            to,
+        } = h;
+        drop(from);
+        drop(to);
+
+        router
+            .remove_first_base()
+            .await
+            .context(ReconfigureRouter {})
+            .context(RotateKey {
+                name: self.name.clone(),
+            })?;
+        let layer = self.crypto.as_mut().unwrap().layers.remove(0);
+        self.destroy_crypto_layer(layer).await
+    }
+}
@@ -0,0 +1,654 @@
+//!
+//! Asynchronous children of the nexus. Unlike a regular child, an async child
+//! does not take part in the IO path of the nexus: it is never read from and
+//! writes to the nexus complete without waiting for it. This allows a replica
+//! in another zone to be kept as a disaster recovery copy without putting its
+//! round-trip latency on every write.
+//!
+//! Every write that completes on the nexus is recorded in the journal of each
+//! async child. The journal tracks the regions that were written since they
+//! were last copied, together with the time they were first written, so its
+//! size is bounded by the size of the nexus no matter how far the child falls
+//! behind. A poller copies the oldest regions, as read through the nexus, to
+//! the async children in the background. A region is removed from the journal
+//! before it is read, so a write that races with the copy records the region
+//! again.
+//!
+//! Once an async child has caught up it can be promoted to a regular child.
+//! It first receives all writes alongside the other children without being
+//! read from, and becomes a regular child once the writes that completed
+//! before that point have been copied.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    os::raw::c_void,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use snafu::{ResultExt, Snafu};
+
+use rpc::mayastor::AsyncChild as RpcAsyncChild;
+use spdk_sys::{spdk_poller, spdk_poller_register, spdk_poller_unregister};
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{
+            CreateChild,
+            DestroyChild,
+            Error,
+            Nexus,
+            NexusStatus,
+            OpenChild,
+            ReplicateChild,
+        },
+        nexus_channel::DREvent,
+        nexus_child::NexusChild,
+        nexus_parity::Yield,
+    },
+    core::{Bdev, BdevHandle, CoreError, DmaError, Reactors},
+    nexus_uri::{bdev_create, bdev_destroy},
+};
+
+/// granularity at which writes are tracked for async children
+pub const REPLICATION_REGION_SIZE: u64 = 1024 * 1024;
+
+/// interval in microseconds at which regions are copied
+const REPLICATION_INTERVAL_US: u64 = 10_000;
+
+/// number of regions copied to each async child per interval
+const REPLICATION_BATCH: usize = 16;
+
+#[derive(Debug, Snafu)]
+pub enum ReplicationError {
+    #[snafu(display("Failed to allocate buffer for replication"))]
+    ReplicationAlloc { source: DmaError },
+    #[snafu(display("Failed to open nexus {} for replication", name))]
+    OpenNexus { source: CoreError, name: String },
+    #[snafu(display("Failed to read region {} from the nexus", region))]
+    ReadRegion { source: CoreError, region: u64 },
+    #[snafu(display("Failed to write region {} to {}", region, child))]
+    WriteRegion {
+        source: CoreError,
+        region: u64,
+        child: String,
+    },
+    #[snafu(display("Async child {} is not open", child))]
+    ChildClosed { child: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AsyncChildState {
+    /// writes are copied in the background
+    Replicating,
+    /// the child receives all writes and is about to become a regular child
+    Promoting,
+}
+
+/// regions of the nexus written since they were last copied to a child
+#[derive(Debug)]
+pub(crate) struct Journal {
+    dirty: Mutex<Dirty>,
+    region_blks: u64,
+    num_blocks: u64,
+}
+
+/// dirty regions in the order in which they were first written, preceded by
+/// the regions of the initial copy which have yet to be copied
+#[derive(Debug, Default)]
+struct Dirty {
+    queue: VecDeque<(u64, Instant)>,
+    queued: HashSet<u64>,
+    /// next region of the initial copy and when the copy started
+    full_copy: Option<(u64, Instant)>,
+}
+
+impl Journal {
+    /// create a journal in which every region is dirty, the child has yet to
+    /// receive a full copy
+    fn new(region_blks: u64, num_blocks: u64) -> Self {
+        let dirty = Dirty {
+            full_copy: Some((0, Instant::now())),
+            ..Default::default()
+        };
+
+        Self {
+            dirty: Mutex::new(dirty),
+            region_blks,
+            num_blocks,
+        }
+    }
+
+    fn num_regions(&self) -> u64 {
+        (self.num_blocks + self.region_blks - 1) / self.region_blks
+    }
+
+    /// record a write to the given range of nexus blocks
+    fn record(&self, offset: u64, num_blocks: u64) {
+        if num_blocks == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut dirty = self.dirty.lock().unwrap();
+        // regions the initial copy has yet to reach are copied by it
+        let first = dirty.full_copy.map_or(self.num_regions(), |(r, _)| r);
+        for region in offset / self.region_blks
+            ..= (offset + num_blocks - 1) / self.region_blks
+        {
+            if region < first && dirty.queued.insert(region) {
+                dirty.queue.push_back((region, now));
+            }
+        }
+    }
+
+    /// remove up to `count` of the regions that have been dirty the longest
+    fn take(&self, count: usize) -> Vec<(u64, Instant)> {
+        let mut dirty = self.dirty.lock().unwrap();
+        let mut regions = Vec::with_capacity(count);
+
+        // the initial copy started before any of the queued regions were
+        // written
+        if let Some((next, since)) = dirty.full_copy {
+            let end = std::cmp::min(next + count as u64, self.num_regions());
+            regions.extend((next .. end).map(|r| (r, since)));
+            dirty.full_copy = if end < self.num_regions() {
+                Some((end, since))
+            } else {
+                None
+            };
+        }
+
+        while regions.len() < count {
+            match dirty.queue.pop_front() {
+                Some((region, since)) => {
+                    dirty.queued.remove(&region);
+                    regions.push((region, since));
+                }
+                None => break,
+            }
+        }
+
+        regions
+    }
+
+    /// put back a region that failed to be copied in front of the others,
+    /// unless it has been written again in the meantime
+    fn requeue(&self, region: u64, since: Instant) {
+        let mut dirty = self.dirty.lock().unwrap();
+        if dirty.queued.insert(region) {
+            dirty.queue.push_front((region, since));
+        }
+    }
+
+    /// range of nexus blocks covered by a region
+    fn blocks(&self, region: u64) -> (u64, u64) {
+        let offset = region * self.region_blks;
+        (
+            offset,
+            std::cmp::min(self.region_blks, self.num_blocks - offset),
+        )
+    }
+
+    /// number of blocks that have yet to be copied and for how long the
+    /// oldest of them has been waiting
+    fn lag(&self) -> (u64, Duration) {
+        let dirty = self.dirty.lock().unwrap();
+        let queued = dirty
+            .queue
+            .iter()
+            .map(|(r, _)| self.blocks(*r).1)
+            .sum::<u64>();
+        let (full_copy, oldest) = match dirty.full_copy {
+            Some((next, since)) => {
+                (self.num_blocks - next * self.region_blks, Some(since))
+            }
+            None => (0, dirty.queue.front().map(|(_, since)| *since)),
+        };
+        let age = oldest.map_or(Duration::default(), |since| since.elapsed());
+        (queued + full_copy, age)
+    }
+
+    fn is_empty(&self) -> bool {
+        let dirty = self.dirty.lock().unwrap();
+        dirty.full_copy.is_none() && dirty.queue.is_empty()
+    }
+}
+
+/// child that is kept up to date in the background
+#[derive(Debug)]
+pub struct AsyncChild {
+    pub(crate) child: NexusChild,
+    pub(crate) state: AsyncChildState,
+    journal: Arc<Journal>,
+}
+
+/// async children of a nexus and the poller which copies to them
+#[derive(Debug)]
+pub struct Replication {
+    pub(crate) children: Vec<AsyncChild>,
+    /// journals of the children, which are written on IO completion on any
+    /// of the cores
+    journals: RwLock<Vec<Arc<Journal>>>,
+    poller: *mut spdk_poller,
+    /// a pass of the poller is in progress, or the children are being changed
+    busy: AtomicBool,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self {
+            children: Vec::new(),
+            journals: RwLock::new(Vec::new()),
+            poller: ptr::null_mut(),
+            busy: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Replication {
+    /// wait for a pass of the poller to finish and keep it from starting
+    async fn lock(&self) {
+        while self.busy.swap(true, Ordering::SeqCst) {
+            Yield::default().await;
+        }
+    }
+
+    fn unlock(&self) {
+        self.busy.store(false, Ordering::SeqCst);
+    }
+
+    fn update_journals(&self) {
+        *self.journals.write().unwrap() = self
+            .children
+            .iter()
+            .map(|c| Arc::clone(&c.journal))
+            .collect();
+    }
+}
+
+impl Nexus {
+    /// Add a child which receives the data of the nexus in the background.
+    /// The child starts out empty, so all of the data is copied to it first.
+    pub async fn add_async_child(&mut self, uri: &str) -> Result<(), Error> {
+        // an async child holds a plain copy of the nexus
+        if self.parity.is_some() {
+            return Err(Error::AsyncChildNotSupported {
+                name: self.name.clone(),
+            });
+        }
+
+        let name = bdev_create(&uri).await.context(CreateChild {
+            name: self.name.clone(),
+        })?;
+
+        let bdev = match Bdev::lookup_by_name(&name) {
+            Some(bdev) => bdev,
+            None => {
+                return Err(Error::ChildMissing {
+                    child: name,
+                    name: self.name.clone(),
+                })
+            }
+        };
+
+        if bdev.block_len() != self.bdev.block_len()
+            || self.min_num_blocks() > bdev.num_blocks()
+        {
+            if let Err(err) = bdev_destroy(uri).await {
+                error!(
+                    "Failed to destroy async child with wrong geometry: {}",
+                    err
+                );
+            }
+            return Err(Error::ChildGeometry {
+                child: name,
+                name: self.name.clone(),
+            });
+        }
+
+        let mut child =
+            NexusChild::new(uri.to_owned(), self.name.clone(), Some(bdev));
+        if let Err(e) = child.open(self.child_size()) {
+            if let Err(err) = bdev_destroy(uri).await {
+                error!("Failed to destroy async child: {}", err);
+            }
+            return Err(e).context(OpenChild {
+                child: uri.to_owned(),
+                name: self.name.clone(),
+            });
+        }
+
+        let region_blks =
+            REPLICATION_REGION_SIZE / u64::from(self.bdev.block_len());
+        let journal =
+            Arc::new(Journal::new(region_blks, self.bdev.num_blocks()));
+
+        self.replication.lock().await;
+        self.replication.children.push(AsyncChild {
+            child,
+            state: AsyncChildState::Replicating,
+            journal,
+        });
+        self.replication.update_journals();
+        self.replication.unlock();
+
+        if self.replication.poller.is_null() {
+            self.replication.poller = unsafe {
+                spdk_poller_register(
+                    Some(Self::replication_poll),
+                    self.as_ptr(),
+                    REPLICATION_INTERVAL_US,
+                )
+            };
+        }
+
+        info!("{}: added async child {}", self.name, uri);
+        Ok(())
+    }
+
+    /// Remove an async child, returns false when there is no async child
+    /// with the given uri
+    pub(crate) async fn remove_async_child(
+        &mut self,
+        uri: &str,
+    ) -> Result<bool, Error> {
+        if !self
+            .replication
+            .children
+            .iter()
+            .any(|c| c.child.name == uri)
+        {
+            return Ok(false);
+        }
+
+        self.replication.lock().await;
+        let idx = self
+            .replication
+            .children
+            .iter()
+            .position(|c| c.child.name == uri)
+            .unwrap();
+        let mut replica = self.replication.children.remove(idx);
+        self.replication.update_journals();
+        self.replication.unlock();
+
+        if replica.state == AsyncChildState::Promoting {
+            self.reconfigure(DREvent::ChildRemove).await;
+        }
+
+        replica.child.close();
+        replica.child.destroy().await.context(DestroyChild {
+            name: self.name.clone(),
+            child: uri,
+        })?;
+
+        Ok(true)
+    }
+
+    /// Turn an async child that has caught up into a regular child
+    pub async fn promote_async_child(
+        &mut self,
+        uri: &str,
+    ) -> Result<NexusStatus, Error> {
+        let replica = match self
+            .replication
+            .children
+            .iter_mut()
+            .find(|c| c.child.name == uri)
+        {
+            Some(replica) => replica,
+            None => {
+                return Err(Error::ChildNotFound {
+                    child: uri.to_owned(),
+                    name: self.name.clone(),
+                })
+            }
+        };
+
+        let (blocks, _) = replica.journal.lag();
+        if blocks != 0 {
+            return Err(Error::AsyncChildLagging {
+                child: uri.to_owned(),
+                name: self.name.clone(),
+                blocks,
+            });
+        }
+
+        // from now on the child receives all writes directly
+        replica.state = AsyncChildState::Promoting;
+        let journal = Arc::clone(&replica.journal);
+        self.reconfigure(DREvent::ChildOnline).await;
+
+        // copy the writes that completed before all channels were updated
+        self.replication.lock().await;
+        let result = self.replicate_all(uri, &journal).await;
+
+        let idx = self
+            .replication
+            .children
+            .iter()
+            .position(|c| c.child.name == uri)
+            .unwrap();
+
+        if let Err(e) = result {
+            self.replication.children[idx].state = AsyncChildState::Replicating;
+            self.replication.unlock();
+            self.reconfigure(DREvent::ChildRemove).await;
+            return Err(e).context(ReplicateChild {
+                child: uri.to_owned(),
+                name: self.name.clone(),
+            });
+        }
+
+        let replica = self.replication.children.remove(idx);
+        self.replication.update_journals();
+        self.replication.unlock();
+
+        self.children.push(replica.child);
+        self.child_count += 1;
+
+        if let Err(e) = self.sync_labels().await {
+            error!("{}: failed to sync labels {:?}", self.name, e);
+        }
+
+        self.reconfigure(DREvent::ChildOnline).await;
+        info!("{}: promoted async child {}", self.name, uri);
+        Ok(self.status())
+    }
+
+    /// close and destroy all async children
+    pub(crate) async fn destroy_async_children(&mut self) {
+        if !self.replication.poller.is_null() {
+            unsafe { spdk_poller_unregister(&mut self.replication.poller) };
+        }
+
+        self.replication.lock().await;
+        let children = std::mem::take(&mut self.replication.children);
+        self.replication.update_journals();
+        self.replication.unlock();
+
+        for mut replica in children {
+            replica.child.close();
+            if let Err(e) = replica.child.destroy().await {
+                error!(
+                    "{}: failed to destroy async child {}: {}",
+                    self.name, replica.child.name, e
+                );
+            }
+        }
+    }
+
+    /// record a write that completed on the nexus in the journal of all async
+    /// children, this is called on the core the IO was submitted on
+    pub(crate) fn replication_record(&self, offset: u64, num_blocks: u64) {
+        for journal in self.replication.journals.read().unwrap().iter() {
+            journal.record(offset, num_blocks);
+        }
+    }
+
+    /// handles of the async children that receive all writes directly
+    pub(crate) fn promoting_handles(&self) -> Vec<BdevHandle> {
+        self.replication
+            .children
+            .iter()
+            .filter(|c| c.state == AsyncChildState::Promoting)
+            .filter_map(|c| {
+                c.child.get_descriptor().and_then(BdevHandle::try_from).ok()
+            })
+            .collect()
+    }
+
+    /// state of the async children as reported over gRPC
+    pub fn async_children(&self) -> Vec<RpcAsyncChild> {
+        self.replication
+            .children
+            .iter()
+            .map(|c| {
+                let (lag_blocks, lag) = c.journal.lag();
+                RpcAsyncChild {
+                    uri: c.child.name.clone(),
+                    promoting: c.state == AsyncChildState::Promoting,
+                    lag_blocks,
+                    lag_seconds: lag.as_secs(),
+                }
+            })
+            .collect()
+    }
+
+    /// called periodically to copy dirty regions to the async children
+    extern "C" fn replication_poll(ctx: *mut c_void) -> i32 {
+        let nexus = unsafe { Nexus::from_raw(ctx) };
+        if nexus.replication.busy.swap(true, Ordering::SeqCst) {
+            return 0;
+        }
+
+        // the async children are not changed while the pass is in progress
+        Reactors::current().spawn_local(async move {
+            let nexus = unsafe { Nexus::from_raw(ctx) };
+            for replica in nexus.replication.children.iter() {
+                if let Err(e) =
+                    nexus.replicate(&replica.child.name, &replica.journal).await
+                {
+                    warn!("{}: {}", nexus.name, e);
+                }
+            }
+            nexus.replication.unlock();
+        });
+
+        1
+    }
+
+    /// copy the oldest regions of the journal to the async child
+    async fn replicate(
+        &self,
+        child: &str,
+        journal: &Journal,
+    ) -> Result<(), ReplicationError> {
+        let regions = journal.take(REPLICATION_BATCH);
+        if regions.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.copy_regions(child, journal, &regions).await;
+        if let Err((copied, e)) = result {
+            regions[copied ..]
+                .iter()
+                .rev()
+                .for_each(|(region, since)| journal.requeue(*region, *since));
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// copy regions until the journal is empty
+    async fn replicate_all(
+        &self,
+        child: &str,
+        journal: &Journal,
+    ) -> Result<(), ReplicationError> {
+        while !journal.is_empty() {
+            self.replicate(child, journal).await?;
+        }
+        Ok(())
+    }
+
+    /// copy the given regions, on failure the number of regions that were
+    /// copied is returned alongside the error
+    async fn copy_regions(
+        &self,
+        child: &str,
+        journal: &Journal,
+        regions: &[(u64, Instant)],
+    ) -> Result<(), (usize, ReplicationError)> {
+        let nexus = BdevHandle::open(&self.name, false, false)
+            .context(OpenNexus {
+                name: self.name.clone(),
+            })
+            .map_err(|e| (0, e))?;
+
+        let target = self
+            .replication
+            .children
+            .iter()
+            .find(|c| c.child.name == child)
+            .and_then(|c| c.child.get_descriptor().ok())
+            .and_then(|d| BdevHandle::try_from(d).ok())
+            .ok_or_else(|| {
+                (
+                    0,
+                    ReplicationError::ChildClosed {
+                        child: child.to_owned(),
+                    },
+                )
+            })?;
+
+        for (idx, (region, _)) in regions.iter().enumerate() {
+            self.copy_region(&nexus, &target, child, journal, *region)
+                .await
+                .map_err(|e| (idx, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// read a region through the nexus and write it to the async child
+    async fn copy_region(
+        &self,
+        nexus: &BdevHandle,
+        target: &BdevHandle,
+        child: &str,
+        journal: &Journal,
+        region: u64,
+    ) -> Result<(), ReplicationError> {
+        let block_len = u64::from(self.bdev.block_len());
+        let (offset, num_blocks) = journal.blocks(region);
+
+        let mut buf = nexus
+            .dma_malloc((num_blocks * block_len) as usize)
+            .context(ReplicationAlloc {})?;
+        nexus.read_at(offset * block_len, &mut buf).await.context(
+            ReadRegion {
+                region,
+            },
+        )?;
+
+        target
+            .write_at((offset + self.data_ent_offset) * block_len, &buf)
+            .await
+            .context(WriteRegion {
+                region,
+                child,
+            })?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,465 @@
+//!
+//! NVMe persistent reservations of the namespace of a nexus exported over
+//! nvmf. The reservation commands themselves are handled by the nvmf target,
+//! which keeps the registrants and the reservation of a namespace in a JSON
+//! file in the `ptpl_dir` of the nexus options while "persist through power
+//! loss" (PTPL) has been activated by a host.
+//!
+//! When the nexus is shared, the latest reservation saved in the MayaMeta
+//! partition of its children is written to that file, so the target restores
+//! it as the namespace is added. While shared, the target calls back into the
+//! nexus whenever a command changed the reservation and only completes the
+//! command once the change has been saved on the children that are in sync.
+//! The reservation thus survives a restart of the nexus or its move to
+//! another node, as long as a child that held the latest save is still part
+//! of it. Without PTPL, registrations are not kept across a restart, as
+//! required by the specification.
+//!
+//! Each node exporting a nexus for multipath has a target of its own, which
+//! would not see the reservation commands received by the others. The
+//! commands are refused while the nexus is exported from several nodes.
+
+use std::{fs, io, os::raw::c_void, path::PathBuf, time::SystemTime};
+
+use futures::channel::oneshot;
+use nix::errno::Errno;
+use serde::Serialize;
+use snafu::ResultExt;
+
+use spdk_sys::{
+    spdk_nvmf_ns,
+    spdk_nvmf_ns_reservation_done_fn,
+    spdk_nvmf_ns_reservation_ops,
+    spdk_uuid,
+};
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{Error, Nexus, SaveReservation, WriteReservation},
+        nexus_child::{ChildStatus, NexusChild},
+        nexus_metadata::MetaDataError,
+        nexus_metadata_content::{
+            NexusConfig,
+            RegistrantState,
+            ReservationState,
+        },
+    },
+    core::{Bdev, Mthread, Reactors},
+    subsys::{AnaState, Config},
+};
+
+/// host registered with the reservation, as written by the target
+#[derive(Debug, Serialize)]
+struct PtplRegistrant {
+    rkey: u64,
+    host_uuid: String,
+}
+
+/// contents of the reservation file of the target
+#[derive(Debug, Serialize)]
+struct PtplFile {
+    ptpl: bool,
+    rtype: u32,
+    crkey: u64,
+    bdev_uuid: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    holder_uuid: String,
+    registrants: Vec<PtplRegistrant>,
+}
+
+impl PtplFile {
+    fn from_state(state: &ReservationState, bdev_uuid: String) -> Self {
+        Self {
+            ptpl: state.ptpl_activated,
+            rtype: state.rtype,
+            crkey: state.crkey,
+            bdev_uuid,
+            holder_uuid: state.holder_uuid.clone(),
+            registrants: state
+                .registrants
+                .iter()
+                .map(|r| PtplRegistrant {
+                    rkey: r.rkey,
+                    host_uuid: r.host_uuid.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// the reservation in the format of the file of the target
+pub(crate) fn ptpl_contents(
+    state: &ReservationState,
+    bdev_uuid: String,
+) -> String {
+    serde_json::to_string(&PtplFile::from_state(state, bdev_uuid)).unwrap()
+}
+
+/// command waiting for a change of the reservation to be saved
+#[derive(Debug)]
+struct Waiter {
+    done_fn: spdk_nvmf_ns_reservation_done_fn,
+    done_ctx: *mut c_void,
+}
+
+/// reservation of the shared nexus and the changes waiting to be saved
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    path: PathBuf,
+    /// generation of the latest reservation saved on the children
+    generation: u64,
+    /// reservation of the namespace after the latest change, not yet saved
+    pending: Option<ReservationState>,
+    /// commands that complete once the pending reservation has been saved
+    waiters: Vec<Waiter>,
+    /// a save to the children is in progress
+    saving: bool,
+    /// notified once the save in progress has finished
+    idle: Vec<oneshot::Sender<()>>,
+}
+
+/// format the host identifier the way the target does in its file
+fn host_uuid(hostid: &spdk_uuid) -> String {
+    uuid::Uuid::from_bytes(unsafe { hostid.u.raw })
+        .to_hyphenated()
+        .to_string()
+}
+
+/// the registrants and the reservation of the namespace
+pub(crate) unsafe fn namespace_reservation(
+    ns: *const spdk_nvmf_ns,
+) -> ReservationState {
+    let ns = &*ns;
+    let mut registrants = Vec::new();
+    let mut reg = ns.registrants.tqh_first;
+
+    while !reg.is_null() {
+        registrants.push(RegistrantState {
+            host_uuid: host_uuid(&(*reg).hostid),
+            rkey: (*reg).rkey,
+        });
+        reg = (*reg).link.tqe_next;
+    }
+
+    ReservationState {
+        generation: 0,
+        ptpl_activated: ns.ptpl_activated,
+        rtype: ns.rtype as u32,
+        crkey: ns.crkey,
+        holder_uuid: if ns.holder.is_null() {
+            String::new()
+        } else {
+            host_uuid(&(*ns.holder).hostid)
+        },
+        registrants,
+    }
+}
+
+impl NexusChild {
+    /// the latest reservation saved on the child, if any
+    async fn load_reservation(
+        &self,
+    ) -> Result<Option<ReservationState>, MetaDataError> {
+        match self
+            .get_latest_selected_config_object(|c| {
+                matches!(c, NexusConfig::Reservation(_))
+            })
+            .await?
+        {
+            Some(NexusConfig::Reservation(state)) => Ok(Some(state)),
+            _ => Ok(None),
+        }
+    }
+
+    /// replace the reservation saved on the child
+    async fn save_reservation(
+        &mut self,
+        config: &NexusConfig,
+        now: &SystemTime,
+    ) -> Result<(), MetaDataError> {
+        self.replace_selected_config_objects(config, now, |c| {
+            matches!(c, NexusConfig::Reservation(_))
+        })
+        .await
+    }
+}
+
+impl Nexus {
+    /// The nexus is exported from several nodes, each with a target of its
+    /// own, when it is not the only path to the namespace or controller IDs
+    /// have been set aside for the other nodes.
+    fn exported_from_several_nodes(&self) -> bool {
+        self.ana_state != AnaState::Optimized
+            || Config::get().nexus_opts.nvmf_cntlid_base != 0
+    }
+
+    /// callbacks with which the target takes the nexus along in the
+    /// reservation commands of the namespace
+    pub(crate) fn reservation_ops(
+        &self,
+    ) -> (spdk_nvmf_ns_reservation_ops, *mut c_void) {
+        let ops = spdk_nvmf_ns_reservation_ops {
+            check: Some(Self::reservation_check),
+            update: Some(Self::reservation_update),
+        };
+        (ops, self.as_ptr())
+    }
+
+    /// Write the latest reservation saved on the children to the file the
+    /// target restores it from when the bdev is added as a namespace, and
+    /// return the path of that file. Errors loading the reservation are not
+    /// fatal, the nexus is shared without one. Nothing is restored while the
+    /// nexus is exported from several nodes.
+    pub(crate) async fn open_reservation(
+        &mut self,
+        share: &Bdev,
+    ) -> Result<Option<String>, Error> {
+        let dir = PathBuf::from(&Config::get().nexus_opts.ptpl_dir);
+        let path = dir.join(format!("{}.ptpl", share.name()));
+        let mut latest: Option<ReservationState> = None;
+
+        for child in self
+            .children
+            .iter()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            match child.load_reservation().await {
+                Ok(Some(state)) => {
+                    if latest
+                        .as_ref()
+                        .map_or(true, |l| l.generation < state.generation)
+                    {
+                        latest = Some(state);
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "{}: failed to load reservation from child {}: {}",
+                    self.name, child.name, e
+                ),
+            }
+        }
+
+        let several_nodes = self.exported_from_several_nodes();
+        let generation = latest.as_ref().map_or(0, |l| l.generation);
+        let contents = match latest.filter(|l| l.ptpl_activated) {
+            Some(_) if several_nodes => {
+                warn!(
+                    "{}: not restoring reservation, exported from several nodes",
+                    self.name
+                );
+                None
+            }
+            Some(state) => {
+                info!(
+                    "{}: restoring reservation with {} registrants",
+                    self.name,
+                    state.registrants.len()
+                );
+                Some(ptpl_contents(&state, share.uuid_as_string()))
+            }
+            None => None,
+        };
+
+        // a file left behind by an earlier share must not be restored
+        let file_path = path.clone();
+        Mthread::spawn_blocking(move || -> io::Result<()> {
+            fs::create_dir_all(&dir)?;
+            match fs::remove_file(&file_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            match contents {
+                Some(contents) => fs::write(&file_path, contents),
+                None => Ok(()),
+            }
+        })
+        .await
+        .context(WriteReservation {
+            name: self.name.clone(),
+        })?;
+
+        self.reservation = Some(Reservation {
+            path: path.clone(),
+            generation,
+            pending: None,
+            waiters: Vec::new(),
+            saving: false,
+            idle: Vec::new(),
+        });
+
+        if several_nodes {
+            Ok(None)
+        } else {
+            Ok(Some(path.to_string_lossy().into_owned()))
+        }
+    }
+
+    /// Wait for the changes made to the reservation to be saved and remove
+    /// its file. Called when the nexus is unshared, once the namespace has
+    /// been removed.
+    pub(crate) async fn close_reservation(&mut self) {
+        let receiver = match self.reservation.as_mut() {
+            Some(reservation) if reservation.saving => {
+                let (sender, receiver) = oneshot::channel();
+                reservation.idle.push(sender);
+                Some(receiver)
+            }
+            Some(_) => None,
+            None => return,
+        };
+
+        if let Some(receiver) = receiver {
+            let _ = receiver.await;
+        }
+
+        if let Some(reservation) = self.reservation.take() {
+            let path = reservation.path;
+            if let Err(e) =
+                Mthread::spawn_blocking(move || fs::remove_file(path)).await
+            {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!(
+                        "{}: failed to remove reservation file: {}",
+                        self.name, e
+                    );
+                }
+            }
+        }
+    }
+
+    /// Save the reservation on all children that are in sync, it is an error
+    /// only when none of them could be written to.
+    async fn save_reservation(
+        &mut self,
+        state: ReservationState,
+    ) -> Result<(), Error> {
+        let config = NexusConfig::Reservation(state);
+        let now = SystemTime::now();
+        let mut saved = false;
+        let mut error = None;
+
+        for child in self
+            .children
+            .iter_mut()
+            .filter(|c| c.status() == ChildStatus::Online)
+        {
+            match child.save_reservation(&config, &now).await {
+                Ok(_) => saved = true,
+                Err(e) => {
+                    error!(
+                        "{}: failed to save reservation on child {}: {}",
+                        self.name, child.name, e
+                    );
+                    error = Some(e);
+                }
+            }
+        }
+
+        match error {
+            Some(e) if !saved => Err(e).context(SaveReservation {
+                name: self.name.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Save the pending reservation and complete the commands waiting for
+    /// it, until no change is left. Changes made while a save is in progress
+    /// are saved together with the next one.
+    async fn save_pending_reservations(&mut self) {
+        loop {
+            let (mut state, waiters) = match self.reservation.as_mut() {
+                Some(reservation) => match reservation.pending.take() {
+                    Some(state) => {
+                        let waiters = std::mem::take(&mut reservation.waiters);
+                        state.generation = reservation.generation + 1;
+                        (state, waiters)
+                    }
+                    None => break,
+                },
+                None => return,
+            };
+            let generation = state.generation;
+
+            let status = match self.save_reservation(state).await {
+                Ok(_) => {
+                    if let Some(reservation) = self.reservation.as_mut() {
+                        reservation.generation = generation;
+                    }
+                    0
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    -(Errno::EIO as i32)
+                }
+            };
+
+            for waiter in waiters {
+                if let Some(done_fn) = waiter.done_fn {
+                    unsafe { done_fn(waiter.done_ctx, status) };
+                }
+            }
+        }
+
+        if let Some(reservation) = self.reservation.as_mut() {
+            reservation.saving = false;
+            for sender in reservation.idle.drain(..) {
+                let _ = sender.send(());
+            }
+        }
+    }
+
+    /// called by the target before it executes a reservation command
+    extern "C" fn reservation_check(
+        _ns: *mut spdk_nvmf_ns,
+        ctx: *mut c_void,
+    ) -> i32 {
+        let nexus = unsafe { Nexus::from_raw(ctx) };
+        if nexus.reservation.is_none() {
+            return -(Errno::ENODEV as i32);
+        }
+        if nexus.exported_from_several_nodes() {
+            warn!(
+                "{}: refusing reservation command, exported from several nodes",
+                nexus.name
+            );
+            return -(Errno::EPERM as i32);
+        }
+        0
+    }
+
+    /// Called by the target once a command changed the reservation, which
+    /// completes the command by calling the done function.
+    extern "C" fn reservation_update(
+        ns: *mut spdk_nvmf_ns,
+        ctx: *mut c_void,
+        done_fn: spdk_nvmf_ns_reservation_done_fn,
+        done_ctx: *mut c_void,
+    ) {
+        let nexus = unsafe { Nexus::from_raw(ctx) };
+        let reservation = match nexus.reservation.as_mut() {
+            Some(reservation) => reservation,
+            None => {
+                if let Some(done_fn) = done_fn {
+                    unsafe { done_fn(done_ctx, -(Errno::ENODEV as i32)) };
+                }
+                return;
+            }
+        };
+
+        reservation.pending = Some(unsafe { namespace_reservation(ns) });
+        reservation.waiters.push(Waiter {
+            done_fn,
+            done_ctx,
+        });
+
+        if !reservation.saving {
+            reservation.saving = true;
+            Reactors::current().spawn_local(async move {
+                let nexus = unsafe { Nexus::from_raw(ctx) };
+                nexus.save_pending_reservations().await;
+            });
+        }
+    }
+}
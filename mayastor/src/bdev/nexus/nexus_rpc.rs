@@ -15,6 +15,7 @@ use rpc::mayastor::{
     RebuildStateRequest,
     RemoveChildNexusRequest,
     ResumeRebuildRequest,
+    RotateNexusKeyRequest,
     ShareProtocolNexus,
     StartRebuildRequest,
     StopRebuildRequest,
@@ -24,7 +25,15 @@ use rpc::mayastor::{
 use crate::{
     bdev::nexus::{
         instances,
-        nexus_bdev::{name_to_uuid, nexus_create, uuid_to_name, Error, Nexus},
+        nexus_bdev::{
+            name_to_uuid,
+            nexus_create_with_opts,
+            uuid_to_name,
+            Error,
+            Nexus,
+            NexusCreateOpts,
+        },
+        nexus_crypto::get_encryption_key,
     },
     jsonrpc::jsonrpc_register,
     rebuild::RebuildJob,
@@ -62,6 +71,11 @@ pub(crate) fn register_rpc_methods() {
                         .collect::<Vec<_>>(),
                     device_path: nexus.get_share_path().unwrap_or_default(),
                     rebuilds: RebuildJob::count() as u32,
+                    layout: nexus.layout() as i32,
+                    cache: nexus.cache_info(),
+                    async_children: nexus.async_children(),
+                    shares: nexus.shares(),
+                    key_rotation: nexus.key_rotation(),
                 })
                 .collect::<Vec<_>>(),
         })
@@ -75,10 +89,17 @@ pub(crate) fn register_rpc_methods() {
                 Ok(name) => name,
                 Err(err) => return Err(err),
             };
+            let opts = NexusCreateOpts::from_request(&args)?;
             // TODO: get rid of hardcoded nexus block size (possibly by
             // deriving it from child bdevs's block sizes).
-            nexus_create(&name, args.size, Some(&args.uuid), &args.children)
-                .await
+            nexus_create_with_opts(
+                &name,
+                args.size,
+                Some(&args.uuid),
+                &args.children,
+                opts,
+            )
+            .await
         };
         fut.boxed_local()
     });
@@ -97,21 +118,12 @@ pub(crate) fn register_rpc_methods() {
 
     jsonrpc_register("publish_nexus", |args: PublishNexusRequest| {
         let fut = async move {
-            // the key has to be 16 characters if it contains "" we consider it
-            // to be empty
-            if args.key != "" && args.key.len() != 16 {
-                warn!("Invalid key specified, are we under attack?!?");
+            // keys are fetched from the key provider by their ID instead
+            if !args.key.is_empty() {
+                warn!("Cleartext key specified, are we under attack?!?");
                 return Err(Error::InvalidKey {});
             }
 
-            // We have no means to validate key correctness right now, this is
-            // fine as we currently, do not support raw block
-            // devices being consumed directly within k8s
-            // the mount will fail if the key is wrong.
-
-            let key: Option<String> =
-                if args.key == "" { None } else { Some(args.key) };
-
             let share_protocol = match ShareProtocolNexus::from_i32(args.share)
             {
                 Some(protocol) => protocol,
@@ -123,6 +135,10 @@ pub(crate) fn register_rpc_methods() {
             };
 
             let nexus = nexus_lookup(&args.uuid)?;
+            let key = match args.key_id.as_str() {
+                "" => None,
+                key_id => Some(get_encryption_key(key_id).await?),
+            };
             nexus.share(share_protocol, key).await.map(|device_path| {
                 PublishNexusReply {
                     device_path,
@@ -135,7 +151,27 @@ pub(crate) fn register_rpc_methods() {
     jsonrpc_register("unpublish_nexus", |args: UnpublishNexusRequest| {
         let fut = async move {
             let nexus = nexus_lookup(&args.uuid)?;
-            nexus.unshare().await
+            if args.protocols.is_empty() {
+                return nexus.unshare().await;
+            }
+            for protocol in args.protocols {
+                let protocol = ShareProtocolNexus::from_i32(protocol).ok_or(
+                    Error::InvalidShareProtocol {
+                        sp_value: protocol,
+                    },
+                )?;
+                nexus.unshare_protocol(protocol).await?;
+            }
+            Ok(())
+        };
+        fut.boxed_local()
+    });
+
+    jsonrpc_register("rotate_nexus_key", |args: RotateNexusKeyRequest| {
+        let fut = async move {
+            let nexus = nexus_lookup(&args.uuid)?;
+            let key = get_encryption_key(&args.key_id).await?;
+            nexus.rotate_key(key).await
         };
         fut.boxed_local()
     });
@@ -164,10 +200,26 @@ pub(crate) fn register_rpc_methods() {
         },
     );
 
+    jsonrpc_register::<rpc::mayastor::ChildNexusRequest, _, _, Error>(
+        "promote_child",
+        |args: ChildNexusRequest| {
+            let fut = async move {
+                let nexus = nexus_lookup(&args.uuid)?;
+                nexus.promote_async_child(&args.uri).await?;
+                Ok(())
+            };
+            fut.boxed_local()
+        },
+    );
+
     jsonrpc_register("add_child_nexus", |args: AddChildNexusRequest| {
         let fut = async move {
             let nexus = nexus_lookup(&args.uuid)?;
-            nexus.add_child(&args.uri, args.norebuild).await.map(|_| ())
+            if args.asynchronous {
+                nexus.add_async_child(&args.uri).await
+            } else {
+                nexus.add_child(&args.uri, args.norebuild).await.map(|_| ())
+            }
         };
         fut.boxed_local()
     });
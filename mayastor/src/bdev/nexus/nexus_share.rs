@@ -1,215 +1,325 @@
-use std::ffi::CString;
+use std::net::IpAddr;
 
-use futures::channel::oneshot;
 use snafu::ResultExt;
 
-use rpc::mayastor::ShareProtocolNexus;
-use spdk_sys::create_crypto_disk;
+use rpc::mayastor::{NexusShare, ShareProtocolNexus};
 
 use crate::{
     bdev::nexus::{
         nexus_bdev::{
-            CreateCryptoBdev,
-            DestroyCryptoBdev,
             Error,
             Nexus,
             NexusTarget,
+            SetAnaState,
             ShareIscsiNexus,
             ShareNbdNexus,
             ShareNvmfNexus,
+            ShareUblkNexus,
+            ShareVhostNexus,
         },
         nexus_iscsi::NexusIscsiTarget,
+        nexus_key_provider::EncryptionKey,
         nexus_nbd::NbdDisk,
         nexus_nvmf::NexusNvmfTarget,
+        nexus_ublk::UblkDisk,
+        nexus_vhost::NexusVhostTarget,
     },
     core::Bdev,
-    ffihelper::{cb_arg, done_errno_cb, errno_result_from_i32, ErrnoResult},
+    subsys::{AnaState, Config, NvmfShareOpts},
 };
 
-/// we are using the multi buffer encryption implementation using CBC as the
-/// algorithm
-const CRYPTO_FLAVOUR: &str = "crypto_aesni_mb";
-
 impl Nexus {
     pub async fn share(
         &mut self,
         share_protocol: ShareProtocolNexus,
-        key: Option<String>,
+        key: Option<EncryptionKey>,
     ) -> Result<String, Error> {
         // We could already be shared -- as CSI is idempotent chances are we get
         // called for some odd reason. Validate indeed -- that we are
-        // shared by walking the target. If so, and the protocol is
-        // correct simply return Ok(). If so, and the protocol is
-        // incorrect, return Error(). If we are not shared but the
-        // variant says we should be, carry on to correct the state.
-        match self.nexus_target {
-            Some(NexusTarget::NbdDisk(ref nbd_disk)) => {
-                if share_protocol != ShareProtocolNexus::NexusNbd {
-                    return Err(Error::AlreadyShared {
-                        name: self.name.clone(),
-                    });
-                } else {
-                    warn!("{} is already shared", self.name);
-                    return Ok(nbd_disk.as_uri());
-                }
-            }
-            Some(NexusTarget::NexusIscsiTarget(ref iscsi_target)) => {
-                if share_protocol != ShareProtocolNexus::NexusIscsi {
-                    return Err(Error::AlreadyShared {
-                        name: self.name.clone(),
-                    });
-                } else {
-                    warn!("{} is already shared", self.name);
-                    return Ok(iscsi_target.as_uri());
-                }
-            }
-            Some(NexusTarget::NexusNvmfTarget(ref nvmf_target)) => {
-                if share_protocol != ShareProtocolNexus::NexusNvmf {
+        // shared by walking the targets. If so over the same protocol simply
+        // return its uri. A nexus can be shared over several protocols at the
+        // same time, all of which export the same share handle.
+        if let Some(target) = self
+            .nexus_targets
+            .iter()
+            .find(|t| t.protocol() == share_protocol)
+        {
+            warn!("{} is already shared", self.name);
+            return Ok(target.as_uri());
+        }
+
+        let (name, created) = match self.share_handle.clone() {
+            // the key given with the first share applies to all of them
+            Some(handle) => {
+                if !self.is_encrypted_with(key.as_ref()) {
                     return Err(Error::AlreadyShared {
                         name: self.name.clone(),
                     });
-                } else {
-                    warn!("{} is already shared", self.name);
-                    return Ok(nvmf_target.as_uri());
                 }
+                (handle, false)
             }
-            None => (),
-        }
-
-        assert_eq!(self.share_handle, None);
-
-        let name = if let Some(key) = key {
-            let name = format!("crypto-{}", self.name);
-
-            // constant
-            let flavour = CString::new(CRYPTO_FLAVOUR).unwrap();
-            // name of the crypto device
-            let cname = CString::new(name.clone()).unwrap();
-            // the nexus device itself
-            let base = CString::new(self.name.clone()).unwrap();
-            // the keys to the castle
-            let key = CString::new(key).unwrap();
-
-            let cipher = CString::new("AES_CBC").unwrap();
-
-            let errno = unsafe {
-                create_crypto_disk(
-                    base.as_ptr(),
-                    cname.as_ptr(),
-                    flavour.as_ptr(),
-                    key.as_ptr(),
-                    cipher.as_ptr(),
-                    std::ptr::null_mut(),
-                )
-            };
-            errno_result_from_i32(name, errno).context(CreateCryptoBdev {
-                name: self.name.clone(),
-            })?
-        } else {
-            self.name.clone()
+            None => (self.create_share_handle(key).await?, true),
         };
 
         debug!("creating share handle for {}", name);
         // The share handle is the actual bdev that is shared through the
         // various protocols.
 
+        match self.create_target(share_protocol, &name).await {
+            Ok(device_id) => {
+                self.share_handle = Some(name);
+                Ok(device_id)
+            }
+            Err(e) => {
+                // no other target exports the share handle created for this
+                // one, so it goes along with its crypto vbdevs and key
+                // rotation
+                if created {
+                    self.share_handle = Some(name);
+                    if let Err(e) = self.destroy_share_handle().await {
+                        error!(
+                            "{}: failed to destroy share handle: {}",
+                            self.name, e
+                        );
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Create the target that exports the share handle over the protocol and
+    /// return its uri.
+    async fn create_target(
+        &mut self,
+        share_protocol: ShareProtocolNexus,
+        name: &str,
+    ) -> Result<String, Error> {
         let device_id = match share_protocol {
             ShareProtocolNexus::NexusNbd => {
                 // Publish the nexus to system using nbd device and return the
                 // path to nbd device.
                 let nbd_disk =
-                    NbdDisk::create(&name).await.context(ShareNbdNexus {
+                    NbdDisk::create(name).await.context(ShareNbdNexus {
                         name: self.name.clone(),
                     })?;
                 let device_path = nbd_disk.as_uri();
-                self.nexus_target = Some(NexusTarget::NbdDisk(nbd_disk));
+                self.nexus_targets.push(NexusTarget::NbdDisk(nbd_disk));
+                device_path
+            }
+            ShareProtocolNexus::NexusUblk => {
+                // Publish the nexus to system using a ublk device and return
+                // the path to it.
+                let ublk_disk =
+                    UblkDisk::create(name).await.context(ShareUblkNexus {
+                        name: self.name.clone(),
+                    })?;
+                let device_path = ublk_disk.as_uri();
+                self.nexus_targets.push(NexusTarget::UblkDisk(ublk_disk));
                 device_path
             }
             ShareProtocolNexus::NexusIscsi => {
                 // Publish the nexus to system using an iscsi target and return
                 // the IQN
-                let iscsi_target = NexusIscsiTarget::create(&name).context(
+                let iscsi_target = NexusIscsiTarget::create(name).context(
                     ShareIscsiNexus {
                         name: self.name.clone(),
                     },
                 )?;
                 let uri = iscsi_target.as_uri();
-                self.nexus_target =
-                    Some(NexusTarget::NexusIscsiTarget(iscsi_target));
+                self.nexus_targets
+                    .push(NexusTarget::NexusIscsiTarget(iscsi_target));
                 uri
             }
             ShareProtocolNexus::NexusNvmf => {
-                let nvmf_target = NexusNvmfTarget::create(&name)
+                // the NGUID and NQN are derived from the nexus so that hosts
+                // see the same namespace when it is exported from several
+                // nodes
+                // the target restores the persistent reservation of the
+                // namespace from the file when it is added and calls back
+                // into the nexus on every change
+                let ptpl_file = match Bdev::lookup_by_name(name) {
+                    Some(bdev) => self.open_reservation(&bdev).await?,
+                    None => None,
+                };
+                let opts = NvmfShareOpts {
+                    nguid: Some(self.bdev.uuid().as_bytes()),
+                    ana_state: self.ana_state,
+                    cntlid_base: Config::get().nexus_opts.nvmf_cntlid_base,
+                    ptpl_file,
+                    reservation_ops: Some(self.reservation_ops()),
+                    ..Default::default()
+                };
+                let nvmf_target = match NexusNvmfTarget::create(name, &opts)
                     .await
                     .context(ShareNvmfNexus {
                         name: self.name.clone(),
-                    })?;
+                    }) {
+                    Ok(nvmf_target) => nvmf_target,
+                    Err(e) => {
+                        self.close_reservation().await;
+                        return Err(e);
+                    }
+                };
                 let uri = nvmf_target.as_uri();
-                self.nexus_target =
-                    Some(NexusTarget::NexusNvmfTarget(nvmf_target));
+                self.nexus_targets
+                    .push(NexusTarget::NexusNvmfTarget(nvmf_target));
+                uri
+            }
+            ShareProtocolNexus::NexusVhostUserBlk => {
+                // Publish the nexus to local VMs using a vhost-user-blk
+                // controller and return the path to its socket
+                let vhost_target = NexusVhostTarget::create(name).context(
+                    ShareVhostNexus {
+                        name: self.name.clone(),
+                    },
+                )?;
+                let uri = vhost_target.as_uri();
+                self.nexus_targets
+                    .push(NexusTarget::NexusVhostTarget(vhost_target));
                 uri
             }
         };
-        self.share_handle = Some(name);
         Ok(device_id)
     }
 
-    /// Undo share operation on nexus. To the chain of bdevs are all claimed
-    /// where the top-level dev is claimed by the subsystem that exports the
-    /// bdev. As such, we must first destroy the share and move our way down
-    /// from there.
+    /// Create the bdev that is shared through the various protocols, which
+    /// is a crypto vbdev on top of the nexus if a key is given, otherwise
+    /// the nexus itself.
+    async fn create_share_handle(
+        &mut self,
+        key: Option<EncryptionKey>,
+    ) -> Result<String, Error> {
+        match key {
+            Some(key) => self.create_crypto_bdev(key).await,
+            None => {
+                self.check_unencrypted().await?;
+                Ok(self.name.clone())
+            }
+        }
+    }
+
+    /// Undo share operation on nexus over all protocols. To the chain of
+    /// bdevs are all claimed where the top-level dev is claimed by the
+    /// subsystems that export the bdev. As such, we must first destroy the
+    /// shares and move our way down from there.
     pub async fn unshare(&mut self) -> Result<(), Error> {
-        match self.nexus_target.take() {
-            Some(NexusTarget::NbdDisk(disk)) => {
+        if self.nexus_targets.is_empty() {
+            warn!("{} was not shared", self.name);
+            return Ok(());
+        }
+
+        while let Some(target) = self.nexus_targets.pop() {
+            self.destroy_target(target).await;
+        }
+        self.destroy_share_handle().await
+    }
+
+    /// Undo the share of the nexus over a single protocol, the share handle
+    /// is destroyed along with the last of the targets.
+    pub async fn unshare_protocol(
+        &mut self,
+        protocol: ShareProtocolNexus,
+    ) -> Result<(), Error> {
+        match self
+            .nexus_targets
+            .iter()
+            .position(|t| t.protocol() == protocol)
+        {
+            Some(idx) => {
+                let target = self.nexus_targets.remove(idx);
+                self.destroy_target(target).await;
+            }
+            None => {
+                warn!("{} was not shared over {:?}", self.name, protocol);
+                return Ok(());
+            }
+        }
+
+        if self.nexus_targets.is_empty() {
+            self.destroy_share_handle().await
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn destroy_target(&mut self, target: NexusTarget) {
+        match target {
+            NexusTarget::NbdDisk(disk) => {
                 disk.destroy();
             }
-            Some(NexusTarget::NexusIscsiTarget(iscsi_target)) => {
+            NexusTarget::UblkDisk(disk) => {
+                disk.destroy().await;
+            }
+            NexusTarget::NexusIscsiTarget(iscsi_target) => {
                 iscsi_target.destroy().await;
             }
-            Some(NexusTarget::NexusNvmfTarget(nvmf_target)) => {
+            NexusTarget::NexusNvmfTarget(nvmf_target) => {
                 nvmf_target.destroy().await;
+                self.close_reservation().await;
             }
-            None => {
-                warn!("{} was not shared", self.name);
-                return Ok(());
+            NexusTarget::NexusVhostTarget(vhost_target) => {
+                vhost_target.destroy();
             }
-        };
+        }
+    }
 
+    async fn destroy_share_handle(&mut self) -> Result<(), Error> {
         let bdev_name = self.share_handle.take().unwrap();
-        if let Some(bdev) = Bdev::lookup_by_name(&bdev_name) {
-            // if the share handle is the same as bdev name it
-            // implies there is no top level bdev, and we are done
-            if self.name != bdev.name() {
-                let (s, r) = oneshot::channel::<ErrnoResult<()>>();
-                // currently, we only have the crypto vbdev
-                unsafe {
-                    spdk_sys::delete_crypto_disk(
-                        bdev.as_ptr(),
-                        Some(done_errno_cb),
-                        cb_arg(s),
-                    );
-                }
-                r.await.expect("crypto delete sender is gone").context(
-                    DestroyCryptoBdev {
-                        name: self.name.clone(),
-                    },
-                )?;
-            }
-        } else {
-            warn!("Missing bdev for a shared device");
+        // if the share handle is the same as bdev name it
+        // implies there is no top level bdev, and we are done
+        if self.name != bdev_name {
+            // currently, we only have the crypto vbdevs
+            self.destroy_crypto_bdev().await?;
         }
+        self.encryption = None;
         Ok(())
     }
 
-    /// Return path /dev/... under which the nexus is shared or None if not
-    /// shared as nbd.
-    pub fn get_share_path(&self) -> Option<String> {
-        match self.nexus_target {
-            Some(NexusTarget::NbdDisk(ref disk)) => Some(disk.as_uri()),
-            Some(NexusTarget::NexusIscsiTarget(ref iscsi_target)) => {
-                Some(iscsi_target.as_uri())
-            }
+    /// Change the ANA state of the nvmf listeners on the given address, or of
+    /// all listeners without one. The state is kept for when the nexus is
+    /// published over nvmf later on.
+    pub async fn set_ana_state(
+        &mut self,
+        state: AnaState,
+        address: Option<&IpAddr>,
+    ) -> Result<(), Error> {
+        let nvmf_target = self.nexus_targets.iter().find_map(|t| match t {
+            NexusTarget::NexusNvmfTarget(nvmf_target) => Some(nvmf_target),
             _ => None,
+        });
+        if let Some(nvmf_target) = nvmf_target {
+            nvmf_target.set_ana_state(state, address).await.context(
+                SetAnaState {
+                    name: self.name.clone(),
+                },
+            )?;
         }
+
+        if address.is_none() {
+            self.ana_state = state;
+        }
+        Ok(())
+    }
+
+    /// Return path /dev/... under which the nexus is shared locally, or the
+    /// uri of its iscsi target or vhost-user-blk socket, or None if it is
+    /// not shared or shared over nvmf.
+    pub fn get_share_path(&self) -> Option<String> {
+        self.nexus_targets.iter().find_map(|t| match t {
+            NexusTarget::NexusNvmfTarget(_) => None,
+            t => Some(t.as_uri()),
+        })
+    }
+
+    /// every protocol the nexus is shared over, as reported over gRPC
+    pub fn shares(&self) -> Vec<NexusShare> {
+        self.nexus_targets
+            .iter()
+            .map(|t| NexusShare {
+                protocol: t.protocol() as i32,
+                uri: t.as_uri(),
+            })
+            .collect()
     }
 }
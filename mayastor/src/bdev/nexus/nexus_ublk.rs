@@ -0,0 +1,903 @@
+//! Local publishing of a nexus as a ublk device, the io_uring based userspace
+//! block driver of Linux 6.0 and later, without the socket round trip of NBD.
+//!
+//! The device is added, started and stopped with io_uring commands on the
+//! control device. Those are issued from a separate thread, as the kernel
+//! reads the partition table while starting the device and stopping it
+//! drains its IO, both of which must be served by the reactor meanwhile.
+//!
+//! The IO goes through a single queue whose io_uring is polled on the core
+//! that created the device. Every tag of the queue has a command outstanding
+//! in the kernel to fetch the next request. Once fetched, the request is
+//! submitted to the nexus and its completion is committed with the same
+//! command, which fetches the next request for the tag in turn.
+
+use std::{
+    ffi::c_void,
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    mem::size_of,
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+};
+
+use futures::channel::oneshot;
+use nix::{errno::Errno, libc};
+use snafu::{ResultExt, Snafu};
+
+use spdk_sys::{
+    spdk_bdev_flush,
+    spdk_bdev_free_io,
+    spdk_bdev_io,
+    spdk_bdev_read,
+    spdk_bdev_write,
+    spdk_poller,
+    spdk_poller_register,
+    spdk_poller_unregister,
+};
+
+use crate::{
+    core::{BdevHandle, CoreError, DmaBuf, DmaError},
+    subsys::Config,
+};
+
+// include/uapi/linux/ublk_cmd.h
+const UBLK_CMD_ADD_DEV: u32 = 0x04;
+const UBLK_CMD_DEL_DEV: u32 = 0x05;
+const UBLK_CMD_START_DEV: u32 = 0x06;
+const UBLK_CMD_STOP_DEV: u32 = 0x07;
+const UBLK_CMD_SET_PARAMS: u32 = 0x08;
+const UBLK_IO_FETCH_REQ: u32 = 0x20;
+const UBLK_IO_COMMIT_AND_FETCH_REQ: u32 = 0x21;
+const UBLK_IO_RES_OK: i32 = 0;
+const UBLK_IO_RES_ABORT: i32 = -libc::ENODEV;
+const UBLK_IO_OP_READ: u32 = 0;
+const UBLK_IO_OP_WRITE: u32 = 1;
+const UBLK_IO_OP_FLUSH: u32 = 2;
+const UBLK_MAX_QUEUE_DEPTH: usize = 4096;
+const UBLK_ATTR_VOLATILE_CACHE: u32 = 1 << 2;
+const UBLK_PARAM_TYPE_BASIC: u32 = 1 << 0;
+
+// include/uapi/linux/io_uring.h
+const IORING_SETUP_SQE128: u32 = 1 << 10;
+const IORING_OP_URING_CMD: u8 = 46;
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+const UBLK_CONTROL: &str = "/dev/ublk-control";
+
+/// size of the buffer of each tag, which bounds the size of a request
+const UBLK_MAX_IO_SIZE: u32 = 128 * 1024;
+
+#[derive(Debug, Snafu)]
+pub enum UblkError {
+    #[snafu(display("ublk is not available (is the ublk_drv kmod loaded?)"))]
+    Unavailable {},
+    #[snafu(display(
+        "Invalid ublk queue depth {}, it must be between 1 and {}",
+        depth,
+        UBLK_MAX_QUEUE_DEPTH
+    ))]
+    InvalidQueueDepth { depth: u16 },
+    #[snafu(display("Failed to open bdev {}", dev))]
+    OpenBdev { source: CoreError, dev: String },
+    #[snafu(display("ublk {} command failed for {}", cmd, dev))]
+    ControlCommand {
+        source: Errno,
+        cmd: &'static str,
+        dev: String,
+    },
+    #[snafu(display("Failed to open {}", path))]
+    OpenDevice { source: io::Error, path: String },
+    #[snafu(display("Failed to set up the queue of {}", path))]
+    SetupQueue { source: io::Error, path: String },
+    #[snafu(display("Failed to allocate the buffers of {}", path))]
+    AllocBuffers { source: DmaError, path: String },
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UblksrvCtrlCmd {
+    dev_id: u32,
+    queue_id: u16,
+    len: u16,
+    addr: u64,
+    data: [u64; 2],
+}
+
+impl UblksrvCtrlCmd {
+    fn new(dev_id: u32) -> Self {
+        Self {
+            dev_id,
+            queue_id: u16::MAX,
+            ..Default::default()
+        }
+    }
+
+    /// pass a structure to or from the kernel with the command, which must
+    /// outlive it
+    fn with_buffer<T>(mut self, buffer: &mut T) -> Self {
+        self.addr = buffer as *mut T as u64;
+        self.len = size_of::<T>() as u16;
+        self
+    }
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UblksrvCtrlDevInfo {
+    nr_hw_queues: u16,
+    queue_depth: u16,
+    state: u16,
+    pad0: u16,
+    max_io_buf_bytes: u32,
+    dev_id: u32,
+    ublksrv_pid: i32,
+    pad1: u32,
+    flags: u64,
+    ublksrv_flags: u64,
+    reserved: [u64; 3],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UblkParamBasic {
+    attrs: u32,
+    logical_bs_shift: u8,
+    physical_bs_shift: u8,
+    io_opt_shift: u8,
+    io_min_shift: u8,
+    max_sectors: u32,
+    chunk_sectors: u32,
+    dev_sectors: u64,
+    virt_boundary_mask: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UblkParams {
+    len: u32,
+    types: u32,
+    basic: UblkParamBasic,
+}
+
+/// request fetched for a tag, as mapped from the character device
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UblksrvIoDesc {
+    op_flags: u32,
+    nr_sectors: u32,
+    start_sector: u64,
+    addr: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug)]
+struct UblksrvIoCmd {
+    q_id: u16,
+    tag: u16,
+    result: i32,
+    addr: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UringParams {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// submission queue entry of a ring set up with IORING_SETUP_SQE128, the
+/// commands of ublk go into its last 80 bytes
+#[allow(dead_code)]
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    cmd_op: u32,
+    pad1: u32,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: u32,
+    cmd: [u8; 80],
+}
+
+impl Sqe {
+    fn uring_cmd<T>(fd: RawFd, cmd_op: u32, user_data: u64, cmd: &T) -> Self {
+        let mut sqe = Self {
+            opcode: IORING_OP_URING_CMD,
+            flags: 0,
+            ioprio: 0,
+            fd,
+            cmd_op,
+            pad1: 0,
+            addr: 0,
+            len: 0,
+            rw_flags: 0,
+            user_data,
+            buf_index: 0,
+            personality: 0,
+            file_index: 0,
+            cmd: [0; 80],
+        };
+        unsafe {
+            ptr::copy_nonoverlapping(
+                cmd as *const T as *const u8,
+                sqe.cmd.as_mut_ptr(),
+                size_of::<T>(),
+            );
+        }
+        sqe
+    }
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// map a region of a file shared with the kernel
+fn map(
+    fd: RawFd,
+    len: usize,
+    prot: i32,
+    offset: libc::off_t,
+) -> io::Result<*mut c_void> {
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            prot,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd,
+            offset,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ptr)
+    }
+}
+
+/// An io_uring with just what is needed to pass commands to ublk, which the
+/// io_uring crate we use does not support.
+struct Uring {
+    fd: RawFd,
+    sq_ring: (*mut c_void, usize),
+    cq_ring: (*mut c_void, usize),
+    sqes: (*mut c_void, usize),
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_array: *mut u32,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    /// tail of the submission queue as filled in by us
+    tail: u32,
+    /// entries queued but not submitted yet
+    to_submit: u32,
+}
+
+impl Uring {
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut p = UringParams {
+            flags: IORING_SETUP_SQE128,
+            ..Default::default()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries as libc::c_long,
+                &mut p as *mut UringParams,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let null = (ptr::null_mut(), 0);
+        let mut ring = Self {
+            fd: fd as RawFd,
+            sq_ring: null,
+            cq_ring: null,
+            sqes: null,
+            sq_tail: ptr::null(),
+            sq_mask: 0,
+            sq_array: ptr::null_mut(),
+            cq_head: ptr::null(),
+            cq_tail: ptr::null(),
+            cq_mask: 0,
+            cqes: ptr::null(),
+            tail: 0,
+            to_submit: 0,
+        };
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+
+        let len = p.sq_off.array as usize + p.sq_entries as usize * 4;
+        ring.sq_ring = (map(ring.fd, len, prot, IORING_OFF_SQ_RING)?, len);
+        let len =
+            p.cq_off.cqes as usize + p.cq_entries as usize * size_of::<Cqe>();
+        ring.cq_ring = (map(ring.fd, len, prot, IORING_OFF_CQ_RING)?, len);
+        let len = p.sq_entries as usize * size_of::<Sqe>();
+        ring.sqes = (map(ring.fd, len, prot, IORING_OFF_SQES)?, len);
+
+        unsafe {
+            let sq = ring.sq_ring.0 as *mut u8;
+            let cq = ring.cq_ring.0 as *mut u8;
+            ring.sq_tail = sq.add(p.sq_off.tail as usize) as *const _;
+            ring.sq_mask = *(sq.add(p.sq_off.ring_mask as usize) as *const u32);
+            ring.sq_array = sq.add(p.sq_off.array as usize) as *mut u32;
+            ring.tail = (*ring.sq_tail).load(Ordering::Relaxed);
+            ring.cq_head = cq.add(p.cq_off.head as usize) as *const _;
+            ring.cq_tail = cq.add(p.cq_off.tail as usize) as *const _;
+            ring.cq_mask = *(cq.add(p.cq_off.ring_mask as usize) as *const u32);
+            ring.cqes = cq.add(p.cq_off.cqes as usize) as *const Cqe;
+        }
+
+        Ok(ring)
+    }
+
+    /// queue an entry, the ring is sized so that it is never full
+    fn push(&mut self, sqe: Sqe) {
+        let idx = self.tail & self.sq_mask;
+        unsafe {
+            ptr::write((self.sqes.0 as *mut Sqe).add(idx as usize), sqe);
+            *self.sq_array.add(idx as usize) = idx;
+        }
+        self.tail = self.tail.wrapping_add(1);
+        self.to_submit += 1;
+        unsafe { (*self.sq_tail).store(self.tail, Ordering::Release) };
+    }
+
+    /// submit the queued entries and wait for the given number of
+    /// completions, which also runs the task work the kernel queued for us
+    fn enter(&mut self, wait: u32) -> io::Result<()> {
+        let rc = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd as libc::c_long,
+                self.to_submit as libc::c_long,
+                wait as libc::c_long,
+                IORING_ENTER_GETEVENTS as libc::c_long,
+                ptr::null::<c_void>(),
+                0 as libc::c_long,
+            )
+        };
+        if rc < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => {
+                    Ok(())
+                }
+                _ => Err(e),
+            };
+        }
+        self.to_submit -= rc as u32;
+        Ok(())
+    }
+
+    /// take the next completion, returning its user data and result
+    fn pop(&mut self) -> Option<(u64, i32)> {
+        unsafe {
+            let head = (*self.cq_head).load(Ordering::Relaxed);
+            if head == (*self.cq_tail).load(Ordering::Acquire) {
+                return None;
+            }
+            let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+            let completion = (cqe.user_data, cqe.res);
+            (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+            Some(completion)
+        }
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        unsafe {
+            for (ptr, len) in &[self.sq_ring, self.cq_ring, self.sqes] {
+                if !ptr.is_null() {
+                    libc::munmap(*ptr, *len);
+                }
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn io_errno(e: io::Error) -> Errno {
+    Errno::from_i32(e.raw_os_error().unwrap_or(libc::EIO))
+}
+
+/// Issue a command on the control device and wait for its completion. This
+/// blocks, so it must not be called from a reactor.
+fn control_sync(cmd_op: u32, cmd: UblksrvCtrlCmd) -> Result<i32, Errno> {
+    let ctrl = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(UBLK_CONTROL)
+        .map_err(io_errno)?;
+    let mut ring = Uring::new(2).map_err(io_errno)?;
+
+    ring.push(Sqe::uring_cmd(ctrl.as_raw_fd(), cmd_op, 0, &cmd));
+    loop {
+        ring.enter(1).map_err(io_errno)?;
+        if let Some((_, res)) = ring.pop() {
+            return if res < 0 {
+                Err(Errno::from_i32(-res))
+            } else {
+                Ok(res)
+            };
+        }
+    }
+}
+
+/// issue a command on the control device from a separate thread, so that
+/// the reactor keeps serving the IO of the device meanwhile
+async fn control(cmd_op: u32, cmd: UblksrvCtrlCmd) -> Result<i32, Errno> {
+    let (s, r) = oneshot::channel::<Result<i32, Errno>>();
+    thread::spawn(move || {
+        let _ = s.send(control_sync(cmd_op, cmd));
+    });
+    r.await.expect("ublk control sender is gone")
+}
+
+/// a tag of the queue and the buffer of its requests
+struct UblkIo {
+    queue: *mut UblkQueue,
+    tag: u16,
+    buf: DmaBuf,
+    /// result reported when the request completes successfully
+    len: i32,
+}
+
+/// the IO queue of a ublk device
+struct UblkQueue {
+    path: String,
+    cdev: File,
+    ring: Uring,
+    descs: (*mut c_void, usize),
+    ios: Vec<UblkIo>,
+    handle: BdevHandle,
+    /// number of tags with a command outstanding in the kernel
+    active: usize,
+    poller: *mut spdk_poller,
+    /// notified once the commands of all tags have been aborted
+    stopped: Option<oneshot::Sender<()>>,
+}
+
+impl UblkQueue {
+    /// Open the character device of the ublk device, map its request
+    /// descriptors and fetch the first request of every tag.
+    fn new(
+        dev_id: u32,
+        handle: BdevHandle,
+        depth: u16,
+    ) -> Result<Box<Self>, UblkError> {
+        let path = format!("/dev/ublkc{}", dev_id);
+        let cdev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .context(OpenDevice {
+                path: path.clone(),
+            })?;
+        let ring = Uring::new(depth as u32).context(SetupQueue {
+            path: path.clone(),
+        })?;
+
+        // the descriptors of queue 0 start at offset 0
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = depth as usize * size_of::<UblksrvIoDesc>();
+        let len = (len + page_size - 1) / page_size * page_size;
+        let descs = (
+            map(cdev.as_raw_fd(), len, libc::PROT_READ, 0).context(
+                SetupQueue {
+                    path: path.clone(),
+                },
+            )?,
+            len,
+        );
+
+        let mut queue = Box::new(Self {
+            path,
+            cdev,
+            ring,
+            descs,
+            ios: Vec::with_capacity(depth as usize),
+            handle,
+            active: 0,
+            poller: ptr::null_mut(),
+            stopped: None,
+        });
+
+        let ctx = &mut *queue as *mut UblkQueue;
+        for tag in 0 .. depth {
+            let buf = queue
+                .handle
+                .dma_malloc(UBLK_MAX_IO_SIZE as usize)
+                .context(AllocBuffers {
+                    path: queue.path.clone(),
+                })?;
+            queue.ios.push(UblkIo {
+                queue: ctx,
+                tag,
+                buf,
+                len: 0,
+            });
+        }
+
+        for tag in 0 .. depth {
+            queue.fetch(tag, UBLK_IO_FETCH_REQ, 0);
+        }
+        queue.ring.enter(0).context(SetupQueue {
+            path: queue.path.clone(),
+        })?;
+        queue.active = depth as usize;
+
+        queue.poller = unsafe {
+            spdk_poller_register(Some(Self::poll), ctx as *mut c_void, 0)
+        };
+        Ok(queue)
+    }
+
+    /// queue a command to fetch the next request of the tag, committing the
+    /// result of the previous one
+    fn fetch(&mut self, tag: u16, cmd_op: u32, result: i32) {
+        let cmd = UblksrvIoCmd {
+            q_id: 0,
+            tag,
+            result,
+            addr: *self.ios[tag as usize].buf as u64,
+        };
+        let sqe =
+            Sqe::uring_cmd(self.cdev.as_raw_fd(), cmd_op, tag as u64, &cmd);
+        self.ring.push(sqe);
+    }
+
+    /// submit the request fetched for the tag to the bdev
+    fn dispatch(&mut self, tag: u16) {
+        let desc = unsafe {
+            ptr::read_volatile(
+                (self.descs.0 as *const UblksrvIoDesc).add(tag as usize),
+            )
+        };
+        let offset = desc.start_sector << 9;
+        let len = (desc.nr_sectors as u64) << 9;
+        let (bdev_desc, channel) = self.handle.io_tuple();
+        let size = self.handle.get_bdev().size_in_bytes();
+
+        let io = &mut self.ios[tag as usize];
+        io.len = len as i32;
+        let buf = *io.buf;
+        let arg = io as *mut UblkIo as *mut c_void;
+
+        let errno = unsafe {
+            match desc.op_flags & 0xff {
+                UBLK_IO_OP_READ => spdk_bdev_read(
+                    bdev_desc,
+                    channel,
+                    buf,
+                    offset,
+                    len,
+                    Some(Self::io_done),
+                    arg,
+                ),
+                UBLK_IO_OP_WRITE => spdk_bdev_write(
+                    bdev_desc,
+                    channel,
+                    buf,
+                    offset,
+                    len,
+                    Some(Self::io_done),
+                    arg,
+                ),
+                UBLK_IO_OP_FLUSH => spdk_bdev_flush(
+                    bdev_desc,
+                    channel,
+                    0,
+                    size,
+                    Some(Self::io_done),
+                    arg,
+                ),
+                _ => -libc::EOPNOTSUPP,
+            }
+        };
+
+        if errno != 0 {
+            self.fetch(tag, UBLK_IO_COMMIT_AND_FETCH_REQ, -errno.abs());
+        }
+    }
+
+    extern "C" fn io_done(
+        io: *mut spdk_bdev_io,
+        success: bool,
+        arg: *mut c_void,
+    ) {
+        unsafe { spdk_bdev_free_io(io) };
+        let (queue, tag, len) = unsafe {
+            let io = &*(arg as *const UblkIo);
+            (io.queue, io.tag, io.len)
+        };
+        let result = if success { len } else { -libc::EIO };
+        unsafe { (*queue).fetch(tag, UBLK_IO_COMMIT_AND_FETCH_REQ, result) };
+    }
+
+    /// submit the queued commands and handle the fetched requests
+    extern "C" fn poll(ctx: *mut c_void) -> i32 {
+        let queue = unsafe { &mut *(ctx as *mut UblkQueue) };
+        if let Err(e) = queue.ring.enter(0) {
+            error!("{}: failed to submit ublk commands: {}", queue.path, e);
+        }
+
+        let mut busy = 0;
+        while let Some((tag, res)) = queue.ring.pop() {
+            busy = 1;
+            match res {
+                UBLK_IO_RES_OK => queue.dispatch(tag as u16),
+                // the device is stopping
+                UBLK_IO_RES_ABORT => queue.active -= 1,
+                _ => {
+                    error!(
+                        "{}: ublk command of tag {} failed: {}",
+                        queue.path,
+                        tag,
+                        Errno::from_i32(-res)
+                    );
+                    queue.active -= 1;
+                }
+            }
+        }
+
+        if queue.active == 0 {
+            if let Some(s) = queue.stopped.take() {
+                let _ = s.send(());
+            }
+        }
+
+        busy
+    }
+}
+
+impl Drop for UblkQueue {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.poller.is_null() {
+                spdk_poller_unregister(&mut self.poller);
+            }
+            libc::munmap(self.descs.0, self.descs.1);
+        }
+    }
+}
+
+/// ublk disk representation.
+pub struct UblkDisk {
+    dev_id: u32,
+    queue: Option<Box<UblkQueue>>,
+}
+
+impl UblkDisk {
+    /// Add a ublk device for the bdev and start it. When the function
+    /// returns the device is ready for IO, as the kernel has read its
+    /// partition table already.
+    pub async fn create(bdev_name: &str) -> Result<Self, UblkError> {
+        let depth = Config::get().nexus_opts.ublk_queue_depth;
+        if depth == 0 || depth as usize > UBLK_MAX_QUEUE_DEPTH {
+            return Err(UblkError::InvalidQueueDepth {
+                depth,
+            });
+        }
+        let handle =
+            BdevHandle::open(bdev_name, true, false).context(OpenBdev {
+                dev: bdev_name.to_string(),
+            })?;
+
+        let mut info = UblksrvCtrlDevInfo {
+            nr_hw_queues: 1,
+            queue_depth: depth,
+            max_io_buf_bytes: UBLK_MAX_IO_SIZE,
+            dev_id: u32::MAX,
+            ublksrv_pid: std::process::id() as i32,
+            ..Default::default()
+        };
+        let cmd = UblksrvCtrlCmd::new(u32::MAX).with_buffer(&mut info);
+        match control(UBLK_CMD_ADD_DEV, cmd).await {
+            Err(Errno::ENOENT) => return Err(UblkError::Unavailable {}),
+            r => r.context(ControlCommand {
+                cmd: "add",
+                dev: bdev_name.to_string(),
+            })?,
+        };
+
+        let mut disk = Self {
+            dev_id: info.dev_id,
+            queue: None,
+        };
+        match disk.start(handle, depth).await {
+            Ok(()) => {
+                info!(
+                    "Started ublk disk {} for {}",
+                    disk.get_path(),
+                    bdev_name
+                );
+                Ok(disk)
+            }
+            Err(e) => {
+                disk.destroy().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn start(
+        &mut self,
+        handle: BdevHandle,
+        depth: u16,
+    ) -> Result<(), UblkError> {
+        let bdev = handle.get_bdev();
+        let bs_shift = bdev.block_len().trailing_zeros() as u8;
+        let mut params = UblkParams {
+            len: size_of::<UblkParams>() as u32,
+            types: UBLK_PARAM_TYPE_BASIC,
+            basic: UblkParamBasic {
+                // so that flushes are passed on to the nexus
+                attrs: UBLK_ATTR_VOLATILE_CACHE,
+                logical_bs_shift: bs_shift,
+                physical_bs_shift: bs_shift,
+                io_opt_shift: bs_shift,
+                io_min_shift: bs_shift,
+                max_sectors: UBLK_MAX_IO_SIZE >> 9,
+                dev_sectors: bdev.size_in_bytes() >> 9,
+                ..Default::default()
+            },
+        };
+        let cmd = UblksrvCtrlCmd::new(self.dev_id).with_buffer(&mut params);
+        control(UBLK_CMD_SET_PARAMS, cmd)
+            .await
+            .context(ControlCommand {
+                cmd: "set params",
+                dev: self.get_path(),
+            })?;
+
+        self.queue = Some(UblkQueue::new(self.dev_id, handle, depth)?);
+
+        let mut cmd = UblksrvCtrlCmd::new(self.dev_id);
+        cmd.data[0] = std::process::id() as u64;
+        control(UBLK_CMD_START_DEV, cmd)
+            .await
+            .context(ControlCommand {
+                cmd: "start",
+                dev: self.get_path(),
+            })?;
+        Ok(())
+    }
+
+    /// Stop and delete the ublk device. Stopping it completes the IO in
+    /// flight, after which the kernel aborts the commands of the queue. When
+    /// the device cannot be stopped, the kernel may still hand out requests
+    /// referring to the buffers of the queue, so the queue is leaked rather
+    /// than freed and the device is left in place.
+    pub async fn destroy(mut self) {
+        let path = self.get_path();
+        let (s, r) = oneshot::channel::<()>();
+        let stopped = match self.queue.as_mut() {
+            Some(queue) if queue.active > 0 => {
+                queue.stopped = Some(s);
+                Some(r)
+            }
+            _ => None,
+        };
+
+        match control(UBLK_CMD_STOP_DEV, UblksrvCtrlCmd::new(self.dev_id)).await
+        {
+            Ok(_) => {
+                if let Some(r) = stopped {
+                    let _ = r.await;
+                }
+            }
+            Err(e) => {
+                error!("Failed to stop ublk disk {}: {}", path, e);
+                if stopped.is_some() {
+                    error!(
+                        "Leaking the queue of ublk disk {} as it is still active",
+                        path
+                    );
+                    std::mem::forget(self.queue.take());
+                    return;
+                }
+            }
+        }
+
+        // the character device must be closed before the device is deleted
+        self.queue.take();
+        if let Err(e) =
+            control(UBLK_CMD_DEL_DEV, UblksrvCtrlCmd::new(self.dev_id)).await
+        {
+            error!("Failed to delete ublk disk {}: {}", path, e);
+        }
+
+        info!("ublk {} device stopped", path);
+    }
+
+    /// Get the ublk device path (/dev/ublkb...) for the ublk disk.
+    pub fn get_path(&self) -> String {
+        format!("/dev/ublkb{}", self.dev_id)
+    }
+
+    /// Get the ublk device path uri (file:///dev/ublkb...) for the ublk disk.
+    pub fn as_uri(&self) -> String {
+        format!("file://{}", self.get_path())
+    }
+}
+
+impl fmt::Debug for UblkDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.as_uri(), self.dev_id)
+    }
+}
+
+impl fmt::Display for UblkDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.get_path())
+    }
+}
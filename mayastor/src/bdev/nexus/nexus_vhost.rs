@@ -0,0 +1,132 @@
+//! Export of a nexus to VMs on the same node with a vhost-user-blk
+//! controller. The controller listens on a UNIX domain socket which QEMU or
+//! cloud-hypervisor connect to, after which the virtqueues of the guest are
+//! processed directly in mayastor without going through the kernel. The VM
+//! must back its memory with shared hugepages for this to work.
+
+use std::{
+    ffi::CString,
+    fmt,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use nix::errno::Errno;
+use snafu::{ResultExt, Snafu};
+
+use spdk_sys::{
+    spdk_vhost_blk_construct,
+    spdk_vhost_dev_find,
+    spdk_vhost_dev_remove,
+    spdk_vhost_lock,
+    spdk_vhost_set_socket_path,
+    spdk_vhost_unlock,
+};
+
+use crate::{core::Bdev, ffihelper::errno_result_from_i32, subsys::Config};
+
+#[derive(Debug, Snafu)]
+pub enum NexusVhostError {
+    #[snafu(display("Bdev not found {}", dev))]
+    BdevNotFound { dev: String },
+    #[snafu(display("Invalid vhost socket directory {}", dir))]
+    SocketDir { source: Errno, dir: String },
+    #[snafu(display(
+        "Failed to create vhost-user-blk controller for bdev {}",
+        dev
+    ))]
+    CreateController { source: Errno, dev: String },
+}
+
+/// vhost-user-blk controller exporting the nexus
+pub struct NexusVhostTarget {
+    /// name of the controller, which is also the name of its socket
+    ctrlr: String,
+    socket: PathBuf,
+}
+
+impl NexusVhostTarget {
+    /// Create a vhost-user-blk controller for the bdev. When the function
+    /// returns a VM can connect to the socket of the controller.
+    pub fn create(bdev_name: &str) -> Result<Self, NexusVhostError> {
+        if Bdev::lookup_by_name(bdev_name).is_none() {
+            return Err(NexusVhostError::BdevNotFound {
+                dev: bdev_name.to_string(),
+            });
+        }
+
+        let dir = Config::get().nexus_opts.vhost_socket_dir.clone();
+        let c_dir = CString::new(dir.clone()).unwrap();
+        let errno = unsafe { spdk_vhost_set_socket_path(c_dir.as_ptr()) };
+        errno_result_from_i32((), errno).context(SocketDir {
+            dir: dir.clone(),
+        })?;
+
+        let ctrlr = bdev_name.to_string();
+        let c_ctrlr = CString::new(ctrlr.clone()).unwrap();
+        let c_bdev = CString::new(bdev_name).unwrap();
+
+        // serve the controller from all cores, without the packed ring
+        // layout which not all VMMs support
+        let errno = unsafe {
+            spdk_vhost_blk_construct(
+                c_ctrlr.as_ptr(),
+                ptr::null(),
+                c_bdev.as_ptr(),
+                false,
+                false,
+            )
+        };
+        errno_result_from_i32((), errno).context(CreateController {
+            dev: bdev_name.to_string(),
+        })?;
+
+        info!("Created vhost-user-blk controller {}", ctrlr);
+        Ok(Self {
+            socket: Path::new(&dir).join(&ctrlr),
+            ctrlr,
+        })
+    }
+
+    /// Remove the controller. This fails while a VM is still connected to
+    /// it, in which case the controller is left in place and the VM loses
+    /// access to the device when its bdev is removed.
+    pub fn destroy(self) {
+        info!("Destroying vhost-user-blk controller {}", self.ctrlr);
+        let c_ctrlr = CString::new(self.ctrlr.clone()).unwrap();
+        let errno = unsafe {
+            spdk_vhost_lock();
+            let vdev = spdk_vhost_dev_find(c_ctrlr.as_ptr());
+            let errno = if vdev.is_null() {
+                -libc::ENODEV
+            } else {
+                spdk_vhost_dev_remove(vdev)
+            };
+            spdk_vhost_unlock();
+            errno
+        };
+
+        if let Err(e) = errno_result_from_i32((), errno) {
+            error!(
+                "Failed to destroy vhost-user-blk controller {}, error {}",
+                self.ctrlr, e
+            );
+        }
+    }
+
+    pub fn as_uri(&self) -> String {
+        format!("vhost-user-blk://{}", self.socket.display())
+    }
+}
+
+impl fmt::Debug for NexusVhostTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{:?}", self.as_uri(), self.ctrlr)
+    }
+}
+
+impl fmt::Display for NexusVhostTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_uri())
+    }
+}
@@ -38,6 +38,31 @@ fn replica_protocol_to_str(idx: i32) -> &'static str {
     }
 }
 
+fn parse_nexus_protocol(pcol: &str) -> Result<rpc::ShareProtocolNexus, Status> {
+    match pcol {
+        "nbd" => Ok(rpc::ShareProtocolNexus::NexusNbd),
+        "nvmf" => Ok(rpc::ShareProtocolNexus::NexusNvmf),
+        "iscsi" => Ok(rpc::ShareProtocolNexus::NexusIscsi),
+        "vhost-user-blk" => Ok(rpc::ShareProtocolNexus::NexusVhostUserBlk),
+        "ublk" => Ok(rpc::ShareProtocolNexus::NexusUblk),
+        _ => Err(Status::new(
+            Code::Internal,
+            "Invalid value of share protocol".to_owned(),
+        )),
+    }
+}
+
+fn nexus_protocol_to_str(idx: i32) -> &'static str {
+    match rpc::ShareProtocolNexus::from_i32(idx) {
+        Some(rpc::ShareProtocolNexus::NexusNbd) => "nbd",
+        Some(rpc::ShareProtocolNexus::NexusNvmf) => "nvmf",
+        Some(rpc::ShareProtocolNexus::NexusIscsi) => "iscsi",
+        Some(rpc::ShareProtocolNexus::NexusVhostUserBlk) => "vhost-user-blk",
+        Some(rpc::ShareProtocolNexus::NexusUblk) => "ublk",
+        None => "unknown",
+    }
+}
+
 fn pool_state_to_str(idx: i32) -> &'static str {
     match rpc::PoolState::from_i32(idx).unwrap() {
         rpc::PoolState::PoolUnknown => "unknown",
@@ -65,6 +90,13 @@ fn child_state_to_str(idx: i32) -> &'static str {
     }
 }
 
+fn backup_kind_to_str(idx: i32) -> &'static str {
+    match rpc::BackupJobKind::from_i32(idx).unwrap() {
+        rpc::BackupJobKind::JobBackup => "backup",
+        rpc::BackupJobKind::JobRestore => "restore",
+    }
+}
+
 pub(crate) fn parse_size(src: &str) -> Result<Byte, String> {
     Byte::from_str(src).map_err(|_| src.to_string())
 }
@@ -254,6 +286,27 @@ async fn nexus_create(
         .split_whitespace()
         .map(|c| c.to_string())
         .collect::<Vec<String>>();
+    let layout = match matches.value_of("layout") {
+        None | Some("mirror") => rpc::NexusLayout::NexusMirror,
+        Some("parity") => rpc::NexusLayout::NexusParity,
+        Some(_) => {
+            return Err(Status::new(
+                Code::Internal,
+                "Invalid value of nexus layout".to_owned(),
+            ))
+        }
+    };
+    let cache = matches.value_of("cache").unwrap_or_default().to_string();
+    let cache_mode = match matches.value_of("cache_mode") {
+        None | Some("write-through") => rpc::NexusCacheMode::CacheWriteThrough,
+        Some("write-back") => rpc::NexusCacheMode::CacheWriteBack,
+        Some(_) => {
+            return Err(Status::new(
+                Code::Internal,
+                "Invalid value of cache mode".to_owned(),
+            ))
+        }
+    };
 
     ctx.v2(&format!(
         "Creating nexus {} of size {} ",
@@ -267,6 +320,9 @@ async fn nexus_create(
             uuid: uuid.clone(),
             size,
             children,
+            layout: layout as i32,
+            cache,
+            cache_mode: cache_mode as i32,
         })
         .await?;
     ctx.v1(&format!("Nexus {} created", uuid));
@@ -324,6 +380,13 @@ async fn nexus_list(
                         .join(","),
                 )
             }
+            row.push(
+                n.shares
+                    .iter()
+                    .map(|s| nexus_protocol_to_str(s.protocol))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
             row
         })
         .collect();
@@ -331,6 +394,7 @@ async fn nexus_list(
     if show_child {
         hdr.push("CHILDREN");
     }
+    hdr.push("SHARES");
     ctx.print_list(hdr, table);
 
     Ok(())
@@ -374,17 +438,15 @@ async fn nexus_publish(
     matches: &ArgMatches<'_>,
 ) -> Result<(), Status> {
     let uuid = matches.value_of("uuid").unwrap().to_string();
-    let key = matches.value_of("key").unwrap_or("").to_string();
+    let key_id = matches.value_of("key_id").unwrap_or("").to_string();
     let prot = match matches.value_of("protocol") {
         None => rpc::ShareProtocolNexus::NexusNbd,
-        Some("nvmf") => rpc::ShareProtocolNexus::NexusNvmf,
-        Some("iscsi") => rpc::ShareProtocolNexus::NexusIscsi,
-        Some(_) => {
-            return Err(Status::new(
-                Code::Internal,
-                "Invalid value of share protocol".to_owned(),
-            ))
-        }
+        Some(pcol) => parse_nexus_protocol(pcol)?,
+    };
+
+    let ana_state = match matches.value_of("ana_state") {
+        None => rpc::NvmeAnaState::NvmeAnaOptimized,
+        Some(state) => parse_ana_state(state)?,
     };
 
     ctx.v2(&format!("Publishing nexus {} over {:?}", uuid, prot));
@@ -392,8 +454,10 @@ async fn nexus_publish(
         .client
         .publish_nexus(rpc::PublishNexusRequest {
             uuid,
-            key,
             share: prot.into(),
+            ana_state: ana_state.into(),
+            key_id,
+            ..Default::default()
         })
         .await?;
     ctx.v1(&format!(
@@ -408,11 +472,18 @@ async fn nexus_unpublish(
     matches: &ArgMatches<'_>,
 ) -> Result<(), Status> {
     let uuid = matches.value_of("uuid").unwrap().to_string();
+    let protocols = match matches.values_of("protocol") {
+        None => Vec::new(),
+        Some(values) => values
+            .map(|p| parse_nexus_protocol(p).map(|p| p as i32))
+            .collect::<Result<Vec<_>, _>>()?,
+    };
 
     ctx.v2(&format!("Unpublishing nexus {}", uuid));
     ctx.client
         .unpublish_nexus(rpc::UnpublishNexusRequest {
             uuid: uuid.clone(),
+            protocols,
         })
         .await?;
     ctx.v1(&format!("Nexus {} unpublished", uuid));
@@ -430,6 +501,7 @@ async fn nexus_add(
         .unwrap_or("false")
         .parse::<bool>()
         .unwrap_or(false);
+    let asynchronous = matches.is_present("async");
 
     ctx.v2(&format!("Adding {} to children of {}", uri, uuid));
     ctx.client
@@ -437,6 +509,7 @@ async fn nexus_add(
             uuid: uuid.clone(),
             uri: uri.clone(),
             norebuild,
+            asynchronous,
         })
         .await?;
     ctx.v1(&format!("Added {} to children of {}", uri, uuid));
@@ -461,6 +534,112 @@ async fn nexus_remove(
     Ok(())
 }
 
+async fn nexus_promote(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_string();
+    let uri = matches.value_of("uri").unwrap().to_string();
+
+    ctx.v2(&format!("Promoting {} of {}", uri, uuid));
+    ctx.client
+        .child_operation(rpc::ChildNexusRequest {
+            uuid: uuid.clone(),
+            uri: uri.clone(),
+            action: rpc::ChildAction::Promote as i32,
+        })
+        .await?;
+    ctx.v1(&format!("Promoted {} of {}", uri, uuid));
+    Ok(())
+}
+
+fn parse_ana_state(state: &str) -> Result<rpc::NvmeAnaState, Status> {
+    match state {
+        "optimized" => Ok(rpc::NvmeAnaState::NvmeAnaOptimized),
+        "non-optimized" => Ok(rpc::NvmeAnaState::NvmeAnaNonOptimized),
+        "inaccessible" => Ok(rpc::NvmeAnaState::NvmeAnaInaccessible),
+        _ => Err(Status::new(
+            Code::Internal,
+            "Invalid value of ANA state".to_owned(),
+        )),
+    }
+}
+
+async fn nexus_ana(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_string();
+    let state = parse_ana_state(matches.value_of("state").unwrap())?;
+    let address = matches.value_of("address").unwrap_or("").to_string();
+
+    ctx.v2(&format!(
+        "Setting ANA state of nexus {} to {:?}",
+        uuid, state
+    ));
+    ctx.client
+        .set_nexus_ana_state(rpc::SetNexusAnaStateRequest {
+            uuid: uuid.clone(),
+            ana_state: state.into(),
+            address,
+        })
+        .await?;
+    ctx.v1(&format!("Set ANA state of {} to {:?}", uuid, state));
+    Ok(())
+}
+
+async fn nexus_rekey(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_string();
+    let key_id = matches.value_of("key_id").unwrap().to_string();
+
+    ctx.v2(&format!("Rotating key of nexus {} to {}", uuid, key_id));
+    ctx.client
+        .rotate_nexus_key(rpc::RotateNexusKeyRequest {
+            uuid: uuid.clone(),
+            key_id: key_id.clone(),
+        })
+        .await?;
+    ctx.v1(&format!("Rotating key of {} to {}", uuid, key_id));
+    Ok(())
+}
+
+async fn nexus_freeze(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_string();
+    let timeout = value_t!(matches.value_of("timeout"), u32).unwrap_or(0);
+
+    ctx.v2(&format!("Freezing nexus {}", uuid));
+    ctx.client
+        .freeze_nexus(rpc::FreezeNexusRequest {
+            uuid: uuid.clone(),
+            timeout,
+        })
+        .await?;
+    ctx.v1(&format!("Froze {}", uuid));
+    Ok(())
+}
+
+async fn nexus_thaw(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_string();
+
+    ctx.v2(&format!("Thawing nexus {}", uuid));
+    ctx.client
+        .thaw_nexus(rpc::ThawNexusRequest {
+            uuid: uuid.clone(),
+        })
+        .await?;
+    ctx.v1(&format!("Thawed {}", uuid));
+    Ok(())
+}
+
 /*
  *
  * REPLICA
@@ -564,6 +743,48 @@ async fn replica_share(
     Ok(())
 }
 
+async fn replica_snapshot(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_owned();
+    let name = matches.value_of("name").unwrap().to_owned();
+
+    ctx.v2(&format!("Creating snapshot {} of replica {}", name, uuid));
+    ctx.client
+        .snapshot_replica(rpc::SnapshotReplicaRequest {
+            uuid,
+            name: name.clone(),
+        })
+        .await?;
+    ctx.v1(&format!("Created snapshot {}", name));
+    Ok(())
+}
+
+async fn replica_clone(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let snapshot = matches.value_of("snapshot").unwrap().to_owned();
+    let uuid = matches.value_of("uuid").unwrap().to_owned();
+    let share = parse_replica_protocol(matches.value_of("protocol"))?;
+
+    ctx.v2(&format!(
+        "Creating replica {} from snapshot {}",
+        uuid, snapshot
+    ));
+    let resp = ctx
+        .client
+        .clone_snapshot(rpc::CloneSnapshotRequest {
+            snapshot,
+            uuid,
+            share,
+        })
+        .await?;
+    ctx.v1(&format!("Created {}", resp.get_ref().uri));
+    Ok(())
+}
+
 async fn replica_stat(
     mut ctx: Context,
     _matches: &ArgMatches<'_>,
@@ -598,6 +819,249 @@ async fn replica_stat(
     Ok(())
 }
 
+/*
+ *
+ * BACKUP
+ *
+ */
+
+async fn backup_create(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_owned();
+    let target = matches.value_of("target").unwrap().to_owned();
+    let name = matches.value_of("name").unwrap().to_owned();
+    let chunk_size = match matches.value_of("chunk-size") {
+        Some(s) => parse_size(s)
+            .map_err(|s| {
+                Status::invalid_argument(format!("Bad chunk size '{}'", s))
+            })?
+            .get_bytes() as u64,
+        None => 0,
+    };
+    let snapshot = matches.is_present("snapshot");
+
+    ctx.v2(&format!(
+        "Backing up replica {} to {} as {}",
+        uuid, target, name
+    ));
+    ctx.client
+        .backup_replica(rpc::BackupReplicaRequest {
+            uuid,
+            target,
+            name: name.clone(),
+            chunk_size,
+            snapshot,
+        })
+        .await?;
+    ctx.v1(&format!("Started backup {}", name));
+    Ok(())
+}
+
+async fn backup_restore(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let target = matches.value_of("target").unwrap().to_owned();
+    let name = matches.value_of("name").unwrap().to_owned();
+    let pool = matches.value_of("pool").unwrap().to_owned();
+    let uuid = matches.value_of("uuid").unwrap().to_owned();
+    let thin = matches.is_present("thin");
+
+    ctx.v2(&format!(
+        "Restoring backup {} from {} to replica {} on pool {}",
+        name, target, uuid, pool
+    ));
+    ctx.client
+        .restore_replica(rpc::RestoreReplicaRequest {
+            target,
+            name: name.clone(),
+            uuid,
+            pool,
+            thin,
+        })
+        .await?;
+    ctx.v1(&format!("Started restore of backup {}", name));
+    Ok(())
+}
+
+async fn backup_list(
+    mut ctx: Context,
+    _matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    ctx.v2("Requesting a list of backup jobs");
+
+    let resp = ctx.client.list_backup_jobs(rpc::Null {}).await?;
+    let jobs = &resp.get_ref().jobs;
+    if jobs.is_empty() {
+        ctx.v1("No backup jobs found");
+        return Ok(());
+    }
+
+    ctx.v2("Found following backup jobs:");
+
+    let table = jobs
+        .iter()
+        .map(|j| {
+            vec![
+                j.name.clone(),
+                backup_kind_to_str(j.kind).to_string(),
+                j.replica.clone(),
+                j.state.clone(),
+                format!("{}%", j.progress),
+                j.error.clone(),
+            ]
+        })
+        .collect();
+    ctx.print_list(
+        vec!["NAME", "KIND", "REPLICA", "STATE", ">PROGRESS", "ERROR"],
+        table,
+    );
+
+    Ok(())
+}
+
+async fn backup_remove(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let name = matches.value_of("name").unwrap().to_owned();
+
+    ctx.v2(&format!("Removing backup job {}", name));
+    ctx.client
+        .remove_backup_job(rpc::RemoveBackupJobRequest {
+            name,
+        })
+        .await?;
+    Ok(())
+}
+
+/*
+ *
+ * CHECKPOINT
+ *
+ */
+
+async fn checkpoint_create(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_owned();
+    let name = matches.value_of("name").unwrap().to_owned();
+    let snapshot = matches.is_present("snapshot");
+
+    ctx.v2(&format!("Creating checkpoint {} of nexus {}", name, uuid));
+    let resp = ctx
+        .client
+        .create_checkpoint(rpc::CreateCheckpointRequest {
+            uuid,
+            name: name.clone(),
+            snapshot,
+        })
+        .await?;
+    ctx.v1(&format!("Created checkpoint {}", name));
+    for snapshot in &resp.get_ref().snapshots {
+        ctx.v1(&format!("Created snapshot {}", snapshot));
+    }
+    Ok(())
+}
+
+async fn checkpoint_destroy(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_owned();
+    let name = matches.value_of("name").unwrap().to_owned();
+
+    ctx.v2(&format!("Destroying checkpoint {} of nexus {}", name, uuid));
+    ctx.client
+        .destroy_checkpoint(rpc::DestroyCheckpointRequest {
+            uuid,
+            name,
+        })
+        .await?;
+    Ok(())
+}
+
+async fn checkpoint_list(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_owned();
+
+    ctx.v2(&format!(
+        "Requesting a list of checkpoints of nexus {}",
+        uuid
+    ));
+
+    let resp = ctx
+        .client
+        .list_checkpoints(rpc::ListCheckpointsRequest {
+            uuid,
+        })
+        .await?;
+    let checkpoints = &resp.get_ref().checkpoints;
+    if checkpoints.is_empty() {
+        ctx.v1("No checkpoints found");
+        return Ok(());
+    }
+
+    ctx.v2("Found following checkpoints:");
+
+    let table = checkpoints
+        .iter()
+        .map(|c| {
+            let changed = ctx.units(Byte::from_bytes(c.changed_bytes.into()));
+            vec![c.name.clone(), c.created.to_string(), changed]
+        })
+        .collect();
+    ctx.print_list(vec!["NAME", ">CREATED", ">CHANGED"], table);
+
+    Ok(())
+}
+
+async fn checkpoint_changes(
+    mut ctx: Context,
+    matches: &ArgMatches<'_>,
+) -> Result<(), Status> {
+    let uuid = matches.value_of("uuid").unwrap().to_owned();
+    let from = matches.value_of("from").unwrap().to_owned();
+    let to = matches.value_of("to").unwrap_or_default().to_owned();
+
+    ctx.v2(&format!(
+        "Requesting extents of nexus {} changed since checkpoint {}",
+        uuid, from
+    ));
+
+    let resp = ctx
+        .client
+        .get_changed_extents(rpc::GetChangedExtentsRequest {
+            uuid,
+            from,
+            to,
+        })
+        .await?;
+    let reply = resp.get_ref();
+    ctx.v2(&format!(
+        "Data offset on the replicas: {}",
+        reply.data_offset
+    ));
+    if reply.extents.is_empty() {
+        ctx.v1("No changed extents found");
+        return Ok(());
+    }
+
+    let table = reply
+        .extents
+        .iter()
+        .map(|e| vec![e.offset.to_string(), e.length.to_string()])
+        .collect();
+    ctx.print_list(vec![">OFFSET", ">LENGTH"], table);
+
+    Ok(())
+}
+
 /*
  *
  * MAIN
@@ -674,6 +1138,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .multiple(true)
                     .index(3)
                     .help("list of children to add"),
+            )
+            .arg(
+                Arg::with_name("layout")
+                    .short("l")
+                    .long("layout")
+                    .value_name("LAYOUT")
+                    .possible_values(&["mirror", "parity"])
+                    .help("layout of the data on the children"),
+            )
+            .arg(
+                Arg::with_name("cache")
+                    .long("cache")
+                    .value_name("URI")
+                    .help("local bdev used to cache the data of the children"),
+            )
+            .arg(
+                Arg::with_name("cache_mode")
+                    .long("cache-mode")
+                    .value_name("MODE")
+                    .possible_values(&["write-through", "write-back"])
+                    .help("how writes are handled by the cache"),
             );
         let destroy = SubCommand::with_name("destroy")
             .about("destroy the nexus with given name")
@@ -686,11 +1171,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let publish = SubCommand::with_name("publish")
             .about("publish the nexus")
             .arg(Arg::with_name("protocol").short("p").long("protocol").value_name("PROTOCOL")
-                .help("Name of a protocol (nvmf, iscsi) used for publishing the nexus remotely"))
+                .help("Name of a protocol (nvmf, iscsi, vhost-user-blk, ublk) used for publishing the nexus, nbd if not given"))
             .arg(Arg::with_name("uuid").required(true).index(1)
                 .help("uuid for the nexus"))
-            .arg(Arg::with_name("key").required(false).index(2)
-                .help("crypto key to use"));
+            .arg(Arg::with_name("key_id").required(false).index(2)
+                .help("ID of the encryption key with the key provider of mayastor"))
+            .arg(Arg::with_name("ana_state").short("a").long("ana-state").value_name("STATE")
+                .possible_values(&["optimized", "non-optimized", "inaccessible"])
+                .help("initial ANA state of the nvmf listeners"));
         let unpublish = SubCommand::with_name("unpublish")
             .about("unpublish the nexus")
             .arg(
@@ -698,6 +1186,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .required(true)
                     .index(1)
                     .help("uuid for the nexus"),
+            )
+            .arg(
+                Arg::with_name("protocol")
+                    .short("p")
+                    .long("protocol")
+                    .value_name("PROTOCOL")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Protocol to unpublish the nexus from, all if not given"),
             );
         let add = SubCommand::with_name("add")
             .about("add a child")
@@ -712,6 +1209,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .required(true)
                     .index(2)
                     .help("uri of child to add"),
+            )
+            .arg(
+                Arg::with_name("async")
+                    .long("async")
+                    .required(false)
+                    .takes_value(false)
+                    .help("replicate to the child in the background"),
+            );
+        let promote = SubCommand::with_name("promote")
+            .about("promote an async child which has caught up")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("uuid for the nexus"),
+            )
+            .arg(
+                Arg::with_name("uri")
+                    .required(true)
+                    .index(2)
+                    .help("uri of child to promote"),
             );
         let remove = SubCommand::with_name("remove")
             .about("remove a child")
@@ -745,6 +1263,72 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .help("uuid of nexus"),
             );
 
+        let freeze = SubCommand::with_name("freeze")
+            .about("hold back writes to the nexus")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("uuid of nexus"),
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .short("t")
+                    .long("timeout")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .help("thaw the nexus after this time (default 10)"),
+            );
+        let ana = SubCommand::with_name("ana")
+            .about("change the ANA state of the nvmf listeners of the nexus")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("uuid of nexus"),
+            )
+            .arg(
+                Arg::with_name("state")
+                    .required(true)
+                    .index(2)
+                    .possible_values(&[
+                        "optimized",
+                        "non-optimized",
+                        "inaccessible",
+                    ])
+                    .help("new ANA state"),
+            )
+            .arg(
+                Arg::with_name("address")
+                    .short("a")
+                    .long("address")
+                    .takes_value(true)
+                    .value_name("ADDRESS")
+                    .help("only change the listeners on this address"),
+            );
+        let rekey = SubCommand::with_name("rekey")
+            .about("re-encrypt the published nexus with another key")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("uuid of nexus"),
+            )
+            .arg(
+                Arg::with_name("key_id")
+                    .required(true)
+                    .index(2)
+                    .help("ID of the new encryption key with the key provider of mayastor"),
+            );
+        let thaw = SubCommand::with_name("thaw")
+            .about("let writes held back by freeze through")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("uuid of nexus"),
+            );
+
         SubCommand::with_name("nexus")
             .about("nexus management")
             .subcommand(create)
@@ -752,9 +1336,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .subcommand(publish)
             .subcommand(add)
             .subcommand(remove)
+            .subcommand(promote)
             .subcommand(unpublish)
             .subcommand(list)
             .subcommand(children)
+            .subcommand(freeze)
+            .subcommand(thaw)
+            .subcommand(ana)
+            .subcommand(rekey)
     };
 
     let replica_subcommand = {
@@ -784,17 +1373,180 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .help("Replica uuid"))
             .arg(Arg::with_name("protocol").required(true).index(2)
                 .help("Name of a protocol (nvmf, iscsi) used for sharing or \"none\" to unshare the replica"));
+        let snapshot = SubCommand::with_name("snapshot")
+            .about("Create read-only snapshot of replica")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("Replica uuid"),
+            )
+            .arg(
+                Arg::with_name("name")
+                    .required(true)
+                    .index(2)
+                    .help("Name of the snapshot"),
+            );
+        let clone = SubCommand::with_name("clone").about("Create replica from snapshot")
+            .arg(Arg::with_name("snapshot").required(true).index(1)
+                .help("Name of the snapshot"))
+            .arg(Arg::with_name("uuid").required(true).index(2)
+                .help("Unique replica uuid"))
+            .arg(Arg::with_name("protocol").short("p").long("protocol")
+                .takes_value(true).value_name("PROTOCOL")
+                .help("Name of a protocol (nvmf, iscsi) used for sharing the replica (default none)"));
         SubCommand::with_name("replica")
             .about("Replica management")
             .subcommand(create)
             .subcommand(destroy)
             .subcommand(share)
+            .subcommand(snapshot)
+            .subcommand(clone)
             .subcommand(SubCommand::with_name("list").about("List replicas"))
             .subcommand(
                 SubCommand::with_name("stats").about("IO stats of replicas"),
             )
     };
 
+    let backup_subcommand = {
+        let create = SubCommand::with_name("create")
+            .about("Back up replica to an object store")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("Replica uuid"),
+            )
+            .arg(Arg::with_name("target").required(true).index(2).help(
+                "URI of the object store (s3://bucket/prefix or file:///path)",
+            ))
+            .arg(
+                Arg::with_name("name")
+                    .required(true)
+                    .index(3)
+                    .help("Name of the backup"),
+            )
+            .arg(
+                Arg::with_name("chunk-size")
+                    .short("c")
+                    .long("chunk-size")
+                    .takes_value(true)
+                    .value_name("NUMBER")
+                    .help("Size of the chunks of the backup (default 4MiB)"),
+            )
+            .arg(
+                Arg::with_name("snapshot")
+                    .short("s")
+                    .long("snapshot")
+                    .takes_value(false)
+                    .help("Back up a snapshot of the replica (default false)"),
+            );
+        let restore = SubCommand::with_name("restore")
+            .about("Restore backup into a new replica")
+            .arg(Arg::with_name("target").required(true).index(1).help(
+                "URI of the object store (s3://bucket/prefix or file:///path)",
+            ))
+            .arg(
+                Arg::with_name("name")
+                    .required(true)
+                    .index(2)
+                    .help("Name of the backup"),
+            )
+            .arg(
+                Arg::with_name("pool")
+                    .required(true)
+                    .index(3)
+                    .help("Storage pool name"),
+            )
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(4)
+                    .help("Unique uuid of the new replica"),
+            )
+            .arg(
+                Arg::with_name("thin")
+                    .short("t")
+                    .long("thin")
+                    .takes_value(false)
+                    .help(
+                        "Whether replica is thin provisioned (default false)",
+                    ),
+            );
+        let remove = SubCommand::with_name("remove")
+            .about("Terminate and remove backup job")
+            .arg(
+                Arg::with_name("name")
+                    .required(true)
+                    .index(1)
+                    .help("Name of the backup"),
+            );
+        SubCommand::with_name("backup")
+            .about("Backup management")
+            .subcommand(create)
+            .subcommand(restore)
+            .subcommand(remove)
+            .subcommand(SubCommand::with_name("list").about("List backup jobs"))
+    };
+
+    let checkpoint_subcommand = {
+        let create = SubCommand::with_name("create").about("Create checkpoint of nexus")
+            .arg(Arg::with_name("uuid").required(true).index(1)
+                .help("Nexus uuid"))
+            .arg(Arg::with_name("name").required(true).index(2)
+                .help("Name of the checkpoint"))
+            .arg(Arg::with_name("snapshot").short("s").long("snapshot").takes_value(false)
+                .help("Snapshot the local replicas of the nexus (default false)"));
+        let destroy = SubCommand::with_name("destroy")
+            .about("Destroy checkpoint of nexus")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("Nexus uuid"),
+            )
+            .arg(
+                Arg::with_name("name")
+                    .required(true)
+                    .index(2)
+                    .help("Name of the checkpoint"),
+            );
+        let list = SubCommand::with_name("list")
+            .about("List checkpoints of nexus")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("Nexus uuid"),
+            );
+        let changes = SubCommand::with_name("changes")
+            .about("List extents of nexus changed since checkpoint")
+            .arg(
+                Arg::with_name("uuid")
+                    .required(true)
+                    .index(1)
+                    .help("Nexus uuid"),
+            )
+            .arg(
+                Arg::with_name("from")
+                    .required(true)
+                    .index(2)
+                    .help("Name of the checkpoint"),
+            )
+            .arg(
+                Arg::with_name("to")
+                    .required(false)
+                    .index(3)
+                    .help("Name of a later checkpoint (default now)"),
+            );
+        SubCommand::with_name("checkpoint")
+            .about("Changed block tracking")
+            .subcommand(create)
+            .subcommand(destroy)
+            .subcommand(list)
+            .subcommand(changes)
+    };
+
     let matches = App::new("Mayastor gRPC client")
         .version("0.1")
         .settings(&[AppSettings::SubcommandRequiredElseHelp,
@@ -817,6 +1569,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .subcommand(pools_subcommand)
         .subcommand(nexus_subcommand)
         .subcommand(replica_subcommand)
+        .subcommand(backup_subcommand)
+        .subcommand(checkpoint_subcommand)
         .get_matches();
 
     let ctx = {
@@ -863,6 +1617,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ("unpublish", Some(m)) => nexus_unpublish(ctx, &m).await?,
             ("add", Some(m)) => nexus_add(ctx, &m).await?,
             ("remove", Some(m)) => nexus_remove(ctx, &m).await?,
+            ("promote", Some(m)) => nexus_promote(ctx, &m).await?,
+            ("freeze", Some(m)) => nexus_freeze(ctx, &m).await?,
+            ("thaw", Some(m)) => nexus_thaw(ctx, &m).await?,
+            ("ana", Some(m)) => nexus_ana(ctx, &m).await?,
+            ("rekey", Some(m)) => nexus_rekey(ctx, &m).await?,
             _ => {}
         },
 
@@ -871,10 +1630,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             ("destroy", Some(m)) => replica_destroy(ctx, &m).await?,
             ("list", Some(m)) => replica_list(ctx, &m).await?,
             ("share", Some(m)) => replica_share(ctx, &m).await?,
+            ("snapshot", Some(m)) => replica_snapshot(ctx, &m).await?,
+            ("clone", Some(m)) => replica_clone(ctx, &m).await?,
             ("stats", Some(m)) => replica_stat(ctx, &m).await?,
             _ => {}
         },
 
+        ("backup", Some(m)) => match m.subcommand() {
+            ("create", Some(m)) => backup_create(ctx, &m).await?,
+            ("restore", Some(m)) => backup_restore(ctx, &m).await?,
+            ("list", Some(m)) => backup_list(ctx, &m).await?,
+            ("remove", Some(m)) => backup_remove(ctx, &m).await?,
+            _ => {}
+        },
+
+        ("checkpoint", Some(m)) => match m.subcommand() {
+            ("create", Some(m)) => checkpoint_create(ctx, &m).await?,
+            ("destroy", Some(m)) => checkpoint_destroy(ctx, &m).await?,
+            ("list", Some(m)) => checkpoint_list(ctx, &m).await?,
+            ("changes", Some(m)) => checkpoint_changes(ctx, &m).await?,
+            _ => {}
+        },
+
         _ => eprintln!("Internal Error: Not implemented"),
     };
     Ok(())
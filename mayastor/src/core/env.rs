@@ -1,7 +1,5 @@
 use std::{
-    env,
     ffi::CString,
-    net::Ipv4Addr,
     os::raw::{c_char, c_void},
     pin::Pin,
     sync::{
@@ -567,17 +565,19 @@ impl MayastorEnvironment {
     /// We implement our own default target init code here. Note that if there
     /// is an existing target we will fail the init process.
     extern "C" fn target_init() -> bool {
-        let address = MayastorEnvironment::get_pod_ip()
+        let cfg = Config::get();
+
+        let addresses = cfg
+            .nexus_opts
+            .listen_addresses()
             .map_err(|e| {
-                error!("Invalid IP address: MY_POD_IP={}", e);
+                error!("Invalid listen addresses: {}", e);
                 mayastor_env_stop(-1);
             })
             .unwrap();
 
-        let cfg = Config::get();
-
         if cfg.nexus_opts.iscsi_enable {
-            if let Err(msg) = iscsi::init(&address) {
+            if let Err(msg) = iscsi::init(&addresses) {
                 error!("Failed to initialize Mayastor iSCSI target: {}", msg);
                 return false;
             }
@@ -586,19 +586,6 @@ impl MayastorEnvironment {
         true
     }
 
-    pub(crate) fn get_pod_ip() -> Result<String, String> {
-        match env::var("MY_POD_IP") {
-            Ok(val) => {
-                if val.parse::<Ipv4Addr>().is_ok() {
-                    Ok(val)
-                } else {
-                    Err(val)
-                }
-            }
-            Err(_) => Ok("127.0.0.1".to_owned()),
-        }
-    }
-
     /// start the  JSON rpc server which listens only to a local path
     extern "C" fn start_rpc(rc: i32, arg: *mut c_void) {
         let ctx = unsafe { Box::from_raw(arg as *mut SubsystemCtx) };
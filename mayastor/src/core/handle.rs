@@ -9,19 +9,37 @@ use std::{
 use futures::channel::oneshot;
 use nix::errno::Errno;
 use serde::export::{fmt::Error, Formatter};
+use snafu::ResultExt;
 
 use spdk_sys::{
     spdk_bdev_desc,
     spdk_bdev_free_io,
     spdk_bdev_io,
+    spdk_bdev_io_get_nvme_status,
+    spdk_bdev_nvme_io_passthru,
     spdk_bdev_read,
     spdk_bdev_reset,
     spdk_bdev_write,
     spdk_io_channel,
+    spdk_nvme_cmd,
+    SPDK_NVME_OPC_RESERVATION_ACQUIRE,
+    SPDK_NVME_OPC_RESERVATION_REGISTER,
+    SPDK_NVME_OPC_RESERVATION_RELEASE,
+    SPDK_NVME_OPC_RESERVATION_REPORT,
+    SPDK_NVME_SCT_GENERIC,
+    SPDK_NVME_SC_RESERVATION_CONFLICT,
 };
 
 use crate::{
-    core::{Bdev, CoreError, Descriptor, DmaBuf, DmaError, IoChannel},
+    core::{
+        Bdev,
+        CoreError,
+        Descriptor,
+        DmaAlloc,
+        DmaBuf,
+        DmaError,
+        IoChannel,
+    },
     ffihelper::cb_arg,
 };
 
@@ -77,22 +95,32 @@ impl BdevHandle {
     }
 
     /// private io completion callback that sends back the success status of the
-    /// IO. When the IO is freed, it is returned to the memory pool. The
-    /// buffer is not freed.
+    /// IO, and whether it failed as the reservation of the device is held by
+    /// another host. When the IO is freed, it is returned to the memory pool.
+    /// The buffer is not freed.
     extern "C" fn io_completion_cb(
         io: *mut spdk_bdev_io,
         success: bool,
         arg: *mut c_void,
     ) {
         let sender = unsafe {
-            Box::from_raw(arg as *const _ as *mut oneshot::Sender<bool>)
+            Box::from_raw(arg as *const _ as *mut oneshot::Sender<(bool, bool)>)
         };
 
+        let mut cdw0 = 0;
+        let mut sct = 0;
+        let mut sc = 0;
         unsafe {
+            spdk_bdev_io_get_nvme_status(io, &mut cdw0, &mut sct, &mut sc);
             spdk_bdev_free_io(io);
         }
+        let conflict = !success
+            && sct == SPDK_NVME_SCT_GENERIC as i32
+            && sc == SPDK_NVME_SC_RESERVATION_CONFLICT as i32;
 
-        sender.send(success).expect("io completion error");
+        sender
+            .send((success, conflict))
+            .expect("io completion error");
     }
 
     /// write the ['DmaBuf'] to the given offset. This function is implemented
@@ -102,7 +130,7 @@ impl BdevHandle {
         offset: u64,
         buffer: &DmaBuf,
     ) -> Result<usize, CoreError> {
-        let (s, r) = oneshot::channel::<bool>();
+        let (s, r) = oneshot::channel::<(bool, bool)>();
         let errno = unsafe {
             spdk_bdev_write(
                 self.desc.as_ptr(),
@@ -123,13 +151,16 @@ impl BdevHandle {
             });
         }
 
-        if r.await.expect("Failed awaiting write IO") {
-            Ok(buffer.len() as usize)
-        } else {
-            Err(CoreError::WriteFailed {
+        match r.await.expect("Failed awaiting write IO") {
+            (true, _) => Ok(buffer.len() as usize),
+            (false, true) => Err(CoreError::ReservationConflict {
+                offset,
+                len: buffer.len(),
+            }),
+            (false, false) => Err(CoreError::WriteFailed {
                 offset,
                 len: buffer.len(),
-            })
+            }),
         }
     }
 
@@ -139,7 +170,7 @@ impl BdevHandle {
         offset: u64,
         buffer: &mut DmaBuf,
     ) -> Result<usize, CoreError> {
-        let (s, r) = oneshot::channel::<bool>();
+        let (s, r) = oneshot::channel::<(bool, bool)>();
         let errno = unsafe {
             spdk_bdev_read(
                 self.desc.as_ptr(),
@@ -160,18 +191,21 @@ impl BdevHandle {
             });
         }
 
-        if r.await.expect("Failed awaiting read IO") {
-            Ok(buffer.len())
-        } else {
-            Err(CoreError::ReadFailed {
+        match r.await.expect("Failed awaiting read IO") {
+            (true, _) => Ok(buffer.len()),
+            (false, true) => Err(CoreError::ReservationConflict {
                 offset,
                 len: buffer.len(),
-            })
+            }),
+            (false, false) => Err(CoreError::ReadFailed {
+                offset,
+                len: buffer.len(),
+            }),
         }
     }
 
     pub async fn reset(&self) -> Result<usize, CoreError> {
-        let (s, r) = oneshot::channel::<bool>();
+        let (s, r) = oneshot::channel::<(bool, bool)>();
         let errno = unsafe {
             spdk_bdev_reset(
                 self.desc.as_ptr(),
@@ -187,12 +221,163 @@ impl BdevHandle {
             });
         }
 
-        if r.await.expect("Failed awaiting reset IO") {
+        if r.await.expect("Failed awaiting reset IO").0 {
             Ok(0)
         } else {
             Err(CoreError::ResetFailed {})
         }
     }
+
+    /// io completion callback of NVMe passthru commands, which sends back
+    /// the NVMe status type and code of the command
+    extern "C" fn nvme_io_completion_cb(
+        io: *mut spdk_bdev_io,
+        success: bool,
+        arg: *mut c_void,
+    ) {
+        let sender = unsafe {
+            Box::from_raw(
+                arg as *const _ as *mut oneshot::Sender<(bool, i32, i32)>,
+            )
+        };
+
+        let mut cdw0 = 0;
+        let mut sct = 0;
+        let mut sc = 0;
+        unsafe {
+            spdk_bdev_io_get_nvme_status(io, &mut cdw0, &mut sct, &mut sc);
+            spdk_bdev_free_io(io);
+        }
+
+        sender
+            .send((success, sct, sc))
+            .expect("io completion error");
+    }
+
+    /// submit an NVMe IO command with the ['DmaBuf'] as its data buffer, the
+    /// bdev must support NVMe IO passthru
+    pub async fn nvme_io_passthru(
+        &self,
+        cmd: &spdk_nvme_cmd,
+        buffer: &mut DmaBuf,
+    ) -> Result<(), CoreError> {
+        let opcode = cmd.opc();
+        let (s, r) = oneshot::channel::<(bool, i32, i32)>();
+        let errno = unsafe {
+            spdk_bdev_nvme_io_passthru(
+                self.desc.as_ptr(),
+                self.channel.as_ptr(),
+                cmd,
+                **buffer,
+                buffer.len() as u64,
+                Some(Self::nvme_io_completion_cb),
+                cb_arg(s),
+            )
+        };
+
+        if errno != 0 {
+            return Err(CoreError::NvmeIoPassthruDispatch {
+                source: Errno::from_i32(errno),
+                opcode,
+            });
+        }
+
+        match r.await.expect("Failed awaiting NVMe IO") {
+            (true, ..) => Ok(()),
+            (false, sct, sc) => Err(CoreError::NvmeIoPassthruFailed {
+                opcode,
+                sct,
+                sc,
+            }),
+        }
+    }
+
+    /// register, unregister or replace the reservation key of the host,
+    /// cptpl changes whether the reservation persists through power loss
+    pub async fn nvme_resv_register(
+        &self,
+        current_key: u64,
+        new_key: u64,
+        register_action: u8,
+        iekey: bool,
+        cptpl: u8,
+    ) -> Result<(), CoreError> {
+        let mut cmd = spdk_nvme_cmd::default();
+        cmd.set_opc(SPDK_NVME_OPC_RESERVATION_REGISTER as u16);
+        cmd.__bindgen_anon_1.cdw10 = u32::from(register_action & 0x7)
+            | (u32::from(iekey) << 3)
+            | (u32::from(cptpl & 0x3) << 30);
+
+        let mut buffer = self.dma_malloc(16).context(DmaAlloc {
+            size: 16usize,
+        })?;
+        let data = buffer.as_mut_slice();
+        data[0 .. 8].copy_from_slice(&current_key.to_le_bytes());
+        data[8 .. 16].copy_from_slice(&new_key.to_le_bytes());
+
+        self.nvme_io_passthru(&cmd, &mut buffer).await
+    }
+
+    /// acquire or preempt the reservation with the given reservation type
+    pub async fn nvme_resv_acquire(
+        &self,
+        current_key: u64,
+        preempt_key: u64,
+        acquire_action: u8,
+        resv_type: u8,
+    ) -> Result<(), CoreError> {
+        let mut cmd = spdk_nvme_cmd::default();
+        cmd.set_opc(SPDK_NVME_OPC_RESERVATION_ACQUIRE as u16);
+        cmd.__bindgen_anon_1.cdw10 =
+            u32::from(acquire_action & 0x7) | (u32::from(resv_type) << 8);
+
+        let mut buffer = self.dma_malloc(16).context(DmaAlloc {
+            size: 16usize,
+        })?;
+        let data = buffer.as_mut_slice();
+        data[0 .. 8].copy_from_slice(&current_key.to_le_bytes());
+        data[8 .. 16].copy_from_slice(&preempt_key.to_le_bytes());
+
+        self.nvme_io_passthru(&cmd, &mut buffer).await
+    }
+
+    /// release or clear the reservation held with the given reservation type
+    pub async fn nvme_resv_release(
+        &self,
+        current_key: u64,
+        release_action: u8,
+        resv_type: u8,
+    ) -> Result<(), CoreError> {
+        let mut cmd = spdk_nvme_cmd::default();
+        cmd.set_opc(SPDK_NVME_OPC_RESERVATION_RELEASE as u16);
+        cmd.__bindgen_anon_1.cdw10 =
+            u32::from(release_action & 0x7) | (u32::from(resv_type) << 8);
+
+        let mut buffer = self.dma_malloc(8).context(DmaAlloc {
+            size: 8usize,
+        })?;
+        buffer.as_mut_slice()[0 .. 8]
+            .copy_from_slice(&current_key.to_le_bytes());
+
+        self.nvme_io_passthru(&cmd, &mut buffer).await
+    }
+
+    /// read the reservation status of the namespace, with the extended data
+    /// of the registered controllers which NVMe-oF targets require, into the
+    /// ['DmaBuf']
+    pub async fn nvme_resv_report(
+        &self,
+        buffer: &mut DmaBuf,
+    ) -> Result<(), CoreError> {
+        let mut cmd = spdk_nvme_cmd::default();
+        cmd.set_opc(SPDK_NVME_OPC_RESERVATION_REPORT as u16);
+        // number of dwords to transfer, zero based
+        cmd.__bindgen_anon_1.cdw10 = (buffer.len() / 4 - 1) as u32;
+        // extended data structure, with 128 bit host identifiers
+        cmd.__bindgen_anon_2.cdw11 = 1;
+
+        self.nvme_io_passthru(&cmd, buffer).await
+    }
 }
 
 impl Drop for BdevHandle {
@@ -86,6 +86,36 @@ pub enum CoreError {
         offset: u64,
         len: usize,
     },
+    #[snafu(display(
+        "IO at offset {} length {} conflicts with the reservation of another host",
+        offset,
+        len
+    ))]
+    ReservationConflict {
+        offset: u64,
+        len: usize,
+    },
     #[snafu(display("Reset failed"))]
     ResetFailed {},
+    #[snafu(display("Failed to dispatch NVMe IO command {:x}h", opcode))]
+    NvmeIoPassthruDispatch {
+        source: Errno,
+        opcode: u16,
+    },
+    #[snafu(display(
+        "NVMe IO command {:x}h failed with status type {:x}h code {:x}h",
+        opcode,
+        sct,
+        sc
+    ))]
+    NvmeIoPassthruFailed {
+        opcode: u16,
+        sct: i32,
+        sc: i32,
+    },
+    #[snafu(display("Failed to allocate a DMA buffer of {} bytes", size))]
+    DmaAlloc {
+        source: DmaError,
+        size: usize,
+    },
 }
@@ -1,5 +1,9 @@
-use std::ffi::{c_void, CString};
+use std::{
+    ffi::{c_void, CString},
+    panic::{self, AssertUnwindSafe},
+};
 
+use futures::channel::oneshot;
 use snafu::Snafu;
 
 use spdk_sys::{
@@ -152,4 +156,46 @@ impl Mthread {
     pub fn unaffinitize() {
         unsafe { spdk_unaffinitize_thread() }
     }
+
+    /// Run the blocking function, such as file IO, on a thread of its own
+    /// and return its result on the current thread, which must be an SPDK
+    /// thread. The result is handed back with a message rather than by waking
+    /// the future from the other thread, which is not safe for the futures
+    /// spawned on the reactors.
+    pub async fn spawn_blocking<F, R>(f: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        type Done<R> = (oneshot::Sender<Option<R>>, Option<R>);
+
+        extern "C" fn done<R>(arg: *mut c_void) {
+            let (sender, result) =
+                *unsafe { Box::from_raw(arg as *mut Done<R>) };
+            let _ = sender.send(result);
+        }
+
+        let thread = Self::current()
+            .expect("blocking function spawned from a non-spdk thread")
+            .0 as usize;
+        let (sender, receiver) = oneshot::channel::<Option<R>>();
+
+        std::thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f)).ok();
+            let arg = Box::into_raw(Box::new((sender, result)));
+            let rc = unsafe {
+                spdk_thread_send_msg(
+                    thread as *mut spdk_thread,
+                    Some(done::<R>),
+                    arg as *mut c_void,
+                )
+            };
+            assert_eq!(rc, 0);
+        });
+
+        receiver
+            .await
+            .expect("blocking function dropped its result")
+            .expect("blocking function panicked")
+    }
 }
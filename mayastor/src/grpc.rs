@@ -1,4 +1,4 @@
-use std::convert::From;
+use std::{convert::From, net::IpAddr, time::Duration};
 
 use tonic::{transport::Server, Request, Response, Status};
 
@@ -8,19 +8,24 @@ use rpc::{
 };
 
 use crate::{
+    backup,
     bdev::{
         nexus::{
             instances,
             nexus_bdev,
             nexus_bdev::{name_to_uuid, uuid_to_name, Nexus, NexusStatus},
             nexus_child::{ChildStatus, NexusChild},
+            nexus_crypto::get_encryption_key,
+            nexus_freeze::DEFAULT_FREEZE_TIMEOUT,
         },
-        nexus_create,
+        nexus_create_with_opts,
+        NexusCreateOpts,
     },
     core::{Cores, Reactors},
     pool,
     rebuild::RebuildJob,
     replica,
+    subsys::AnaState,
 };
 
 #[derive(Debug)]
@@ -42,6 +47,19 @@ fn nexus_lookup(
     }
 }
 
+/// Convert the ANA state of a request
+fn ana_state(state: i32) -> std::result::Result<AnaState, Status> {
+    match NvmeAnaState::from_i32(state) {
+        Some(NvmeAnaState::NvmeAnaOptimized) => Ok(AnaState::Optimized),
+        Some(NvmeAnaState::NvmeAnaNonOptimized) => Ok(AnaState::NonOptimized),
+        Some(NvmeAnaState::NvmeAnaInaccessible) => Ok(AnaState::Inaccessible),
+        None => Err(Status::invalid_argument(format!(
+            "invalid ANA state {}",
+            state
+        ))),
+    }
+}
+
 #[derive(Debug)]
 pub struct MayastorGrpc {}
 
@@ -235,6 +253,84 @@ impl Mayastor for MayastorGrpc {
         Ok(Response::new(reply))
     }
 
+    async fn snapshot_replica(
+        &self,
+        request: Request<SnapshotReplicaRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let uuid = args.uuid.clone();
+        let name = args.name.clone();
+        debug!("Creating snapshot {} of replica {} ...", name, uuid);
+        locally! { replica::snapshot_replica(args) };
+        info!("Created snapshot {} of replica {}", name, uuid);
+        Ok(Response::new(Null {}))
+    }
+
+    async fn clone_snapshot(
+        &self,
+        request: Request<CloneSnapshotRequest>,
+    ) -> Result<Response<CreateReplicaReply>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let uuid = args.uuid.clone();
+        let snapshot = args.snapshot.clone();
+        debug!("Creating replica {} from snapshot {} ...", uuid, snapshot);
+        let reply = locally! { replica::clone_snapshot(args) };
+        info!("Created replica {} from snapshot {}", uuid, snapshot);
+        Ok(Response::new(reply))
+    }
+
+    async fn backup_replica(
+        &self,
+        request: Request<BackupReplicaRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let name = args.name.clone();
+        debug!("Starting backup {} of replica {} ...", name, args.uuid);
+        locally! { backup::backup_replica(args) };
+        info!("Started backup {}", name);
+        Ok(Response::new(Null {}))
+    }
+
+    async fn restore_replica(
+        &self,
+        request: Request<RestoreReplicaRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let name = args.name.clone();
+        debug!("Starting restore of backup {} to {} ...", name, args.uuid);
+        locally! { backup::restore_replica(args) };
+        info!("Started restore of backup {}", name);
+        Ok(Response::new(Null {}))
+    }
+
+    async fn list_backup_jobs(
+        &self,
+        request: Request<Null>,
+    ) -> Result<Response<ListBackupJobsReply>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        assert_eq!(Cores::current(), Cores::first());
+        let reply = backup::list_backup_jobs();
+        trace!("{:?}", reply);
+        Ok(Response::new(reply))
+    }
+
+    async fn remove_backup_job(
+        &self,
+        request: Request<RemoveBackupJobRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let name = args.name.clone();
+        locally! { backup::remove_backup_job(args) };
+        info!("Removed backup job {}", name);
+        Ok(Response::new(Null {}))
+    }
+
     async fn create_nexus(
         &self,
         request: Request<CreateNexusRequest>,
@@ -243,9 +339,16 @@ impl Mayastor for MayastorGrpc {
         trace!("{:?}", args);
         let uuid = args.uuid.clone();
         let name = uuid_to_name(&args.uuid)?;
+        let opts = NexusCreateOpts::from_request(&args)?;
         debug!("Creating nexus {} ...", uuid);
         locally! { async move {
-            nexus_create(&name, args.size, Some(&args.uuid), &args.children).await
+            nexus_create_with_opts(
+                &name,
+                args.size,
+                Some(&args.uuid),
+                &args.children,
+                opts,
+            ).await
         }};
         info!("Created nexus {}", uuid);
         Ok(Response::new(Null {}))
@@ -284,6 +387,11 @@ impl Mayastor for MayastorGrpc {
                         .map(Child::from)
                         .collect::<Vec<_>>(),
                     rebuilds: RebuildJob::count() as u32,
+                    layout: n.layout() as i32,
+                    cache: n.cache_info(),
+                    async_children: n.async_children(),
+                    shares: n.shares(),
+                    key_rotation: n.key_rotation(),
                 })
                 .collect::<Vec<_>>(),
         };
@@ -300,7 +408,12 @@ impl Mayastor for MayastorGrpc {
         let uuid = args.uuid.clone();
         debug!("Adding child {} to nexus {} ...", args.uri, uuid);
         locally! { async move {
-            nexus_lookup(&args.uuid)?.add_child(&args.uri, args.norebuild).await.map(|_| ())
+            let nexus = nexus_lookup(&args.uuid)?;
+            if args.asynchronous {
+                nexus.add_async_child(&args.uri).await
+            } else {
+                nexus.add_child(&args.uri, args.norebuild).await.map(|_| ())
+            }
         }};
         info!("Added child to nexus {}", uuid);
         Ok(Response::new(Null {}))
@@ -330,16 +443,11 @@ impl Mayastor for MayastorGrpc {
         let uuid = args.uuid.clone();
         debug!("Publishing nexus {} ...", uuid);
 
-        if args.key != "" && args.key.len() != 16 {
+        // keys are fetched from the key provider by their ID instead
+        if !args.key.is_empty() {
             return Err(nexus_bdev::Error::InvalidKey {}.into());
         }
 
-        let key: Option<String> = if args.key.is_empty() {
-            None
-        } else {
-            Some(args.key.clone())
-        };
-
         let share_protocol = match ShareProtocolNexus::from_i32(args.share) {
             Some(protocol) => protocol,
             None => {
@@ -350,8 +458,16 @@ impl Mayastor for MayastorGrpc {
             }
         };
 
+        let ana_state = ana_state(args.ana_state)?;
+
         let device_path = locally! { async move {
-            nexus_lookup(&args.uuid)?.share(share_protocol, key).await
+            let nexus = nexus_lookup(&args.uuid)?;
+            let key = match args.key_id.as_str() {
+                "" => None,
+                key_id => Some(get_encryption_key(key_id).await?),
+            };
+            nexus.ana_state = ana_state;
+            nexus.share(share_protocol, key).await
         }};
 
         info!("Published nexus {} under {}", uuid, device_path);
@@ -367,9 +483,27 @@ impl Mayastor for MayastorGrpc {
         let args = request.into_inner();
         trace!("{:?}", args);
         let uuid = args.uuid.clone();
+        let protocols = args
+            .protocols
+            .iter()
+            .map(|p| {
+                ShareProtocolNexus::from_i32(*p).ok_or(
+                    nexus_bdev::Error::InvalidShareProtocol {
+                        sp_value: *p,
+                    },
+                )
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
         debug!("Unpublishing nexus {} ...", uuid);
         locally! { async move {
-            nexus_lookup(&args.uuid)?.unshare().await
+            let nexus = nexus_lookup(&args.uuid)?;
+            if protocols.is_empty() {
+                return nexus.unshare().await;
+            }
+            for protocol in protocols {
+                nexus.unshare_protocol(protocol).await?;
+            }
+            Ok(())
         }};
         info!("Unpublished nexus {}", uuid);
         Ok(Response::new(Null {}))
@@ -382,18 +516,19 @@ impl Mayastor for MayastorGrpc {
         let args = request.into_inner();
         trace!("{:?}", args);
 
-        let onl = match args.action {
-            1 => Ok(true),
-            0 => Ok(false),
-            _ => Err(Status::invalid_argument("Bad child operation")),
+        let action = match ChildAction::from_i32(args.action) {
+            Some(action) => Ok(action),
+            None => Err(Status::invalid_argument("Bad child operation")),
         }?;
 
         locally! { async move {
             let nexus = nexus_lookup(&args.uuid)?;
-            if onl {
-                nexus.online_child(&args.uri).await
-            } else {
-                nexus.offline_child(&args.uri).await
+            match action {
+                ChildAction::Online => nexus.online_child(&args.uri).await,
+                ChildAction::Offline => nexus.offline_child(&args.uri).await,
+                ChildAction::Promote => {
+                    nexus.promote_async_child(&args.uri).await
+                }
             }
         }};
 
@@ -471,6 +606,147 @@ impl Mayastor for MayastorGrpc {
             nexus_lookup(&args.uuid)?.get_rebuild_progress(&args.uri)
         }}))
     }
+
+    async fn set_nexus_ana_state(
+        &self,
+        request: Request<SetNexusAnaStateRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let uuid = args.uuid.clone();
+        let state = ana_state(args.ana_state)?;
+        let address = if args.address.is_empty() {
+            None
+        } else {
+            Some(args.address.parse::<IpAddr>().map_err(|_| {
+                Status::invalid_argument(format!(
+                    "invalid address {}",
+                    args.address
+                ))
+            })?)
+        };
+        debug!("Setting ANA state of nexus {} to {} ...", uuid, state);
+        locally! { async move {
+            nexus_lookup(&args.uuid)?
+                .set_ana_state(state, address.as_ref())
+                .await
+        }};
+        info!("Set ANA state of nexus {} to {}", uuid, state);
+        Ok(Response::new(Null {}))
+    }
+
+    async fn rotate_nexus_key(
+        &self,
+        request: Request<RotateNexusKeyRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let uuid = args.uuid.clone();
+        let key_id = args.key_id.clone();
+        debug!("Rotating key of nexus {} to {} ...", uuid, key_id);
+        locally! { async move {
+            let nexus = nexus_lookup(&args.uuid)?;
+            let key = get_encryption_key(&args.key_id).await?;
+            nexus.rotate_key(key).await
+        }};
+        info!("Rotating key of nexus {} to {}", uuid, key_id);
+        Ok(Response::new(Null {}))
+    }
+
+    async fn freeze_nexus(
+        &self,
+        request: Request<FreezeNexusRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let uuid = args.uuid.clone();
+        let timeout = if args.timeout == 0 {
+            DEFAULT_FREEZE_TIMEOUT
+        } else {
+            Duration::from_secs(u64::from(args.timeout))
+        };
+        debug!("Freezing nexus {} ...", uuid);
+        locally! { async move {
+            nexus_lookup(&args.uuid)?.freeze(timeout).await
+        }};
+        info!("Froze nexus {}", uuid);
+        Ok(Response::new(Null {}))
+    }
+
+    async fn thaw_nexus(
+        &self,
+        request: Request<ThawNexusRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let uuid = args.uuid.clone();
+        debug!("Thawing nexus {} ...", uuid);
+        locally! { async move {
+            nexus_lookup(&args.uuid)?.thaw().await
+        }};
+        info!("Thawed nexus {}", uuid);
+        Ok(Response::new(Null {}))
+    }
+
+    async fn create_checkpoint(
+        &self,
+        request: Request<CreateCheckpointRequest>,
+    ) -> Result<Response<CreateCheckpointReply>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let uuid = args.uuid.clone();
+        let name = args.name.clone();
+        debug!("Creating checkpoint {} of nexus {} ...", name, uuid);
+        let snapshots = locally! { async move {
+            nexus_lookup(&args.uuid)?
+                .create_checkpoint(&args.name, args.snapshot)
+                .await
+        }};
+        info!("Created checkpoint {} of nexus {}", name, uuid);
+        Ok(Response::new(CreateCheckpointReply {
+            snapshots,
+        }))
+    }
+
+    async fn destroy_checkpoint(
+        &self,
+        request: Request<DestroyCheckpointRequest>,
+    ) -> Result<Response<Null>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let uuid = args.uuid.clone();
+        let name = args.name.clone();
+        debug!("Destroying checkpoint {} of nexus {} ...", name, uuid);
+        locally! { async move {
+            nexus_lookup(&args.uuid)?.destroy_checkpoint(&args.name).await
+        }};
+        info!("Destroyed checkpoint {} of nexus {}", name, uuid);
+        Ok(Response::new(Null {}))
+    }
+
+    async fn list_checkpoints(
+        &self,
+        request: Request<ListCheckpointsRequest>,
+    ) -> Result<Response<ListCheckpointsReply>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        let reply = ListCheckpointsReply {
+            checkpoints: nexus_lookup(&args.uuid)?.checkpoints(),
+        };
+        trace!("{:?}", reply);
+        Ok(Response::new(reply))
+    }
+
+    async fn get_changed_extents(
+        &self,
+        request: Request<GetChangedExtentsRequest>,
+    ) -> Result<Response<GetChangedExtentsReply>> {
+        let args = request.into_inner();
+        trace!("{:?}", args);
+        Ok(Response::new(locally! { async move {
+            nexus_lookup(&args.uuid)?.changed_extents(&args.from, &args.to)
+        }}))
+    }
 }
 
 pub async fn grpc_server_run(endpoint: &str) -> std::result::Result<(), ()> {
@@ -9,7 +9,7 @@ extern crate serde_json;
 extern crate snafu;
 extern crate spdk_sys;
 
-pub use replicas::{rebuild, replica};
+pub use replicas::{backup, rebuild, replica};
 
 pub mod bdev;
 pub mod core;
@@ -0,0 +1,383 @@
+#![warn(missing_docs)]
+
+use crossbeam::channel::{Receiver, Sender};
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+use rpc::mayastor::{
+    BackupJobInfo,
+    BackupJobKind,
+    BackupReplicaRequest,
+    ListBackupJobsReply,
+    RemoveBackupJobRequest,
+    RestoreReplicaRequest,
+};
+
+use crate::{
+    bdev::VerboseError,
+    core::{BdevHandle, CoreError, DmaError},
+    rebuild::{
+        rebuild_impl::RebuildStates,
+        ClientOperations,
+        RebuildError,
+        RebuildState,
+    },
+    replica::{self, Replica},
+};
+
+use super::{
+    backup_impl::*,
+    backup_store::{ObjectStore, StoreError},
+};
+
+/// Version of the manifest format
+pub const MANIFEST_VERSION: u32 = 1;
+/// Size of the chunks of a backup when none is given
+pub const DEFAULT_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+#[allow(missing_docs)]
+/// Various backup errors when interacting with a backup job or encountered
+/// while copying the chunks
+pub enum BackupError {
+    #[snafu(display("Replica {} not found", replica))]
+    ReplicaNotFound { replica: String },
+    #[snafu(display("Invalid backup name {}", name))]
+    InvalidBackupName { name: String },
+    #[snafu(display(
+        "Chunk size {} is not a multiple of the block size {}",
+        size,
+        block_len
+    ))]
+    InvalidChunkSize { size: u64, block_len: u32 },
+    #[snafu(display("Failed to find backup job {}", job))]
+    JobNotFound { job: String },
+    #[snafu(display("Backup job {} already exists", job))]
+    JobAlreadyExists { job: String },
+    #[snafu(display("Failed to get a handle for bdev {}", bdev))]
+    NoBdevHandle { source: CoreError, bdev: String },
+    #[snafu(display("Failed to allocate buffer for the backup copy"))]
+    NoCopyBuffer { source: DmaError },
+    #[snafu(display("Failed to open object store {}", uri))]
+    OpenStore { source: StoreError, uri: String },
+    #[snafu(display("Read IO failed for bdev {}", bdev))]
+    ReadIoError { source: CoreError, bdev: String },
+    #[snafu(display("Write IO failed for bdev {}", bdev))]
+    WriteIoError { source: CoreError, bdev: String },
+    #[snafu(display("Failed to store chunk {}", chunk))]
+    StoreChunk { source: StoreError, chunk: u64 },
+    #[snafu(display("Failed to fetch chunk {}", chunk))]
+    FetchChunk { source: StoreError, chunk: u64 },
+    #[snafu(display("Failed to (de)compress chunk {}", chunk))]
+    CompressChunk { source: std::io::Error, chunk: u64 },
+    #[snafu(display("Checksum mismatch for chunk {}", chunk))]
+    ChecksumMismatch { chunk: u64 },
+    #[snafu(display("Failed to store the manifest of backup {}", name))]
+    StoreManifest { source: StoreError, name: String },
+    #[snafu(display("Failed to fetch the manifest of backup {}", name))]
+    FetchManifest { source: StoreError, name: String },
+    #[snafu(display("Invalid manifest of backup {}", name))]
+    InvalidManifest {
+        source: serde_json::Error,
+        name: String,
+    },
+    #[snafu(display(
+        "Unsupported version {} of the manifest of backup {}",
+        version,
+        name
+    ))]
+    ManifestVersion { version: u32, name: String },
+    #[snafu(display("Failed to snapshot replica {}", replica))]
+    SnapshotReplica {
+        source: replica::Error,
+        replica: String,
+    },
+    #[snafu(display("Failed to create replica {}", replica))]
+    CreateReplica {
+        source: replica::Error,
+        replica: String,
+    },
+    #[snafu(display(
+        "Block size {} of replica {} does not match the backup's {}",
+        block_len,
+        replica,
+        expected
+    ))]
+    BlockSizeMismatch {
+        replica: String,
+        block_len: u32,
+        expected: u32,
+    },
+    #[snafu(display("Operation on backup job {} failed", job))]
+    JobOperation { source: RebuildError, job: String },
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+/// Direction of a backup job
+pub enum BackupKind {
+    /// Copies a replica to the object store
+    Backup,
+    /// Copies a backup from the object store into a new replica
+    Restore,
+}
+
+/// Manifest of a backup, stored next to its chunks once all of them have
+/// been stored. Chunks which only hold zeroes are not stored at all.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// version of the manifest format
+    pub version: u32,
+    /// name of the backup
+    pub name: String,
+    /// uuid of the replica that was backed up
+    pub replica: String,
+    /// time at which the backup was started
+    pub created: String,
+    /// size of the replica in bytes
+    pub size: u64,
+    /// size in bytes of each block of the replica
+    pub block_len: u32,
+    /// size in bytes of each chunk, except the last one
+    pub chunk_size: u64,
+    /// stored chunks ordered by their index
+    pub chunks: Vec<ManifestChunk>,
+}
+
+/// A chunk of the backup as stored in the object store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestChunk {
+    /// index of the chunk within the replica
+    pub index: u64,
+    /// size of the data of the chunk in bytes
+    pub len: u64,
+    /// size of the compressed chunk in bytes
+    pub stored_len: u64,
+    /// SHA-256 of the data of the chunk
+    pub sha256: String,
+}
+
+/// A backup job copies a replica, or a snapshot of it, to an object store as
+/// a set of compressed and checksummed chunks, or restores such a backup into
+/// a new replica. The chunks are copied by the same concurrent segment tasks
+/// as a rebuild and the job goes through the same states.
+#[derive(Debug)]
+pub struct BackupJob {
+    /// name of the backup
+    pub name: String,
+    /// direction of the job
+    pub kind: BackupKind,
+    /// uuid of the replica that is backed up or restored into
+    pub replica: String,
+    /// URI of the object store holding the backup
+    pub target: String,
+    pub(super) store: Box<dyn ObjectStore>,
+    /// handle of the replica (or its snapshot), closed when the job is done
+    pub(super) hdl: Option<BdevHandle>,
+    /// snapshot taken for the backup, destroyed when the job is done
+    pub(super) snapshot: Option<Replica>,
+    pub(super) manifest: Manifest,
+    /// chunks (backup) or manifest entries (restore) to copy
+    pub(super) units: u64,
+    pub(super) next: u64,
+    pub(super) task_pool: BackupTasks,
+    /// channel used to signal backup update
+    pub notify_chan: (Sender<RebuildState>, Receiver<RebuildState>),
+    pub(super) states: RebuildStates,
+    /// channel list which allows the await of the backup
+    pub(super) complete_chan: Vec<oneshot::Sender<RebuildState>>,
+    /// copy error, if any
+    pub error: Option<BackupError>,
+}
+
+impl BackupJob {
+    /// Starts a backup of the replica to the object store at the target URI
+    /// under the given name. With snapshot set, the backup is taken from a
+    /// snapshot of the replica so that it is consistent while the replica
+    /// is being written to.
+    pub async fn backup<'a>(
+        replica: &str,
+        target: &str,
+        name: &'a str,
+        chunk_size: u64,
+        snapshot: bool,
+    ) -> Result<&'a mut Self, BackupError> {
+        Self::new_backup(replica, target, name, chunk_size, snapshot)
+            .await?
+            .insert()?;
+
+        let job = Self::lookup(name)?;
+        job.as_client().start().map_err(|source| {
+            BackupError::JobOperation {
+                source,
+                job: name.to_owned(),
+            }
+        })?;
+        Ok(job)
+    }
+
+    /// Restores the backup with the given name from the object store at the
+    /// target URI into a new replica on the pool
+    pub async fn restore<'a>(
+        target: &str,
+        name: &'a str,
+        replica: &str,
+        pool: &str,
+        thin: bool,
+    ) -> Result<&'a mut Self, BackupError> {
+        Self::new_restore(target, name, replica, pool, thin)
+            .await?
+            .insert()?;
+
+        let job = Self::lookup(name)?;
+        job.as_client().start().map_err(|source| {
+            BackupError::JobOperation {
+                source,
+                job: name.to_owned(),
+            }
+        })?;
+        Ok(job)
+    }
+
+    /// Lookup a backup job by the name of its backup and return it
+    pub fn lookup(name: &str) -> Result<&mut Self, BackupError> {
+        if let Some(job) = Self::get_instances().get_mut(name) {
+            Ok(job)
+        } else {
+            Err(BackupError::JobNotFound {
+                job: name.to_owned(),
+            })
+        }
+    }
+
+    /// All backup jobs, including the ones that are done
+    pub fn list() -> Vec<&'static mut Self> {
+        Self::get_instances()
+            .values_mut()
+            .map(|j| j.as_mut())
+            .collect()
+    }
+
+    /// Terminates the job if it is still running, then removes it
+    pub async fn remove(name: &str) -> Result<(), BackupError> {
+        let job = Self::lookup(name)?;
+        if !job.state().done() {
+            if let Err(e) = job.as_client().terminate().await {
+                error!("Error {} when waiting for the job to terminate", e);
+            }
+        }
+
+        // a paused job is stopped without running again
+        job.release().await;
+        Self::get_instances().remove(name);
+        Ok(())
+    }
+
+    /// State of the backup job
+    pub fn state(&self) -> RebuildState {
+        self.states.current
+    }
+
+    /// Error description
+    pub fn error_desc(&self) -> String {
+        match self.error.as_ref() {
+            Some(e) => e.verbose(),
+            _ => "".to_string(),
+        }
+    }
+
+    /// ClientOperations trait
+    pub fn as_client(&mut self) -> &mut impl ClientOperations {
+        self
+    }
+}
+
+impl From<BackupError> for tonic::Status {
+    fn from(e: BackupError) -> Self {
+        match e {
+            BackupError::ReplicaNotFound {
+                ..
+            } => Self::not_found(e.to_string()),
+            BackupError::JobNotFound {
+                ..
+            } => Self::not_found(e.to_string()),
+            BackupError::JobAlreadyExists {
+                ..
+            } => Self::already_exists(e.to_string()),
+            BackupError::InvalidBackupName {
+                ..
+            } => Self::invalid_argument(e.to_string()),
+            BackupError::InvalidChunkSize {
+                ..
+            } => Self::invalid_argument(e.to_string()),
+            BackupError::OpenStore {
+                ..
+            } => Self::invalid_argument(e.to_string()),
+            BackupError::ManifestVersion {
+                ..
+            } => Self::failed_precondition(e.to_string()),
+            BackupError::BlockSizeMismatch {
+                ..
+            } => Self::failed_precondition(e.to_string()),
+            BackupError::CreateReplica {
+                source, ..
+            } => Self::from(source),
+            _ => Self::internal(e.verbose()),
+        }
+    }
+}
+
+pub(crate) async fn backup_replica(
+    args: BackupReplicaRequest,
+) -> Result<(), BackupError> {
+    BackupJob::backup(
+        &args.uuid,
+        &args.target,
+        &args.name,
+        args.chunk_size,
+        args.snapshot,
+    )
+    .await
+    .map(|_| ())
+}
+
+pub(crate) async fn restore_replica(
+    args: RestoreReplicaRequest,
+) -> Result<(), BackupError> {
+    BackupJob::restore(
+        &args.target,
+        &args.name,
+        &args.uuid,
+        &args.pool,
+        args.thin,
+    )
+    .await
+    .map(|_| ())
+}
+
+pub(crate) fn list_backup_jobs() -> ListBackupJobsReply {
+    ListBackupJobsReply {
+        jobs: BackupJob::list()
+            .into_iter()
+            .map(|job| BackupJobInfo {
+                name: job.name.clone(),
+                kind: match job.kind {
+                    BackupKind::Backup => BackupJobKind::JobBackup as i32,
+                    BackupKind::Restore => BackupJobKind::JobRestore as i32,
+                },
+                replica: job.replica.clone(),
+                target: job.target.clone(),
+                state: job.state().to_string(),
+                progress: job.as_client().stats().progress as u32,
+                error: job.error_desc(),
+            })
+            .collect(),
+    }
+}
+
+pub(crate) async fn remove_backup_job(
+    args: RemoveBackupJobRequest,
+) -> Result<(), BackupError> {
+    BackupJob::remove(&args.name).await
+}
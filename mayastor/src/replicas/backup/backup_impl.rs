@@ -0,0 +1,748 @@
+#![warn(missing_docs)]
+
+use std::{
+    cell::UnsafeCell,
+    collections::HashMap,
+    io::{Read, Write},
+};
+
+use chrono::Utc;
+use crossbeam::channel::unbounded;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use futures::channel::oneshot;
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use snafu::ResultExt;
+
+use spdk_sys::spdk_get_thread;
+
+use crate::{
+    bdev::VerboseError,
+    core::{BdevHandle, DmaBuf, Reactors},
+    rebuild::{
+        rebuild_impl::{
+            JobStateMachine,
+            RebuildOperation,
+            RebuildStates,
+            RebuildTasks,
+            TaskResult,
+            SEGMENT_TASKS,
+        },
+        ClientOperations,
+        RebuildError,
+        RebuildState,
+        RebuildStats,
+    },
+    replica::Replica,
+};
+
+use super::{backup_api::*, backup_store::store_from_uri};
+
+/// Pool of concurrent chunk copy tasks, the same as for a rebuild
+pub(crate) type BackupTasks = RebuildTasks<BackupError>;
+
+/// Global list of backup jobs using a static OnceCell
+struct BackupInstances {
+    inner: UnsafeCell<HashMap<String, Box<BackupJob>>>,
+}
+
+unsafe impl Sync for BackupInstances {}
+unsafe impl Send for BackupInstances {}
+
+/// key of the manifest of a backup within the object store
+fn manifest_key(name: &str) -> String {
+    format!("{}/manifest.json", name)
+}
+
+/// key of a chunk of a backup within the object store
+fn chunk_key(name: &str, index: u64) -> String {
+    format!("{}/chunks/{:010}", name, index)
+}
+
+/// The name of a backup is part of the keys of its objects, so only allow
+/// characters which need no escaping
+fn validate_name(name: &str) -> Result<(), BackupError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| {
+            c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+        });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(BackupError::InvalidBackupName {
+            name: name.to_owned(),
+        })
+    }
+}
+
+impl BackupJob {
+    /// Stores a backup job in the backup job list
+    pub(super) fn insert(self) -> Result<(), BackupError> {
+        let backup_list = Self::get_instances();
+
+        if backup_list.contains_key(&self.name) {
+            Err(BackupError::JobAlreadyExists {
+                job: self.name,
+            })
+        } else {
+            let _ = backup_list.insert(self.name.clone(), Box::new(self));
+            Ok(())
+        }
+    }
+
+    /// Fails early if a job for the backup exists, before any resources are
+    /// allocated for the new one
+    fn check_new(name: &str) -> Result<(), BackupError> {
+        validate_name(name)?;
+        if Self::get_instances().contains_key(name) {
+            Err(BackupError::JobAlreadyExists {
+                job: name.to_owned(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Opens the bdev and allocates a copy buffer of a chunk for each task
+    fn open_with_tasks(
+        bdev: &str,
+        write: bool,
+        chunk_size: u64,
+    ) -> Result<(BdevHandle, BackupTasks), BackupError> {
+        let hdl =
+            BdevHandle::open(bdev, write, false).context(NoBdevHandle {
+                bdev,
+            })?;
+
+        let block_len = hdl.get_bdev().block_len();
+        if chunk_size == 0 || chunk_size % block_len as u64 != 0 {
+            return Err(BackupError::InvalidChunkSize {
+                size: chunk_size,
+                block_len,
+            });
+        }
+
+        let mut buffers = Vec::new();
+        for _ in 0 .. SEGMENT_TASKS {
+            buffers.push(
+                hdl.dma_malloc(chunk_size as usize)
+                    .context(NoCopyBuffer {})?,
+            );
+        }
+
+        Ok((hdl, RebuildTasks::new(buffers)))
+    }
+
+    /// Returns a new backup job of the replica, or a snapshot of it
+    pub(super) async fn new_backup(
+        replica: &str,
+        target: &str,
+        name: &str,
+        chunk_size: u64,
+        snapshot: bool,
+    ) -> Result<Self, BackupError> {
+        Self::check_new(name)?;
+
+        let store = store_from_uri(target).context(OpenStore {
+            uri: target,
+        })?;
+        let source = match Replica::lookup(replica) {
+            Some(source) => source,
+            None => {
+                return Err(BackupError::ReplicaNotFound {
+                    replica: replica.to_owned(),
+                })
+            }
+        };
+
+        let snapshot = if snapshot {
+            Some(
+                source
+                    .snapshot(&format!("{}-{}", replica, name))
+                    .await
+                    .context(SnapshotReplica {
+                        replica,
+                    })?,
+            )
+        } else {
+            None
+        };
+
+        let bdev = match snapshot.as_ref() {
+            Some(snapshot) => snapshot.get_bdev_name(),
+            None => source.get_bdev_name(),
+        };
+        let chunk_size = if chunk_size == 0 {
+            DEFAULT_CHUNK_SIZE
+        } else {
+            chunk_size
+        };
+
+        let (hdl, task_pool) =
+            match Self::open_with_tasks(&bdev, false, chunk_size) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    if let Some(snapshot) = snapshot {
+                        if let Err(e) = snapshot.destroy().await {
+                            error!(
+                                "Failed to destroy snapshot of backup {}: {}",
+                                name,
+                                e.verbose()
+                            );
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+
+        let bdev = hdl.get_bdev();
+        let block_len = bdev.block_len();
+        let size = bdev.num_blocks() * block_len as u64;
+
+        Ok(Self {
+            name: name.to_owned(),
+            kind: BackupKind::Backup,
+            replica: replica.to_owned(),
+            target: target.to_owned(),
+            store,
+            hdl: Some(hdl),
+            snapshot,
+            manifest: Manifest {
+                version: MANIFEST_VERSION,
+                name: name.to_owned(),
+                replica: replica.to_owned(),
+                created: Utc::now().to_rfc3339(),
+                size,
+                block_len,
+                chunk_size,
+                chunks: Vec::new(),
+            },
+            units: (size + chunk_size - 1) / chunk_size,
+            next: 0,
+            task_pool,
+            notify_chan: unbounded::<RebuildState>(),
+            states: Default::default(),
+            complete_chan: Vec::new(),
+            error: None,
+        })
+    }
+
+    /// Returns a new job which restores the backup into a new replica
+    pub(super) async fn new_restore(
+        target: &str,
+        name: &str,
+        replica: &str,
+        pool: &str,
+        thin: bool,
+    ) -> Result<Self, BackupError> {
+        Self::check_new(name)?;
+
+        let store = store_from_uri(target).context(OpenStore {
+            uri: target,
+        })?;
+        let data =
+            store
+                .get(&manifest_key(name))
+                .await
+                .context(FetchManifest {
+                    name,
+                })?;
+        let manifest: Manifest =
+            serde_json::from_slice(&data).context(InvalidManifest {
+                name,
+            })?;
+
+        if manifest.version != MANIFEST_VERSION {
+            return Err(BackupError::ManifestVersion {
+                version: manifest.version,
+                name: name.to_owned(),
+            });
+        }
+
+        let destination = Replica::create(replica, pool, manifest.size, thin)
+            .await
+            .context(CreateReplica {
+                replica,
+            })?;
+
+        let opened = Self::open_with_tasks(
+            &destination.get_bdev_name(),
+            true,
+            manifest.chunk_size,
+        )
+        .and_then(|(hdl, task_pool)| {
+            let block_len = hdl.get_bdev().block_len();
+            if block_len == manifest.block_len {
+                Ok((hdl, task_pool))
+            } else {
+                Err(BackupError::BlockSizeMismatch {
+                    replica: replica.to_owned(),
+                    block_len,
+                    expected: manifest.block_len,
+                })
+            }
+        });
+
+        let (hdl, task_pool) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                if let Err(e) = destination.destroy().await {
+                    error!(
+                        "Failed to destroy replica {}: {}",
+                        replica,
+                        e.verbose()
+                    );
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            name: name.to_owned(),
+            kind: BackupKind::Restore,
+            replica: replica.to_owned(),
+            target: target.to_owned(),
+            store,
+            hdl: Some(hdl),
+            snapshot: None,
+            units: manifest.chunks.len() as u64,
+            manifest,
+            next: 0,
+            task_pool,
+            notify_chan: unbounded::<RebuildState>(),
+            states: Default::default(),
+            complete_chan: Vec::new(),
+            error: None,
+        })
+    }
+
+    // Runs the management async task that kicks off N chunk copy tasks and
+    // awaits each completion. When any task completes it kicks off another
+    // until all chunks are copied
+    async fn run(&mut self) {
+        self.start_all_tasks();
+        if self.task_pool.active == 0 {
+            // nothing to copy, e.g. restoring a backup of zeroes
+            self.complete();
+        }
+
+        while self.task_pool.active > 0 {
+            match self.task_pool.await_one().await {
+                Some(r) => match r.error {
+                    None => match self.states.pending() {
+                        None | Some(RebuildState::Running) => {
+                            self.start_task_by_id(r.id);
+                        }
+                        _ => {
+                            // await all active tasks as we might still have
+                            // ongoing IO
+                            self.task_pool.await_all().await;
+                            break;
+                        }
+                    },
+                    Some(e) => {
+                        error!(
+                            "Failed to copy chunk {} of backup {} with error: {}",
+                            r.blk,
+                            self.name,
+                            e.verbose()
+                        );
+                        self.fail();
+                        self.task_pool.await_all().await;
+                        self.error = Some(e);
+                        break;
+                    }
+                },
+                None => {
+                    // all senders have disconnected, out of place termination?
+                    error!(
+                        "Out of place termination with potentially {} active tasks",
+                        self.task_pool.active
+                    );
+                    let _ = self.terminate();
+                    break;
+                }
+            }
+        }
+
+        // a backup is only complete once its manifest has been stored
+        if self.kind == BackupKind::Backup
+            && self.states.pending() == Some(RebuildState::Completed)
+        {
+            if let Err(e) = self.store_manifest().await {
+                error!("{}", e.verbose());
+                self.fail();
+                self.error = Some(e);
+            }
+        }
+
+        if self.states.pending().map_or(false, |s| s.done()) {
+            self.release().await;
+        }
+        self.reconcile();
+    }
+
+    /// Closes the handle of the replica and destroys the snapshot, if any.
+    /// Called once the job is done.
+    pub(super) async fn release(&mut self) {
+        self.hdl.take();
+        if let Some(snapshot) = self.snapshot.take() {
+            if let Err(e) = snapshot.destroy().await {
+                error!(
+                    "Failed to destroy snapshot of backup {}: {}",
+                    self.name,
+                    e.verbose()
+                );
+            }
+        }
+    }
+
+    /// Reads one chunk of the replica, then compresses and stores it unless
+    /// it only holds zeroes
+    async fn backup_chunk(
+        &mut self,
+        id: usize,
+        index: u64,
+    ) -> Result<(), BackupError> {
+        let chunk_size = self.manifest.chunk_size;
+        let offset = index * chunk_size;
+        let len = std::cmp::min(chunk_size, self.manifest.size - offset);
+        let hdl = self.hdl.as_ref().expect("backup job is done");
+
+        let mut last_buffer: DmaBuf;
+        let buffer = if len == chunk_size {
+            &mut self.task_pool.tasks[id].buffer
+        } else {
+            last_buffer =
+                hdl.dma_malloc(len as usize).context(NoCopyBuffer {})?;
+            &mut last_buffer
+        };
+
+        hdl.read_at(offset, buffer).await.context(ReadIoError {
+            bdev: hdl.get_bdev().name(),
+        })?;
+
+        let data = buffer.as_slice();
+        if data.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+
+        let sha256 = hex::encode(Sha256::digest(data));
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).context(CompressChunk {
+            chunk: index,
+        })?;
+        let compressed = encoder.finish().context(CompressChunk {
+            chunk: index,
+        })?;
+        let stored_len = compressed.len() as u64;
+
+        self.store
+            .put(&chunk_key(&self.name, index), compressed)
+            .await
+            .context(StoreChunk {
+                chunk: index,
+            })?;
+
+        self.manifest.chunks.push(ManifestChunk {
+            index,
+            len,
+            stored_len,
+            sha256,
+        });
+        Ok(())
+    }
+
+    /// Fetches one chunk of the backup, verifies its checksum and writes it
+    /// to the replica
+    async fn restore_chunk(
+        &mut self,
+        id: usize,
+        entry: u64,
+    ) -> Result<(), BackupError> {
+        let chunk = self.manifest.chunks[entry as usize].clone();
+        let chunk_size = self.manifest.chunk_size;
+
+        let compressed = self
+            .store
+            .get(&chunk_key(&self.name, chunk.index))
+            .await
+            .context(FetchChunk {
+                chunk: chunk.index,
+            })?;
+
+        let mut data = Vec::with_capacity(chunk.len as usize);
+        ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut data)
+            .context(CompressChunk {
+                chunk: chunk.index,
+            })?;
+
+        if data.len() as u64 != chunk.len
+            || hex::encode(Sha256::digest(&data)) != chunk.sha256
+        {
+            return Err(BackupError::ChecksumMismatch {
+                chunk: chunk.index,
+            });
+        }
+
+        let hdl = self.hdl.as_ref().expect("restore job is done");
+        let mut last_buffer: DmaBuf;
+        let buffer = if chunk.len == chunk_size {
+            &mut self.task_pool.tasks[id].buffer
+        } else {
+            last_buffer = hdl
+                .dma_malloc(chunk.len as usize)
+                .context(NoCopyBuffer {})?;
+            &mut last_buffer
+        };
+        buffer.as_mut_slice().copy_from_slice(&data);
+
+        hdl.write_at(chunk.index * chunk_size, buffer)
+            .await
+            .context(WriteIoError {
+                bdev: hdl.get_bdev().name(),
+            })?;
+
+        Ok(())
+    }
+
+    /// Stores the manifest, which marks the backup as complete
+    async fn store_manifest(&mut self) -> Result<(), BackupError> {
+        self.manifest.chunks.sort_by_key(|c| c.index);
+        let data = serde_json::to_vec_pretty(&self.manifest).context(
+            InvalidManifest {
+                name: &self.name,
+            },
+        )?;
+
+        self.store
+            .put(&manifest_key(&self.name), data)
+            .await
+            .context(StoreManifest {
+                name: &self.name,
+            })
+    }
+
+    fn schedule_run(&self) {
+        match self.state() {
+            RebuildState::Paused | RebuildState::Init => {
+                let name = self.name.clone();
+                Reactors::master().send_future(async move {
+                    let job = match BackupJob::lookup(&name) {
+                        Ok(job) => job,
+                        Err(_) => {
+                            return error!(
+                                "Failed to find and start the backup job {}",
+                                name
+                            );
+                        }
+                    };
+
+                    if job.reconcile_to_state(RebuildState::Running) {
+                        job.run().await;
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn start_all_tasks(&mut self) {
+        assert_eq!(
+            self.task_pool.active, 0,
+            "{} active tasks",
+            self.task_pool.active
+        );
+
+        for n in 0 .. self.task_pool.total {
+            self.next = match self.send_chunk_task(n) {
+                Some(next) => {
+                    self.task_pool.active += 1;
+                    next
+                }
+                None => break,
+            };
+        }
+    }
+
+    fn start_task_by_id(&mut self, id: usize) {
+        match self.send_chunk_task(id) {
+            Some(next) => {
+                self.task_pool.active += 1;
+                self.next = next;
+            }
+            None => {
+                if self.task_pool.active == 0 {
+                    self.complete();
+                }
+            }
+        };
+    }
+
+    /// Copies one chunk in a reactor future and notifies the management
+    /// channel. Returns the next chunk to copy, if any. The object stores
+    /// need the tokio runtime which only runs on the master reactor.
+    fn send_chunk_task(&self, id: usize) -> Option<u64> {
+        if self.next >= self.units {
+            None
+        } else {
+            let unit = self.next;
+            let name = self.name.clone();
+
+            Reactors::master().send_future(async move {
+                let job = Self::lookup(&name).unwrap();
+
+                let result = match job.kind {
+                    BackupKind::Backup => job.backup_chunk(id, unit).await,
+                    BackupKind::Restore => job.restore_chunk(id, unit).await,
+                };
+                let r = TaskResult {
+                    blk: unit,
+                    id,
+                    error: result.err(),
+                };
+
+                let task = &mut job.task_pool.tasks[id];
+                if let Err(e) = task.sender.start_send(r) {
+                    error!("Failed to notify job of chunk id: {} unit: {} completion, err: {}", id, unit, e.verbose());
+                }
+            });
+
+            Some(unit + 1)
+        }
+    }
+
+    /// Get the backup job instances container, we ensure that this can only
+    /// ever be called on a properly allocated thread
+    pub(super) fn get_instances() -> &'static mut HashMap<String, Box<Self>> {
+        let thread = unsafe { spdk_get_thread() };
+        if thread.is_null() {
+            panic!("not called from SPDK thread")
+        }
+
+        static BACKUP_INSTANCES: OnceCell<BackupInstances> = OnceCell::new();
+
+        let global_instances =
+            BACKUP_INSTANCES.get_or_init(|| BackupInstances {
+                inner: UnsafeCell::new(HashMap::new()),
+            });
+
+        unsafe { &mut *global_instances.inner.get() }
+    }
+}
+
+impl JobStateMachine for BackupJob {
+    fn job_name(&self) -> &str {
+        &self.name
+    }
+
+    fn states(&self) -> &RebuildStates {
+        &self.states
+    }
+
+    fn states_mut(&mut self) -> &mut RebuildStates {
+        &mut self.states
+    }
+
+    fn schedule(&self) {
+        self.schedule_run();
+    }
+
+    fn notify(&mut self) {
+        self.stats();
+        let state = self.state();
+        if let Err(e) = self.notify_chan.0.send(state) {
+            error!(
+                "Backup job {} failed to send complete via the unbound channel with err {}",
+                self.name, e
+            );
+        }
+
+        if state.done() {
+            self.complete_chan.drain(..).for_each(|s| {
+                s.send(state).ok();
+            });
+        }
+    }
+}
+
+impl ClientOperations for BackupJob {
+    fn stats(&self) -> RebuildStats {
+        let block_size = self.manifest.block_len as u64;
+        let blocks_total = self.manifest.size / block_size;
+
+        // zero chunks are not restored, so count the restored chunks instead
+        let blocks_recovered = match self.kind {
+            BackupKind::Backup => std::cmp::min(
+                self.task_pool.segments_done * self.manifest.chunk_size
+                    / block_size,
+                blocks_total,
+            ),
+            BackupKind::Restore if self.units == 0 => blocks_total,
+            BackupKind::Restore => {
+                blocks_total * self.task_pool.segments_done / self.units
+            }
+        };
+
+        let progress = if blocks_total == 0 {
+            100
+        } else {
+            (blocks_recovered * 100) / blocks_total
+        };
+
+        info!(
+            "State: {}, Backup: {}, Replica: {}, Target: {}, \
+             next: {}/{}, chunk_size: {}, recovered_blks: {}, progress: {}%",
+            self.state(),
+            self.name,
+            self.replica,
+            self.target,
+            self.next,
+            self.units,
+            self.manifest.chunk_size,
+            blocks_recovered,
+            progress,
+        );
+
+        RebuildStats {
+            blocks_total,
+            blocks_recovered,
+            progress,
+            segment_size_blks: self.manifest.chunk_size / block_size,
+            block_size,
+        }
+    }
+
+    fn start(
+        &mut self,
+    ) -> Result<oneshot::Receiver<RebuildState>, RebuildError> {
+        self.exec_client_op(RebuildOperation::Start)?;
+        let end_channel = oneshot::channel();
+        self.complete_chan.push(end_channel.0);
+        Ok(end_channel.1)
+    }
+
+    fn stop(&mut self) -> Result<(), RebuildError> {
+        self.exec_client_op(RebuildOperation::Stop)
+    }
+
+    fn pause(&mut self) -> Result<(), RebuildError> {
+        self.exec_client_op(RebuildOperation::Pause)
+    }
+
+    fn resume(&mut self) -> Result<(), RebuildError> {
+        self.exec_client_op(RebuildOperation::Resume)
+    }
+
+    fn terminate(&mut self) -> oneshot::Receiver<RebuildState> {
+        // a job which is not running stops right away, so the channel has to
+        // be in place before
+        let end_channel = oneshot::channel();
+        self.complete_chan.push(end_channel.0);
+        self.exec_internal_op(RebuildOperation::Stop).ok();
+        end_channel.1
+    }
+}
@@ -0,0 +1,348 @@
+//!
+//! Object stores which hold the chunks and manifests of backups. A store is
+//! selected by the scheme of its URI:
+//!
+//! - `s3://bucket/prefix?endpoint=http://host:9000&region=us-east-1` for S3
+//!   compatible endpoints, with the credentials taken from the
+//!   `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment variables
+//! - `file:///path` for a local directory, e.g. an NFS mount
+//!
+//! The S3 store uses the tokio runtime, so its requests must be made from the
+//! master reactor.
+
+use std::{
+    fmt::{self, Debug},
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use hyper::{body, client::HttpConnector, Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet};
+use sha2::{Digest, Sha256};
+use snafu::{ResultExt, Snafu};
+use url::Url;
+
+use crate::core::Mthread;
+
+/// default region used when signing S3 requests
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// characters encoded in a segment of the path of a signed S3 request, all
+/// but the unreserved ones
+const S3_PATH_SEGMENT: &AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+#[allow(missing_docs)]
+/// Errors of the object stores
+pub enum StoreError {
+    #[snafu(display("Invalid object store URI {}", uri))]
+    InvalidStoreUri { uri: String },
+    #[snafu(display("Unsupported object store scheme {}", scheme))]
+    UnsupportedScheme { scheme: String },
+    #[snafu(display("Missing credentials for object store {}", uri))]
+    MissingCredentials { uri: String },
+    #[snafu(display("IO failed for object {}", path))]
+    FileIo {
+        source: std::io::Error,
+        path: String,
+    },
+    #[snafu(display("Failed to build request for object {}", key))]
+    InvalidRequest {
+        source: hyper::http::Error,
+        key: String,
+    },
+    #[snafu(display("Request for object {} failed", key))]
+    HttpRequest { source: hyper::Error, key: String },
+    #[snafu(display(
+        "Request for object {} failed with status {}",
+        key,
+        status
+    ))]
+    HttpStatus { status: u16, key: String },
+}
+
+/// A flat namespace of objects, addressed by keys relative to the URI of the
+/// store
+#[async_trait(?Send)]
+pub trait ObjectStore: Debug {
+    /// Stores the object under the key, replacing any previous object
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StoreError>;
+    /// Returns the object stored under the key
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StoreError>;
+}
+
+/// Returns the object store for the URI
+pub fn store_from_uri(uri: &str) -> Result<Box<dyn ObjectStore>, StoreError> {
+    let url = Url::parse(uri).map_err(|_| StoreError::InvalidStoreUri {
+        uri: uri.to_string(),
+    })?;
+
+    match url.scheme() {
+        "file" => Ok(Box::new(FileStore {
+            root: PathBuf::from(url.path()),
+        })),
+        "s3" => Ok(Box::new(S3Store::new(uri, &url)?)),
+        scheme => Err(StoreError::UnsupportedScheme {
+            scheme: scheme.to_string(),
+        }),
+    }
+}
+
+/// Objects stored as files in a local directory
+#[derive(Debug)]
+struct FileStore {
+    root: PathBuf,
+}
+
+#[async_trait(?Send)]
+impl ObjectStore for FileStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StoreError> {
+        let path = self.root.join(key);
+        let display = path.display().to_string();
+
+        Mthread::spawn_blocking(move || -> io::Result<()> {
+            let tmp = path.with_extension("tmp");
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            // an object must never be seen partially written
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)
+        })
+        .await
+        .context(FileIo {
+            path: display,
+        })
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StoreError> {
+        let path = self.root.join(key);
+        let display = path.display().to_string();
+
+        Mthread::spawn_blocking(move || fs::read(path))
+            .await
+            .context(FileIo {
+                path: display,
+            })
+    }
+}
+
+/// Objects stored in a bucket of an S3 compatible endpoint, using path style
+/// requests signed with AWS signature version 4
+struct S3Store {
+    endpoint: Url,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Debug for S3Store {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the credentials
+        f.debug_struct("S3Store")
+            .field("endpoint", &self.endpoint.as_str())
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl S3Store {
+    fn new(uri: &str, url: &Url) -> Result<Self, StoreError> {
+        let invalid = || StoreError::InvalidStoreUri {
+            uri: uri.to_string(),
+        };
+
+        let bucket = url.host_str().ok_or_else(invalid)?.to_string();
+        let prefix = percent_decode_str(url.path().trim_matches('/'))
+            .decode_utf8()
+            .map_err(|_| invalid())?
+            .to_string();
+
+        let mut endpoint = None;
+        let mut region = None;
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "endpoint" => {
+                    endpoint = Some(Url::parse(&v).map_err(|_| invalid())?)
+                }
+                "region" => region = Some(v.to_string()),
+                _ => return Err(invalid()),
+            }
+        }
+
+        let region = region
+            .or_else(|| std::env::var("AWS_REGION").ok())
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint,
+            None => Url::parse(&format!("https://s3.{}.amazonaws.com", region))
+                .map_err(|_| invalid())?,
+        };
+
+        let credentials = (
+            std::env::var("AWS_ACCESS_KEY_ID"),
+            std::env::var("AWS_SECRET_ACCESS_KEY"),
+        );
+        let (access_key, secret_key) = match credentials {
+            (Ok(access_key), Ok(secret_key)) => (access_key, secret_key),
+            _ => {
+                return Err(StoreError::MissingCredentials {
+                    uri: uri.to_string(),
+                })
+            }
+        };
+
+        Ok(Self {
+            endpoint,
+            bucket,
+            prefix,
+            region,
+            access_key,
+            secret_key,
+            client: Client::builder().build(HttpsConnector::new()),
+        })
+    }
+
+    /// path of the object relative to the endpoint, with each segment URI
+    /// encoded as required by the canonical request of the signature
+    fn object_path(&self, key: &str) -> String {
+        let path = if self.prefix.is_empty() {
+            format!("{}/{}", self.bucket, key)
+        } else {
+            format!("{}/{}/{}", self.bucket, self.prefix, key)
+        };
+        path.split('/').fold(String::new(), |mut encoded, segment| {
+            encoded.push('/');
+            encoded.extend(utf8_percent_encode(segment, S3_PATH_SEGMENT));
+            encoded
+        })
+    }
+
+    /// value of the host header, which is part of the signature
+    fn host(&self) -> String {
+        let host = self.endpoint.host_str().unwrap_or_default();
+        match self.endpoint.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        }
+    }
+
+    /// Signs and sends the request, returning the body of the response
+    async fn request(
+        &self,
+        method: Method,
+        key: &str,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, StoreError> {
+        let path = self.object_path(key);
+        let host = self.host();
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&data));
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method.as_str(),
+            path,
+            host,
+            payload_hash,
+            amz_date,
+            signed_headers,
+            payload_hash,
+        );
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+
+        let key_date =
+            hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date);
+        let key_region = hmac(&key_date, &self.region);
+        let key_service = hmac(&key_region, "s3");
+        let key_signing = hmac(&key_service, "aws4_request");
+        let signature = hex::encode(hmac(&key_signing, &string_to_sign));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature,
+        );
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+
+        let request = Request::builder()
+            .method(method)
+            .uri(url.as_str())
+            .header("host", host)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(Body::from(data))
+            .context(InvalidRequest {
+                key,
+            })?;
+
+        let response =
+            self.client.request(request).await.context(HttpRequest {
+                key,
+            })?;
+        let status = response.status();
+        let body = body::to_bytes(response.into_body()).await.context(
+            HttpRequest {
+                key,
+            },
+        )?;
+
+        if !status.is_success() {
+            return Err(StoreError::HttpStatus {
+                status: status.as_u16(),
+                key: key.to_string(),
+            });
+        }
+
+        Ok(body.to_vec())
+    }
+}
+
+#[async_trait(?Send)]
+impl ObjectStore for S3Store {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), StoreError> {
+        self.request(Method::PUT, key, data).await.map(|_| ())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StoreError> {
+        self.request(Method::GET, key, Vec::new()).await
+    }
+}
+
+/// HMAC-SHA256 of the message
+fn hmac(key: &[u8], msg: &str) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.input(msg.as_bytes());
+    mac.result().code().to_vec()
+}
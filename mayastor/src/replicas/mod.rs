@@ -1,5 +1,17 @@
 pub mod replica;
 
+pub mod backup {
+    pub use backup_api::*;
+    pub use backup_store::{ObjectStore, StoreError};
+
+    /// Backup api module
+    pub mod backup_api;
+    /// Backup implementation module
+    pub mod backup_impl;
+    /// Object stores holding the backups
+    pub mod backup_store;
+}
+
 pub mod rebuild {
     pub use rebuild_api::*;
     // for the tests only
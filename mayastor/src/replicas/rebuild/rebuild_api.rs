@@ -134,6 +134,24 @@ pub struct RebuildJob {
     pub(super) complete_chan: Vec<oneshot::Sender<RebuildState>>,
     /// rebuild copy error, if any
     pub error: Option<RebuildError>,
+    /// additional sources when reconstructing a column of a parity nexus
+    pub(super) parity: Option<RebuildParity>,
+}
+
+/// Surviving children and geometry of a parity nexus. The destination is
+/// reconstructed from the XOR of all surviving columns rather than copied
+/// from a single source
+#[derive(Debug, Clone)]
+pub struct ParitySources {
+    /// URIs of the children holding all other columns, the first one is used
+    /// as the source of the rebuild job
+    pub survivors: Vec<String>,
+    /// number of blocks of a strip stored on a single child
+    pub strip_blks: u64,
+    /// number of nexus blocks in a full stripe
+    pub stripe_blks: u64,
+    /// number of blocks of the nexus
+    pub nexus_blks: u64,
 }
 
 /// rebuild statistics
@@ -190,6 +208,22 @@ impl RebuildJob {
         Ok(Self::lookup(destination)?)
     }
 
+    /// Creates a new RebuildJob which reconstructs the destination column of
+    /// a parity nexus from all surviving columns, otherwise the same as
+    /// `create`
+    pub fn create_parity<'a>(
+        nexus: &str,
+        sources: ParitySources,
+        destination: &'a str,
+        range: std::ops::Range<u64>,
+        notify_fn: fn(String, String) -> (),
+    ) -> Result<&'a mut Self, RebuildError> {
+        Self::new_parity(nexus, sources, destination, range, notify_fn)?
+            .store()?;
+
+        Ok(Self::lookup(destination)?)
+    }
+
     /// Lookup a rebuild job by its destination uri and return it
     pub fn lookup(name: &str) -> Result<&mut Self, RebuildError> {
         if let Some(job) = Self::get_instances().get_mut(name) {
@@ -201,11 +235,12 @@ impl RebuildJob {
         }
     }
 
-    /// Lookup all rebuilds jobs with name as its source
+    /// Lookup all rebuilds jobs with name as its source, this includes the
+    /// surviving columns of parity rebuilds
     pub fn lookup_src(name: &str) -> Vec<&mut Self> {
         Self::get_instances()
             .iter_mut()
-            .filter(|j| j.1.source == name)
+            .filter(|j| j.1.source == name || j.1.parity_source(name))
             .map(|j| j.1.as_mut())
             .collect::<Vec<_>>()
     }
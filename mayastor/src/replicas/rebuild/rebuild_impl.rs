@@ -13,7 +13,7 @@ use snafu::ResultExt;
 use spdk_sys::spdk_get_thread;
 
 use crate::{
-    bdev::VerboseError,
+    bdev::{nexus::nexus_parity::xor_into, VerboseError},
     core::{Bdev, BdevHandle, DmaBuf, RangeContext, Reactors},
     nexus_uri::bdev_get_name,
 };
@@ -32,41 +32,91 @@ unsafe impl Send for RebuildInstances {}
 /// used to communicate with the management task indicating that the
 /// segment task worker is ready to copy another segment
 #[derive(Debug, Clone)]
-struct TaskResult {
+pub(crate) struct TaskResult<E = RebuildError> {
     /// block that was being rebuilt
-    blk: u64,
+    pub(crate) blk: u64,
     /// id of the task
-    id: usize,
+    pub(crate) id: usize,
     /// encountered error, if any
-    error: Option<RebuildError>,
+    pub(crate) error: Option<E>,
 }
 
 /// Number of concurrent copy tasks per rebuild job
-const SEGMENT_TASKS: usize = 4;
+pub(crate) const SEGMENT_TASKS: usize = 4;
 /// Size of each segment used by the copy task
 pub const SEGMENT_SIZE: u64 = 10 * 1024; // 10KiB
 
 /// Each rebuild task needs a unique buffer to read/write from source to target
 /// A mpsc channel is used to communicate with the management task
 #[derive(Debug)]
-struct RebuildTask {
-    buffer: DmaBuf,
-    sender: mpsc::Sender<TaskResult>,
-    error: Option<TaskResult>,
+pub(crate) struct RebuildTask<E = RebuildError> {
+    pub(crate) buffer: DmaBuf,
+    pub(crate) sender: mpsc::Sender<TaskResult<E>>,
 }
 
 /// Pool of rebuild tasks and progress tracking
 /// Each task uses a clone of the sender allowing the management task to poll a
 /// single receiver
 #[derive(Debug)]
-pub(super) struct RebuildTasks {
-    tasks: Vec<RebuildTask>,
+pub(crate) struct RebuildTasks<E = RebuildError> {
+    pub(crate) tasks: Vec<RebuildTask<E>>,
 
-    channel: (mpsc::Sender<TaskResult>, mpsc::Receiver<TaskResult>),
-    active: usize,
-    total: usize,
+    channel: (mpsc::Sender<TaskResult<E>>, mpsc::Receiver<TaskResult<E>>),
+    pub(crate) active: usize,
+    pub(crate) total: usize,
 
-    segments_done: u64,
+    pub(crate) segments_done: u64,
+}
+
+impl<E> RebuildTasks<E> {
+    /// Returns a pool with one task for each of the copy buffers
+    pub(crate) fn new(buffers: Vec<DmaBuf>) -> Self {
+        // only sending one message per channel at a time so we don't need
+        // the extra buffer
+        let channel = mpsc::channel(0);
+        let tasks = buffers
+            .into_iter()
+            .map(|buffer| RebuildTask {
+                buffer,
+                sender: channel.0.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            total: tasks.len(),
+            tasks,
+            channel,
+            active: 0,
+            segments_done: 0,
+        }
+    }
+
+    /// Awaits the completion of any active task
+    pub(crate) async fn await_one(&mut self) -> Option<TaskResult<E>> {
+        self.channel.1.next().await.map(|f| {
+            self.active -= 1;
+            if f.error.is_none() {
+                self.segments_done += 1;
+            }
+            f
+        })
+    }
+
+    /// Awaits the completion of all active tasks
+    pub(crate) async fn await_all(&mut self) {
+        while self.await_one().await.is_some() {
+            if self.active == 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Handles to the surviving columns of a parity nexus, other than the source
+#[derive(Debug)]
+pub(super) struct RebuildParity {
+    sources: ParitySources,
+    handles: Vec<BdevHandle>,
 }
 
 /// Checks whether a range is contained within another range
@@ -142,26 +192,15 @@ impl RebuildJob {
         let block_size = destination_hdl.get_bdev().block_len() as u64;
         let segment_size_blks = (SEGMENT_SIZE / block_size) as u64;
 
-        let mut tasks = RebuildTasks {
-            tasks: Vec::new(),
-            // only sending one message per channel at a time so we don't need
-            // the extra buffer
-            channel: mpsc::channel(0),
-            active: 0,
-            total: SEGMENT_TASKS,
-            segments_done: 0,
-        };
-
-        for _ in 0 .. tasks.total {
-            let copy_buffer = destination_hdl
-                .dma_malloc((segment_size_blks * block_size) as usize)
-                .context(NoCopyBuffer {})?;
-            tasks.tasks.push(RebuildTask {
-                buffer: copy_buffer,
-                sender: tasks.channel.0.clone(),
-                error: None,
-            });
+        let mut buffers = Vec::new();
+        for _ in 0 .. SEGMENT_TASKS {
+            buffers.push(
+                destination_hdl
+                    .dma_malloc((segment_size_blks * block_size) as usize)
+                    .context(NoCopyBuffer {})?,
+            );
         }
+        let tasks = RebuildTasks::new(buffers);
 
         let (source, destination, nexus) = (
             source.to_string(),
@@ -191,9 +230,66 @@ impl RebuildJob {
             states: Default::default(),
             complete_chan: Vec::new(),
             error: None,
+            parity: None,
         })
     }
 
+    /// Returns a new rebuild job which reconstructs a column of a parity
+    /// nexus, the first survivor is the source of the job
+    pub(super) fn new_parity(
+        nexus: &str,
+        sources: ParitySources,
+        destination: &str,
+        range: std::ops::Range<u64>,
+        notify_fn: fn(String, String) -> (),
+    ) -> Result<Self, RebuildError> {
+        let source = match sources.survivors.first() {
+            Some(source) => source.clone(),
+            None => return Err(RebuildError::InvalidParameters {}),
+        };
+
+        let mut job = Self::new(nexus, &source, destination, range, notify_fn)?;
+
+        let mut handles = Vec::new();
+        for uri in sources.survivors.iter().skip(1) {
+            let hdl = BdevHandle::open(
+                &bdev_get_name(uri).context(BdevInvalidURI {
+                    uri: uri.to_string(),
+                })?,
+                false,
+                false,
+            )
+            .context(NoBdevHandle {
+                bdev: uri,
+            })?;
+
+            if !Self::validate(
+                &hdl.get_bdev(),
+                &job.destination_hdl.get_bdev(),
+                &job.range,
+            ) {
+                return Err(RebuildError::InvalidParameters {});
+            }
+
+            handles.push(hdl);
+        }
+
+        job.parity = Some(RebuildParity {
+            sources,
+            handles,
+        });
+
+        Ok(job)
+    }
+
+    /// True if name is one of the surviving columns of a parity rebuild
+    pub(super) fn parity_source(&self, name: &str) -> bool {
+        match self.parity.as_ref() {
+            Some(parity) => parity.sources.survivors.iter().any(|s| s == name),
+            None => false,
+        }
+    }
+
     // Runs the management async task that kicks off N rebuild copy tasks and
     // awaits each completion. When any task completes it kicks off another
     // until the bdev is fully rebuilt
@@ -243,6 +339,27 @@ impl RebuildJob {
         self.segment_size_blks
     }
 
+    /// Returns the range of nexus blocks, as offset and length, that must be
+    /// locked while copying the given blocks of the destination. Each block of
+    /// a parity column belongs to a stripe of the nexus that spans all
+    /// columns, so all stripes involved are locked.
+    fn nexus_lock_range(&self, blk: u64, len: u64) -> (u64, u64) {
+        let offset = blk - self.range.start;
+
+        match self.parity.as_ref() {
+            Some(parity) => {
+                let geo = &parity.sources;
+                let first = offset / geo.strip_blks;
+                let last = (offset + len - 1) / geo.strip_blks;
+                let start = first * geo.stripe_blks;
+                let end =
+                    std::cmp::min((last + 1) * geo.stripe_blks, geo.nexus_blks);
+                (start, end - start)
+            }
+            None => (offset, len),
+        }
+    }
+
     /// Copies one segment worth of data from source into destination. During
     /// this time the LBA range being copied is locked so that there cannot be
     /// front end I/O to the same LBA range.
@@ -266,7 +383,8 @@ impl RebuildJob {
         // nexus has a data partition only. Because we are locking the range on
         // the nexus, we need to calculate the offset from the start of the data
         // partition.
-        let mut ctx = RangeContext::new(blk - self.range.start, len);
+        let (lock_blk, lock_len) = self.nexus_lock_range(blk, len);
+        let mut ctx = RangeContext::new(lock_blk, lock_len);
         let ch = self
             .nexus_descriptor
             .get_channel()
@@ -334,6 +452,27 @@ impl RebuildJob {
                 bdev: &self.source,
             })?;
 
+        // the missing column is the XOR of all surviving columns
+        if let Some(parity) = self.parity.as_ref() {
+            let mut scratch = self
+                .destination_hdl
+                .dma_malloc(copy_buffer.len())
+                .context(NoCopyBuffer {})?;
+
+            for (hdl, uri) in parity
+                .handles
+                .iter()
+                .zip(parity.sources.survivors.iter().skip(1))
+            {
+                hdl.read_at(blk * self.block_size, &mut scratch)
+                    .await
+                    .context(ReadIoError {
+                        bdev: uri,
+                    })?;
+                xor_into(copy_buffer.as_mut_slice(), scratch.as_slice());
+            }
+        }
+
         self.destination_hdl
             .write_at(blk * self.block_size, copy_buffer)
             .await
@@ -344,11 +483,6 @@ impl RebuildJob {
         Ok(())
     }
 
-    fn notify(&mut self) {
-        self.stats();
-        self.send_notify();
-    }
-
     /// Calls the job's registered notify fn callback and notify sender channel
     fn send_notify(&mut self) {
         // should this return a status before we notify the sender channel?
@@ -372,30 +506,7 @@ impl RebuildJob {
             && source.block_len() == destination.block_len()
     }
 
-    /// reconcile the pending state to the current and clear the pending
-    fn reconcile(&mut self) {
-        let old = self.state();
-        let new = self.states.reconcile();
-
-        if old != new {
-            info!(
-                "Rebuild job {}: changing state from {:?} to {:?}",
-                self.destination, old, new
-            );
-            self.notify();
-        }
-    }
-
-    /// reconciles to state if it's the same as the pending value
-    fn reconcile_to_state(&mut self, state: RebuildState) -> bool {
-        if self.states.pending_equals(state) {
-            self.reconcile();
-            true
-        } else {
-            false
-        }
-    }
-    fn schedule(&self) {
+    fn schedule_run(&self) {
         match self.state() {
             RebuildState::Paused | RebuildState::Init => {
                 let destination = self.destination.clone();
@@ -440,7 +551,7 @@ impl RebuildJob {
 
 #[derive(Debug)]
 /// Operations used to control the state of the job
-enum RebuildOperation {
+pub(crate) enum RebuildOperation {
     /// Client Operations
     ///
     /// Starts the job for the first time
@@ -529,24 +640,6 @@ impl ClientOperations for RebuildJob {
     }
 }
 
-/// Internal facing operations on a Rebuild Job
-trait InternalOperations {
-    /// Fails the job, overriding any pending client operation
-    fn fail(&mut self);
-    /// Completes the job, overriding any pending operation
-    fn complete(&mut self);
-}
-
-impl InternalOperations for RebuildJob {
-    fn fail(&mut self) {
-        self.exec_internal_op(RebuildOperation::Fail).ok();
-    }
-
-    fn complete(&mut self) {
-        self.exec_internal_op(RebuildOperation::Complete).ok();
-    }
-}
-
 impl RebuildJob {
     fn start_all_tasks(&mut self) {
         assert_eq!(
@@ -582,23 +675,11 @@ impl RebuildJob {
     }
 
     async fn await_one_task(&mut self) -> Option<TaskResult> {
-        self.task_pool.channel.1.next().await.map(|f| {
-            self.task_pool.active -= 1;
-            if f.error.is_none() {
-                self.task_pool.segments_done += 1;
-            } else {
-                self.task_pool.tasks[f.id].error = Some(f.clone());
-            }
-            f
-        })
+        self.task_pool.await_one().await
     }
 
     async fn await_all_tasks(&mut self) {
-        while self.await_one_task().await.is_some() {
-            if self.task_pool.active == 0 {
-                break;
-            }
-        }
+        self.task_pool.await_all().await
     }
 
     /// Sends one segment worth of data in a reactor future and notifies the
@@ -635,7 +716,7 @@ impl RebuildJob {
 }
 
 #[derive(Debug, Default)]
-pub(super) struct RebuildStates {
+pub(crate) struct RebuildStates {
     /// Current state of the rebuild job
     pub current: RebuildState,
 
@@ -680,6 +761,11 @@ impl RebuildStates {
         }
     }
 
+    /// the pending state, if any
+    pub(crate) fn pending(&self) -> Option<RebuildState> {
+        self.pending
+    }
+
     /// a change to `state` is pending
     fn pending_equals(&self, state: RebuildState) -> bool {
         self.pending == Some(state)
@@ -696,7 +782,57 @@ impl RebuildStates {
     }
 }
 
-impl RebuildJob {
+/// State machine of a job which copies a bdev in segments. It is shared by
+/// the rebuild job and other jobs built on the same segment tasks, which only
+/// have to provide their states and how to run and notify
+pub(crate) trait JobStateMachine {
+    /// name of the job used in the logs
+    fn job_name(&self) -> &str;
+    /// current and pending states of the job
+    fn states(&self) -> &RebuildStates;
+    /// mutable current and pending states of the job
+    fn states_mut(&mut self) -> &mut RebuildStates;
+    /// schedules the job to run (again) if it is not already running
+    fn schedule(&self);
+    /// notifies the owner of the job of a state change
+    fn notify(&mut self);
+
+    /// reconcile the pending state to the current and clear the pending
+    fn reconcile(&mut self) {
+        let old = self.states().current;
+        let new = self.states_mut().reconcile();
+
+        if old != new {
+            info!(
+                "Job {}: changing state from {:?} to {:?}",
+                self.job_name(),
+                old,
+                new
+            );
+            self.notify();
+        }
+    }
+
+    /// reconciles to state if it's the same as the pending value
+    fn reconcile_to_state(&mut self, state: RebuildState) -> bool {
+        if self.states().pending_equals(state) {
+            self.reconcile();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Fails the job, overriding any pending client operation
+    fn fail(&mut self) {
+        self.exec_internal_op(RebuildOperation::Fail).ok();
+    }
+
+    /// Completes the job, overriding any pending operation
+    fn complete(&mut self) {
+        self.exec_internal_op(RebuildOperation::Complete).ok();
+    }
+
     /// Client operations are now allowed to skip over previous operations
     fn exec_client_op(
         &mut self,
@@ -720,7 +856,7 @@ impl RebuildJob {
         type S = RebuildState;
         let e = RebuildError::OpError {
             operation: op.to_string(),
-            state: self.states.to_string(),
+            state: self.states().to_string(),
         };
 
         trace!(
@@ -731,31 +867,31 @@ impl RebuildJob {
 
         match op {
             RebuildOperation::Start => {
-                match self.state() {
+                match self.states().current {
                     // start only allowed when... starting
                     S::Stopped | S::Paused | S::Failed | S::Completed => Err(e),
                     // for idempotence sake
                     S::Running => Ok(()),
                     S::Init => {
-                        self.states.set_pending(S::Running, false)?;
+                        self.states_mut().set_pending(S::Running, false)?;
                         self.schedule();
                         Ok(())
                     }
                 }
             }
             RebuildOperation::Stop => {
-                match self.state() {
+                match self.states().current {
                     // We're already stopping anyway, so all is well
                     S::Failed | S::Completed => Err(e),
                     // for idempotence sake
                     S::Stopped => Ok(()),
                     S::Running => {
-                        self.states
+                        self.states_mut()
                             .set_pending(S::Stopped, override_pending)?;
                         Ok(())
                     }
                     S::Init | S::Paused => {
-                        self.states
+                        self.states_mut()
                             .set_pending(S::Stopped, override_pending)?;
 
                         // The rebuild is not running so we need to reconcile
@@ -764,39 +900,64 @@ impl RebuildJob {
                     }
                 }
             }
-            RebuildOperation::Pause => match self.state() {
+            RebuildOperation::Pause => match self.states().current {
                 S::Stopped | S::Failed | S::Completed => Err(e),
                 S::Init | S::Running | S::Paused => {
-                    self.states.set_pending(S::Paused, false)?;
+                    self.states_mut().set_pending(S::Paused, false)?;
                     Ok(())
                 }
             },
-            RebuildOperation::Resume => match self.state() {
+            RebuildOperation::Resume => match self.states().current {
                 S::Init | S::Stopped | S::Failed | S::Completed => Err(e),
                 S::Running | S::Paused => {
-                    self.states.set_pending(S::Running, false)?;
+                    self.states_mut().set_pending(S::Running, false)?;
                     self.schedule();
                     Ok(())
                 }
             },
-            RebuildOperation::Fail => match self.state() {
+            RebuildOperation::Fail => match self.states().current {
                 S::Init | S::Stopped | S::Paused | S::Completed => Err(e),
                 // for idempotence sake
                 S::Failed => Ok(()),
                 S::Running => {
-                    self.states.set_pending(S::Failed, override_pending)?;
+                    self.states_mut()
+                        .set_pending(S::Failed, override_pending)?;
                     Ok(())
                 }
             },
-            RebuildOperation::Complete => match self.state() {
+            RebuildOperation::Complete => match self.states().current {
                 S::Init | S::Paused | S::Stopped | S::Failed | S::Completed => {
                     Err(e)
                 }
                 S::Running => {
-                    self.states.set_pending(S::Completed, override_pending)?;
+                    self.states_mut()
+                        .set_pending(S::Completed, override_pending)?;
                     Ok(())
                 }
             },
         }
     }
 }
+
+impl JobStateMachine for RebuildJob {
+    fn job_name(&self) -> &str {
+        &self.destination
+    }
+
+    fn states(&self) -> &RebuildStates {
+        &self.states
+    }
+
+    fn states_mut(&mut self) -> &mut RebuildStates {
+        &mut self.states
+    }
+
+    fn schedule(&self) {
+        self.schedule_run();
+    }
+
+    fn notify(&mut self) {
+        self.stats();
+        self.send_notify();
+    }
+}
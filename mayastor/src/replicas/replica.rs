@@ -3,7 +3,13 @@
 //! Replica is a logical data volume exported over nvmf (in SPDK terminology
 //! an lvol). Here we define methods for easy management of replicas.
 
-use std::ffi::{c_void, CStr, CString};
+use std::{
+    ffi::{c_void, CStr, CString},
+    fs,
+    io,
+    path::PathBuf,
+    ptr,
+};
 
 use futures::{
     channel::oneshot,
@@ -13,6 +19,7 @@ use nix::errno::Errno;
 use snafu::{ResultExt, Snafu};
 
 use rpc::mayastor::{
+    CloneSnapshotRequest,
     CreateReplicaReply,
     CreateReplicaRequest,
     DestroyReplicaRequest,
@@ -22,12 +29,22 @@ use rpc::mayastor::{
     ShareProtocolReplica,
     ShareReplicaReply,
     ShareReplicaRequest,
+    SnapshotReplicaRequest,
     StatReplicasReply,
     Stats,
 };
 use spdk_sys::{
+    spdk_blob_get_xattr_value,
+    spdk_blob_is_snapshot,
+    spdk_blob_set_xattr,
+    spdk_blob_sync_md,
     spdk_lvol,
+    spdk_nvmf_ns,
+    spdk_nvmf_ns_reservation_done_fn,
+    spdk_nvmf_ns_reservation_ops,
     vbdev_lvol_create,
+    vbdev_lvol_create_clone,
+    vbdev_lvol_create_snapshot,
     vbdev_lvol_destroy,
     vbdev_lvol_get_from_bdev,
     LVOL_CLEAR_WITH_UNMAP,
@@ -36,11 +53,12 @@ use spdk_sys::{
 };
 
 use crate::{
-    core::Bdev,
+    bdev::nexus::nexus_reservation::{namespace_reservation, ptpl_contents},
+    core::{Bdev, Mthread},
     ffihelper::{cb_arg, done_errno_cb, errno_result_from_i32, ErrnoResult},
     jsonrpc::{jsonrpc_register, Code, RpcErrorCode},
     pool::Pool,
-    subsys::NvmfSubsystem,
+    subsys::{Config, NvmfError, NvmfShareOpts, NvmfSubsystem},
     target,
 };
 
@@ -53,6 +71,10 @@ pub enum RpcError {
     DestroyReplica { source: Error, uuid: String },
     #[snafu(display("Failed to (un)share replica {}", uuid))]
     ShareReplica { source: Error, uuid: String },
+    #[snafu(display("Failed to snapshot replica {}", uuid))]
+    SnapshotReplica { source: Error, uuid: String },
+    #[snafu(display("Failed to clone snapshot {}", snapshot))]
+    CloneSnapshot { source: Error, snapshot: String },
 }
 
 impl RpcErrorCode for RpcError {
@@ -67,6 +89,12 @@ impl RpcErrorCode for RpcError {
             RpcError::ShareReplica {
                 source, ..
             } => source.rpc_error_code(),
+            RpcError::SnapshotReplica {
+                source, ..
+            } => source.rpc_error_code(),
+            RpcError::CloneSnapshot {
+                source, ..
+            } => source.rpc_error_code(),
         }
     }
 }
@@ -83,6 +111,12 @@ impl From<RpcError> for tonic::Status {
             RpcError::ShareReplica {
                 source, ..
             } => Self::from(source),
+            RpcError::SnapshotReplica {
+                source, ..
+            } => Self::from(source),
+            RpcError::CloneSnapshot {
+                source, ..
+            } => Self::from(source),
         }
     }
 }
@@ -100,20 +134,28 @@ pub enum Error {
     CreateLvol { source: Errno },
     #[snafu(display("Failed to destroy lvol"))]
     DestroyLvol { source: Errno },
+    #[snafu(display("Failed to create snapshot {}", name))]
+    CreateSnapshot { source: Errno, name: String },
+    #[snafu(display("Failed to create clone {}", name))]
+    CreateClone { source: Errno, name: String },
+    #[snafu(display("Replica is not a snapshot"))]
+    NotSnapshot {},
     #[snafu(display("Replica has been already shared"))]
     ReplicaShared {},
     #[snafu(display("share nvmf"))]
-    ShareNvmf { source: target::nvmf::Error },
+    ShareNvmf { source: NvmfError },
     #[snafu(display("share iscsi"))]
     ShareIscsi { source: target::iscsi::Error },
     #[snafu(display("unshare nvmf"))]
-    UnshareNvmf { source: target::nvmf::Error },
+    UnshareNvmf { source: NvmfError },
     #[snafu(display("unshare iscsi"))]
     UnshareIscsi { source: target::iscsi::Error },
     #[snafu(display("Invalid share protocol {} in request", protocol))]
     InvalidProtocol { protocol: i32 },
     #[snafu(display("Replica does not exist"))]
     ReplicaNotFound {},
+    #[snafu(display("Failed to restore the reservation of the replica"))]
+    RestoreReservation { source: std::io::Error },
 }
 
 impl RpcErrorCode for Error {
@@ -137,6 +179,9 @@ impl RpcErrorCode for Error {
             Error::InvalidProtocol {
                 ..
             } => Code::InvalidParams,
+            Error::NotSnapshot {
+                ..
+            } => Code::InvalidParams,
             Error::ShareNvmf {
                 source, ..
             } => source.rpc_error_code(),
@@ -172,6 +217,15 @@ impl From<Error> for tonic::Status {
             Error::DestroyLvol {
                 ..
             } => Self::internal(e.to_string()),
+            Error::CreateSnapshot {
+                ..
+            } => Self::internal(e.to_string()),
+            Error::CreateClone {
+                ..
+            } => Self::internal(e.to_string()),
+            Error::NotSnapshot {
+                ..
+            } => Self::invalid_argument(e.to_string()),
             Error::ReplicaShared {
                 ..
             } => Self::internal(e.to_string()),
@@ -193,18 +247,26 @@ impl From<Error> for tonic::Status {
             Error::ReplicaNotFound {
                 ..
             } => Self::not_found(e.to_string()),
+            Error::RestoreReservation {
+                ..
+            } => Self::internal(e.to_string()),
         }
     }
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// extended attribute of the lvol holding the NVMe reservation of the
+/// replica, which carries the generation of the nexus that owns it
+const RESERVATION_XATTR: &str = "mayastor.reservation";
+
 /// Structure representing a replica which is basically SPDK lvol.
 ///
 /// Note about safety: The structure wraps raw C pointer from SPDK.
 /// It is safe to use only in synchronous context. If you keep Replica for
 /// longer than that then something else can run on reactor_0 inbetween
 /// which may destroy the replica and invalidate the pointer!
+#[derive(Debug)]
 pub struct Replica {
     lvol_ptr: *mut spdk_lvol,
 }
@@ -220,16 +282,12 @@ pub enum ShareType {
 fn detect_share(uuid: &str) -> Option<(ShareType, String)> {
     // first try nvmf and then try iscsi
     if let Some(s) = NvmfSubsystem::nqn_lookup(uuid) {
-        let mut ep = s.uri_endpoints().unwrap();
-        return Some((ShareType::Nvmf, ep.pop().unwrap()));
+        return Some((ShareType::Nvmf, s.uri().unwrap()));
     }
 
-    match target::nvmf::get_uri(uuid) {
-        Some(uri) => Some((ShareType::Nvmf, uri)),
-        None => match target::iscsi::get_uri(target::Side::Replica, uuid) {
-            Some(uri) => Some((ShareType::Iscsi, uri)),
-            None => None,
-        },
+    match target::iscsi::get_uri(target::Side::Replica, uuid) {
+        Some(uri) => Some((ShareType::Iscsi, uri)),
+        None => None,
     }
 }
 
@@ -336,6 +394,69 @@ impl Replica {
         Ok(())
     }
 
+    /// Create a read-only snapshot of the replica. Writes to the replica
+    /// after this point do not change the data of the snapshot.
+    pub async fn snapshot(&self, name: &str) -> Result<Self> {
+        let c_name = CString::new(name).unwrap();
+        let (sender, receiver) =
+            oneshot::channel::<ErrnoResult<*mut spdk_lvol>>();
+        unsafe {
+            vbdev_lvol_create_snapshot(
+                self.lvol_ptr,
+                c_name.as_ptr(),
+                Some(Self::replica_done_cb),
+                cb_arg(sender),
+            );
+        }
+
+        let lvol_ptr = receiver
+            .await
+            .expect("Cancellation is not supported")
+            .context(CreateSnapshot {
+            name,
+        })?;
+
+        info!("Created snapshot {} of replica {}", name, self.get_uuid());
+        Ok(Self {
+            lvol_ptr,
+        })
+    }
+
+    /// Create a writable replica from the snapshot, which reads the data of
+    /// the snapshot until it is overwritten.
+    pub async fn create_clone(&self, name: &str) -> Result<Self> {
+        if !self.is_snapshot() {
+            return Err(Error::NotSnapshot {});
+        }
+        if Self::lookup(name).is_some() {
+            return Err(Error::ReplicaExists {});
+        }
+
+        let c_name = CString::new(name).unwrap();
+        let (sender, receiver) =
+            oneshot::channel::<ErrnoResult<*mut spdk_lvol>>();
+        unsafe {
+            vbdev_lvol_create_clone(
+                self.lvol_ptr,
+                c_name.as_ptr(),
+                Some(Self::replica_done_cb),
+                cb_arg(sender),
+            );
+        }
+
+        let lvol_ptr = receiver
+            .await
+            .expect("Cancellation is not supported")
+            .context(CreateClone {
+            name,
+        })?;
+
+        info!("Created clone {} of snapshot {}", name, self.get_uuid());
+        Ok(Self {
+            lvol_ptr,
+        })
+    }
+
     /// Expose replica over supported remote access storage protocols (nvmf
     /// and iscsi).
     pub async fn share(&self, kind: ShareType) -> Result<()> {
@@ -347,9 +468,23 @@ impl Replica {
         let bdev = unsafe { Bdev::from((*self.lvol_ptr).bdev) };
 
         match kind {
-            ShareType::Nvmf => target::nvmf::share(&uuid, &bdev)
-                .await
-                .context(ShareNvmf {})?,
+            ShareType::Nvmf => {
+                // the target restores the reservation from the file when
+                // the namespace is added, every change is saved on the
+                // replica before the command making it completes
+                let ops = spdk_nvmf_ns_reservation_ops {
+                    check: None,
+                    update: Some(Self::reservation_update),
+                };
+                let opts = NvmfShareOpts {
+                    ptpl_file: Some(self.restore_reservation().await?),
+                    reservation_ops: Some((ops, self.lvol_ptr as *mut c_void)),
+                    ..Default::default()
+                };
+                NvmfSubsystem::share(&uuid, &bdev, &opts)
+                    .await
+                    .context(ShareNvmf {})?;
+            }
             ShareType::Iscsi => {
                 target::iscsi::share(&uuid, &bdev, target::Side::Replica)
                     .context(ShareIscsi {})?
@@ -364,9 +499,15 @@ impl Replica {
         let uuid = self.get_uuid().to_owned();
         if let Some((share_type, _)) = detect_share(&uuid) {
             match share_type {
-                ShareType::Nvmf => target::nvmf::unshare(&uuid)
-                    .await
-                    .context(UnshareNvmf {})?,
+                ShareType::Nvmf => {
+                    NvmfSubsystem::unshare(&uuid)
+                        .await
+                        .context(UnshareNvmf {})?;
+                    let path = self.reservation_file();
+                    let _ =
+                        Mthread::spawn_blocking(move || fs::remove_file(path))
+                            .await;
+                }
                 ShareType::Iscsi => target::iscsi::unshare(&uuid)
                     .await
                     .context(UnshareIscsi {})?,
@@ -396,6 +537,12 @@ impl Replica {
         u64::from(bdev.block_len()) * bdev.num_blocks()
     }
 
+    /// Get name of the bdev of the replica.
+    pub fn get_bdev_name(&self) -> String {
+        let bdev: Bdev = unsafe { (*self.lvol_ptr).bdev.into() };
+        bdev.name()
+    }
+
     /// Get name of the pool which replica belongs to.
     pub fn get_pool_name(&self) -> &str {
         unsafe {
@@ -418,11 +565,169 @@ impl Replica {
         unsafe { (*self.lvol_ptr).thin_provision }
     }
 
+    /// Return if the replica is a read-only snapshot of another replica.
+    pub fn is_snapshot(&self) -> bool {
+        unsafe { spdk_blob_is_snapshot((*self.lvol_ptr).blob) }
+    }
+
     /// Return raw pointer to lvol (C struct spdk_lvol).
     pub fn as_ptr(&self) -> *mut spdk_lvol {
         self.lvol_ptr
     }
 
+    /// Return the generation of the nexus that owns the replica, which is
+    /// the key of the holder of its reservation, if any.
+    pub fn owner_generation(&self) -> Option<u64> {
+        let saved: serde_json::Value =
+            serde_json::from_str(&self.saved_reservation()?).ok()?;
+        match saved["holder_uuid"].as_str() {
+            Some(holder) if !holder.is_empty() => saved["crkey"].as_u64(),
+            _ => None,
+        }
+    }
+
+    /// file in which the nvmf target keeps the reservation of the namespace
+    fn reservation_file(&self) -> PathBuf {
+        PathBuf::from(&Config::get().nexus_opts.ptpl_dir)
+            .join(format!("replica-{}.ptpl", self.get_uuid()))
+    }
+
+    /// the reservation saved on the replica, in the format of the file
+    fn saved_reservation(&self) -> Option<String> {
+        let name = CString::new(RESERVATION_XATTR).unwrap();
+        let mut value: *const c_void = ptr::null();
+        let mut len = 0;
+        let rc = unsafe {
+            spdk_blob_get_xattr_value(
+                (*self.lvol_ptr).blob,
+                name.as_ptr(),
+                &mut value,
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return None;
+        }
+
+        let value = unsafe {
+            std::slice::from_raw_parts(value as *const u8, len as usize)
+        };
+        String::from_utf8(value.to_vec()).ok()
+    }
+
+    /// Write the reservation saved on the replica to the file the target
+    /// restores it from and return the path of the file.
+    async fn restore_reservation(&self) -> Result<String> {
+        let path = self.reservation_file();
+        let saved = self.saved_reservation();
+        let file_path = path.clone();
+
+        Mthread::spawn_blocking(move || -> io::Result<()> {
+            if let Some(dir) = file_path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            // a file left behind by an earlier share must not be restored
+            match fs::remove_file(&file_path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            match saved {
+                Some(saved) => fs::write(&file_path, saved),
+                None => Ok(()),
+            }
+        })
+        .await
+        .context(RestoreReservation {})?;
+
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Called by the target once a command changed the reservation of the
+    /// namespace of the replica. The reservation is saved on the replica and
+    /// the command completes once its metadata has been synced.
+    extern "C" fn reservation_update(
+        ns: *mut spdk_nvmf_ns,
+        ctx: *mut c_void,
+        done_fn: spdk_nvmf_ns_reservation_done_fn,
+        done_ctx: *mut c_void,
+    ) {
+        let replica = Replica {
+            lvol_ptr: ctx as *mut spdk_lvol,
+        };
+        let done = move |status: i32| {
+            if let Some(done_fn) = done_fn {
+                unsafe { done_fn(done_ctx, status) };
+            }
+        };
+
+        // snapshots are read only, there is nothing to protect
+        if replica.is_snapshot() {
+            return done(0);
+        }
+
+        let bdev = unsafe { Bdev::from((*replica.lvol_ptr).bdev) };
+        let state = unsafe { namespace_reservation(ns) };
+        let contents = ptpl_contents(&state, bdev.uuid_as_string());
+        if contents.len() > u16::MAX as usize {
+            error!(
+                "Reservation of replica {} is too large to save",
+                replica.get_uuid()
+            );
+            return done(-(Errno::E2BIG as i32));
+        }
+
+        let name = CString::new(RESERVATION_XATTR).unwrap();
+        let rc = unsafe {
+            spdk_blob_set_xattr(
+                (*replica.lvol_ptr).blob,
+                name.as_ptr(),
+                contents.as_ptr() as *const c_void,
+                contents.len() as u16,
+            )
+        };
+        if rc != 0 {
+            error!(
+                "Failed to save the reservation of replica {}: {}",
+                replica.get_uuid(),
+                Errno::from_i32(-rc)
+            );
+            return done(rc);
+        }
+
+        let ctx = Box::new((replica.get_uuid().to_owned(), done_fn, done_ctx));
+        unsafe {
+            spdk_blob_sync_md(
+                (*replica.lvol_ptr).blob,
+                Some(Self::sync_md_cb),
+                Box::into_raw(ctx) as *mut c_void,
+            );
+        }
+    }
+
+    /// Callback called from SPDK when the metadata of the replica is synced,
+    /// which completes the reservation command that changed it.
+    extern "C" fn sync_md_cb(ctx: *mut c_void, errno: i32) {
+        let (uuid, done_fn, done_ctx) = *unsafe {
+            Box::from_raw(
+                ctx as *mut (
+                    String,
+                    spdk_nvmf_ns_reservation_done_fn,
+                    *mut c_void,
+                ),
+            )
+        };
+        if errno != 0 {
+            error!(
+                "Failed to sync the reservation of replica {}: {}",
+                uuid,
+                Errno::from_i32(-errno)
+            );
+        }
+        if let Some(done_fn) = done_fn {
+            unsafe { done_fn(done_ctx, errno) };
+        }
+    }
+
     /// Callback called from SPDK for replica create method.
     extern "C" fn replica_done_cb(
         sender_ptr: *mut c_void,
@@ -580,6 +885,7 @@ pub(crate) fn list_replicas() -> ListReplicasReply {
                     None => ShareProtocolReplica::ReplicaNone as i32,
                 },
                 uri: r.get_share_uri(),
+                snapshot: r.is_snapshot(),
             })
             .collect::<Vec<ReplicaJson>>(),
     }
@@ -682,6 +988,75 @@ pub(crate) async fn share_replica(
     })
 }
 
+pub(crate) async fn snapshot_replica(
+    args: SnapshotReplicaRequest,
+) -> Result<(), RpcError> {
+    let replica = match Replica::lookup(&args.uuid) {
+        Some(replica) => replica,
+        None => Err(Error::ReplicaNotFound {}).context(SnapshotReplica {
+            uuid: args.uuid.clone(),
+        })?,
+    };
+    if Replica::lookup(&args.name).is_some() {
+        return Err(Error::ReplicaExists {}).context(SnapshotReplica {
+            uuid: args.uuid,
+        });
+    }
+    replica
+        .snapshot(&args.name)
+        .await
+        .context(SnapshotReplica {
+            uuid: args.uuid,
+        })?;
+    Ok(())
+}
+
+pub(crate) async fn clone_snapshot(
+    args: CloneSnapshotRequest,
+) -> Result<CreateReplicaReply, RpcError> {
+    let want_share = match ShareProtocolReplica::from_i32(args.share) {
+        Some(val) => val,
+        None => Err(Error::InvalidProtocol {
+            protocol: args.share,
+        })
+        .context(CloneSnapshot {
+            snapshot: args.snapshot.clone(),
+        })?,
+    };
+    let snapshot = match Replica::lookup(&args.snapshot) {
+        Some(snapshot) => snapshot,
+        None => Err(Error::ReplicaNotFound {}).context(CloneSnapshot {
+            snapshot: args.snapshot.clone(),
+        })?,
+    };
+    let replica =
+        snapshot
+            .create_clone(&args.uuid)
+            .await
+            .context(CloneSnapshot {
+                snapshot: args.snapshot.clone(),
+            })?;
+
+    match want_share {
+        ShareProtocolReplica::ReplicaNvmf => replica
+            .share(ShareType::Nvmf)
+            .await
+            .context(CloneSnapshot {
+                snapshot: args.snapshot.clone(),
+            })?,
+        ShareProtocolReplica::ReplicaIscsi => replica
+            .share(ShareType::Iscsi)
+            .await
+            .context(CloneSnapshot {
+                snapshot: args.snapshot.clone(),
+            })?,
+        ShareProtocolReplica::ReplicaNone => (),
+    }
+    Ok(CreateReplicaReply {
+        uri: replica.get_share_uri(),
+    })
+}
+
 /// Register replica json-rpc methods.
 pub fn register_replica_methods() {
     jsonrpc_register::<_, _, _, RpcError>(
@@ -708,4 +1083,14 @@ pub fn register_replica_methods() {
             async move { share_replica(args).await }.boxed_local()
         },
     );
+
+    jsonrpc_register::<_, _, _, RpcError>(
+        "snapshot_replica",
+        |args: SnapshotReplicaRequest| snapshot_replica(args).boxed_local(),
+    );
+
+    jsonrpc_register::<_, _, _, RpcError>(
+        "clone_snapshot",
+        |args: CloneSnapshotRequest| clone_snapshot(args).boxed_local(),
+    );
 }
@@ -12,8 +12,10 @@ use byte_unit::Byte;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 
+use rpc::mayastor::{NexusCacheMode, NexusLayout};
+
 use crate::{
-    bdev::{nexus::instances, nexus_create},
+    bdev::{nexus::instances, nexus_create_with_opts, NexusCreateOpts},
     core::{Bdev, Cores, Reactor},
     nexus_uri::bdev_create,
     pool::{create_pool, PoolsIter},
@@ -26,6 +28,7 @@ use crate::{
             NvmeBdevOpts,
             NvmfTgtConfig,
         },
+        NvmfShareOpts,
         NvmfSubsystem,
     },
 };
@@ -135,15 +138,32 @@ impl Config {
         // collect nexus bdevs and insert them into the config
         let nexus_bdevs = instances()
             .iter()
-            .map(|nexus| NexusBdev {
-                name: nexus.name.clone(),
-                uuid: nexus.bdev.uuid_as_string(),
-                size: nexus.bdev.size_in_bytes().to_string(),
-                children: nexus
-                    .children
-                    .iter()
-                    .map(|child| child.name.clone())
-                    .collect::<Vec<_>>(),
+            .map(|nexus| {
+                // the order of the children determines the columns of a
+                // parity nexus
+                let mut children = nexus.children.iter().collect::<Vec<_>>();
+                children.sort_by_key(|child| child.column);
+
+                NexusBdev {
+                    name: nexus.name.clone(),
+                    uuid: nexus.bdev.uuid_as_string(),
+                    size: nexus.bdev.size_in_bytes().to_string(),
+                    children: children
+                        .iter()
+                        .map(|child| child.name.clone())
+                        .collect::<Vec<_>>(),
+                    parity: nexus.parity.is_some(),
+                    cache: nexus
+                        .cache_spec
+                        .as_ref()
+                        .map(|(uri, _)| uri.clone()),
+                    cache_write_back: nexus
+                        .cache_spec
+                        .as_ref()
+                        .map_or(false, |(_, mode)| {
+                            *mode == NexusCacheMode::CacheWriteBack
+                        }),
+                }
             })
             .collect::<Vec<_>>();
 
@@ -216,11 +236,26 @@ impl Config {
                 info!("creating nexus {}", nexus.name);
                 match Byte::from_str(&nexus.size) {
                     Ok(val) => {
-                        if nexus_create(
+                        let opts = NexusCreateOpts {
+                            layout: if nexus.parity {
+                                NexusLayout::NexusParity
+                            } else {
+                                NexusLayout::NexusMirror
+                            },
+                            cache: nexus.cache.clone(),
+                            cache_mode: if nexus.cache_write_back {
+                                NexusCacheMode::CacheWriteBack
+                            } else {
+                                NexusCacheMode::CacheWriteThrough
+                            },
+                        };
+
+                        if nexus_create_with_opts(
                             &nexus.name,
                             val.get_bytes() as u64,
                             Some(&nexus.uuid),
                             &nexus.children,
+                            opts,
                         )
                         .await
                         .is_err()
@@ -271,13 +306,14 @@ impl Config {
                     continue;
                 }
 
-                if let Ok(ss) = NvmfSubsystem::new_with_uuid(&uuid, &my_bdev) {
-                    ss.start()
-                        .await
-                        .map_err(|_| {
-                            warn!("failed to share {}", my_bdev);
-                        })
-                        .unwrap();
+                if let Err(e) = NvmfSubsystem::share(
+                    &uuid,
+                    &my_bdev,
+                    &NvmfShareOpts::default(),
+                )
+                .await
+                {
+                    warn!("failed to share {}: {}", my_bdev, e);
                 }
             }
         }
@@ -331,6 +367,16 @@ pub struct NexusBdev {
     pub size: String,
     /// the children the nexus should be created on
     pub children: Vec<String>,
+    /// stripe the data with parity across the children rather than mirroring
+    /// it, the order of the children must not change
+    #[serde(default)]
+    pub parity: bool,
+    /// uri of a local bdev used to cache the data of the children
+    #[serde(default)]
+    pub cache: Option<String>,
+    /// acknowledge writes once they are stored in the cache
+    #[serde(default)]
+    pub cache_write_back: bool,
 }
 
 #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -19,11 +19,12 @@ use spdk_sys::{
     spdk_subsystem_fini_next,
     spdk_subsystem_init_next,
 };
-pub use subsystem::{NvmfSubsystem, SubType};
+pub use subsystem::{AnaState, NvmfSubsystem, ShareOpts, SubType};
 pub use target::Target;
 
 use crate::{
     core::Bdev,
+    jsonrpc::{Code, RpcErrorCode},
     subsys::{nvmf::target::NVMF_TGT, Config},
 };
 
@@ -67,6 +68,17 @@ pub enum Error {
     Namespace { bdev: String, msg: String },
 }
 
+impl RpcErrorCode for Error {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            Error::Share {
+                ..
+            } => Code::AlreadyExists,
+            _ => Code::InternalError,
+        }
+    }
+}
+
 thread_local! {
     pub (crate) static NVMF_PGS: RefCell<Vec<PollGroup>> = RefCell::new(Vec::new());
 }
@@ -3,6 +3,7 @@ use std::{
     fmt,
     fmt::{Debug, Display},
     mem::size_of,
+    net::IpAddr,
     ptr,
     ptr::NonNull,
 };
@@ -12,10 +13,14 @@ use nix::errno::Errno;
 use serde::export::{Formatter, TryFrom};
 
 use spdk_sys::{
-    spdk_bdev_nvme_opts,
+    nvmf_subsystem_set_ana_state,
+    spdk_nvme_ana_state,
     spdk_nvmf_ns_get_bdev,
     spdk_nvmf_ns_opts,
+    spdk_nvmf_ns_reservation_ops,
+    spdk_nvmf_ns_set_reservation_ops,
     spdk_nvmf_subsystem,
+    spdk_nvmf_subsystem_add_host,
     spdk_nvmf_subsystem_add_listener,
     spdk_nvmf_subsystem_add_ns,
     spdk_nvmf_subsystem_create,
@@ -26,6 +31,8 @@ use spdk_sys::{
     spdk_nvmf_subsystem_get_next,
     spdk_nvmf_subsystem_get_next_listener,
     spdk_nvmf_subsystem_get_nqn,
+    spdk_nvmf_subsystem_get_ns,
+    spdk_nvmf_subsystem_listener,
     spdk_nvmf_subsystem_listener_get_trid,
     spdk_nvmf_subsystem_pause,
     spdk_nvmf_subsystem_resume,
@@ -35,6 +42,9 @@ use spdk_sys::{
     spdk_nvmf_subsystem_start,
     spdk_nvmf_subsystem_stop,
     spdk_nvmf_tgt,
+    SPDK_NVME_ANA_INACCESSIBLE_STATE,
+    SPDK_NVME_ANA_NON_OPTIMIZED_STATE,
+    SPDK_NVME_ANA_OPTIMIZED_STATE,
     SPDK_NVMF_SUBTYPE_DISCOVERY,
     SPDK_NVMF_SUBTYPE_NVME,
 };
@@ -63,6 +73,68 @@ impl Display for SubType {
     }
 }
 
+/// Asymmetric namespace access state of a listener, when the same subsystem
+/// is exported from several nodes the host uses it to pick a path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnaState {
+    Optimized,
+    NonOptimized,
+    Inaccessible,
+}
+
+impl Default for AnaState {
+    fn default() -> Self {
+        AnaState::Optimized
+    }
+}
+
+impl From<AnaState> for spdk_nvme_ana_state {
+    fn from(state: AnaState) -> Self {
+        match state {
+            AnaState::Optimized => SPDK_NVME_ANA_OPTIMIZED_STATE,
+            AnaState::NonOptimized => SPDK_NVME_ANA_NON_OPTIMIZED_STATE,
+            AnaState::Inaccessible => SPDK_NVME_ANA_INACCESSIBLE_STATE,
+        }
+    }
+}
+
+impl Display for AnaState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            AnaState::Optimized => write!(f, "optimized"),
+            AnaState::NonOptimized => write!(f, "non-optimized"),
+            AnaState::Inaccessible => write!(f, "inaccessible"),
+        }
+    }
+}
+
+/// Options used when sharing a bdev through a subsystem
+#[derive(Debug, Default, Clone)]
+pub struct ShareOpts {
+    /// addresses to listen on, these must be addresses the target listens on
+    /// and default to all of the configured listen addresses
+    pub addresses: Vec<IpAddr>,
+    /// port to listen on, defaults to the replica port
+    pub port: Option<u16>,
+    /// NQNs of the hosts allowed to connect, any host may connect when empty
+    pub hosts: Vec<String>,
+    /// namespace ID, zero lets the target pick the first free one
+    pub nsid: u32,
+    /// NGUID of the namespace, defaults to the UUID of the bdev
+    pub nguid: Option<[u8; 16]>,
+    /// initial ANA state of the listeners
+    pub ana_state: AnaState,
+    /// controller IDs are allocated from here on, nodes exporting the same
+    /// subsystem need distinct ranges as hosts reject duplicate IDs
+    pub cntlid_base: u16,
+    /// file the target restores the persistent reservation of the namespace
+    /// from and saves it to on every change
+    pub ptpl_file: Option<String>,
+    /// callbacks of the reservation of the namespace and their context,
+    /// which must stay valid for as long as the namespace exists
+    pub reservation_ops: Option<(spdk_nvmf_ns_reservation_ops, *mut c_void)>,
+}
+
 pub struct NvmfSubsystem(pub(crate) NonNull<spdk_nvmf_subsystem>);
 pub struct NvmfSubsystemIterator(*mut spdk_nvmf_subsystem);
 
@@ -177,17 +249,205 @@ impl NvmfSubsystem {
         Ok(ss)
     }
 
+    /// share the bdev through a new subsystem, sharing a bdev which is shared
+    /// already is not an error
+    pub async fn share(
+        uuid: &str,
+        bdev: &Bdev,
+        opts: &ShareOpts,
+    ) -> Result<NvmfSubsystem, Error> {
+        if let Some(ss) = NvmfSubsystem::nqn_lookup(uuid) {
+            if ss.bdev().map(|b| b.name()) != Some(bdev.name()) {
+                return Err(Error::Share {
+                    bdev: Bdev::from(bdev.as_ptr()),
+                    msg: format!("{} shares another bdev", ss.get_nqn()),
+                });
+            }
+            return Ok(ss);
+        }
+
+        let ss = NvmfSubsystem::new(uuid)?;
+
+        if let Err(e) = ss.configure(bdev, opts).await {
+            ss.destroy();
+            return Err(e);
+        }
+
+        ss.start_subsystem().await?;
+        Ok(ss)
+    }
+
+    /// stop and destroy the subsystem of the given UUID, unsharing a bdev
+    /// which is not shared is not an error
+    pub async fn unshare(uuid: &str) -> Result<(), Error> {
+        if let Some(ss) = NvmfSubsystem::nqn_lookup(uuid) {
+            ss.stop().await?;
+            ss.destroy();
+        }
+        Ok(())
+    }
+
+    /// add the hosts, namespace and listeners of the share options
+    async fn configure(
+        &self,
+        bdev: &Bdev,
+        opts: &ShareOpts,
+    ) -> Result<(), Error> {
+        self.allow_any(opts.hosts.is_empty());
+        for host in &opts.hosts {
+            self.add_host(host)?;
+        }
+
+        self.add_namespace_with(bdev, opts)?;
+
+        if opts.cntlid_base != 0 {
+            unsafe { (*self.0.as_ptr()).next_cntlid = opts.cntlid_base };
+        }
+
+        let cfg = Config::get();
+        let addresses = if opts.addresses.is_empty() {
+            cfg.nexus_opts.listen_addresses().map_err(|msg| {
+                Error::Transport {
+                    source: Errno::EINVAL,
+                    msg,
+                }
+            })?
+        } else {
+            opts.addresses.clone()
+        };
+        let port = opts.port.unwrap_or(cfg.nexus_opts.nvmf_replica_port);
+
+        self.add_listeners(&addresses, port).await?;
+
+        if opts.ana_state != AnaState::Optimized {
+            self.set_ana_state(opts.ana_state, None).await?;
+        }
+        Ok(())
+    }
+
+    /// change the ANA state of the listeners on the given address, or of all
+    /// listeners when no address is given
+    pub async fn set_ana_state(
+        &self,
+        state: AnaState,
+        address: Option<&IpAddr>,
+    ) -> Result<(), Error> {
+        extern "C" fn ana_state_cb(arg: *mut c_void, status: i32) {
+            let s = unsafe { Box::from_raw(arg as *mut oneshot::Sender<i32>) };
+            s.send(status).unwrap();
+        }
+
+        let address = address.map(|a| a.to_string());
+        let listeners = self
+            .listeners_to_vec()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|trid| match address {
+                Some(ref address) => trid.traddr.as_str() == address,
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        if listeners.is_empty() {
+            return Err(Error::Subsystem {
+                source: Errno::ENOENT,
+                nqn: self.get_nqn(),
+                msg: format!(
+                    "no listener on {}",
+                    address.unwrap_or_else(|| "any address".into())
+                ),
+            });
+        }
+
+        for trid in listeners {
+            let (s, r) = oneshot::channel::<i32>();
+            unsafe {
+                nvmf_subsystem_set_ana_state(
+                    self.0.as_ptr(),
+                    trid.as_ptr(),
+                    state.into(),
+                    Some(ana_state_cb),
+                    cb_arg(s),
+                );
+            }
+
+            r.await.expect("ana state callback gone").to_result(|e| {
+                Error::Subsystem {
+                    source: Errno::from_i32(e),
+                    nqn: self.get_nqn(),
+                    msg: format!("failed to set ANA state of {}", trid),
+                }
+            })?;
+        }
+
+        info!("{}: ANA state set to {}", self.get_nqn(), state);
+        Ok(())
+    }
+
+    /// the ANA state of each of the listeners by their URI
+    pub fn ana_states(&self) -> Vec<(String, AnaState)> {
+        let nqn = self.get_nqn();
+        let mut states = Vec::new();
+        unsafe {
+            let mut listener =
+                spdk_nvmf_subsystem_get_first_listener(self.0.as_ptr());
+            while !listener.is_null() {
+                if let Some(state) = ana_state(listener) {
+                    let trid = TransportID(
+                        *spdk_nvmf_subsystem_listener_get_trid(listener),
+                    );
+                    states.push((format!("{}/{}", trid, nqn), state));
+                }
+                listener = spdk_nvmf_subsystem_get_next_listener(
+                    self.0.as_ptr(),
+                    listener,
+                );
+            }
+        }
+        states
+    }
+
+    /// allow the host with the given NQN to connect to the subsystem
+    pub fn add_host(&self, host: &str) -> Result<(), Error> {
+        let hostnqn = host.into_cstring();
+        unsafe {
+            spdk_nvmf_subsystem_add_host(self.0.as_ptr(), hostnqn.as_ptr())
+        }
+        .to_result(|e| Error::Subsystem {
+            source: Errno::from_i32(e),
+            nqn: self.get_nqn(),
+            msg: format!("failed to add host {}", host),
+        })
+    }
+
     /// add the given bdev to this namespace
     pub fn add_namespace(&self, bdev: &Bdev) -> Result<(), Error> {
+        self.add_namespace_with(bdev, &ShareOpts::default())
+    }
+
+    /// add the given bdev to this namespace with the namespace ID, NGUID,
+    /// reservation file and reservation callbacks of the share options
+    pub fn add_namespace_with(
+        &self,
+        bdev: &Bdev,
+        share_opts: &ShareOpts,
+    ) -> Result<(), Error> {
         let mut opts = spdk_nvmf_ns_opts::default();
-        opts.nguid = bdev.uuid().as_bytes();
+        opts.nsid = share_opts.nsid;
+        opts.nguid = share_opts.nguid.unwrap_or_else(|| bdev.uuid().as_bytes());
+        let ptpl_file = share_opts
+            .ptpl_file
+            .as_ref()
+            .map(|file| file.as_str().into_cstring());
         let ns_id = unsafe {
             spdk_nvmf_subsystem_add_ns(
                 self.0.as_ptr(),
                 bdev.as_ptr(),
                 &opts as *const _,
-                size_of::<spdk_bdev_nvme_opts>() as u64,
-                ptr::null_mut(),
+                size_of::<spdk_nvmf_ns_opts>() as u64,
+                ptpl_file
+                    .as_ref()
+                    .map_or(ptr::null_mut(), |f| f.as_ptr() as *mut _),
             )
         };
 
@@ -200,6 +460,12 @@ impl NvmfSubsystem {
                 msg: "failed to add namespace ID".to_string(),
             })
         } else {
+            if let Some((ops, ctx)) = share_opts.reservation_ops.as_ref() {
+                unsafe {
+                    let ns = spdk_nvmf_subsystem_get_ns(self.0.as_ptr(), ns_id);
+                    spdk_nvmf_ns_set_reservation_ops(ns, ops, *ctx);
+                }
+            }
             info!("added NS ID {}", ns_id);
             Ok(())
         }
@@ -225,41 +491,62 @@ impl NvmfSubsystem {
         };
     }
 
-    // we currently allow all listeners to the subsystem
-    async fn add_listener(&self) -> Result<(), Error> {
+    /// add a listener for each of the addresses on the given port
+    async fn add_listeners(
+        &self,
+        addresses: &[IpAddr],
+        port: u16,
+    ) -> Result<(), Error> {
         extern "C" fn listen_cb(arg: *mut c_void, status: i32) {
             let s = unsafe { Box::from_raw(arg as *mut oneshot::Sender<i32>) };
             s.send(status).unwrap();
         }
 
-        let cfg = Config::get();
-
-        // dont yet enable both ports, IOW just add one transportID now
+        // the first address ends up first in the URIs
+        for address in addresses {
+            let trid = TransportID::new(address, port);
 
-        let trid_replica = TransportID::new(cfg.nexus_opts.nvmf_replica_port);
+            let (s, r) = oneshot::channel::<i32>();
+            unsafe {
+                spdk_nvmf_subsystem_add_listener(
+                    self.0.as_ptr(),
+                    trid.as_ptr(),
+                    Some(listen_cb),
+                    cb_arg(s),
+                );
+            }
 
-        let (s, r) = oneshot::channel::<i32>();
-        unsafe {
-            spdk_nvmf_subsystem_add_listener(
-                self.0.as_ptr(),
-                trid_replica.as_ptr(),
-                Some(listen_cb),
-                cb_arg(s),
-            );
+            r.await.expect("listen a callback gone").to_result(|e| {
+                Error::Transport {
+                    source: Errno::from_i32(e),
+                    msg: format!("Failed to add listener {}", trid),
+                }
+            })?;
         }
 
-        r.await.expect("listen a callback gone").to_result(|e| {
+        Ok(())
+    }
+
+    /// start the subsystem previously created listening on the replica port
+    /// of all configured listen addresses
+    pub async fn start(self) -> Result<(), Error> {
+        let cfg = Config::get();
+        let addresses = cfg.nexus_opts.listen_addresses().map_err(|msg| {
             Error::Transport {
-                source: Errno::from_i32(e),
-                msg: "Failed to add listener".to_string(),
+                source: Errno::EINVAL,
+                msg,
             }
-        })
+        })?;
+
+        // dont yet enable both ports, IOW just add the replica port
+        self.add_listeners(&addresses, cfg.nexus_opts.nvmf_replica_port)
+            .await?;
+        self.start_subsystem().await
     }
 
-    /// start the subsystem previously created -- note that we destroy it on
-    /// failure to ensure the state is not in limbo and to avoid leaking
-    /// resources
-    pub async fn start(self) -> Result<(), Error> {
+    /// start the subsystem -- note that we destroy it on failure to ensure the
+    /// state is not in limbo and to avoid leaking resources
+    async fn start_subsystem(&self) -> Result<(), Error> {
         extern "C" fn start_cb(
             ss: *mut spdk_nvmf_subsystem,
             arg: *mut c_void,
@@ -278,8 +565,6 @@ impl NvmfSubsystem {
             s.send(status).unwrap();
         }
 
-        self.add_listener().await?;
-
         let (s, r) = oneshot::channel::<i32>();
 
         unsafe {
@@ -479,8 +764,7 @@ impl NvmfSubsystem {
     /// lookup a subsystem by its UUID
     pub fn nqn_lookup(uuid: &str) -> Option<NvmfSubsystem> {
         let nqn = gen_nqn(uuid);
-        NvmfSubsystem::first()
-            .unwrap()
+        NvmfSubsystem::first()?
             .into_iter()
             .find(|s| s.get_nqn() == nqn)
     }
@@ -541,6 +825,13 @@ impl NvmfSubsystem {
         }
     }
 
+    /// return the URI of the first (primary) address this subsystem is
+    /// listening on
+    pub fn uri(&self) -> Option<String> {
+        self.uri_endpoints()
+            .and_then(|endpoints| endpoints.into_iter().next())
+    }
+
     /// return the URI's this subsystem is listening on
     pub fn uri_endpoints(&self) -> Option<Vec<String>> {
         if let Some(v) = self.listeners_to_vec() {
@@ -556,6 +847,18 @@ impl NvmfSubsystem {
     }
 }
 
+/// the ANA state of the listener, None for the states that we do not set
+unsafe fn ana_state(
+    listener: *mut spdk_nvmf_subsystem_listener,
+) -> Option<AnaState> {
+    match (*listener).ana_state {
+        SPDK_NVME_ANA_OPTIMIZED_STATE => Some(AnaState::Optimized),
+        SPDK_NVME_ANA_NON_OPTIMIZED_STATE => Some(AnaState::NonOptimized),
+        SPDK_NVME_ANA_INACCESSIBLE_STATE => Some(AnaState::Inaccessible),
+        _ => None,
+    }
+}
+
 fn gen_nqn(id: &str) -> String {
     format!("nqn.2019-05.io.openebs:{}", id)
 }
@@ -41,7 +41,7 @@ use crate::{
             poll_groups::PollGroup,
             subsystem::NvmfSubsystem,
             transport,
-            transport::{get_ipv4_address, TransportID},
+            transport::TransportID,
             Error,
             NVMF_PGS,
         },
@@ -318,36 +318,47 @@ impl Target {
         0
     }
 
-    /// Listen for incoming connections by default we only listen on the replica
-    /// port
+    /// Listen for incoming connections on both the nexus and the replica port
+    /// of every configured listen address
     fn listen(&mut self) -> Result<()> {
         let cfg = Config::get();
-        let trid_nexus = TransportID::new(cfg.nexus_opts.nvmf_nexus_port);
-        let rc = unsafe {
-            spdk_nvmf_tgt_listen(self.tgt.as_ptr(), trid_nexus.as_ptr())
-        };
-
-        if rc != 0 {
-            return Err(Error::CreateTarget {
-                msg: "failed to back target".into(),
-            });
-        }
+        let addresses = cfg.nexus_opts.listen_addresses().map_err(|msg| {
+            Error::CreateTarget {
+                msg,
+            }
+        })?;
+
+        for address in &addresses {
+            let trid_nexus =
+                TransportID::new(address, cfg.nexus_opts.nvmf_nexus_port);
+            let rc = unsafe {
+                spdk_nvmf_tgt_listen(self.tgt.as_ptr(), trid_nexus.as_ptr())
+            };
+
+            if rc != 0 {
+                return Err(Error::CreateTarget {
+                    msg: format!("failed to back target on {}", trid_nexus),
+                });
+            }
 
-        let trid_replica = TransportID::new(cfg.nexus_opts.nvmf_replica_port);
-        let rc = unsafe {
-            spdk_nvmf_tgt_listen(self.tgt.as_ptr(), trid_replica.as_ptr())
-        };
+            let trid_replica =
+                TransportID::new(address, cfg.nexus_opts.nvmf_replica_port);
+            let rc = unsafe {
+                spdk_nvmf_tgt_listen(self.tgt.as_ptr(), trid_replica.as_ptr())
+            };
 
-        if rc != 0 {
-            return Err(Error::CreateTarget {
-                msg: "failed to front target".into(),
-            });
+            if rc != 0 {
+                return Err(Error::CreateTarget {
+                    msg: format!("failed to front target on {}", trid_replica),
+                });
+            }
         }
+
         info!(
-            "nvmf target listening on {}:({},{})",
-            get_ipv4_address().unwrap(),
-            trid_nexus.trsvcid.as_str(),
-            trid_replica.trsvcid.as_str(),
+            "nvmf target listening on {:?}:({},{})",
+            addresses,
+            cfg.nexus_opts.nvmf_nexus_port,
+            cfg.nexus_opts.nvmf_replica_port,
         );
         self.next_state();
         Ok(())
@@ -433,7 +444,9 @@ impl Target {
 
     /// final state for the target during init
     pub fn running(&mut self) {
-        self.enable_discovery();
+        if Config::get().nexus_opts.nvmf_discovery_enable {
+            self.enable_discovery();
+        }
         info!(
             "nvmf target accepting new connections and is ready to role..{}",
             '\u{1F483}'
@@ -454,16 +467,28 @@ impl Target {
         unsafe { spdk_poller_unregister(&mut self.acceptor_poller.as_ptr()) };
 
         let cfg = Config::get();
-        let trid_nexus = TransportID::new(cfg.nexus_opts.nvmf_nexus_port);
-        let trid_replica = TransportID::new(cfg.nexus_opts.nvmf_replica_port);
+        let addresses = cfg.nexus_opts.listen_addresses().unwrap_or_default();
 
-        unsafe {
-            spdk_nvmf_tgt_stop_listen(self.tgt.as_ptr(), trid_replica.as_ptr())
-        };
+        for address in &addresses {
+            let trid_nexus =
+                TransportID::new(address, cfg.nexus_opts.nvmf_nexus_port);
+            let trid_replica =
+                TransportID::new(address, cfg.nexus_opts.nvmf_replica_port);
 
-        unsafe {
-            spdk_nvmf_tgt_stop_listen(self.tgt.as_ptr(), trid_nexus.as_ptr())
-        };
+            unsafe {
+                spdk_nvmf_tgt_stop_listen(
+                    self.tgt.as_ptr(),
+                    trid_replica.as_ptr(),
+                )
+            };
+
+            unsafe {
+                spdk_nvmf_tgt_stop_listen(
+                    self.tgt.as_ptr(),
+                    trid_nexus.as_ptr(),
+                )
+            };
+        }
 
         unsafe {
             spdk_nvmf_tgt_destroy(
@@ -1,8 +1,7 @@
 use std::{
-    env,
     ffi::CString,
     fmt::{Debug, Display},
-    net::Ipv4Addr,
+    net::IpAddr,
     ops::{Deref, DerefMut},
     ptr::copy_nonoverlapping,
 };
@@ -18,6 +17,7 @@ use spdk_sys::{
     spdk_nvmf_transport_create,
     SPDK_NVME_TRANSPORT_TCP,
     SPDK_NVMF_ADRFAM_IPV4,
+    SPDK_NVMF_ADRFAM_IPV6,
     SPDK_NVMF_TRSVCID_MAX_LEN,
 };
 
@@ -85,14 +85,15 @@ impl DerefMut for TransportID {
 }
 
 impl TransportID {
-    pub fn new(port: u16) -> Self {
-        let address = get_ipv4_address().unwrap();
-
+    pub fn new(address: &IpAddr, port: u16) -> Self {
         let mut trid: spdk_nvme_transport_id = Default::default();
         trid.trtype = SPDK_NVME_TRANSPORT_TCP;
-        trid.adrfam = SPDK_NVMF_ADRFAM_IPV4;
+        trid.adrfam = match address {
+            IpAddr::V4(_) => SPDK_NVMF_ADRFAM_IPV4,
+            IpAddr::V6(_) => SPDK_NVMF_ADRFAM_IPV6,
+        };
 
-        let c_addr = address.into_cstring();
+        let c_addr = address.to_string().into_cstring();
         let port = format!("{}", port);
 
         assert!(port.len() < SPDK_NVMF_TRSVCID_MAX_LEN as usize);
@@ -125,12 +126,22 @@ impl TransportID {
 
 impl Display for TransportID {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "nvmf://{}:{}",
-            self.0.traddr.as_str(),
-            self.0.trsvcid.as_str()
-        )
+        // IPv6 addresses are bracketed to set them apart from the port
+        if self.0.adrfam == SPDK_NVMF_ADRFAM_IPV6 {
+            write!(
+                f,
+                "nvmf://[{}]:{}",
+                self.0.traddr.as_str(),
+                self.0.trsvcid.as_str()
+            )
+        } else {
+            write!(
+                f,
+                "nvmf://{}:{}",
+                self.0.traddr.as_str(),
+                self.0.trsvcid.as_str()
+            )
+        }
     }
 }
 
@@ -144,19 +155,3 @@ impl Debug for TransportID {
             .finish()
     }
 }
-pub(crate) fn get_ipv4_address() -> Result<String, Error> {
-    let address = match env::var("MY_POD_IP") {
-        Ok(val) => {
-            if val.parse::<Ipv4Addr>().is_ok() {
-                Ok(val)
-            } else {
-                Err(Error::CreateTarget {
-                    msg: "Invalid IPv4 address".into(),
-                })
-            }
-        }
-        Err(_) => Ok("127.0.0.1".to_owned()),
-    }?;
-
-    Ok(address)
-}
@@ -2,8 +2,9 @@
 //! types. Naturally this is a good reason, but it means we have to copy things
 //! around. If the structures change, we will know about it because we use the
 //! from trait, and we are not allowed to skip or use different types.
-use std::ptr::copy_nonoverlapping;
+use std::{env, net::IpAddr, ptr::copy_nonoverlapping};
 
+use nix::{ifaddrs::getifaddrs, sys::socket::SockAddr};
 use serde::{Deserialize, Serialize};
 
 use spdk_sys::{
@@ -37,12 +38,36 @@ pub struct NexusOpts {
     /// NOTE: we do not (yet) differentiate between
     /// the nexus and replica nvmf target
     pub nvmf_replica_port: u16,
+    /// first controller ID handed out by the nexus subsystems, instances
+    /// exporting the same nexus for multipath must use distinct values
+    pub nvmf_cntlid_base: u16,
+    /// directory in which the target keeps the persistent reservations of
+    /// the namespaces of nexus exported over nvmf
+    pub ptpl_dir: String,
     /// enable iSCSI support
     pub iscsi_enable: bool,
     /// Port for nexus target portal
     pub iscsi_nexus_port: u16,
     /// Port for replica target portal
     pub iscsi_replica_port: u16,
+    /// addresses the nvmf and iSCSI targets listen on, each of which is an
+    /// IPv4 or IPv6 address or the name of a network interface standing for
+    /// all of its addresses. The first address is the one put into the URIs
+    /// of shared replicas and nexus. When empty, the addresses in MY_POD_IP
+    /// are used or the loopback address if it is not set.
+    pub listen_addresses: Vec<String>,
+    /// directory in which the sockets of the vhost-user-blk controllers of
+    /// nexus shared with local VMs are created
+    pub vhost_socket_dir: String,
+    /// depth of the queue of ublk devices, between 1 and 4096, each entry
+    /// of which holds a buffer of 128KiB in hugepage memory
+    pub ublk_queue_depth: u16,
+    /// URI of the provider of the keys nexus are encrypted with, either
+    /// `file:///dir` or `vault://mount/prefix?endpoint=URL`
+    pub key_provider: String,
+    /// DPDK crypto driver of the crypto vbdevs encrypting nexus, which must
+    /// support AES-XTS as crypto_qat does
+    pub crypto_pmd: String,
 }
 
 /// Default nvmf port used for replicas.
@@ -55,6 +80,20 @@ const NVMF_PORT_NEXUS: u16 = 4421;
 const ISCSI_PORT_NEXUS: u16 = 3260;
 const ISCSI_PORT_REPLICA: u16 = 3262;
 
+/// Default directory for the reservation files of the nvmf target, which
+/// must not be cleared on a reboot of the node
+const PTPL_DIR: &str = "/var/local/mayastor/ptpl";
+
+/// Default directory for the sockets of vhost-user-blk controllers
+const VHOST_SOCKET_DIR: &str = "/var/tmp";
+
+/// Default provider of encryption keys, a directory a secret can be mounted on
+const KEY_PROVIDER: &str = "file:///etc/mayastor/keys";
+
+/// Default DPDK crypto driver, Intel QuickAssist, the only one of the crypto
+/// vbdev that supports AES-XTS
+const CRYPTO_PMD: &str = "crypto_qat";
+
 impl Default for NexusOpts {
     fn default() -> Self {
         Self {
@@ -62,10 +101,77 @@ impl Default for NexusOpts {
             nvmf_discovery_enable: true,
             nvmf_nexus_port: NVMF_PORT_NEXUS,
             nvmf_replica_port: NVMF_PORT_REPLICA,
+            nvmf_cntlid_base: 0,
+            ptpl_dir: PTPL_DIR.into(),
             iscsi_enable: true,
             iscsi_nexus_port: ISCSI_PORT_NEXUS,
             iscsi_replica_port: ISCSI_PORT_REPLICA,
+            listen_addresses: Vec::new(),
+            vhost_socket_dir: VHOST_SOCKET_DIR.into(),
+            ublk_queue_depth: 128,
+            key_provider: KEY_PROVIDER.into(),
+            crypto_pmd: CRYPTO_PMD.into(),
+        }
+    }
+}
+
+impl NexusOpts {
+    /// Resolve the listen addresses into IP addresses, in the order in which
+    /// they are configured and without duplicates.
+    pub fn listen_addresses(&self) -> Result<Vec<IpAddr>, String> {
+        let mut resolved = Vec::new();
+
+        if self.listen_addresses.is_empty() {
+            // a dual-stack pod has one address of each family separated by a
+            // comma
+            let pod_ips =
+                env::var("MY_POD_IP").unwrap_or_else(|_| "127.0.0.1".into());
+            for ip in pod_ips.split(',') {
+                resolved.push(
+                    ip.trim()
+                        .parse::<IpAddr>()
+                        .map_err(|_| format!("Invalid IP address {}", ip))?,
+                );
+            }
+        } else {
+            for entry in &self.listen_addresses {
+                match entry.parse::<IpAddr>() {
+                    Ok(address) => resolved.push(address),
+                    Err(_) => resolved.extend(interface_addresses(entry)?),
+                }
+            }
+        }
+
+        let mut addresses = Vec::new();
+        for address in resolved {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
         }
+        Ok(addresses)
+    }
+}
+
+/// The addresses of the network interface. IPv6 link-local addresses are left
+/// out as they cannot be used without the scope of the interface.
+fn interface_addresses(name: &str) -> Result<Vec<IpAddr>, String> {
+    let addresses: Vec<IpAddr> = getifaddrs()
+        .map_err(|e| format!("Failed to list network interfaces: {}", e))?
+        .filter(|ifa| ifa.interface_name == name)
+        .filter_map(|ifa| match ifa.address {
+            Some(SockAddr::Inet(inet)) => Some(inet.ip().to_std()),
+            _ => None,
+        })
+        .filter(|address| match address {
+            IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 != 0xfe80,
+            IpAddr::V4(_) => true,
+        })
+        .collect();
+
+    if addresses.is_empty() {
+        Err(format!("No usable address on network interface {}", name))
+    } else {
+        Ok(addresses)
     }
 }
 
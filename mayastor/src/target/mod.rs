@@ -0,0 +1,7 @@
+pub mod iscsi;
+
+// Which kind of target interface to use for a bdev
+pub enum Side {
+    Nexus,
+    Replica,
+}
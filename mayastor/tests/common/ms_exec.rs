@@ -154,11 +154,21 @@ impl MayastorProcess {
         &self,
         method: &str,
         arg: serde_json::Value,
+    ) -> Result<serde_json::Value, ()> {
+        Self::rpc_call_at(&self.rpc_path, method, arg)
+    }
+
+    /// call json-rpc method on the given socket using mctl, which can be done
+    /// from another thread while the test polls its reactor
+    pub fn rpc_call_at(
+        rpc_path: &str,
+        method: &str,
+        arg: serde_json::Value,
     ) -> Result<serde_json::Value, ()> {
         let mctl = get_path("mctl");
 
         let output = Command::new(mctl)
-            .args(&["-s", &self.rpc_path, "raw", method])
+            .args(&["-s", rpc_path, "raw", method])
             .arg(serde_json::to_string(&arg).unwrap())
             .output()
             .expect("could not exec mctl");
@@ -166,7 +176,7 @@ impl MayastorProcess {
         if !output.status.success() {
             panic!(
                 "RPC to socket {} with method {} failed arguments {:?}",
-                self.rpc_path, method, arg
+                rpc_path, method, arg
             );
         }
 
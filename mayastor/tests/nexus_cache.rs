@@ -0,0 +1,275 @@
+use common::error_bdev::{
+    create_error_bdev,
+    inject_error,
+    SPDK_BDEV_IO_TYPE_READ,
+    SPDK_BDEV_IO_TYPE_WRITE,
+    VBDEV_IO_FAILURE,
+};
+use mayastor::{
+    bdev::{
+        nexus_create,
+        nexus_create_with_opts,
+        nexus_lookup,
+        ChildStatus,
+        NexusCreateOpts,
+        NexusStatus,
+    },
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+};
+use rpc::mayastor::{NexusCacheMode, NexusLayout};
+
+pub mod common;
+
+const NEXUS_NAME: &str = "cache_nexus";
+const NEXUS_SIZE: u64 = 60 * 1024 * 1024;
+const DISK_SIZE: u64 = 64 * 1024 * 1024;
+const CACHE_SIZE: u64 = 8 * 1024 * 1024;
+const NUM_NEXUS_CHILDREN: u64 = 2;
+
+// not aligned to a cache line and spanning multiple lines
+const IO_OFFSET: u64 = 100 * 1024;
+const IO_SIZE: usize = 300 * 1024;
+
+const CACHE_DISK: &str = "/tmp/cache-disk.img";
+
+fn test_ini() {
+    test_init!();
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+        common::truncate_file_bytes(&get_disk(i), DISK_SIZE);
+    }
+    common::delete_file(&[CACHE_DISK.into()]);
+    common::truncate_file_bytes(CACHE_DISK, CACHE_SIZE);
+}
+
+fn test_fini() {
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+    }
+    common::delete_file(&[CACHE_DISK.into()]);
+}
+
+fn get_disk(number: u64) -> String {
+    format!("/tmp/cache-child{}.img", number)
+}
+
+fn get_dev(number: u64) -> String {
+    format!("aio://{}?blk_size=512", get_disk(number))
+}
+
+fn children() -> Vec<String> {
+    (0 .. NUM_NEXUS_CHILDREN).map(get_dev).collect()
+}
+
+async fn create_nexus(cache_mode: NexusCacheMode) {
+    create_nexus_with_children(cache_mode, &children()).await;
+}
+
+async fn create_nexus_with_children(
+    cache_mode: NexusCacheMode,
+    children: &[String],
+) {
+    nexus_create_with_opts(
+        NEXUS_NAME,
+        NEXUS_SIZE,
+        None,
+        children,
+        NexusCreateOpts {
+            layout: NexusLayout::NexusMirror,
+            cache: Some(format!("aio://{}?blk_size=512", CACHE_DISK)),
+            cache_mode,
+        },
+    )
+    .await
+    .unwrap();
+}
+
+async fn destroy_nexus() {
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    nexus.destroy().await.unwrap();
+}
+
+/// write a pattern to the nexus which depends on the seed
+async fn write_pattern(offset: u64, seed: u8) {
+    let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    buf.as_mut_slice()
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = (i % 251) as u8 ^ seed);
+
+    hdl.write_at(offset, &buf).await.unwrap();
+}
+
+/// read back the data from the nexus and verify the pattern
+async fn verify_pattern(offset: u64, seed: u8) {
+    let hdl = BdevHandle::open(NEXUS_NAME, false, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    hdl.read_at(offset, &mut buf).await.unwrap();
+
+    buf.as_slice()
+        .iter()
+        .enumerate()
+        .for_each(|(i, b)| assert_eq!(*b, (i % 251) as u8 ^ seed));
+}
+
+#[test]
+fn cache_nexus() {
+    test_ini();
+
+    // a parity nexus cannot be cached
+    Reactor::block_on(async {
+        assert!(nexus_create_with_opts(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &children(),
+            NexusCreateOpts {
+                layout: NexusLayout::NexusParity,
+                cache: Some(format!("aio://{}?blk_size=512", CACHE_DISK)),
+                cache_mode: NexusCacheMode::CacheWriteThrough,
+            },
+        )
+        .await
+        .is_err());
+    });
+
+    // reads that miss fill the cache, after which they are served from it
+    Reactor::block_on(async {
+        create_nexus(NexusCacheMode::CacheWriteThrough).await;
+
+        write_pattern(IO_OFFSET, 0).await;
+        verify_pattern(IO_OFFSET, 0).await;
+        verify_pattern(IO_OFFSET, 0).await;
+
+        let info = nexus_lookup(NEXUS_NAME).unwrap().cache_info().unwrap();
+        assert_eq!(info.dirty, 0);
+        assert!(info.misses > 0);
+        assert!(info.hits > 0);
+
+        // writes to cached lines must update them
+        write_pattern(IO_OFFSET, 0x3c).await;
+        verify_pattern(IO_OFFSET, 0x3c).await;
+
+        destroy_nexus().await;
+    });
+
+    // writes are absorbed by the cache and written back to the children
+    Reactor::block_on(async {
+        create_nexus(NexusCacheMode::CacheWriteBack).await;
+
+        write_pattern(IO_OFFSET, 0xa5).await;
+        verify_pattern(IO_OFFSET, 0xa5).await;
+
+        // a write beyond the size of the cache evicts dirty lines, which
+        // must be written back first
+        write_pattern(IO_OFFSET + CACHE_SIZE, 0x5a).await;
+        verify_pattern(IO_OFFSET, 0xa5).await;
+        verify_pattern(IO_OFFSET + CACHE_SIZE, 0x5a).await;
+
+        destroy_nexus().await;
+    });
+
+    // all dirty lines must have reached the children when the nexus was
+    // destroyed
+    Reactor::block_on(async {
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &children())
+            .await
+            .unwrap();
+
+        verify_pattern(IO_OFFSET, 0xa5).await;
+        verify_pattern(IO_OFFSET + CACHE_SIZE, 0x5a).await;
+
+        destroy_nexus().await;
+    });
+
+    // a device that does not hold a cache marked for discard is not formatted
+    std::fs::write(CACHE_DISK, vec![0xffu8; 4096]).unwrap();
+    common::truncate_file_bytes(CACHE_DISK, CACHE_SIZE);
+    Reactor::block_on(async {
+        assert!(nexus_create_with_opts(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &children(),
+            NexusCreateOpts {
+                layout: NexusLayout::NexusMirror,
+                cache: Some(format!("aio://{}?blk_size=512", CACHE_DISK)),
+                cache_mode: NexusCacheMode::CacheWriteThrough,
+            },
+        )
+        .await
+        .is_err());
+        assert!(nexus_lookup(NEXUS_NAME).is_none());
+    });
+
+    test_fini();
+}
+
+/// create the nexus with its first child on top of an error bdev
+fn error_nexus_ini(error_device: &str) -> String {
+    test_ini();
+
+    let ee_device = format!("EE_{}", error_device);
+    let mut ch = vec![format!("bdev:///{}", ee_device)];
+    ch.extend((1 .. NUM_NEXUS_CHILDREN).map(get_dev));
+    let error_device = error_device.to_string();
+    Reactor::block_on(async move {
+        create_error_bdev(&error_device, &get_disk(0));
+        create_nexus_with_children(NexusCacheMode::CacheWriteThrough, &ch)
+            .await;
+    });
+
+    ee_device
+}
+
+/// the status of the first child once the nexus had time to fault it
+fn error_child_status() -> ChildStatus {
+    reactor_poll!(100);
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    nexus.children[0].status()
+}
+
+#[test]
+fn cache_nexus_child_error() {
+    // a read that misses and fails on a child is retried on the next one
+    let ee_device = error_nexus_ini("cache_read_error");
+    Reactor::block_on(async move {
+        write_pattern(IO_OFFSET, 0x3c).await;
+        inject_error(
+            &ee_device,
+            SPDK_BDEV_IO_TYPE_READ,
+            VBDEV_IO_FAILURE,
+            1000,
+        );
+        verify_pattern(IO_OFFSET, 0x3c).await;
+    });
+    assert_eq!(error_child_status(), ChildStatus::Faulted);
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.status(), NexusStatus::Degraded);
+        verify_pattern(IO_OFFSET, 0x3c).await;
+        destroy_nexus().await;
+    });
+    test_fini();
+
+    // a write that fails on a child completes on the others
+    let ee_device = error_nexus_ini("cache_write_error");
+    Reactor::block_on(async move {
+        inject_error(
+            &ee_device,
+            SPDK_BDEV_IO_TYPE_WRITE,
+            VBDEV_IO_FAILURE,
+            1000,
+        );
+        write_pattern(IO_OFFSET, 0xc3).await;
+    });
+    assert_eq!(error_child_status(), ChildStatus::Faulted);
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.status(), NexusStatus::Degraded);
+        verify_pattern(IO_OFFSET, 0xc3).await;
+        destroy_nexus().await;
+    });
+    test_fini();
+}
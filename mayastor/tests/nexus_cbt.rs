@@ -0,0 +1,120 @@
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup},
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+};
+
+pub mod common;
+
+const NEXUS_NAME: &str = "cbt_nexus";
+const NEXUS_UUID: &str = "4e3a2c1b-8f7d-4d6e-9a5b-0c1d2e3f4a5b";
+const NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+const DISK_SIZE: u64 = 16 * 1024 * 1024;
+const NUM_NEXUS_CHILDREN: u64 = 2;
+
+// regions in which changes are tracked are 1MiB in size
+const MB: u64 = 1024 * 1024;
+const IO_SIZE: usize = 4096;
+
+fn test_ini() {
+    test_init!();
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+        common::truncate_file_bytes(&get_disk(i), DISK_SIZE);
+    }
+}
+
+fn test_fini() {
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+    }
+}
+
+fn get_disk(number: u64) -> String {
+    format!("/tmp/cbt-disk{}.img", number)
+}
+
+fn get_dev(number: u64) -> String {
+    format!("aio://{}?blk_size=512", get_disk(number))
+}
+
+async fn create_nexus() {
+    let ch: Vec<String> = (0 .. NUM_NEXUS_CHILDREN).map(get_dev).collect();
+    nexus_create(NEXUS_NAME, NEXUS_SIZE, Some(NEXUS_UUID), &ch)
+        .await
+        .unwrap();
+}
+
+async fn write_at(offset: u64) {
+    let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    buf.fill(0x5a);
+    hdl.write_at(offset, &buf).await.unwrap();
+}
+
+fn extents(from: &str, to: &str) -> Vec<(u64, u64)> {
+    nexus_lookup(NEXUS_NAME)
+        .unwrap()
+        .changed_extents(from, to)
+        .unwrap()
+        .extents
+        .iter()
+        .map(|e| (e.offset, e.length))
+        .collect()
+}
+
+#[test]
+fn nexus_cbt() {
+    test_ini();
+
+    Reactor::block_on(async {
+        create_nexus().await;
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        // writes before the first checkpoint are not tracked
+        write_at(0).await;
+        nexus.create_checkpoint("c1", false).await.unwrap();
+        assert!(nexus.create_checkpoint("c1", false).await.is_err());
+        assert!(extents("c1", "").is_empty());
+
+        // writes to adjacent regions are merged into one extent
+        write_at(2 * MB + 512).await;
+        write_at(3 * MB).await;
+        nexus.create_checkpoint("c2", false).await.unwrap();
+        write_at(7 * MB).await;
+
+        assert_eq!(extents("c1", "c2"), vec![(2 * MB, 2 * MB)]);
+        assert_eq!(extents("c2", ""), vec![(7 * MB, MB)]);
+        assert_eq!(extents("c1", ""), vec![(2 * MB, 2 * MB), (7 * MB, MB)]);
+        assert!(nexus.changed_extents("c2", "c1").is_err());
+        assert!(nexus.changed_extents("c3", "").is_err());
+
+        let checkpoints = nexus.checkpoints();
+        assert_eq!(checkpoints.len(), 2);
+        assert_eq!(checkpoints[0].name, "c1");
+        assert_eq!(checkpoints[0].changed_bytes, 3 * MB);
+        assert_eq!(checkpoints[1].changed_bytes, MB);
+
+        nexus.destroy().await.unwrap();
+    });
+
+    // the checkpoints survive the nexus being destroyed and created again
+    Reactor::block_on(async {
+        create_nexus().await;
+        assert_eq!(extents("c1", "c2"), vec![(2 * MB, 2 * MB)]);
+        assert_eq!(extents("c2", ""), vec![(7 * MB, MB)]);
+
+        // changes since a destroyed checkpoint are changes since the one
+        // before it
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        nexus.destroy_checkpoint("c2").await.unwrap();
+        assert!(nexus.destroy_checkpoint("c2").await.is_err());
+        assert_eq!(extents("c1", ""), vec![(2 * MB, 2 * MB), (7 * MB, MB)]);
+
+        nexus.destroy_checkpoint("c1").await.unwrap();
+        assert!(nexus.checkpoints().is_empty());
+
+        nexus.destroy().await.unwrap();
+    });
+
+    test_fini();
+}
@@ -0,0 +1,475 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::TcpListener,
+    thread,
+};
+
+use rpc::mayastor::ShareProtocolNexus;
+
+use mayastor::{
+    bdev::{
+        key_provider_from_uri,
+        nexus_create,
+        nexus_lookup,
+        Cipher,
+        EncryptionKey,
+        NexusError,
+    },
+    core::{
+        BdevHandle,
+        MayastorCliArgs,
+        MayastorEnvironment,
+        Reactor,
+        Reactors,
+    },
+};
+
+pub mod common;
+
+const NEXUS_NAME: &str = "crypto_nexus";
+const NEXUS_UUID: &str = "4f5b0c2e-8a61-4d7b-9e3f-2c1a6b8d9e07";
+const MIB: u64 = 1024 * 1024;
+const NEXUS_SIZE: u64 = 10 * MIB;
+const DISK_SIZE: u64 = 16 * 1024 * 1024;
+const DISK_NAME: &str = "/tmp/crypto-disk.img";
+
+const KEY_DIR: &str = "/tmp/crypto-keys";
+/// directory of the default key provider
+const DEFAULT_KEY_DIR: &str = "/etc/mayastor/keys";
+const VAULT_TOKEN: &str = "s.mayastor-test";
+
+fn test_ini() {
+    test_init!();
+    common::delete_file(&[DISK_NAME.into()]);
+    common::truncate_file_bytes(DISK_NAME, DISK_SIZE);
+}
+
+fn test_fini() {
+    common::delete_file(&[DISK_NAME.into()]);
+}
+
+fn key(id: &str, fill: u8) -> Option<EncryptionKey> {
+    Some(EncryptionKey::new(id, vec![fill; 32]).unwrap())
+}
+
+async fn create_nexus() {
+    let ch = vec![format!("aio://{}?blk_size=512", DISK_NAME)];
+    nexus_create(NEXUS_NAME, NEXUS_SIZE, Some(NEXUS_UUID), &ch)
+        .await
+        .unwrap();
+}
+
+#[test]
+fn nexus_crypto() {
+    test_ini();
+
+    Reactor::block_on(async {
+        create_nexus().await;
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        // a nexus that has never been encrypted can be shared without a key
+        nexus
+            .share(ShareProtocolNexus::NexusNvmf, None)
+            .await
+            .unwrap();
+        nexus.unshare().await.unwrap();
+
+        let uri = nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("key1", b'k'))
+            .await
+            .unwrap();
+        assert!(uri.ends_with(&format!("crypto-{}", NEXUS_NAME)));
+
+        // further shares must use the same key
+        nexus
+            .share(ShareProtocolNexus::NexusIscsi, key("key1", b'k'))
+            .await
+            .unwrap();
+        assert!(nexus
+            .share(ShareProtocolNexus::NexusNbd, None)
+            .await
+            .is_err());
+        assert!(nexus
+            .share(ShareProtocolNexus::NexusNbd, key("key2", b'l'))
+            .await
+            .is_err());
+
+        nexus.unshare().await.unwrap();
+        nexus.destroy().await.unwrap();
+    });
+
+    // the marker is kept on the child when the nexus is created again
+    Reactor::block_on(async {
+        create_nexus().await;
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        match nexus.share(ShareProtocolNexus::NexusNvmf, None).await {
+            Err(NexusError::EncryptionRequired {
+                key_id, ..
+            }) => assert_eq!(key_id, "key1"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("key2", b'l'))
+            .await
+        {
+            Err(NexusError::EncryptionKeyMismatch {
+                ..
+            }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        // the material behind the ID must not have changed either
+        match nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("key1", b'x'))
+            .await
+        {
+            Err(NexusError::EncryptionKeyMismatch {
+                ..
+            }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("key1", b'k'))
+            .await
+            .unwrap();
+        nexus.unshare().await.unwrap();
+        nexus.destroy().await.unwrap();
+    });
+
+    test_fini();
+}
+
+async fn write_pattern(name: &str, offset: u64, len: usize, fill: u8) {
+    let hdl = BdevHandle::open(name, true, false).unwrap();
+    let mut buf = hdl.dma_malloc(len).unwrap();
+    buf.fill(fill);
+    hdl.write_at(offset, &buf).await.unwrap();
+}
+
+async fn check_pattern(name: &str, offset: u64, len: usize, fill: u8) {
+    let hdl = BdevHandle::open(name, false, false).unwrap();
+    let mut buf = hdl.dma_malloc(len).unwrap();
+    hdl.read_at(offset, &mut buf).await.unwrap();
+    assert!(buf.as_slice().iter().all(|b| *b == fill));
+}
+
+#[test]
+fn nexus_key_rotation() {
+    test_ini();
+    let share = format!("crypto-{}", NEXUS_NAME);
+
+    Reactor::block_on(async {
+        create_nexus().await;
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        // only a nexus published with a key can be rotated
+        match nexus.rotate_key(key("key2", b'l').unwrap()).await {
+            Err(NexusError::NotEncrypted {
+                ..
+            }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("key1", b'k'))
+            .await
+            .unwrap();
+        write_pattern(&share, 0, 3 * 1024 * 1024, 0xa5).await;
+
+        nexus.rotate_key(key("key2", b'l').unwrap()).await.unwrap();
+        let rotation = nexus.key_rotation().unwrap();
+        assert_eq!(rotation.key_id, "key2");
+
+        // IO continues while the key is rotated, and a second rotation has
+        // to wait for the first
+        write_pattern(&share, 8 * 1024 * 1024, 1024 * 1024, 0x5a).await;
+        match nexus.rotate_key(key("key3", b'm').unwrap()).await {
+            Err(NexusError::KeyRotationInProgress {
+                key_id, ..
+            }) => assert_eq!(key_id, "key2"),
+            r => panic!("unexpected result {:?}", r),
+        }
+    });
+
+    // the rotation runs in the background
+    while let Some(rotation) = nexus_lookup(NEXUS_NAME).unwrap().key_rotation()
+    {
+        assert!(rotation.running);
+        Reactors::current().poll_once();
+    }
+
+    Reactor::block_on(async {
+        check_pattern(&share, 0, 3 * 1024 * 1024, 0xa5).await;
+        check_pattern(&share, 8 * 1024 * 1024, 1024 * 1024, 0x5a).await;
+
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        // publishing again requires the new key
+        assert!(nexus
+            .share(ShareProtocolNexus::NexusIscsi, key("key1", b'k'))
+            .await
+            .is_err());
+        nexus.unshare().await.unwrap();
+        nexus.destroy().await.unwrap();
+    });
+
+    // the old key is gone from the marker
+    Reactor::block_on(async {
+        create_nexus().await;
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        match nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("key1", b'k'))
+            .await
+        {
+            Err(NexusError::EncryptionKeyMismatch {
+                ..
+            }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("key2", b'l'))
+            .await
+            .unwrap();
+        check_pattern(&share, 0, 3 * 1024 * 1024, 0xa5).await;
+        check_pattern(&share, 8 * 1024 * 1024, 1024 * 1024, 0x5a).await;
+
+        nexus.unshare().await.unwrap();
+        nexus.destroy().await.unwrap();
+    });
+
+    test_fini();
+}
+
+/// Poll the reactor until the rotation of the key of the nexus has got to
+/// the given progress, or has completed if None.
+fn wait_key_rotation(progress: Option<u32>) {
+    while let Some(rotation) = nexus_lookup(NEXUS_NAME).unwrap().key_rotation()
+    {
+        if progress.map_or(false, |p| rotation.progress >= p) {
+            return;
+        }
+        assert!(rotation.running);
+        Reactors::current().poll_once();
+    }
+    assert!(progress.is_none(), "key rotation completed");
+}
+
+/// Stop the rotation of the key by unpublishing the nexus and create the
+/// nexus again, as when the node restarts.
+async fn restart_nexus() {
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    nexus.unshare().await.unwrap();
+    nexus.destroy().await.unwrap();
+    create_nexus().await;
+}
+
+/// the data nexus_key_rotation_resume writes, the third segment of which is
+/// rewritten once it has been copied
+async fn check_resume_data(share: &str) {
+    check_pattern(share, 0, 2 * MIB as usize, 0xa5).await;
+    check_pattern(share, 2 * MIB, MIB as usize, 0x3c).await;
+    check_pattern(share, 3 * MIB, (NEXUS_SIZE - 3 * MIB) as usize, 0xa5).await;
+}
+
+#[test]
+fn nexus_key_rotation_resume() {
+    test_ini();
+    let share = format!("crypto-{}", NEXUS_NAME);
+    // the rotation fetches the other key from the key provider when it is
+    // resumed
+    fs::create_dir_all(DEFAULT_KEY_DIR).unwrap();
+    for (id, fill) in &[("resume1", b'k'), ("resume2", b'l')] {
+        fs::write(
+            format!("{}/{}", DEFAULT_KEY_DIR, id),
+            format!("{:02x}", fill).repeat(32),
+        )
+        .unwrap();
+    }
+
+    Reactor::block_on(async {
+        create_nexus().await;
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("resume1", b'k'))
+            .await
+            .unwrap();
+        write_pattern(&share, 0, NEXUS_SIZE as usize, 0xa5).await;
+        nexus
+            .rotate_key(key("resume2", b'l').unwrap())
+            .await
+            .unwrap();
+    });
+    wait_key_rotation(Some(30));
+
+    // the segment below the watermark is written with the new key, which
+    // the watermark saved with the segment tells after a restart
+    Reactor::block_on(async {
+        write_pattern(&share, 2 * MIB, MIB as usize, 0x3c).await;
+    });
+
+    // the rotation resumes when the nexus is published with the current key
+    Reactor::block_on(async {
+        restart_nexus().await;
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("resume1", b'k'))
+            .await
+            .unwrap();
+        let rotation = nexus.key_rotation().unwrap();
+        assert_eq!(rotation.key_id, "resume2");
+        // the segment being copied when it stopped is copied again
+        assert!(rotation.progress >= 30);
+        check_resume_data(&share).await;
+    });
+    wait_key_rotation(Some(60));
+
+    // and with the new key
+    Reactor::block_on(async {
+        restart_nexus().await;
+        nexus_lookup(NEXUS_NAME)
+            .unwrap()
+            .share(ShareProtocolNexus::NexusNvmf, key("resume2", b'l'))
+            .await
+            .unwrap();
+    });
+    wait_key_rotation(None);
+
+    Reactor::block_on(async {
+        check_resume_data(&share).await;
+        restart_nexus().await;
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        assert!(nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("resume1", b'k'))
+            .await
+            .is_err());
+        nexus
+            .share(ShareProtocolNexus::NexusNvmf, key("resume2", b'l'))
+            .await
+            .unwrap();
+        check_resume_data(&share).await;
+        nexus.unshare().await.unwrap();
+        nexus.destroy().await.unwrap();
+    });
+
+    for id in &["resume1", "resume2"] {
+        let _ = fs::remove_file(format!("{}/{}", DEFAULT_KEY_DIR, id));
+    }
+    test_fini();
+}
+
+#[test]
+fn file_key_provider() {
+    test_init!();
+    let _ = fs::remove_dir_all(KEY_DIR);
+    fs::create_dir_all(KEY_DIR).unwrap();
+    fs::write(format!("{}/hex", KEY_DIR), "a".repeat(64) + "\n").unwrap();
+    // 32 times "b"
+    fs::write(
+        format!("{}/base64", KEY_DIR),
+        "YmJiYmJiYmJiYmJiYmJiYmJiYmJiYmJiYmJiYmJiYmI=",
+    )
+    .unwrap();
+    fs::write(
+        format!("{}/raw", KEY_DIR),
+        "0123456789abcdefghijklmnopqrstuv",
+    )
+    .unwrap();
+    fs::write(format!("{}/key512", KEY_DIR), "b".repeat(128)).unwrap();
+    fs::write(format!("{}/key128", KEY_DIR), "c".repeat(32)).unwrap();
+    fs::write(format!("{}/nul", KEY_DIR), "00".repeat(32)).unwrap();
+
+    let provider =
+        key_provider_from_uri(&format!("file://{}", KEY_DIR)).unwrap();
+
+    Reactor::block_on(async {
+        let key = provider.get_key("hex").await.unwrap();
+        assert_eq!(key.id(), "hex");
+        assert_eq!(key.cipher(), Cipher::AesXts256);
+
+        let key = provider.get_key("base64").await.unwrap();
+        assert_eq!(key.cipher(), Cipher::AesXts256);
+
+        // keys must be encoded
+        assert!(provider.get_key("raw").await.is_err());
+        // only AES-XTS-256 is supported, with two keys of 128 bits
+        assert!(provider.get_key("key512").await.is_err());
+        assert!(provider.get_key("key128").await.is_err());
+        // any byte can be part of a key
+        let key = provider.get_key("nul").await.unwrap();
+        assert_eq!(key.cipher(), Cipher::AesXts256);
+        assert!(provider.get_key("missing").await.is_err());
+        // IDs cannot point outside of the directory
+        assert!(provider.get_key("../crypto-keys/key256").await.is_err());
+        assert!(provider.get_key("").await.is_err());
+    });
+
+    let _ = fs::remove_dir_all(KEY_DIR);
+}
+
+/// Serve the given number of requests for the KV version 2 secrets engine,
+/// which only has a secret holding a hex encoded 256 bit key.
+fn mock_vault(requests: usize) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+        for stream in listener.incoming().take(requests) {
+            let mut stream = stream.unwrap();
+            let mut buf = [0u8; 4096];
+            let n = stream.read(&mut buf).unwrap();
+            let request = String::from_utf8_lossy(&buf[.. n]).to_lowercase();
+
+            let authorized =
+                request.contains(&format!("x-vault-token: {}", VAULT_TOKEN));
+            let (status, body) = if !authorized {
+                ("403 Forbidden", r#"{"errors":[]}"#.to_string())
+            } else if request
+                .starts_with("get /v1/secret/data/mayastor/vault-key ")
+            {
+                let secret = serde_json::json!({
+                    "data": {
+                        "data": { "key": "c".repeat(64) },
+                        "metadata": { "version": 1 },
+                    }
+                });
+                ("200 OK", secret.to_string())
+            } else {
+                ("404 Not Found", r#"{"errors":[]}"#.to_string())
+            };
+
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+
+    endpoint
+}
+
+#[test]
+fn vault_key_provider() {
+    let endpoint = mock_vault(2);
+    std::env::set_var("VAULT_TOKEN", VAULT_TOKEN);
+
+    let provider = key_provider_from_uri(&format!(
+        "vault://secret/mayastor?endpoint={}",
+        endpoint
+    ))
+    .unwrap();
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let key = provider.get_key("vault-key").await.unwrap();
+        assert_eq!(key.id(), "vault-key");
+        assert_eq!(key.cipher(), Cipher::AesXts256);
+
+        assert!(provider.get_key("missing").await.is_err());
+    });
+}
@@ -0,0 +1,107 @@
+use rpc::mayastor::ShareProtocolNexus;
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup, EncryptionKey},
+    core::{MayastorCliArgs, MayastorEnvironment, Reactor},
+};
+
+pub mod common;
+
+const NEXUS_NAME: &str = "multi_share_nexus";
+const NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+const DISK_SIZE: u64 = 16 * 1024 * 1024;
+const DISK_NAME: &str = "/tmp/multi-share-disk.img";
+
+fn test_ini() {
+    test_init!();
+    common::delete_file(&[DISK_NAME.into()]);
+    common::truncate_file_bytes(DISK_NAME, DISK_SIZE);
+}
+
+fn test_fini() {
+    common::delete_file(&[DISK_NAME.into()]);
+}
+
+fn protocols(nexus_name: &str) -> Vec<ShareProtocolNexus> {
+    nexus_lookup(nexus_name)
+        .unwrap()
+        .shares()
+        .iter()
+        .map(|s| ShareProtocolNexus::from_i32(s.protocol).unwrap())
+        .collect()
+}
+
+#[test]
+fn nexus_multi_share() {
+    test_ini();
+
+    Reactor::block_on(async {
+        let ch = vec![format!("aio://{}?blk_size=512", DISK_NAME)];
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &ch)
+            .await
+            .unwrap();
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        let nvmf_uri = nexus
+            .share(ShareProtocolNexus::NexusNvmf, None)
+            .await
+            .unwrap();
+        let iscsi_uri = nexus
+            .share(ShareProtocolNexus::NexusIscsi, None)
+            .await
+            .unwrap();
+        assert!(nvmf_uri.starts_with("nvmf://"));
+        assert!(iscsi_uri.starts_with("iscsi://"));
+        assert_eq!(
+            protocols(NEXUS_NAME),
+            vec![
+                ShareProtocolNexus::NexusNvmf,
+                ShareProtocolNexus::NexusIscsi
+            ]
+        );
+        assert_eq!(nexus.get_share_path(), Some(iscsi_uri.clone()));
+
+        // sharing again over either protocol returns the existing share
+        assert_eq!(
+            nexus
+                .share(ShareProtocolNexus::NexusNvmf, None)
+                .await
+                .unwrap(),
+            nvmf_uri
+        );
+
+        // all shares export the same bdev, so the key must agree
+        assert!(nexus
+            .share(
+                ShareProtocolNexus::NexusNbd,
+                Some(EncryptionKey::new("key1", vec![b'k'; 32]).unwrap())
+            )
+            .await
+            .is_err());
+
+        // unsharing one protocol leaves the others in place
+        nexus
+            .unshare_protocol(ShareProtocolNexus::NexusIscsi)
+            .await
+            .unwrap();
+        assert_eq!(protocols(NEXUS_NAME), vec![ShareProtocolNexus::NexusNvmf]);
+        assert_eq!(nexus.get_share_path(), None);
+
+        // and unsharing a protocol the nexus is not shared over is a no-op
+        nexus
+            .unshare_protocol(ShareProtocolNexus::NexusIscsi)
+            .await
+            .unwrap();
+
+        nexus
+            .share(ShareProtocolNexus::NexusIscsi, None)
+            .await
+            .unwrap();
+        nexus.unshare().await.unwrap();
+        assert!(protocols(NEXUS_NAME).is_empty());
+
+        nexus.destroy().await.unwrap();
+    });
+
+    test_fini();
+}
@@ -0,0 +1,271 @@
+use std::time::Duration;
+
+use common::error_bdev::{
+    create_error_bdev,
+    inject_error,
+    SPDK_BDEV_IO_TYPE_READ,
+    SPDK_BDEV_IO_TYPE_WRITE,
+    VBDEV_IO_FAILURE,
+};
+use mayastor::{
+    bdev::{
+        nexus_create_with_opts,
+        nexus_lookup,
+        ChildStatus,
+        NexusCreateOpts,
+        NexusStatus,
+    },
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+    rebuild::RebuildState,
+};
+use rpc::mayastor::NexusLayout;
+
+pub mod common;
+
+const NEXUS_NAME: &str = "parity_nexus";
+const NEXUS_SIZE: u64 = 64 * 1024 * 1024;
+const DISK_SIZE: u64 = 40 * 1024 * 1024;
+const NUM_NEXUS_CHILDREN: u64 = 3;
+
+// not aligned to a strip and spanning multiple stripes
+const IO_OFFSET: u64 = 100 * 1024;
+const IO_SIZE: usize = 300 * 1024;
+
+fn test_ini() {
+    test_init!();
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+        common::truncate_file_bytes(&get_disk(i), DISK_SIZE);
+    }
+
+    Reactor::block_on(async {
+        create_nexus().await;
+    });
+}
+
+fn test_fini() {
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        nexus.destroy().await.unwrap();
+    });
+
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+    }
+}
+
+fn get_disk(number: u64) -> String {
+    format!("/tmp/parity-disk{}.img", number)
+}
+
+fn get_dev(number: u64) -> String {
+    format!("aio://{}?blk_size=512", get_disk(number))
+}
+
+fn parity() -> NexusCreateOpts {
+    NexusCreateOpts {
+        layout: NexusLayout::NexusParity,
+        ..Default::default()
+    }
+}
+
+async fn create_nexus() {
+    let mut ch = Vec::new();
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        ch.push(get_dev(i));
+    }
+
+    nexus_create_with_opts(NEXUS_NAME, NEXUS_SIZE, None, &ch, parity())
+        .await
+        .unwrap();
+}
+
+/// write a pattern to the nexus which depends on the seed
+async fn write_pattern(offset: u64, seed: u8) {
+    let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    buf.as_mut_slice()
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = (i % 251) as u8 ^ seed);
+
+    hdl.write_at(offset, &buf).await.unwrap();
+}
+
+/// read back the data from the nexus and verify the pattern
+async fn verify_pattern(offset: u64, seed: u8) {
+    let hdl = BdevHandle::open(NEXUS_NAME, false, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    hdl.read_at(offset, &mut buf).await.unwrap();
+
+    buf.as_slice()
+        .iter()
+        .enumerate()
+        .for_each(|(i, b)| assert_eq!(*b, (i % 251) as u8 ^ seed));
+}
+
+#[test]
+fn parity_nexus() {
+    test_ini();
+
+    // a parity nexus needs at least two data children and one for parity
+    Reactor::block_on(async {
+        let ch = vec![get_dev(0), get_dev(1)];
+        assert!(nexus_create_with_opts(
+            "parity_nexus_narrow",
+            NEXUS_SIZE,
+            None,
+            &ch,
+            parity(),
+        )
+        .await
+        .is_err());
+    });
+
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.layout(), NexusLayout::NexusParity);
+        assert_eq!(nexus.status(), NexusStatus::Online);
+
+        write_pattern(IO_OFFSET, 0).await;
+        verify_pattern(IO_OFFSET, 0).await;
+    });
+
+    // lose one of the children, the data must be reconstructed from the
+    // remaining children and parity
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        let status = nexus.offline_child(&get_dev(0)).await.unwrap();
+        assert_eq!(status, NexusStatus::Degraded);
+
+        verify_pattern(IO_OFFSET, 0).await;
+
+        // writes to a degraded nexus only end up in the parity for the
+        // missing child
+        write_pattern(IO_OFFSET * 3, 0x5a).await;
+        verify_pattern(IO_OFFSET * 3, 0x5a).await;
+    });
+
+    // bring the child back, which reconstructs it from the others
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        nexus.online_child(&get_dev(0)).await.unwrap();
+    });
+
+    common::wait_for_rebuild(
+        get_dev(0),
+        RebuildState::Completed,
+        Duration::from_secs(20),
+    )
+    .unwrap();
+
+    // with another child gone, the data must now come from the rebuilt child
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        let status = nexus.offline_child(&get_dev(1)).await.unwrap();
+        assert_eq!(status, NexusStatus::Degraded);
+
+        verify_pattern(IO_OFFSET, 0).await;
+        verify_pattern(IO_OFFSET * 3, 0x5a).await;
+    });
+
+    // the children keep their layout when the nexus is created again
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        nexus.destroy().await.unwrap();
+
+        let ch: Vec<String> = (0 .. NUM_NEXUS_CHILDREN).map(get_dev).collect();
+        assert!(nexus_create_with_opts(
+            NEXUS_NAME,
+            NEXUS_SIZE,
+            None,
+            &ch,
+            NexusCreateOpts::default(),
+        )
+        .await
+        .is_err());
+
+        // the columns come from the children, not from the order given
+        let ch: Vec<String> = ch.into_iter().rev().collect();
+        nexus_create_with_opts(NEXUS_NAME, NEXUS_SIZE, None, &ch, parity())
+            .await
+            .unwrap();
+
+        verify_pattern(IO_OFFSET, 0).await;
+        verify_pattern(IO_OFFSET * 3, 0x5a).await;
+    });
+
+    test_fini();
+}
+
+/// create the nexus with its first child on top of an error bdev
+fn error_nexus_ini(error_device: &str) -> String {
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+        common::truncate_file_bytes(&get_disk(i), DISK_SIZE);
+    }
+
+    let ee_device = format!("EE_{}", error_device);
+    let mut ch = vec![format!("bdev:///{}", ee_device)];
+    ch.extend((1 .. NUM_NEXUS_CHILDREN).map(get_dev));
+    let error_device = error_device.to_string();
+    Reactor::block_on(async move {
+        create_error_bdev(&error_device, &get_disk(0));
+        nexus_create_with_opts(NEXUS_NAME, NEXUS_SIZE, None, &ch, parity())
+            .await
+            .unwrap();
+    });
+
+    ee_device
+}
+
+/// the status of the first child once the nexus had time to fault it
+fn error_child_status() -> ChildStatus {
+    reactor_poll!(100);
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    nexus.children[0].status()
+}
+
+#[test]
+fn parity_nexus_column_error() {
+    test_init!();
+
+    // a failed read is reconstructed from the other columns
+    let ee_device = error_nexus_ini("parity_read_error");
+    Reactor::block_on(async move {
+        write_pattern(IO_OFFSET, 0x3c).await;
+        inject_error(
+            &ee_device,
+            SPDK_BDEV_IO_TYPE_READ,
+            VBDEV_IO_FAILURE,
+            1000,
+        );
+        verify_pattern(IO_OFFSET, 0x3c).await;
+    });
+    assert_eq!(error_child_status(), ChildStatus::Faulted);
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.status(), NexusStatus::Degraded);
+        verify_pattern(IO_OFFSET, 0x3c).await;
+    });
+    test_fini();
+
+    // a failed write is part of the parity
+    let ee_device = error_nexus_ini("parity_write_error");
+    Reactor::block_on(async move {
+        inject_error(
+            &ee_device,
+            SPDK_BDEV_IO_TYPE_WRITE,
+            VBDEV_IO_FAILURE,
+            1000,
+        );
+        write_pattern(IO_OFFSET, 0xc3).await;
+    });
+    assert_eq!(error_child_status(), ChildStatus::Faulted);
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.status(), NexusStatus::Degraded);
+        verify_pattern(IO_OFFSET, 0xc3).await;
+    });
+    test_fini();
+}
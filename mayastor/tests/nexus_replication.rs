@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup, NexusStatus},
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+};
+
+pub mod common;
+
+const NEXUS_NAME: &str = "replication_nexus";
+const NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+const DISK_SIZE: u64 = 12 * 1024 * 1024;
+const NUM_NEXUS_CHILDREN: u64 = 2;
+
+// the async child is the last disk
+const ASYNC_CHILD: u64 = NUM_NEXUS_CHILDREN;
+
+const IO_OFFSET: u64 = 100 * 1024;
+const IO_SIZE: usize = 300 * 1024;
+
+fn test_ini() {
+    test_init!();
+    for i in 0 ..= ASYNC_CHILD {
+        common::delete_file(&[get_disk(i)]);
+        common::truncate_file_bytes(&get_disk(i), DISK_SIZE);
+    }
+}
+
+fn test_fini() {
+    for i in 0 ..= ASYNC_CHILD {
+        common::delete_file(&[get_disk(i)]);
+    }
+}
+
+fn get_disk(number: u64) -> String {
+    format!("/tmp/replication-disk{}.img", number)
+}
+
+fn get_dev(number: u64) -> String {
+    format!("aio://{}?blk_size=512", get_disk(number))
+}
+
+/// write a pattern to the nexus which depends on the seed
+async fn write_pattern(offset: u64, seed: u8) {
+    let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    buf.as_mut_slice()
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = (i % 251) as u8 ^ seed);
+
+    hdl.write_at(offset, &buf).await.unwrap();
+}
+
+/// read back the data from the nexus and verify the pattern
+async fn verify_pattern(offset: u64, seed: u8) {
+    let hdl = BdevHandle::open(NEXUS_NAME, false, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    hdl.read_at(offset, &mut buf).await.unwrap();
+
+    buf.as_slice()
+        .iter()
+        .enumerate()
+        .for_each(|(i, b)| assert_eq!(*b, (i % 251) as u8 ^ seed));
+}
+
+/// wait for the async child to have caught up with the nexus
+fn wait_for_replication() {
+    common::retry(100, Duration::from_millis(100), || {
+        Reactor::block_on(async {
+            let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+            if nexus.async_children()[0].lag_blocks == 0 {
+                Ok(())
+            } else {
+                Err(())
+            }
+        })
+        .unwrap()
+    });
+}
+
+#[test]
+fn replication_nexus() {
+    test_ini();
+
+    Reactor::block_on(async {
+        let ch: Vec<String> = (0 .. NUM_NEXUS_CHILDREN).map(get_dev).collect();
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &ch)
+            .await
+            .unwrap();
+        write_pattern(IO_OFFSET, 0).await;
+
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        nexus.add_async_child(&get_dev(ASYNC_CHILD)).await.unwrap();
+
+        // the async child does not count towards the state of the nexus
+        assert_eq!(nexus.status(), NexusStatus::Online);
+        assert_eq!(nexus.children.len(), NUM_NEXUS_CHILDREN as usize);
+
+        // all of the nexus has to be copied before it can be promoted
+        let info = nexus.async_children();
+        assert_eq!(info.len(), 1);
+        assert!(info[0].lag_blocks > 0);
+        assert!(nexus
+            .promote_async_child(&get_dev(ASYNC_CHILD))
+            .await
+            .is_err());
+    });
+
+    wait_for_replication();
+
+    // writes after the initial copy must reach the async child as well
+    Reactor::block_on(async {
+        write_pattern(IO_OFFSET * 4, 0x5a).await;
+    });
+
+    wait_for_replication();
+
+    // promote the child and drop the others, so all reads are served by it
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        nexus
+            .promote_async_child(&get_dev(ASYNC_CHILD))
+            .await
+            .unwrap();
+        assert!(nexus.async_children().is_empty());
+        assert_eq!(nexus.children.len(), ASYNC_CHILD as usize + 1);
+
+        for i in 0 .. NUM_NEXUS_CHILDREN {
+            nexus.offline_child(&get_dev(i)).await.unwrap();
+        }
+        assert_eq!(nexus.status(), NexusStatus::Degraded);
+
+        verify_pattern(IO_OFFSET, 0).await;
+        verify_pattern(IO_OFFSET * 4, 0x5a).await;
+
+        nexus.destroy().await.unwrap();
+    });
+
+    test_fini();
+}
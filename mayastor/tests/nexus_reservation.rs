@@ -0,0 +1,195 @@
+use std::convert::TryInto;
+
+use rpc::mayastor::ShareProtocolNexus;
+use spdk_sys::{spdk_nvme_cmd, SPDK_NVME_OPC_RESERVATION_REPORT};
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup},
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+    nexus_uri::{bdev_create, bdev_destroy},
+    subsys::AnaState,
+};
+
+pub mod common;
+
+const NEXUS_NAME: &str = "reservation_nexus";
+const NEXUS_UUID: &str = "9b2f6d4e-1c3a-4e8b-b7d5-3f0a6c2e9d14";
+const NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+const DISK_SIZE: u64 = 16 * 1024 * 1024;
+const NUM_NEXUS_CHILDREN: u64 = 2;
+
+const RESV_KEY: u64 = 0xabcd;
+/// register action to register a key and the change of PTPL activating it
+const REGISTER: u8 = 0;
+const ACTIVATE_PTPL: u8 = 2;
+/// acquire and release actions
+const ACQUIRE: u8 = 0;
+const RELEASE: u8 = 0;
+const WRITE_EXCLUSIVE: u8 = 1;
+
+fn test_ini() {
+    test_init!();
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+        common::truncate_file_bytes(&get_disk(i), DISK_SIZE);
+    }
+}
+
+fn test_fini() {
+    for i in 0 .. NUM_NEXUS_CHILDREN {
+        common::delete_file(&[get_disk(i)]);
+    }
+}
+
+fn get_disk(number: u64) -> String {
+    format!("/tmp/reservation-disk{}.img", number)
+}
+
+fn get_dev(number: u64) -> String {
+    format!("aio://{}?blk_size=512", get_disk(number))
+}
+
+/// reservation of the namespace as reported to the host
+#[derive(Debug, PartialEq)]
+struct Report {
+    rtype: u8,
+    ptpl: bool,
+    /// keys of the registered controllers and whether they hold the
+    /// reservation
+    registrants: Vec<(u64, bool)>,
+}
+
+async fn create_nexus() {
+    let ch: Vec<String> = (0 .. NUM_NEXUS_CHILDREN).map(get_dev).collect();
+    nexus_create(NEXUS_NAME, NEXUS_SIZE, Some(NEXUS_UUID), &ch)
+        .await
+        .unwrap();
+}
+
+/// share the nexus over nvmf and connect to it, returning the uri of the
+/// nvme bdev of the connection
+async fn share_and_connect() -> (String, BdevHandle) {
+    let uri = nexus_lookup(NEXUS_NAME)
+        .unwrap()
+        .share(ShareProtocolNexus::NexusNvmf, None)
+        .await
+        .unwrap();
+    let name = bdev_create(&uri).await.unwrap();
+    let hdl = BdevHandle::open(&name, true, false).unwrap();
+    (uri, hdl)
+}
+
+async fn disconnect_and_unshare(uri: String, hdl: BdevHandle) {
+    drop(hdl);
+    bdev_destroy(&uri).await.unwrap();
+    nexus_lookup(NEXUS_NAME).unwrap().unshare().await.unwrap();
+}
+
+/// read the extended reservation status of the namespace, the only one the
+/// nvmf target reports
+async fn report(hdl: &BdevHandle) -> Report {
+    let mut buf = hdl.dma_malloc(4096).unwrap();
+    let mut cmd = spdk_nvme_cmd::default();
+    cmd.set_opc(SPDK_NVME_OPC_RESERVATION_REPORT as u16);
+    cmd.__bindgen_anon_1.cdw10 = (buf.len() / 4 - 1) as u32;
+    cmd.__bindgen_anon_2.cdw11 = 1;
+    hdl.nvme_io_passthru(&cmd, &mut buf).await.unwrap();
+    let data = buf.as_slice();
+    let registered = u16::from_le_bytes(data[5 .. 7].try_into().unwrap());
+
+    Report {
+        rtype: data[4],
+        ptpl: data[9] != 0,
+        registrants: data[64 ..]
+            .chunks_exact(64)
+            .take(registered as usize)
+            .map(|c| {
+                (
+                    u64::from_le_bytes(c[8 .. 16].try_into().unwrap()),
+                    c[2] & 1 != 0,
+                )
+            })
+            .collect(),
+    }
+}
+
+fn held_by_us() -> Report {
+    Report {
+        rtype: WRITE_EXCLUSIVE,
+        ptpl: true,
+        registrants: vec![(RESV_KEY, true)],
+    }
+}
+
+#[test]
+fn nexus_reservation() {
+    test_ini();
+
+    Reactor::block_on(async {
+        create_nexus().await;
+        let (uri, hdl) = share_and_connect().await;
+        assert_eq!(
+            report(&hdl).await,
+            Report {
+                rtype: 0,
+                ptpl: false,
+                registrants: Vec::new(),
+            }
+        );
+
+        // register with PTPL activated and take a write exclusive
+        // reservation, both are saved on the children before the commands
+        // complete
+        hdl.nvme_resv_register(0, RESV_KEY, REGISTER, true, ACTIVATE_PTPL)
+            .await
+            .unwrap();
+        hdl.nvme_resv_acquire(RESV_KEY, 0, ACQUIRE, WRITE_EXCLUSIVE)
+            .await
+            .unwrap();
+        assert_eq!(report(&hdl).await, held_by_us());
+
+        // the reservation survives unsharing and sharing the nexus
+        disconnect_and_unshare(uri, hdl).await;
+        let (uri, hdl) = share_and_connect().await;
+        assert_eq!(report(&hdl).await, held_by_us());
+        disconnect_and_unshare(uri, hdl).await;
+
+        // and re-creating it from the children
+        nexus_lookup(NEXUS_NAME).unwrap().destroy().await.unwrap();
+        create_nexus().await;
+        let (uri, hdl) = share_and_connect().await;
+        assert_eq!(report(&hdl).await, held_by_us());
+
+        // the release is saved as well
+        hdl.nvme_resv_release(RESV_KEY, RELEASE, WRITE_EXCLUSIVE)
+            .await
+            .unwrap();
+        disconnect_and_unshare(uri, hdl).await;
+        let (uri, hdl) = share_and_connect().await;
+        assert_eq!(
+            report(&hdl).await,
+            Report {
+                rtype: 0,
+                ptpl: true,
+                registrants: vec![(RESV_KEY, false)],
+            }
+        );
+
+        // the targets of other nodes exporting the nexus would not see the
+        // reservation, commands are refused while it is not the only path
+        nexus_lookup(NEXUS_NAME)
+            .unwrap()
+            .set_ana_state(AnaState::NonOptimized, None)
+            .await
+            .unwrap();
+        assert!(hdl
+            .nvme_resv_acquire(RESV_KEY, 0, ACQUIRE, WRITE_EXCLUSIVE)
+            .await
+            .is_err());
+        disconnect_and_unshare(uri, hdl).await;
+
+        nexus_lookup(NEXUS_NAME).unwrap().destroy().await.unwrap();
+    });
+
+    test_fini();
+}
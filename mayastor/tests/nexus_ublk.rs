@@ -0,0 +1,82 @@
+use std::{fs::OpenOptions, io::Write, path::Path, thread};
+
+use futures::channel::oneshot;
+
+use rpc::mayastor::ShareProtocolNexus;
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup},
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+};
+
+pub mod common;
+
+const NEXUS_NAME: &str = "ublk_nexus";
+const NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+const DISK_SIZE: u64 = 16 * 1024 * 1024;
+const DISK_NAME: &str = "/tmp/ublk-disk.img";
+const IO_SIZE: usize = 4096;
+
+fn test_ini() {
+    test_init!();
+    common::delete_file(&[DISK_NAME.into()]);
+    common::truncate_file_bytes(DISK_NAME, DISK_SIZE);
+}
+
+fn test_fini() {
+    common::delete_file(&[DISK_NAME.into()]);
+}
+
+#[test]
+fn nexus_ublk() {
+    if !Path::new("/dev/ublk-control").exists() {
+        println!("Skipping ublk test, ublk_drv is not loaded");
+        return;
+    }
+
+    test_ini();
+
+    Reactor::block_on(async {
+        let ch = vec![format!("aio://{}?blk_size=512", DISK_NAME)];
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &ch)
+            .await
+            .unwrap();
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        let uri = nexus
+            .share(ShareProtocolNexus::NexusUblk, None)
+            .await
+            .unwrap();
+        assert!(uri.starts_with("file:///dev/ublkb"));
+        assert_eq!(nexus.get_share_path(), Some(uri.clone()));
+
+        let device = common::device_path_from_uri(uri);
+        assert!(common::get_device_size(&device) <= NEXUS_SIZE);
+
+        // write through the device from another thread, as the reactor must
+        // serve the IO meanwhile
+        let (s, r) = oneshot::channel::<()>();
+        let path = device.clone();
+        thread::spawn(move || {
+            let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+            f.write_all(&[0xa5; IO_SIZE]).unwrap();
+            f.sync_all().unwrap();
+            s.send(()).unwrap();
+        });
+        r.await.unwrap();
+
+        let hdl = BdevHandle::open(NEXUS_NAME, false, false).unwrap();
+        let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+        hdl.read_at(0, &mut buf).await.unwrap();
+        assert!(buf.as_slice().iter().all(|b| *b == 0xa5));
+        drop(hdl);
+
+        nexus.unshare().await.unwrap();
+        assert!(!Path::new(&device).exists());
+        assert_eq!(nexus.get_share_path(), None);
+
+        nexus.destroy().await.unwrap();
+    });
+
+    test_fini();
+}
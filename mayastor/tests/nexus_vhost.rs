@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use rpc::mayastor::ShareProtocolNexus;
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup},
+    core::{MayastorCliArgs, MayastorEnvironment, Reactor},
+};
+
+pub mod common;
+
+const NEXUS_NAME: &str = "vhost_nexus";
+const NEXUS_SIZE: u64 = 10 * 1024 * 1024;
+const DISK_SIZE: u64 = 16 * 1024 * 1024;
+const DISK_NAME: &str = "/tmp/vhost-disk.img";
+
+fn test_ini() {
+    test_init!();
+    common::delete_file(&[DISK_NAME.into()]);
+    common::truncate_file_bytes(DISK_NAME, DISK_SIZE);
+}
+
+fn test_fini() {
+    common::delete_file(&[DISK_NAME.into()]);
+}
+
+#[test]
+fn nexus_vhost() {
+    test_ini();
+
+    Reactor::block_on(async {
+        let ch = vec![format!("aio://{}?blk_size=512", DISK_NAME)];
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &ch)
+            .await
+            .unwrap();
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        let uri = nexus
+            .share(ShareProtocolNexus::NexusVhostUserBlk, None)
+            .await
+            .unwrap();
+        let socket = format!("/var/tmp/{}", NEXUS_NAME);
+        assert_eq!(uri, format!("vhost-user-blk://{}", socket));
+        assert!(Path::new(&socket).exists());
+        assert_eq!(nexus.get_share_path(), Some(uri.clone()));
+
+        // sharing again over the same protocol is idempotent
+        assert_eq!(
+            nexus
+                .share(ShareProtocolNexus::NexusVhostUserBlk, None)
+                .await
+                .unwrap(),
+            uri
+        );
+
+        // the nexus can be shared over another protocol at the same time
+        nexus
+            .share(ShareProtocolNexus::NexusNvmf, None)
+            .await
+            .unwrap();
+        assert_eq!(nexus.shares().len(), 2);
+        nexus
+            .unshare_protocol(ShareProtocolNexus::NexusNvmf)
+            .await
+            .unwrap();
+        assert_eq!(nexus.get_share_path(), Some(uri.clone()));
+
+        nexus.unshare().await.unwrap();
+        assert!(!Path::new(&socket).exists());
+        assert_eq!(nexus.get_share_path(), None);
+
+        nexus.destroy().await.unwrap();
+    });
+
+    test_fini();
+}
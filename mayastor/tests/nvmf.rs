@@ -1,4 +1,4 @@
-use std::convert::TryFrom;
+use std::{convert::TryFrom, net::IpAddr};
 
 use mayastor::{
     core::{
@@ -9,13 +9,15 @@ use mayastor::{
         Reactor,
     },
     nexus_uri::bdev_create,
-    subsys::{NvmfSubsystem, SubType},
+    subsys::{AnaState, NvmfShareOpts, NvmfSubsystem, SubType},
 };
 
 pub mod common;
 
 static DISKNAME1: &str = "/tmp/disk1.img";
 static BDEVNAME1: &str = "aio:///tmp/disk1.img?blk_size=512";
+static SHARE_UUID: &str = "5c0e1d8a-6b27-4f93-8d41-2a9c7e3b6f10";
+static HOSTNQN: &str = "nqn.2019-05.io.openebs:host1";
 
 #[test]
 fn nvmf_target() {
@@ -74,6 +76,84 @@ fn nvmf_target() {
                     assert_eq!(bdev.claimed_by(), None);
                 }
             });
+            // share the bdev again with a host list and namespace options
+            Reactor::block_on(async {
+                let bdev = Bdev::lookup_by_name(BDEVNAME1).unwrap();
+                let opts = NvmfShareOpts {
+                    hosts: vec![HOSTNQN.into()],
+                    nsid: 2,
+                    ..Default::default()
+                };
+
+                let ss = NvmfSubsystem::share(SHARE_UUID, &bdev, &opts)
+                    .await
+                    .unwrap();
+                assert!(ss.uri().unwrap().ends_with(SHARE_UUID));
+                assert_eq!(bdev.claimed_by().unwrap(), "NVMe-oF Target");
+
+                // sharing it twice is not an error
+                NvmfSubsystem::share(SHARE_UUID, &bdev, &opts)
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    NvmfSubsystem::first().unwrap().into_iter().count(),
+                    2
+                );
+
+                NvmfSubsystem::unshare(SHARE_UUID).await.unwrap();
+                assert!(NvmfSubsystem::nqn_lookup(SHARE_UUID).is_none());
+                assert_eq!(bdev.is_claimed(), false);
+            });
+
+            // export the bdev as the non-optimized path of a multipath
+            // subsystem and change the ANA state of its listeners
+            Reactor::block_on(async {
+                let bdev = Bdev::lookup_by_name(BDEVNAME1).unwrap();
+                let opts = NvmfShareOpts {
+                    ana_state: AnaState::NonOptimized,
+                    cntlid_base: 0x8000,
+                    ..Default::default()
+                };
+
+                let ss = NvmfSubsystem::share(SHARE_UUID, &bdev, &opts)
+                    .await
+                    .unwrap();
+                let states = ss.ana_states();
+                assert_ne!(states.len(), 0);
+                assert!(states
+                    .iter()
+                    .all(|(_, state)| *state == AnaState::NonOptimized));
+
+                let loopback = "127.0.0.1".parse::<IpAddr>().unwrap();
+                ss.set_ana_state(AnaState::Inaccessible, Some(&loopback))
+                    .await
+                    .unwrap();
+                assert!(ss
+                    .ana_states()
+                    .iter()
+                    .filter(|(uri, _)| uri.contains("127.0.0.1"))
+                    .all(|(_, state)| *state == AnaState::Inaccessible));
+
+                ss.set_ana_state(AnaState::Optimized, None).await.unwrap();
+                assert!(ss
+                    .ana_states()
+                    .iter()
+                    .all(|(_, state)| *state == AnaState::Optimized));
+
+                // there is no listener on this address
+                let other = "10.255.255.1".parse::<IpAddr>().unwrap();
+                assert!(ss
+                    .set_ana_state(AnaState::Optimized, Some(&other))
+                    .await
+                    .is_err());
+
+                NvmfSubsystem::unshare(SHARE_UUID).await.unwrap();
+                assert_eq!(bdev.is_claimed(), false);
+
+                // unsharing it twice is not an error either
+                NvmfSubsystem::unshare(SHARE_UUID).await.unwrap();
+            });
+
             // this should clean/up kill the discovery controller
             mayastor_env_stop(0);
         })
@@ -0,0 +1,192 @@
+use std::{fs, time::Duration};
+
+use mayastor::{
+    backup::BackupJob,
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+    pool::{create_base_bdev, Pool},
+    rebuild::RebuildState,
+    replica::Replica,
+};
+use rpc::mayastor::PoolIoIf;
+
+pub mod common;
+
+const POOL_NAME: &str = "backup_pool";
+const POOL_DISK: &str = "/tmp/backup-pool.img";
+const DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+const STORE_DIR: &str = "/tmp/backup-store";
+const STORE_URI: &str = "file:///tmp/backup-store";
+
+const REPLICA_UUID: &str = "2f0e6b9c-5b1a-4ee2-9d3e-4a7cbd8a3f01";
+const RESTORED_UUID: &str = "6d4b8a1e-0c73-4f5e-8b2a-91f0e7c3d402";
+const CORRUPT_UUID: &str = "a8c1f3d5-7e29-4b60-b4d7-3e5f2a9c6b03";
+const REPLICA_SIZE: u64 = 16 * 1024 * 1024;
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+const BACKUP_NAME: &str = "backup1";
+
+// spans two chunks, the other chunks only hold zeroes and are not stored
+const IO_OFFSET: u64 = 3 * 1024 * 1024 - 64 * 1024;
+const IO_SIZE: usize = 256 * 1024;
+
+fn test_ini() {
+    test_init!();
+    common::delete_file(&[POOL_DISK.into()]);
+    common::truncate_file_bytes(POOL_DISK, DISK_SIZE);
+    let _ = fs::remove_dir_all(STORE_DIR);
+}
+
+fn test_fini() {
+    common::delete_file(&[POOL_DISK.into()]);
+    let _ = fs::remove_dir_all(STORE_DIR);
+}
+
+/// write a pattern to the replica which depends on the seed
+async fn write_pattern(uuid: &str, seed: u8) {
+    let replica = Replica::lookup(uuid).unwrap();
+    let hdl = BdevHandle::open(&replica.get_bdev_name(), true, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    buf.as_mut_slice()
+        .iter_mut()
+        .enumerate()
+        .for_each(|(i, b)| *b = (i % 251) as u8 ^ seed);
+
+    hdl.write_at(IO_OFFSET, &buf).await.unwrap();
+}
+
+/// read back the data from the replica and verify the pattern
+async fn verify_pattern(uuid: &str, seed: u8) {
+    let replica = Replica::lookup(uuid).unwrap();
+    let hdl = BdevHandle::open(&replica.get_bdev_name(), false, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    hdl.read_at(IO_OFFSET, &mut buf).await.unwrap();
+
+    buf.as_slice()
+        .iter()
+        .enumerate()
+        .for_each(|(i, b)| assert_eq!(*b, (i % 251) as u8 ^ seed));
+}
+
+/// wait for the job of the backup to be done and return its final state
+fn wait_for_job(name: &str) -> RebuildState {
+    common::retry(100, Duration::from_millis(100), || {
+        Reactor::block_on(async {
+            let state = BackupJob::lookup(name).unwrap().state();
+            if state.done() {
+                Ok(state)
+            } else {
+                Err(())
+            }
+        })
+        .unwrap()
+    })
+}
+
+/// remove the job of the backup
+fn remove_job(name: &str) {
+    Reactor::block_on(async {
+        BackupJob::remove(name).await.unwrap();
+    });
+}
+
+#[test]
+fn replica_backup() {
+    test_ini();
+
+    Reactor::block_on(async {
+        create_base_bdev(POOL_DISK, 512, PoolIoIf::PoolIoAio).unwrap();
+        Pool::create(POOL_NAME, POOL_DISK).await.unwrap();
+        Replica::create(REPLICA_UUID, POOL_NAME, REPLICA_SIZE, false)
+            .await
+            .unwrap();
+        write_pattern(REPLICA_UUID, 0x3c).await;
+
+        // the chunks must be whole blocks
+        assert!(BackupJob::backup(
+            REPLICA_UUID,
+            STORE_URI,
+            "unaligned",
+            CHUNK_SIZE + 1,
+            false
+        )
+        .await
+        .is_err());
+
+        BackupJob::backup(
+            REPLICA_UUID,
+            STORE_URI,
+            BACKUP_NAME,
+            CHUNK_SIZE,
+            true,
+        )
+        .await
+        .unwrap();
+    });
+
+    assert_eq!(wait_for_job(BACKUP_NAME), RebuildState::Completed);
+    remove_job(BACKUP_NAME);
+
+    // only the manifest and the chunks holding data have been stored
+    let chunks = fs::read_dir(format!("{}/{}/chunks", STORE_DIR, BACKUP_NAME))
+        .unwrap()
+        .count();
+    assert_eq!(chunks, 2);
+
+    // changes to the replica after the backup must not show in the restore
+    Reactor::block_on(async {
+        write_pattern(REPLICA_UUID, 0xa5).await;
+        BackupJob::restore(
+            STORE_URI,
+            BACKUP_NAME,
+            RESTORED_UUID,
+            POOL_NAME,
+            false,
+        )
+        .await
+        .unwrap();
+    });
+
+    assert_eq!(wait_for_job(BACKUP_NAME), RebuildState::Completed);
+    remove_job(BACKUP_NAME);
+
+    Reactor::block_on(async {
+        verify_pattern(RESTORED_UUID, 0x3c).await;
+    });
+
+    // a corrupted chunk fails the restore
+    let chunk = fs::read_dir(format!("{}/{}/chunks", STORE_DIR, BACKUP_NAME))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let mut data = fs::read(&chunk).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&chunk, data).unwrap();
+
+    Reactor::block_on(async {
+        BackupJob::restore(
+            STORE_URI,
+            BACKUP_NAME,
+            CORRUPT_UUID,
+            POOL_NAME,
+            false,
+        )
+        .await
+        .unwrap();
+    });
+
+    assert_eq!(wait_for_job(BACKUP_NAME), RebuildState::Failed);
+    remove_job(BACKUP_NAME);
+
+    Reactor::block_on(async {
+        for uuid in &[REPLICA_UUID, RESTORED_UUID, CORRUPT_UUID] {
+            Replica::lookup(uuid).unwrap().destroy().await.unwrap();
+        }
+        Pool::lookup(POOL_NAME).unwrap().destroy().await.unwrap();
+    });
+
+    test_fini();
+}
@@ -0,0 +1,163 @@
+use std::{panic, sync::mpsc, thread};
+
+use common::ms_exec::MayastorProcess;
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup, ChildStatus, NexusStatus},
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+    pool::{create_base_bdev, Pool},
+    replica::{Replica, ShareType},
+    subsys::Config,
+};
+use rpc::mayastor::PoolIoIf;
+
+pub mod common;
+
+const POOL_NAME: &str = "fence_pool";
+const POOL_DISK: &str = "/tmp/fence-pool.img";
+const DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+const REPLICA_UUID: &str = "3a7d9e21-5c4b-4f80-b6e2-8d1f0a9c7b43";
+const REPLICA_SIZE: u64 = 16 * 1024 * 1024;
+
+const NEXUS_NAME: &str = "fence_nexus";
+const NEXUS_SIZE: u64 = 8 * 1024 * 1024;
+
+const IO_SIZE: usize = 4096;
+
+/// configuration of the mayastor instance standing in for another node
+const OTHER_NODE_CONFIG: &str = "/tmp/fence-other-node.yaml";
+const OTHER_NEXUS_UUID: &str = "6e0c4b9a-2f71-4d3e-8a5b-c19d7f3e2a68";
+
+fn test_ini() {
+    test_init!();
+    common::delete_file(&[POOL_DISK.into()]);
+    common::truncate_file_bytes(POOL_DISK, DISK_SIZE);
+}
+
+fn test_fini() {
+    common::delete_file(&[POOL_DISK.into()]);
+}
+
+/// start a mayastor instance which takes the place of another node, with
+/// ports of its own
+fn start_other_node() -> MayastorProcess {
+    let mut config = Config::default();
+    config.nexus_opts.iscsi_enable = false;
+    config.nexus_opts.nvmf_replica_port = 8432;
+    config.nexus_opts.nvmf_nexus_port = 8442;
+    config.write(OTHER_NODE_CONFIG).unwrap();
+
+    let args = vec![
+        "-s".to_string(),
+        "128".to_string(),
+        "-y".to_string(),
+        OTHER_NODE_CONFIG.to_string(),
+    ];
+    MayastorProcess::new(Box::from(args)).unwrap()
+}
+
+/// open a nexus on the replica over nvmf, write to it and destroy it again,
+/// the reservation of the replica is saved as it is unshared
+async fn open_nexus(replica: &Replica) {
+    replica.share(ShareType::Nvmf).await.unwrap();
+    nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &[replica.get_share_uri()])
+        .await
+        .unwrap();
+
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    assert_eq!(nexus.status(), NexusStatus::Online);
+
+    // the owner of the reservation can write to the replica
+    let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    buf.fill(0xa5);
+    hdl.write_at(0, &buf).await.unwrap();
+    drop(hdl);
+    assert_eq!(nexus.status(), NexusStatus::Online);
+
+    nexus.destroy().await.unwrap();
+    replica.unshare().await.unwrap();
+}
+
+#[test]
+fn replica_fence() {
+    let other_node = start_other_node();
+    test_ini();
+
+    Reactor::block_on(async {
+        create_base_bdev(POOL_DISK, 512, PoolIoIf::PoolIoAio).unwrap();
+        Pool::create(POOL_NAME, POOL_DISK).await.unwrap();
+        let replica =
+            Replica::create(REPLICA_UUID, POOL_NAME, REPLICA_SIZE, false)
+                .await
+                .unwrap();
+        assert_eq!(replica.owner_generation(), None);
+
+        // the first nexus to open the replica owns it with generation 1
+        open_nexus(&replica).await;
+        assert_eq!(replica.owner_generation(), Some(1));
+
+        // the reservation is restored when the replica is shared again, so
+        // the next nexus takes over with a newer generation
+        open_nexus(&replica).await;
+        assert_eq!(replica.owner_generation(), Some(2));
+
+        // a nexus of this instance owns the replica while a nexus on another
+        // node opens it
+        replica.share(ShareType::Nvmf).await.unwrap();
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &[replica.get_share_uri()])
+            .await
+            .unwrap();
+        assert_eq!(replica.owner_generation(), Some(3));
+    });
+
+    // the other node connects to the target of the replica served by our
+    // reactor, which is polled while the call is in progress
+    let uri = Replica::lookup(REPLICA_UUID).unwrap().get_share_uri();
+    let rpc_path = other_node.rpc_path.clone();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = panic::catch_unwind(|| {
+            MayastorProcess::rpc_call_at(
+                &rpc_path,
+                "create_nexus",
+                serde_json::json!({
+                    "uuid": OTHER_NEXUS_UUID,
+                    "size": NEXUS_SIZE,
+                    "children": [uri],
+                }),
+            )
+        });
+        sender.send(result.is_ok()).unwrap();
+    });
+    let created: bool;
+    reactor_poll!(receiver, created);
+    assert!(created);
+
+    // the stale owner can no longer write to the replica
+    Reactor::block_on(async {
+        let replica = Replica::lookup(REPLICA_UUID).unwrap();
+        assert_eq!(replica.owner_generation(), Some(4));
+
+        let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+        buf.fill(0x5a);
+        assert!(hdl.write_at(0, &buf).await.is_err());
+    });
+
+    // the child is faulted on the management core
+    reactor_poll!(1000);
+
+    Reactor::block_on(async {
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+        assert_eq!(nexus.children[0].status(), ChildStatus::Faulted);
+        assert!(nexus.children[0].is_fenced());
+        nexus.destroy().await.unwrap();
+
+        let replica = Replica::lookup(REPLICA_UUID).unwrap();
+        replica.destroy().await.unwrap();
+    });
+
+    drop(other_node);
+    test_fini();
+}
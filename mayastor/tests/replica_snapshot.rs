@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup},
+    core::{BdevHandle, MayastorCliArgs, MayastorEnvironment, Reactor},
+    pool::{create_base_bdev, Pool},
+    replica::Replica,
+};
+use rpc::mayastor::PoolIoIf;
+
+pub mod common;
+
+const POOL_NAME: &str = "snapshot_pool";
+const POOL_DISK: &str = "/tmp/snapshot-pool.img";
+const DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+const REPLICA_UUID: &str = "0b1c7d4e-3f52-4a86-9e0d-6c2b8f1a5d01";
+const CLONE_UUID: &str = "7e5a2c9f-1d84-4b36-a0f7-3b9e6d4c2a02";
+const SNAPSHOT_NAME: &str = "0b1c7d4e-3f52-4a86-9e0d-6c2b8f1a5d01@snap1";
+const REPLICA_SIZE: u64 = 16 * 1024 * 1024;
+
+const NEXUS_NAME: &str = "freeze_nexus";
+const NEXUS_SIZE: u64 = 8 * 1024 * 1024;
+const NEXUS_DISK: &str = "/tmp/freeze-disk.img";
+
+const IO_OFFSET: u64 = 1024 * 1024;
+const IO_SIZE: usize = 64 * 1024;
+
+fn test_ini(disk: &str) {
+    test_init!();
+    common::delete_file(&[disk.into()]);
+    common::truncate_file_bytes(disk, DISK_SIZE);
+}
+
+fn test_fini(disk: &str) {
+    common::delete_file(&[disk.into()]);
+}
+
+/// fill the IO range of the bdev with the given byte
+async fn write_byte(bdev: &str, byte: u8) {
+    let hdl = BdevHandle::open(bdev, true, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    buf.fill(byte);
+    hdl.write_at(IO_OFFSET, &buf).await.unwrap();
+}
+
+/// check that the IO range of the bdev holds the given byte
+async fn verify_byte(bdev: &str, byte: u8) {
+    let hdl = BdevHandle::open(bdev, false, false).unwrap();
+    let mut buf = hdl.dma_malloc(IO_SIZE).unwrap();
+    hdl.read_at(IO_OFFSET, &mut buf).await.unwrap();
+    buf.as_slice().iter().for_each(|b| assert_eq!(*b, byte));
+}
+
+#[test]
+fn replica_snapshot() {
+    test_ini(POOL_DISK);
+
+    Reactor::block_on(async {
+        create_base_bdev(POOL_DISK, 512, PoolIoIf::PoolIoAio).unwrap();
+        Pool::create(POOL_NAME, POOL_DISK).await.unwrap();
+        let replica =
+            Replica::create(REPLICA_UUID, POOL_NAME, REPLICA_SIZE, false)
+                .await
+                .unwrap();
+        assert!(!replica.is_snapshot());
+        write_byte(REPLICA_UUID, 0xa5).await;
+
+        let snapshot = replica.snapshot(SNAPSHOT_NAME).await.unwrap();
+        assert!(snapshot.is_snapshot());
+        assert!(replica.snapshot(SNAPSHOT_NAME).await.is_err());
+
+        // the snapshot keeps the data written before it was taken
+        write_byte(REPLICA_UUID, 0x5a).await;
+        verify_byte(REPLICA_UUID, 0x5a).await;
+        verify_byte(SNAPSHOT_NAME, 0xa5).await;
+
+        // only snapshots can be cloned
+        assert!(replica.create_clone(CLONE_UUID).await.is_err());
+        let clone = snapshot.create_clone(CLONE_UUID).await.unwrap();
+        assert!(!clone.is_snapshot());
+        verify_byte(CLONE_UUID, 0xa5).await;
+
+        // writes to the clone do not change the snapshot
+        write_byte(CLONE_UUID, 0x3c).await;
+        verify_byte(CLONE_UUID, 0x3c).await;
+        verify_byte(SNAPSHOT_NAME, 0xa5).await;
+
+        // the clone has to go before its snapshot
+        Replica::lookup(CLONE_UUID)
+            .unwrap()
+            .destroy()
+            .await
+            .unwrap();
+        Replica::lookup(SNAPSHOT_NAME)
+            .unwrap()
+            .destroy()
+            .await
+            .unwrap();
+        Replica::lookup(REPLICA_UUID)
+            .unwrap()
+            .destroy()
+            .await
+            .unwrap();
+        Pool::lookup(POOL_NAME).unwrap().destroy().await.unwrap();
+    });
+
+    test_fini(POOL_DISK);
+}
+
+#[test]
+fn nexus_freeze() {
+    test_ini(NEXUS_DISK);
+
+    Reactor::block_on(async {
+        let ch = vec![format!("aio://{}?blk_size=512", NEXUS_DISK)];
+        nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &ch)
+            .await
+            .unwrap();
+        let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+        nexus.freeze(Duration::from_secs(10)).await.unwrap();
+        assert!(nexus.is_frozen());
+        assert!(nexus.freeze(Duration::from_secs(10)).await.is_err());
+
+        nexus.thaw().await.unwrap();
+        assert!(!nexus.is_frozen());
+        // thawing twice is not an error
+        nexus.thaw().await.unwrap();
+
+        // writes go through again once the nexus has been thawed
+        write_byte(NEXUS_NAME, 0x11).await;
+        verify_byte(NEXUS_NAME, 0x11).await;
+
+        nexus.freeze(Duration::from_secs(1)).await.unwrap();
+    });
+
+    // the nexus thaws by itself once the timeout expires
+    common::retry(30, Duration::from_millis(100), || {
+        Reactor::block_on(async {
+            if nexus_lookup(NEXUS_NAME).unwrap().is_frozen() {
+                Err(())
+            } else {
+                Ok(())
+            }
+        })
+        .unwrap()
+    });
+
+    Reactor::block_on(async {
+        write_byte(NEXUS_NAME, 0x22).await;
+        verify_byte(NEXUS_NAME, 0x22).await;
+        nexus_lookup(NEXUS_NAME).unwrap().destroy().await.unwrap();
+    });
+
+    test_fini(NEXUS_DISK);
+}
@@ -1,4 +1,4 @@
-use std::{fs::metadata, sync::Mutex, time::Duration};
+use std::{fs::metadata, net::IpAddr, sync::Mutex, time::Duration};
 
 use common::ms_exec::run_test;
 use mayastor::{subsys, subsys::Config};
@@ -287,3 +287,51 @@ fn yaml_multi_maya() {
         "/tmp/second.yaml".into(),
     ])
 }
+
+#[test]
+// Listen addresses are read from the config file, interface names are
+// resolved into their addresses and mayastor starts listening on all of them.
+fn yaml_listen_addresses() {
+    common::delete_file(&["/tmp/listen.yaml".to_string()]);
+
+    let mut cfg = Config::default();
+    cfg.nexus_opts.listen_addresses =
+        vec!["127.0.0.1".into(), "::1".into(), "lo".into()];
+    cfg.write("/tmp/listen.yaml").unwrap();
+
+    let cfg = Config::read("/tmp/listen.yaml").unwrap();
+    let addresses = cfg.nexus_opts.listen_addresses().unwrap();
+
+    // the addresses of lo are already listed and are not added twice
+    assert_eq!(
+        addresses,
+        vec![
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            "::1".parse::<IpAddr>().unwrap()
+        ]
+    );
+
+    let mut unknown = Config::default();
+    unknown.nexus_opts.listen_addresses = vec!["nosuchif0".into()];
+    assert!(unknown.nexus_opts.listen_addresses().is_err());
+
+    let mut ipv4 = Config::default();
+    ipv4.nexus_opts.listen_addresses = vec!["127.0.0.1".into(), "lo".into()];
+    ipv4.write("/tmp/listen.yaml").unwrap();
+
+    let args = vec![
+        "-s".to_string(),
+        "128".into(),
+        "-y".into(),
+        "/tmp/listen.yaml".into(),
+    ];
+
+    run_test(Box::from(args), |ms| {
+        let out = ms
+            .rpc_call("nvmf_get_subsystems", serde_json::json!(null))
+            .unwrap();
+        assert_ne!(out.as_array().unwrap().len(), 0);
+    });
+
+    common::delete_file(&["/tmp/listen.yaml".to_string()]);
+}
@@ -31,6 +31,12 @@ stdenv.mkDerivation rec {
     fetchSubmodules = true;
   };
 
+  # changes needed by mayastor that are not in the fork yet
+  patches = [
+    ./crypto-key-length.patch
+    ./nvmf-reservation-ops.patch
+  ];
+
   buildInputs = [
     binutils
     libaio
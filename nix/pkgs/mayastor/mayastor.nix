@@ -51,7 +51,7 @@ rec {
 
   mayastor = rustPlatform.buildRustPackage rec {
     name = "mayastor";
-    cargoSha256 = "06zcprq4hvfpy8ikvxiqy4wy936h5ymnnkyzp6bkpqqq691xcrgf";
+    cargoSha256 = "0000000000000000000000000000000000000000000000000000";
     version = "0.1.1";
     src = if release then release-src else whitelistSource ../../../. [
       "Cargo.lock"
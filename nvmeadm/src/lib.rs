@@ -37,6 +37,7 @@ use std::{
     str::FromStr,
 };
 
+pub mod nvme_admin;
 pub mod nvme_namespaces;
 mod nvme_page;
 pub mod nvmf_discovery;
@@ -47,6 +48,10 @@ const NVME_FABRICS_PATH: &str = "/dev/nvme-fabrics";
 /// ioctl for passing any NVMe command to the kernel
 const NVME_ADMIN_CMD_IOCTL: u32 =
     iowr!(b'N', 0x41, std::mem::size_of::<NvmeAdminCmd>());
+/// ioctl returning the namespace id of a namespace block device
+const NVME_IOCTL_ID: u32 = io!(b'N', 0x40);
+/// ioctl for rescanning the namespaces of a controller
+const NVME_IOCTL_RESCAN: u32 = io!(b'N', 0x46);
 
 #[derive(Debug, Fail)]
 pub enum NvmeError {
@@ -58,6 +63,15 @@ pub enum NvmeError {
     CtlNotFound(String),
     #[fail(display = "no nvmf subsystems found")]
     NoSubsystems,
+    #[fail(
+        display = "admin command {:#04x} failed with status {:#x}",
+        opcode, status
+    )]
+    CmdFailed { opcode: u8, status: i32 },
+    #[fail(display = "{} log page is truncated", _0)]
+    InvalidLogPage(String),
+    #[fail(display = "{} not supported by the controller", _0)]
+    NotSupported(String),
 }
 impl From<io::Error> for NvmeError {
     fn from(err: io::Error) -> NvmeError {
@@ -0,0 +1,505 @@
+//! Typed NVMe admin commands issued through the kernel nvme driver to a
+//! connected controller (/dev/nvmeX) or to the controller behind a namespace
+//! block device (/dev/nvmeXnY). The data returned by the device is little
+//! endian and converted to native rust types.
+
+use crate::{
+    nvme_page::{
+        NvmeAdminCmd,
+        NvmeAnaGroupDesc,
+        NvmeAnaRspHdr,
+        NvmeErrorLogEntry,
+        NvmeIdCtrl,
+        NvmeIdNs,
+        NvmeSmartLog,
+    },
+    NvmeError,
+    NVME_ADMIN_CMD_IOCTL,
+    NVME_IOCTL_ID,
+    NVME_IOCTL_RESCAN,
+};
+use failure::Error;
+use nix::libc::ioctl as nix_ioctl;
+use num_traits::FromPrimitive;
+use std::{
+    fs::{File, OpenOptions},
+    mem::size_of,
+    os::unix::io::AsRawFd,
+};
+
+/// admin command opcodes, see NVM-Express-1_4 5
+const OPC_GET_LOG_PAGE: u8 = 0x02;
+const OPC_IDENTIFY: u8 = 0x06;
+
+/// controller or namespace structure returned by identify
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+
+/// log page identifiers
+const LID_ERROR: u32 = 0x01;
+const LID_SMART: u32 = 0x02;
+const LID_ANA: u32 = 0x0c;
+
+/// namespace id that addresses all namespaces of a controller
+const NSID_ALL: u32 = 0xffff_ffff;
+
+fn trim_ascii(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes)
+        .trim_end_matches('\0')
+        .trim()
+        .to_string()
+}
+
+/// the kernel reports the counters in the SMART log as 128 bit values
+fn le128(bytes: &[u8; 16]) -> u128 {
+    u128::from_le_bytes(*bytes)
+}
+
+/// Data returned by Identify Controller
+#[derive(Debug, Clone)]
+pub struct IdentifyController {
+    /// PCI vendor id
+    pub vid: u16,
+    /// PCI subsystem vendor id
+    pub ssvid: u16,
+    /// serial number
+    pub serial: String,
+    /// model number
+    pub model: String,
+    /// firmware revision
+    pub firmware_rev: String,
+    /// IEEE OUI identifier of the vendor
+    pub ieee_oui: u32,
+    /// id of the controller within the subsystem
+    pub cntlid: u16,
+    /// NVMe version supported, major in bits 31:16, minor in bits 15:8
+    pub version: u32,
+    /// the number of error log entries minus one
+    pub elpe: u8,
+    /// ANA capabilities, zero when ANA reporting is not supported
+    pub anacap: u8,
+    /// ANA transition time in seconds
+    pub anatt: u8,
+    /// the largest ANA group id
+    pub anagrpmax: u32,
+    /// number of ANA group ids supported
+    pub nanagrpid: u32,
+    /// the largest namespace id
+    pub nn: u32,
+    /// NVMe qualified name of the subsystem
+    pub subnqn: String,
+}
+
+impl From<&NvmeIdCtrl> for IdentifyController {
+    fn from(id: &NvmeIdCtrl) -> Self {
+        let subnqn: Vec<u8> = id.subnqn.iter().map(|c| *c as u8).collect();
+        IdentifyController {
+            vid: u16::from_le(id.vid),
+            ssvid: u16::from_le(id.ssvid),
+            serial: trim_ascii(&id.sn),
+            model: trim_ascii(&id.mn),
+            firmware_rev: trim_ascii(&id.fr),
+            ieee_oui: u32::from(id.ieee[0])
+                | u32::from(id.ieee[1]) << 8
+                | u32::from(id.ieee[2]) << 16,
+            cntlid: u16::from_le(id.cntlid),
+            version: u32::from_le(id.ver),
+            elpe: id.elpe,
+            anacap: id.anacap,
+            anatt: id.anatt,
+            anagrpmax: u32::from_le(id.anagrpmax),
+            nanagrpid: u32::from_le(id.nanagrpid),
+            nn: u32::from_le(id.nn),
+            subnqn: trim_ascii(&subnqn),
+        }
+    }
+}
+
+/// Data returned by Identify Namespace
+#[derive(Debug, Clone)]
+pub struct IdentifyNamespace {
+    /// the namespace id
+    pub nsid: u32,
+    /// size of the namespace in blocks
+    pub nsze: u64,
+    /// capacity of the namespace in blocks
+    pub ncap: u64,
+    /// blocks allocated to the namespace
+    pub nuse: u64,
+    /// size of a block in bytes of the formatted LBA format
+    pub lba_size: u64,
+    /// namespace multipath and sharing capabilities
+    pub nmic: u8,
+    /// the ANA group the namespace belongs to
+    pub anagrpid: u32,
+    /// namespace globally unique identifier
+    pub nguid: [u8; 16],
+    /// IEEE extended unique identifier
+    pub eui64: [u8; 8],
+}
+
+impl IdentifyNamespace {
+    fn new(nsid: u32, id: &NvmeIdNs) -> Self {
+        let lbaf = id.lbaf[usize::from(id.flbas & 0xf)];
+        IdentifyNamespace {
+            nsid,
+            nsze: u64::from_le(id.nsze),
+            ncap: u64::from_le(id.ncap),
+            nuse: u64::from_le(id.nuse),
+            lba_size: 1u64 << lbaf.ds,
+            nmic: id.nmic,
+            anagrpid: u32::from_le(id.anagrpid),
+            nguid: id.nguid,
+            eui64: id.eui64,
+        }
+    }
+
+    /// The NGUID in the format of an uuid, mayastor sets the NGUID of the
+    /// namespace of a nexus to the uuid of the nexus.
+    pub fn nguid_uuid(&self) -> uuid::Uuid {
+        uuid::Uuid::from_bytes(self.nguid)
+    }
+}
+
+/// Data returned by the SMART / Health Information log page
+#[derive(Debug, Clone)]
+pub struct SmartLog {
+    /// bit field of critical warnings, zero when there are none
+    pub critical_warning: u8,
+    /// composite temperature in Kelvin
+    pub temperature: u16,
+    /// available spare capacity in percent
+    pub avail_spare: u8,
+    /// threshold of the available spare in percent
+    pub spare_thresh: u8,
+    /// estimate of the life used in percent
+    pub percent_used: u8,
+    /// data read in units of 1000 blocks of 512 bytes
+    pub data_units_read: u128,
+    /// data written in units of 1000 blocks of 512 bytes
+    pub data_units_written: u128,
+    pub host_reads: u128,
+    pub host_writes: u128,
+    /// minutes the controller has been busy with IO
+    pub ctrl_busy_time: u128,
+    pub power_cycles: u128,
+    pub power_on_hours: u128,
+    pub unsafe_shutdowns: u128,
+    pub media_errors: u128,
+    pub num_err_log_entries: u128,
+}
+
+impl From<&NvmeSmartLog> for SmartLog {
+    fn from(log: &NvmeSmartLog) -> Self {
+        SmartLog {
+            critical_warning: log.critical_warning,
+            temperature: u16::from_le_bytes(log.temperature),
+            avail_spare: log.avail_spare,
+            spare_thresh: log.spare_thresh,
+            percent_used: log.percent_used,
+            data_units_read: le128(&log.data_units_read),
+            data_units_written: le128(&log.data_units_written),
+            host_reads: le128(&log.host_reads),
+            host_writes: le128(&log.host_writes),
+            ctrl_busy_time: le128(&log.ctrl_busy_time),
+            power_cycles: le128(&log.power_cycles),
+            power_on_hours: le128(&log.power_on_hours),
+            unsafe_shutdowns: le128(&log.unsafe_shutdowns),
+            media_errors: le128(&log.media_errors),
+            num_err_log_entries: le128(&log.num_err_log_entries),
+        }
+    }
+}
+
+/// Entry of the Error Information log page
+#[derive(Debug, Clone)]
+pub struct ErrorLogEntry {
+    /// unique and incrementing number of the error, zero for an unused entry
+    pub error_count: u64,
+    /// submission queue the command was submitted to
+    pub sqid: u16,
+    /// id of the command which failed
+    pub cmdid: u16,
+    /// status of the failed command
+    pub status_field: u16,
+    pub parm_error_location: u16,
+    /// first block of the failed command
+    pub lba: u64,
+    /// namespace of the failed command
+    pub nsid: u32,
+}
+
+impl From<&NvmeErrorLogEntry> for ErrorLogEntry {
+    fn from(e: &NvmeErrorLogEntry) -> Self {
+        ErrorLogEntry {
+            error_count: u64::from_le(e.error_count),
+            sqid: u16::from_le(e.sqid),
+            cmdid: u16::from_le(e.cmdid),
+            status_field: u16::from_le(e.status_field),
+            parm_error_location: u16::from_le(e.parm_error_location),
+            lba: u64::from_le(e.lba),
+            nsid: u32::from_le(e.nsid),
+        }
+    }
+}
+
+/// Asymmetric namespace access state of an ANA group
+#[derive(Debug, Clone, Copy, PartialEq, Primitive)]
+pub enum AnaState {
+    Optimized = 0x01,
+    NonOptimized = 0x02,
+    Inaccessible = 0x03,
+    PersistentLoss = 0x04,
+    Change = 0x0f,
+}
+
+/// ANA group descriptor of the ANA log page
+#[derive(Debug, Clone)]
+pub struct AnaGroup {
+    pub grpid: u32,
+    pub change_count: u64,
+    /// None for a state not defined by the specification
+    pub state: Option<AnaState>,
+    /// the namespaces in the group
+    pub nsids: Vec<u32>,
+}
+
+/// Data returned by the Asymmetric Namespace Access log page
+#[derive(Debug, Clone)]
+pub struct AnaLog {
+    pub change_count: u64,
+    pub groups: Vec<AnaGroup>,
+}
+
+impl AnaLog {
+    /// Parse the log page, which is made of the header followed by the group
+    /// descriptors, each of which is followed by its namespace ids.
+    fn parse(buf: &[u8]) -> Result<Self, Error> {
+        let truncated = || NvmeError::InvalidLogPage("ANA".into());
+        let hdr_len = size_of::<NvmeAnaRspHdr>();
+        let desc_len = size_of::<NvmeAnaGroupDesc>();
+
+        if buf.len() < hdr_len {
+            return Err(truncated().into());
+        }
+        let hdr = unsafe {
+            std::ptr::read_unaligned(buf.as_ptr() as *const NvmeAnaRspHdr)
+        };
+
+        let mut groups = Vec::new();
+        let mut offset = hdr_len;
+        for _ in 0 .. u16::from_le(hdr.ngrps) {
+            if buf.len() < offset + desc_len {
+                return Err(truncated().into());
+            }
+            let desc = unsafe {
+                std::ptr::read_unaligned(
+                    buf[offset ..].as_ptr() as *const NvmeAnaGroupDesc
+                )
+            };
+            offset += desc_len;
+
+            let nnsids = u32::from_le(desc.nnsids) as usize;
+            if buf.len() < offset + nnsids * 4 {
+                return Err(truncated().into());
+            }
+            let nsids = buf[offset .. offset + nnsids * 4]
+                .chunks(4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+            offset += nnsids * 4;
+
+            groups.push(AnaGroup {
+                grpid: u32::from_le(desc.grpid),
+                change_count: u64::from_le(desc.chgcnt),
+                state: AnaState::from_u8(desc.state & 0xf),
+                nsids,
+            });
+        }
+
+        Ok(AnaLog {
+            change_count: u64::from_le(hdr.chgcnt),
+            groups,
+        })
+    }
+}
+
+/// Handle to a controller or namespace device to issue admin commands to.
+///
+/// # Example
+/// ```rust
+/// use nvmeadm::nvme_admin::NvmeAdmin;
+///
+/// if let Ok(ctrl) = NvmeAdmin::open("/dev/nvme0") {
+///     let id = ctrl.identify_controller();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct NvmeAdmin {
+    path: String,
+    file: File,
+}
+
+impl NvmeAdmin {
+    /// open the controller or namespace device, only root can do this
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(NvmeAdmin {
+            path: path.into(),
+            file,
+        })
+    }
+
+    /// the path of the device
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Pass the command to the kernel and wait for it to complete. A failed
+    /// command returns its NVMe status.
+    fn submit(&self, cmd: &mut NvmeAdminCmd) -> Result<u32, Error> {
+        let status = unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                u64::from(NVME_ADMIN_CMD_IOCTL),
+                cmd as *mut NvmeAdminCmd
+            ))?
+        };
+        if status != 0 {
+            return Err(NvmeError::CmdFailed {
+                opcode: cmd.opcode,
+                status,
+            }
+            .into());
+        }
+        Ok(cmd.result)
+    }
+
+    /// Read the log page into the buffer, the size of which must be a
+    /// multiple of 4 bytes. With rae set the asynchronous event of the log
+    /// page is retained for the kernel to handle.
+    fn get_log_page(
+        &self,
+        lid: u32,
+        nsid: u32,
+        rae: bool,
+        buf: *mut u8,
+        len: usize,
+    ) -> Result<(), Error> {
+        let numd = (len as u32 >> 2) - 1;
+        let mut cmd = NvmeAdminCmd::default();
+
+        cmd.opcode = OPC_GET_LOG_PAGE;
+        cmd.nsid = nsid;
+        cmd.dptr = buf as u64;
+        cmd.dptr_len = len as u32;
+        cmd.cdw10 = lid | (numd & 0xffff) << 16;
+        if rae {
+            cmd.cdw10 |= 1 << 15;
+        }
+        cmd.cdw11 = numd >> 16;
+
+        self.submit(&mut cmd)?;
+        Ok(())
+    }
+
+    /// The id of the namespace of a namespace block device.
+    pub fn namespace_id(&self) -> Result<u32, Error> {
+        let nsid = unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                u64::from(NVME_IOCTL_ID)
+            ))?
+        };
+        Ok(nsid as u32)
+    }
+
+    pub fn identify_controller(&self) -> Result<IdentifyController, Error> {
+        let id = Box::new(NvmeIdCtrl::default());
+        let mut cmd = NvmeAdminCmd::default();
+
+        cmd.opcode = OPC_IDENTIFY;
+        cmd.dptr = &*id as *const _ as u64;
+        cmd.dptr_len = size_of::<NvmeIdCtrl>() as u32;
+        cmd.cdw10 = CNS_CONTROLLER;
+
+        self.submit(&mut cmd)?;
+        Ok(IdentifyController::from(&*id))
+    }
+
+    pub fn identify_namespace(
+        &self,
+        nsid: u32,
+    ) -> Result<IdentifyNamespace, Error> {
+        let id = Box::new(NvmeIdNs::default());
+        let mut cmd = NvmeAdminCmd::default();
+
+        cmd.opcode = OPC_IDENTIFY;
+        cmd.nsid = nsid;
+        cmd.dptr = &*id as *const _ as u64;
+        cmd.dptr_len = size_of::<NvmeIdNs>() as u32;
+        cmd.cdw10 = CNS_NAMESPACE;
+
+        self.submit(&mut cmd)?;
+        Ok(IdentifyNamespace::new(nsid, &id))
+    }
+
+    /// SMART / Health information of the controller as a whole
+    pub fn smart_log(&self) -> Result<SmartLog, Error> {
+        let mut log = Box::new(NvmeSmartLog::default());
+        self.get_log_page(
+            LID_SMART,
+            NSID_ALL,
+            false,
+            &mut *log as *mut _ as *mut u8,
+            size_of::<NvmeSmartLog>(),
+        )?;
+        Ok(SmartLog::from(&*log))
+    }
+
+    /// The entries of the error log which are in use, the most recent error
+    /// first.
+    pub fn error_log(&self) -> Result<Vec<ErrorLogEntry>, Error> {
+        let count = usize::from(self.identify_controller()?.elpe) + 1;
+        let mut entries = vec![NvmeErrorLogEntry::default(); count];
+        self.get_log_page(
+            LID_ERROR,
+            NSID_ALL,
+            false,
+            entries.as_mut_ptr() as *mut u8,
+            count * size_of::<NvmeErrorLogEntry>(),
+        )?;
+        Ok(entries
+            .iter()
+            .map(ErrorLogEntry::from)
+            .filter(|e| e.error_count != 0)
+            .collect())
+    }
+
+    /// The ANA state of the namespaces of the controller, as seen through
+    /// this path.
+    pub fn ana_log(&self) -> Result<AnaLog, Error> {
+        let id = self.identify_controller()?;
+        if id.anacap == 0 {
+            return Err(NvmeError::NotSupported("ANA reporting".into()).into());
+        }
+        let len = size_of::<NvmeAnaRspHdr>()
+            + id.nanagrpid as usize * size_of::<NvmeAnaGroupDesc>()
+            + id.nn as usize * 4;
+        let mut buf = vec![0u8; len];
+        self.get_log_page(LID_ANA, 0, true, buf.as_mut_ptr(), len)?;
+        AnaLog::parse(&buf)
+    }
+
+    /// Let the controller rescan its namespaces, which picks up namespaces
+    /// that were added or removed and a change of the size of a namespace.
+    pub fn rescan(&self) -> Result<(), Error> {
+        unsafe {
+            convert_ioctl_res!(nix_ioctl(
+                self.file.as_raw_fd(),
+                u64::from(NVME_IOCTL_RESCAN)
+            ))?
+        };
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use crate::parse_value;
+use crate::{nvme_admin::NvmeAdmin, parse_value};
 use failure::Error;
 use glob::glob;
 use std::{os::unix::fs::FileTypeExt, path::Path};
@@ -91,3 +91,30 @@ impl NvmeDeviceList {
         list
     }
 }
+
+/// Return the block devices of the namespaces with the NGUID, which is read
+/// from the namespaces with identify rather than taken from udev. With native
+/// multipath the paths to a namespace share a single block device.
+pub fn find_by_nguid(nguid: &str) -> Result<Vec<String>, Error> {
+    let nguid = uuid::Uuid::parse_str(nguid)?;
+    let mut devices = Vec::new();
+
+    for path in NvmeDeviceList::new().devices {
+        let dev = match NvmeAdmin::open(&path) {
+            Ok(dev) => dev,
+            Err(_) => continue,
+        };
+        let ns = match dev
+            .namespace_id()
+            .and_then(|nsid| dev.identify_namespace(nsid))
+        {
+            Ok(ns) => ns,
+            Err(_) => continue,
+        };
+        if ns.nguid_uuid() == nguid {
+            devices.push(path);
+        }
+    }
+    devices.sort();
+    Ok(devices)
+}
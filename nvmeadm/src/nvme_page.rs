@@ -121,3 +121,234 @@ pub struct nvmf_disc_rsp_page_entry_tsas_rdma {
     pub pkey: u16,
     pub resv10: [c_uchar; 246usize],
 }
+
+/// Identify Controller data structure, see NVM-Express-1_4 5.15.2.2
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct NvmeIdCtrl {
+    pub vid: u16,
+    pub ssvid: u16,
+    pub sn: [c_uchar; 20usize],
+    pub mn: [c_uchar; 40usize],
+    pub fr: [c_uchar; 8usize],
+    pub rab: c_uchar,
+    pub ieee: [c_uchar; 3usize],
+    pub cmic: c_uchar,
+    pub mdts: c_uchar,
+    pub cntlid: u16,
+    pub ver: u32,
+    pub rtd3r: u32,
+    pub rtd3e: u32,
+    pub oaes: u32,
+    pub ctratt: u32,
+    pub resv100: [c_uchar; 28usize],
+    pub crdt: [u16; 3usize],
+    pub resv134: [c_uchar; 122usize],
+    pub oacs: u16,
+    pub acl: c_uchar,
+    pub aerl: c_uchar,
+    pub frmw: c_uchar,
+    pub lpa: c_uchar,
+    pub elpe: c_uchar,
+    pub npss: c_uchar,
+    pub avscc: c_uchar,
+    pub apsta: c_uchar,
+    pub wctemp: u16,
+    pub cctemp: u16,
+    pub mtfa: u16,
+    pub hmpre: u32,
+    pub hmmin: u32,
+    pub tnvmcap: [c_uchar; 16usize],
+    pub unvmcap: [c_uchar; 16usize],
+    pub rpmbs: u32,
+    pub edstt: u16,
+    pub dsto: c_uchar,
+    pub fwug: c_uchar,
+    pub kas: u16,
+    pub hctma: u16,
+    pub mntmt: u16,
+    pub mxtmt: u16,
+    pub sanicap: u32,
+    pub hmminds: u32,
+    pub hmmaxd: u16,
+    pub resv338: [c_uchar; 4usize],
+    pub anatt: c_uchar,
+    pub anacap: c_uchar,
+    pub anagrpmax: u32,
+    pub nanagrpid: u32,
+    pub resv352: [c_uchar; 160usize],
+    pub sqes: c_uchar,
+    pub cqes: c_uchar,
+    pub maxcmd: u16,
+    pub nn: u32,
+    pub oncs: u16,
+    pub fuses: u16,
+    pub fna: c_uchar,
+    pub vwc: c_uchar,
+    pub awun: u16,
+    pub awupf: u16,
+    pub nvscc: c_uchar,
+    pub nwpc: c_uchar,
+    pub acwu: u16,
+    pub resv534: [c_uchar; 2usize],
+    pub sgls: u32,
+    pub mnan: u32,
+    pub resv544: [c_uchar; 224usize],
+    pub subnqn: [c_char; 256usize],
+    pub resv1024: [c_uchar; 768usize],
+    pub ioccsz: u32,
+    pub iorcsz: u32,
+    pub icdoff: u16,
+    pub ctrattr: c_uchar,
+    pub msdbd: c_uchar,
+    pub resv1804: [c_uchar; 244usize],
+    pub psd: [[c_uchar; 32usize]; 32usize],
+    pub vs: [c_uchar; 1024usize],
+}
+
+impl Default for NvmeIdCtrl {
+    fn default() -> Self {
+        NvmeIdCtrl {
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+}
+
+/// LBA format of a namespace
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NvmeLbaf {
+    pub ms: u16,
+    pub ds: c_uchar,
+    pub rp: c_uchar,
+}
+
+/// Identify Namespace data structure, see NVM-Express-1_4 6.1.5
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct NvmeIdNs {
+    pub nsze: u64,
+    pub ncap: u64,
+    pub nuse: u64,
+    pub nsfeat: c_uchar,
+    pub nlbaf: c_uchar,
+    pub flbas: c_uchar,
+    pub mc: c_uchar,
+    pub dpc: c_uchar,
+    pub dps: c_uchar,
+    pub nmic: c_uchar,
+    pub rescap: c_uchar,
+    pub fpi: c_uchar,
+    pub dlfeat: c_uchar,
+    pub nawun: u16,
+    pub nawupf: u16,
+    pub nacwu: u16,
+    pub nabsn: u16,
+    pub nabo: u16,
+    pub nabspf: u16,
+    pub noiob: u16,
+    pub nvmcap: [c_uchar; 16usize],
+    pub npwg: u16,
+    pub npwa: u16,
+    pub npdg: u16,
+    pub npda: u16,
+    pub nows: u16,
+    pub resv74: [c_uchar; 18usize],
+    pub anagrpid: u32,
+    pub resv96: [c_uchar; 3usize],
+    pub nsattr: c_uchar,
+    pub nvmsetid: u16,
+    pub endgid: u16,
+    pub nguid: [c_uchar; 16usize],
+    pub eui64: [c_uchar; 8usize],
+    pub lbaf: [NvmeLbaf; 16usize],
+    pub resv192: [c_uchar; 192usize],
+    pub vs: [c_uchar; 3712usize],
+}
+
+impl Default for NvmeIdNs {
+    fn default() -> Self {
+        NvmeIdNs {
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+}
+
+/// SMART / Health Information log page, see NVM-Express-1_4 5.14.1.2
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct NvmeSmartLog {
+    pub critical_warning: c_uchar,
+    pub temperature: [c_uchar; 2usize],
+    pub avail_spare: c_uchar,
+    pub spare_thresh: c_uchar,
+    pub percent_used: c_uchar,
+    pub endu_grp_crit_warn_sumry: c_uchar,
+    pub resv7: [c_uchar; 25usize],
+    pub data_units_read: [c_uchar; 16usize],
+    pub data_units_written: [c_uchar; 16usize],
+    pub host_reads: [c_uchar; 16usize],
+    pub host_writes: [c_uchar; 16usize],
+    pub ctrl_busy_time: [c_uchar; 16usize],
+    pub power_cycles: [c_uchar; 16usize],
+    pub power_on_hours: [c_uchar; 16usize],
+    pub unsafe_shutdowns: [c_uchar; 16usize],
+    pub media_errors: [c_uchar; 16usize],
+    pub num_err_log_entries: [c_uchar; 16usize],
+    pub warning_temp_time: u32,
+    pub critical_comp_time: u32,
+    pub temp_sensor: [u16; 8usize],
+    pub thm_temp1_trans_count: u32,
+    pub thm_temp2_trans_count: u32,
+    pub thm_temp1_total_time: u32,
+    pub thm_temp2_total_time: u32,
+    pub resv232: [c_uchar; 280usize],
+}
+
+impl Default for NvmeSmartLog {
+    fn default() -> Self {
+        NvmeSmartLog {
+            ..unsafe { std::mem::zeroed() }
+        }
+    }
+}
+
+/// Entry of the Error Information log page, see NVM-Express-1_4 5.14.1.1
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NvmeErrorLogEntry {
+    pub error_count: u64,
+    pub sqid: u16,
+    pub cmdid: u16,
+    pub status_field: u16,
+    pub parm_error_location: u16,
+    pub lba: u64,
+    pub nsid: u32,
+    pub vs: c_uchar,
+    pub trtype: c_uchar,
+    pub resv30: [c_uchar; 2usize],
+    pub cs: u64,
+    pub trtype_spec_info: u16,
+    pub resv42: [c_uchar; 22usize],
+}
+
+/// Header of the ANA log page, see NVM-Express-1_4 5.14.1.12
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NvmeAnaRspHdr {
+    pub chgcnt: u64,
+    pub ngrps: u16,
+    pub resv10: [u16; 3usize],
+}
+
+/// ANA group descriptor of the ANA log page, followed by the ids of the
+/// namespaces in the group
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NvmeAnaGroupDesc {
+    pub grpid: u32,
+    pub nnsids: u32,
+    pub chgcnt: u64,
+    pub state: c_uchar,
+    pub resv17: [c_uchar; 15usize],
+}
@@ -1,4 +1,4 @@
-use crate::{parse_value, NvmeError};
+use crate::{nvme_admin::NvmeAdmin, parse_value, NvmeError};
 use failure::Error;
 use glob::glob;
 use std::{fs::OpenOptions, io::Write, path::Path};
@@ -81,6 +81,10 @@ impl Subsystem {
         file.write_all(b"1")?;
         Ok(())
     }
+    /// open the controller for issuing admin commands
+    pub fn admin(&self) -> Result<NvmeAdmin, Error> {
+        NvmeAdmin::open(&format!("/dev/{}", self.name))
+    }
     /// resets the nvme controller
     pub fn reset(&self) -> Result<(), Error> {
         let target = format!("/sys/class/nvme/{}/reset_controller", self.name);
@@ -1,4 +1,8 @@
-use nvmeadm::nvmf_discovery::{disconnect, DiscoveryBuilder};
+use nvmeadm::{
+    nvme_namespaces,
+    nvmf_discovery::{disconnect, DiscoveryBuilder},
+    nvmf_subsystem::NvmeSubsystems,
+};
 
 use failure::Error;
 use std::{
@@ -194,6 +198,40 @@ fn test_against_real_target() {
         .connect(SERVED_DISK_NQN)
         .expect("Problem connecting to valid target");
 
+    // Check the admin commands on the connected controller
+    let ctrl = NvmeSubsystems::new()
+        .unwrap()
+        .filter_map(Result::ok)
+        .find(|s| s.nqn == SERVED_DISK_NQN)
+        .expect("No controller connected to served target")
+        .admin()
+        .unwrap();
+
+    let id = ctrl.identify_controller().unwrap();
+    assert_eq!(id.serial, "MAYASTOR0000000001");
+    assert_eq!(id.model, "NEXUSController1");
+    assert_eq!(id.subnqn, SERVED_DISK_NQN);
+
+    let ns = ctrl.identify_namespace(1).unwrap();
+    assert_eq!(ns.lba_size, 4096);
+    assert_eq!(ns.nsze * ns.lba_size, 64 * 1024 * 1024);
+
+    ctrl.smart_log().unwrap();
+    ctrl.error_log().unwrap();
+    ctrl.rescan().unwrap();
+
+    // The namespace block device shows up after the controller
+    let nguid = ns.nguid_uuid().to_string();
+    let mut devices = Vec::new();
+    for _ in 0 .. 20 {
+        devices = nvme_namespaces::find_by_nguid(&nguid).unwrap();
+        if !devices.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(devices.len(), 1);
+
     // Check that we CAN disconnect from a served NQN
     let num_disconnects = disconnect(SERVED_DISK_NQN);
     assert_eq!(num_disconnects.unwrap(), 1);
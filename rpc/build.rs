@@ -5,6 +5,26 @@ fn main() {
     tonic_build::configure()
         .build_server(true)
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        // allow json clients to omit fields that were added later on
+        .field_attribute("CreateNexusRequest.layout", "#[serde(default)]")
+        .field_attribute("CreateNexusRequest.cache", "#[serde(default)]")
+        .field_attribute("CreateNexusRequest.cache_mode", "#[serde(default)]")
+        .field_attribute("Nexus.layout", "#[serde(default)]")
+        .field_attribute("Nexus.async_children", "#[serde(default)]")
+        .field_attribute("Nexus.shares", "#[serde(default)]")
+        .field_attribute("Nexus.key_rotation", "#[serde(default)]")
+        .field_attribute("Replica.snapshot", "#[serde(default)]")
+        .field_attribute("PublishNexusRequest.ana_state", "#[serde(default)]")
+        .field_attribute("PublishNexusRequest.key", "#[serde(default)]")
+        .field_attribute("PublishNexusRequest.key_id", "#[serde(default)]")
+        .field_attribute(
+            "UnpublishNexusRequest.protocols",
+            "#[serde(default)]",
+        )
+        .field_attribute(
+            "AddChildNexusRequest.asynchronous",
+            "#[serde(default)]",
+        )
         .compile(&["proto/mayastor.proto"], &["proto"])
         .unwrap_or_else(|e| {
             panic!("mayastor protobuf compilation failed: {}", e)
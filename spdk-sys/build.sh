@@ -8,6 +8,16 @@ pushd spdk || { echo "Can not find spdk directory"; exit; }
 
 [ ! -d dpdk/.git ] || { echo "Submodules not checked out?"; exit; }
 
+# the patches applied by nix, unless they have been applied already
+for patch in ../../nix/pkgs/libspdk/*.patch; do
+	if git apply --reverse --check "$patch" 2>/dev/null; then
+		echo "$patch has been applied already"
+	elif ! git apply "$patch"; then
+		echo "Can not apply $patch"
+		exit 1
+	fi
+done
+
 
 ./configure --enable-debug \
 	--target-arch=nehalem \
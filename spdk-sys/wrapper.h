@@ -29,6 +29,7 @@
 #include <spdk/thread.h>
 #include <spdk/uuid.h>
 #include <spdk/version.h>
+#include <spdk/vhost.h>
 #include <spdk_internal/event.h>
 #include <spdk_internal/thread.h>
 #include <spdk_internal/lvolstore.h>